@@ -1,9 +1,9 @@
 use std::collections::{HashMap, HashSet};
 
-use bitcoin::{Transaction, Txid, Wtxid};
+use bitcoin::{p2p::message_blockdata::Inventory, Block, OutPoint, Transaction, Txid, Wtxid};
 use tokio::sync::oneshot;
 
-use crate::Package;
+use crate::{error::BroadcastError, messages::RejectPayload, Package, TxBroadcastPolicy};
 
 #[derive(Debug)]
 pub(crate) struct BroadcastQueue {
@@ -13,12 +13,13 @@ pub(crate) struct BroadcastQueue {
     // Notify the user when:
     // 1. a singleton transaction was broadcast
     // 2. the final transaction in a package was broadcast
-    callbacks: HashMap<Wtxid, (oneshot::Sender<Wtxid>, Wtxid)>,
+    callbacks: HashMap<Wtxid, (BroadcastCallback, Wtxid)>,
     // These transactions will be fetched by the usual `Wtxid`.
     witness_data: HashMap<Wtxid, Transaction>,
     // These transactions represent missing inputs to a previously broadcast transaction. Because
     // the inputs use the legacy `Txid` in the outpoint, these transactions are indexed by `Txid`.
     legacy_data: HashMap<Txid, Transaction>,
+    policy: TxBroadcastPolicy,
 }
 
 impl BroadcastQueue {
@@ -28,10 +29,23 @@ impl BroadcastQueue {
             callbacks: HashMap::new(),
             witness_data: HashMap::new(),
             legacy_data: HashMap::new(),
+            policy: TxBroadcastPolicy::default(),
         }
     }
 
-    pub(crate) fn add_to_queue(&mut self, package: Package, oneshot: oneshot::Sender<Wtxid>) {
+    pub(crate) fn set_policy(&mut self, policy: TxBroadcastPolicy) {
+        self.policy = match policy {
+            TxBroadcastPolicy::NPeers(peers) => TxBroadcastPolicy::NPeers(peers.max(1)),
+            policy => policy,
+        };
+    }
+
+    pub(crate) fn policy(&self) -> TxBroadcastPolicy {
+        self.policy
+    }
+
+    pub(crate) fn add_to_queue(&mut self, package: Package, oneshot: impl Into<BroadcastCallback>) {
+        let oneshot = oneshot.into();
         let advertise_wtxid = package.advertise_package();
         self.advertise.insert(advertise_wtxid);
         let parent = package.parent();
@@ -62,16 +76,259 @@ impl BroadcastQueue {
         }
     }
 
+    // Find the transactions a peer requested with a `getdata`, marking each as sent. Requested
+    // transactions that are not in the queue are returned to be answered with a `notfound`.
+    pub(crate) fn answer_getdata(
+        &mut self,
+        requests: Vec<Inventory>,
+    ) -> (Vec<Transaction>, Vec<Inventory>) {
+        let mut transactions = Vec::new();
+        let mut not_found = Vec::new();
+        for inv in requests {
+            let transaction = match inv {
+                Inventory::WTx(wtxid) => self.fetch_tx(wtxid),
+                Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) => {
+                    self.fetch_tx(txid)
+                }
+                _ => continue,
+            };
+            match transaction {
+                Some(transaction) => {
+                    self.sent_transaction_payload(transaction.compute_wtxid());
+                    transactions.push(transaction);
+                }
+                None => not_found.push(inv),
+            }
+        }
+        (transactions, not_found)
+    }
+
     pub(crate) fn sent_transaction_payload(&mut self, wtxid: Wtxid) {
         if let Some((callback, child)) = self.callbacks.remove(&wtxid) {
-            self.advertise.remove(&child);
-            let _ = callback.send(child);
+            // Persistent transactions are announced until they are confirmed or cancelled
+            if self.policy != TxBroadcastPolicy::Persistent {
+                self.advertise.remove(&child);
+            }
+            match callback {
+                BroadcastCallback::Sent(oneshot) => {
+                    let _ = oneshot.send(child);
+                }
+                BroadcastCallback::Accepted(oneshot) => {
+                    let _ = oneshot.send(Ok(child));
+                }
+            }
+        }
+    }
+
+    // A peer rejected a transaction. If the user is waiting on the result, the package is removed
+    // from the queue and the user is notified.
+    pub(crate) fn rejected(&mut self, payload: RejectPayload) {
+        let Some(parent_wtxid) = self
+            .callbacks
+            .iter()
+            .find(|(parent, (callback, child))| {
+                matches!(callback, BroadcastCallback::Accepted(_))
+                    && (payload.wtxid.eq(*parent) || payload.wtxid.eq(child))
+            })
+            .map(|(parent, _)| *parent)
+        else {
+            return;
+        };
+        let Some((BroadcastCallback::Accepted(oneshot), child)) =
+            self.callbacks.remove(&parent_wtxid)
+        else {
+            return;
+        };
+        if let Some(parent) = self
+            .legacy_data
+            .values()
+            .find(|tx| tx.compute_wtxid().eq(&parent_wtxid))
+            .map(|tx| tx.compute_txid())
+        {
+            self.legacy_data.remove(&parent);
+        }
+        self.remove_wtxid(parent_wtxid);
+        self.remove_wtxid(child);
+        let _ = oneshot.send(Err(BroadcastError::Rejected(payload)));
+    }
+
+    fn remove_wtxid(&mut self, wtxid: Wtxid) {
+        self.advertise.remove(&wtxid);
+        self.witness_data.remove(&wtxid);
+    }
+
+    // Stop announcing a transaction, along with the parent of a package.
+    pub(crate) fn cancel(&mut self, wtxid: Wtxid) -> bool {
+        let Some(transaction) = self.witness_data.remove(&wtxid) else {
+            return false;
+        };
+        self.advertise.remove(&wtxid);
+        self.callbacks
+            .retain(|parent, (_, child)| parent.ne(&wtxid) && (*child).ne(&wtxid));
+        for input in &transaction.input {
+            if let Some(parent) = self.legacy_data.remove(&input.previous_output.txid) {
+                self.callbacks.remove(&parent.compute_wtxid());
+            }
+        }
+        true
+    }
+
+    // Remove any pending transactions that were confirmed in this block, along with the rest of
+    // their package, returning the `Wtxid` of each confirmed transaction.
+    pub(crate) fn confirmed(&mut self, block: &Block) -> Vec<Wtxid> {
+        let mut confirmed = Vec::new();
+        for tx in block.txdata.iter().skip(1) {
+            let txid = tx.compute_txid();
+            if let Some(wtxid) = self
+                .witness_data
+                .iter()
+                .find(|(_, pending)| pending.compute_txid().eq(&txid))
+                .map(|(wtxid, _)| *wtxid)
+                .or_else(|| self.legacy_data.get(&txid).map(|tx| tx.compute_wtxid()))
+            {
+                self.remove_package(txid, wtxid);
+                confirmed.push(wtxid);
+            }
+        }
+        confirmed
+    }
+
+    // Remove a transaction and the other member of its package. Only the parent of a package is
+    // indexed by `Txid`, and the child is found by the inputs that spend it.
+    fn remove_package(&mut self, txid: Txid, wtxid: Wtxid) {
+        let mut package = vec![(txid, wtxid)];
+        let related = if self.legacy_data.contains_key(&txid) {
+            self.witness_data
+                .values()
+                .filter(|child| {
+                    child
+                        .input
+                        .iter()
+                        .any(|input| input.previous_output.txid.eq(&txid))
+                })
+                .map(|child| (child.compute_txid(), child.compute_wtxid()))
+                .collect::<Vec<_>>()
+        } else {
+            self.witness_data
+                .get(&wtxid)
+                .into_iter()
+                .flat_map(|child| &child.input)
+                .filter_map(|input| self.legacy_data.get(&input.previous_output.txid))
+                .map(|parent| (parent.compute_txid(), parent.compute_wtxid()))
+                .collect()
+        };
+        package.extend(related);
+        self.callbacks.retain(|parent, (_, child)| {
+            !package
+                .iter()
+                .any(|(_, wtxid)| wtxid.eq(parent) || wtxid.eq(child))
+        });
+        for (txid, wtxid) in package {
+            self.remove(txid, wtxid);
         }
     }
 
     pub(crate) fn pending_wtxid(&self) -> Vec<Wtxid> {
         self.advertise.iter().copied().collect()
     }
+
+    // Find any pending transactions that spend the same inputs as a transaction in this block,
+    // removing them from the queue. Transactions that depend on a conflicted transaction are
+    // removed as well.
+    pub(crate) fn remove_conflicts(&mut self, block: &Block) -> Vec<BroadcastConflict> {
+        let spends: HashMap<OutPoint, (Txid, Wtxid)> = self
+            .witness_data
+            .values()
+            .chain(self.legacy_data.values())
+            .flat_map(|tx| {
+                let ids = (tx.compute_txid(), tx.compute_wtxid());
+                tx.input
+                    .iter()
+                    .map(move |input| (input.previous_output, ids))
+            })
+            .collect();
+        if spends.is_empty() {
+            return Vec::new();
+        }
+        let mut conflicts = Vec::new();
+        for tx in block.txdata.iter().skip(1) {
+            let txid = tx.compute_txid();
+            for input in &tx.input {
+                if let Some((pending_txid, wtxid)) = spends.get(&input.previous_output) {
+                    if pending_txid.ne(&txid)
+                        && !conflicts
+                            .iter()
+                            .any(|c: &BroadcastConflict| c.wtxid.eq(wtxid))
+                    {
+                        conflicts.push(BroadcastConflict {
+                            txid: *pending_txid,
+                            wtxid: *wtxid,
+                            conflicting_txid: txid,
+                        });
+                    }
+                }
+            }
+        }
+        // Descendants of a conflicted transaction can never be mined either
+        let mut index = 0;
+        while index < conflicts.len() {
+            let BroadcastConflict {
+                txid,
+                conflicting_txid,
+                ..
+            } = conflicts[index];
+            for (outpoint, (child_txid, child_wtxid)) in &spends {
+                if outpoint.txid.eq(&txid) && !conflicts.iter().any(|c| c.wtxid.eq(child_wtxid)) {
+                    conflicts.push(BroadcastConflict {
+                        txid: *child_txid,
+                        wtxid: *child_wtxid,
+                        conflicting_txid,
+                    });
+                }
+            }
+            index += 1;
+        }
+        for conflict in &conflicts {
+            self.remove(conflict.txid, conflict.wtxid);
+        }
+        conflicts
+    }
+
+    fn remove(&mut self, txid: Txid, wtxid: Wtxid) {
+        self.advertise.remove(&wtxid);
+        self.callbacks.remove(&wtxid);
+        self.witness_data.remove(&wtxid);
+        self.legacy_data.remove(&txid);
+    }
+}
+
+// How to notify the user of a transaction being sent to a peer.
+#[derive(Debug)]
+pub(crate) enum BroadcastCallback {
+    // A peer requested the transaction
+    Sent(oneshot::Sender<Wtxid>),
+    // A peer requested the transaction, or a peer rejected it
+    Accepted(oneshot::Sender<Result<Wtxid, BroadcastError>>),
+}
+
+impl From<oneshot::Sender<Wtxid>> for BroadcastCallback {
+    fn from(value: oneshot::Sender<Wtxid>) -> Self {
+        Self::Sent(value)
+    }
+}
+
+impl From<oneshot::Sender<Result<Wtxid, BroadcastError>>> for BroadcastCallback {
+    fn from(value: oneshot::Sender<Result<Wtxid, BroadcastError>>) -> Self {
+        Self::Accepted(value)
+    }
+}
+
+// A pending transaction that can no longer be mined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BroadcastConflict {
+    pub(crate) txid: Txid,
+    pub(crate) wtxid: Wtxid,
+    pub(crate) conflicting_txid: Txid,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, std::hash::Hash)]
@@ -96,10 +353,15 @@ impl From<Wtxid> for TxIdentifier {
 mod tests {
     use std::fs::File;
 
-    use bitcoin::Transaction;
+    use bitcoin::{
+        constants::genesis_block, p2p::message_blockdata::Inventory, Network, OutPoint,
+        Transaction, Wtxid,
+    };
     use corepc_node::serde_json;
 
-    use super::BroadcastQueue;
+    use crate::{error::BroadcastError, messages::RejectPayload, Package, TxBroadcastPolicy};
+
+    use super::{BroadcastConflict, BroadcastQueue};
 
     #[derive(Debug, Clone)]
     struct HexTx(Transaction);
@@ -118,9 +380,9 @@ mod tests {
         let transaction_1: Transaction = tx_data.transactions[0].clone().0;
         let transaction_2: Transaction = tx_data.transactions[1].clone().0;
         let mut queue = BroadcastQueue::new();
-        let (tx, _) = tokio::sync::oneshot::channel();
+        let (tx, _) = tokio::sync::oneshot::channel::<Wtxid>();
         queue.add_to_queue(transaction_1.clone().into(), tx);
-        let (tx, _) = tokio::sync::oneshot::channel();
+        let (tx, _) = tokio::sync::oneshot::channel::<Wtxid>();
         queue.add_to_queue(transaction_2.clone().into(), tx);
         assert_eq!(queue.pending_wtxid().len(), 2);
         queue.sent_transaction_payload(transaction_1.compute_wtxid());
@@ -130,4 +392,165 @@ mod tests {
         queue.sent_transaction_payload(transaction_2.compute_wtxid());
         assert_eq!(queue.pending_wtxid().len(), 0);
     }
+
+    #[test]
+    fn test_broadcast_conflicts_removed() {
+        let tx_file = File::open("./tests/data/transactions.json").unwrap();
+        let tx_data: TransactionFile = serde_json::from_reader(&tx_file).unwrap();
+        let transaction_1: Transaction = tx_data.transactions[0].clone().0;
+        let transaction_2: Transaction = tx_data.transactions[1].clone().0;
+        let mut queue = BroadcastQueue::new();
+        let (tx, _) = tokio::sync::oneshot::channel::<Wtxid>();
+        queue.add_to_queue(transaction_1.clone().into(), tx);
+        let (tx, _) = tokio::sync::oneshot::channel::<Wtxid>();
+        queue.add_to_queue(transaction_2.clone().into(), tx);
+        // Spend the same input as the first transaction to a different output
+        let mut replacement = transaction_1.clone();
+        replacement.output.pop();
+        let mut block = genesis_block(Network::Regtest);
+        let confirmed = block.txdata.first().cloned().unwrap();
+        assert!(queue.remove_conflicts(&block).is_empty());
+        // The pending transaction itself being mined is not a conflict
+        block.txdata = vec![confirmed.clone(), transaction_1.clone()];
+        assert!(queue.remove_conflicts(&block).is_empty());
+        block.txdata = vec![confirmed, replacement.clone()];
+        let conflicts = queue.remove_conflicts(&block);
+        assert_eq!(
+            conflicts,
+            vec![BroadcastConflict {
+                txid: transaction_1.compute_txid(),
+                wtxid: transaction_1.compute_wtxid(),
+                conflicting_txid: replacement.compute_txid(),
+            }]
+        );
+        assert_eq!(queue.pending_wtxid(), vec![transaction_2.compute_wtxid()]);
+        assert!(queue.fetch_tx(transaction_1.compute_wtxid()).is_none());
+        assert!(queue.remove_conflicts(&block).is_empty());
+    }
+
+    #[test]
+    fn test_persistent_until_confirmed() {
+        let tx_file = File::open("./tests/data/transactions.json").unwrap();
+        let tx_data: TransactionFile = serde_json::from_reader(&tx_file).unwrap();
+        let transaction_1: Transaction = tx_data.transactions[0].clone().0;
+        let transaction_2: Transaction = tx_data.transactions[1].clone().0;
+        let mut queue = BroadcastQueue::new();
+        queue.set_policy(TxBroadcastPolicy::Persistent);
+        let (tx, _) = tokio::sync::oneshot::channel::<Wtxid>();
+        queue.add_to_queue(transaction_1.clone().into(), tx);
+        let (tx, _) = tokio::sync::oneshot::channel::<Wtxid>();
+        queue.add_to_queue(transaction_2.clone().into(), tx);
+        // Requested transactions are still announced to new peers
+        queue.sent_transaction_payload(transaction_1.compute_wtxid());
+        assert_eq!(queue.pending_wtxid().len(), 2);
+        let mut block = genesis_block(Network::Regtest);
+        block.txdata.push(transaction_1.clone());
+        assert_eq!(queue.confirmed(&block), vec![transaction_1.compute_wtxid()]);
+        assert_eq!(queue.pending_wtxid(), vec![transaction_2.compute_wtxid()]);
+        assert!(queue.confirmed(&block).is_empty());
+        assert!(queue.cancel(transaction_2.compute_wtxid()));
+        assert!(!queue.cancel(transaction_2.compute_wtxid()));
+        assert!(queue.pending_wtxid().is_empty());
+    }
+
+    #[test]
+    fn test_n_peers_until_sent() {
+        let tx_file = File::open("./tests/data/transactions.json").unwrap();
+        let tx_data: TransactionFile = serde_json::from_reader(&tx_file).unwrap();
+        let transaction_1: Transaction = tx_data.transactions[0].clone().0;
+        let mut queue = BroadcastQueue::new();
+        queue.set_policy(TxBroadcastPolicy::NPeers(3));
+        let (tx, _) = tokio::sync::oneshot::channel::<Wtxid>();
+        queue.add_to_queue(transaction_1.clone().into(), tx);
+        assert_eq!(queue.pending_wtxid(), vec![transaction_1.compute_wtxid()]);
+        // Announcing to several peers stops once the transaction is requested
+        queue.sent_transaction_payload(transaction_1.compute_wtxid());
+        assert!(queue.pending_wtxid().is_empty());
+        // A transaction is always announced to at least one peer
+        queue.set_policy(TxBroadcastPolicy::NPeers(0));
+        assert_eq!(queue.policy(), TxBroadcastPolicy::NPeers(1));
+    }
+
+    #[test]
+    fn test_confirmed_package_removed() {
+        let tx_file = File::open("./tests/data/transactions.json").unwrap();
+        let tx_data: TransactionFile = serde_json::from_reader(&tx_file).unwrap();
+        let parent: Transaction = tx_data.transactions[0].clone().0;
+        let mut child: Transaction = tx_data.transactions[1].clone().0;
+        child.input[0].previous_output = OutPoint {
+            txid: parent.compute_txid(),
+            vout: 0,
+        };
+        let package = Package::new_one_parent_one_child(parent.clone(), child.clone()).unwrap();
+        // Either member confirming removes the whole package
+        for member in [&parent, &child] {
+            let mut queue = BroadcastQueue::new();
+            let (tx, _) = tokio::sync::oneshot::channel::<Wtxid>();
+            queue.add_to_queue(package.clone(), tx);
+            let mut block = genesis_block(Network::Regtest);
+            block.txdata.push(member.clone());
+            assert_eq!(queue.confirmed(&block), vec![member.compute_wtxid()]);
+            assert!(queue.pending_wtxid().is_empty());
+            assert!(queue.callbacks.is_empty());
+            assert!(queue.fetch_tx(parent.compute_txid()).is_none());
+            assert!(queue.fetch_tx(child.compute_wtxid()).is_none());
+        }
+    }
+
+    #[test]
+    fn test_rejection_resolves_callback() {
+        let tx_file = File::open("./tests/data/transactions.json").unwrap();
+        let tx_data: TransactionFile = serde_json::from_reader(&tx_file).unwrap();
+        let transaction_1: Transaction = tx_data.transactions[0].clone().0;
+        let transaction_2: Transaction = tx_data.transactions[1].clone().0;
+        let mut queue = BroadcastQueue::new();
+        let (tx, _) = tokio::sync::oneshot::channel::<Wtxid>();
+        queue.add_to_queue(transaction_1.clone().into(), tx);
+        let (tx, mut rx) = tokio::sync::oneshot::channel::<Result<Wtxid, BroadcastError>>();
+        queue.add_to_queue(transaction_2.clone().into(), tx);
+        let payload = RejectPayload {
+            reason: None,
+            wtxid: transaction_1.compute_wtxid(),
+        };
+        // Without a confirmation, other peers may still accept the transaction
+        queue.rejected(payload);
+        assert_eq!(queue.pending_wtxid().len(), 2);
+        let payload = RejectPayload {
+            reason: None,
+            wtxid: transaction_2.compute_wtxid(),
+        };
+        queue.rejected(payload);
+        assert_eq!(queue.pending_wtxid(), vec![transaction_1.compute_wtxid()]);
+        assert!(queue.fetch_tx(transaction_2.compute_wtxid()).is_none());
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Err(BroadcastError::Rejected(_)))
+        ));
+    }
+
+    #[test]
+    fn test_getdata_answered() {
+        let tx_file = File::open("./tests/data/transactions.json").unwrap();
+        let tx_data: TransactionFile = serde_json::from_reader(&tx_file).unwrap();
+        let transaction_1: Transaction = tx_data.transactions[0].clone().0;
+        let transaction_2: Transaction = tx_data.transactions[1].clone().0;
+        let mut queue = BroadcastQueue::new();
+        let (tx, mut rx) = tokio::sync::oneshot::channel::<Wtxid>();
+        queue.add_to_queue(transaction_1.clone().into(), tx);
+        // The transaction is announced with an `inv`
+        let announced = queue.pending_wtxid();
+        assert_eq!(announced, vec![transaction_1.compute_wtxid()]);
+        // The peer requests it, along with a transaction we never announced
+        let unknown = Inventory::WTx(transaction_2.compute_wtxid());
+        let requests = vec![
+            Inventory::WTx(announced[0]),
+            unknown,
+            Inventory::Block(genesis_block(Network::Regtest).block_hash()),
+        ];
+        let (transactions, not_found) = queue.answer_getdata(requests);
+        assert_eq!(transactions, vec![transaction_1.clone()]);
+        assert_eq!(not_found, vec![unknown]);
+        assert_eq!(rx.try_recv().unwrap(), transaction_1.compute_wtxid());
+        assert!(queue.pending_wtxid().is_empty());
+    }
 }
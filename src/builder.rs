@@ -1,15 +1,25 @@
-use std::{path::PathBuf, time::Duration};
+use std::{future::Future, path::PathBuf, sync::Arc, time::Duration};
 
-use bitcoin::Network;
+use bitcoin::{
+    block::Header,
+    p2p::{Magic, ServiceFlags},
+    Network,
+};
 
 use super::{client::Client, node::Node};
-use crate::chain::ChainState;
+use crate::chain::{graph::MIN_PRUNE_DEPTH, ChainState, MAX_FILTER_BATCH_SIZE};
 use crate::network::ConnectionType;
-use crate::{BlockType, Config, FilterType};
+use crate::{
+    BlockSource, BlockType, Config, DnsResolver, FilterHeaderCheckpoint, FilterType,
+    HashCheckpoint, NodeState, StaleTipStrategy, TxBroadcastPolicy,
+};
 use crate::{Socks5Proxy, TrustedPeer};
 
 const MIN_PEERS: u8 = 1;
 const MAX_PEERS: u8 = 15;
+const MIN_PIPELINE_DEPTH: u8 = 1;
+const MAX_PIPELINE_DEPTH: u8 = 8;
+const MAX_RECONNECT_JITTER: Duration = Duration::from_secs(5 * 60);
 
 /// Build a [`Node`] in an additive way.
 ///
@@ -56,6 +66,71 @@ impl Builder {
         self
     }
 
+    /// Only complete connections that are encrypted with the BIP-324 V2 transport. The encrypted
+    /// handshake is attempted with every peer, including those that do not signal for it and
+    /// those reached over a proxy, and a peer that does not complete it is disconnected instead
+    /// of falling back to plaintext. The transport of each peer is reported in
+    /// [`Event::PeerConnected`](crate::Event::PeerConnected).
+    ///
+    /// If this is not set, plaintext V1 connections are made with peers that do not signal for V2.
+    pub fn require_v2_transport(mut self) -> Self {
+        self.config.require_v2_transport = true;
+        self
+    }
+
+    /// Use a custom message start for peer-to-peer messages, for instance the magic of a custom
+    /// signet, which is derived from its block signing challenge. Messages from peers with any
+    /// other magic are rejected. Combine with [`Builder::chain_state`] and
+    /// [`Builder::add_peer`] to connect to a private network.
+    ///
+    /// ## Note
+    ///
+    /// The BIP-324 V2 transport derives its keys from the magic of the standard networks, so
+    /// connections with a custom magic use the plaintext V1 transport. If
+    /// [`Builder::require_v2_transport`] is set, no connection with a custom magic can be made.
+    ///
+    /// If none is provided, the magic of the configured [`Network`] is used.
+    pub fn network_magic(mut self, magic: Magic) -> Self {
+        self.config.network_magic = Some(magic);
+        self
+    }
+
+    /// Advertise a custom user agent to peers in the version handshake. Peers disconnect from
+    /// nodes with a user agent longer than 256 bytes, so a longer user agent is rejected with a
+    /// [`Warning::UnexpectedSyncError`](crate::Warning::UnexpectedSyncError) and the default is
+    /// used.
+    ///
+    /// If none is provided, the user agent names the versions of this crate and `rust-bitcoin`.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.config.local_version.user_agent = user_agent.into();
+        self
+    }
+
+    /// Advertise service flags to peers in the version handshake. The node does not serve any
+    /// data to peers, so services should only be advertised when another process answers for
+    /// them.
+    ///
+    /// If none are provided, no services are advertised.
+    pub fn advertise_services(mut self, services: ServiceFlags) -> Self {
+        self.config.local_version.services = services;
+        self
+    }
+
+    /// Connect only to the provided peers, cycling through them as connections end. Like
+    /// [`Builder::whitelist_only`], new peers are never discovered via DNS seeding or addr gossip,
+    /// but disconnected peers are tried again instead of exhausting the list. If every peer has
+    /// been banned, the node will exit with
+    /// [`NodeError::NoReachablePeers`](crate::error::NodeError::NoReachablePeers).
+    ///
+    /// This is useful for reproducible test environments, such as regtest or signet nodes under
+    /// the user's control.
+    pub fn peers_only(mut self, peers: impl IntoIterator<Item = TrustedPeer>) -> Self {
+        self.config.white_list.extend(peers);
+        self.config.whitelist_only = true;
+        self.config.peers_only = true;
+        self
+    }
+
     /// Add preferred peers to try to connect to.
     pub fn add_peers(mut self, whitelist: impl IntoIterator<Item = TrustedPeer>) -> Self {
         self.config.white_list.extend(whitelist);
@@ -84,6 +159,44 @@ impl Builder {
         self
     }
 
+    /// The number of peers that must agree on the tip of the chain before the headers are
+    /// considered synced. Any peer that presents a chain with more work will be synced from
+    /// instead, as the chain of most work is always selected.
+    ///
+    /// If none is provided, a single peer is trusted. The value is limited to the number of
+    /// required peers.
+    pub fn tip_confirmations(mut self, num_peers: u8) -> Self {
+        self.config.tip_confirmations = num_peers.max(MIN_PEERS);
+        self
+    }
+
+    /// The deepest reorganization of the chain the node will accept. A peer proposing a chain of
+    /// more work that would disconnect more blocks than this is banned.
+    ///
+    /// If none is provided, reorganizations of up to 100 blocks are accepted.
+    pub fn max_reorg_depth(mut self, depth: u32) -> Self {
+        self.config.max_reorg_depth = depth;
+        self
+    }
+
+    /// Drop block headers more than this many blocks below the tip once the chain has been
+    /// scanned, bounding the memory used by a node that runs for a long time.
+    ///
+    /// ## Note
+    ///
+    /// Headers below the pruned depth are forgotten. A reorganization that forks below them can
+    /// never be followed, and blocks, headers, or rescans below them are no longer available
+    /// from the node. To keep reorganizations within [`Builder::max_reorg_depth`] safe, a depth
+    /// less than a difficulty adjustment period past the reorganization limit is raised to that
+    /// floor, which is 2,116 blocks by default. Headers are only pruned after every filter has
+    /// been checked.
+    ///
+    /// If none is provided, every header synced is kept.
+    pub fn prune_headers_below_depth(mut self, depth: u32) -> Self {
+        self.config.prune_depth = Some(depth.max(MIN_PRUNE_DEPTH));
+        self
+    }
+
     /// Initialize the chain state of the node with previous information or a starting checkpoint.
     /// This information will be used to inform the client of any block reorganizations and to
     /// enforce consensus rules on proof of work.
@@ -92,6 +205,96 @@ impl Builder {
         self
     }
 
+    /// Extend the chain state with a contiguous range of trusted headers, for instance a recent
+    /// set of headers bundled with an application, so they are not downloaded again on the first
+    /// launch. The headers must connect to the tip of the configured chain state and pass their
+    /// own proof of work, otherwise the preload is rejected with a
+    /// [`Warning::UnexpectedSyncError`](crate::Warning::UnexpectedSyncError) and the headers are
+    /// synced from peers instead. Headers preceding an invalid header in the batch are still
+    /// kept, as each was validated before it was accepted. Preloading happens before the node
+    /// runs, so no chain update events are emitted for these headers.
+    ///
+    /// If none are provided, headers are synced from peers starting at the chain state.
+    pub fn preload_headers(mut self, headers: Vec<Header>) -> Self {
+        self.config.preload_headers = headers;
+        self
+    }
+
+    /// The number of [`Event`](crate::Event) the client may leave unread. Events are never dropped.
+    /// Once the buffer is full, the node stops processing messages from peers, slowing the
+    /// sync and any block downloads, until the client receives events again. Larger buffers
+    /// allow bursts of events, like a rescan, at the cost of memory.
+    ///
+    /// If none is provided, a buffer of 1024 events is used.
+    pub fn event_buffer(mut self, capacity: usize) -> Self {
+        self.config.event_buffer = capacity.max(1);
+        self
+    }
+
+    /// Retain up to this many compact block filters after they are checked, so scripts may be
+    /// tested against them with [`Requester::test_script`](crate::Requester::test_script), and a
+    /// [`Requester::rescan`](crate::Requester::rescan) over recent blocks, for instance for a new
+    /// wallet, is served without downloading the filters again. The least recently used filter
+    /// is evicted once the count is reached. Basic filters on the Bitcoin network are tens of
+    /// kilobytes each, so the memory used is bounded by roughly the count times 50 kilobytes.
+    ///
+    /// If none is provided, no filters are retained.
+    pub fn retain_filters(mut self, count: usize) -> Self {
+        self.config.filter_retention = count;
+        self
+    }
+
+    /// Require the headers at these heights to have the given hashes, replacing any trust placed
+    /// in the peers for those heights. A peer that sends a conflicting header is disconnected, so
+    /// the node cannot be led onto a chain that excludes a checkpoint. The checkpoints must be
+    /// ordered by strictly increasing height, otherwise they are rejected with a
+    /// [`Warning::UnexpectedSyncError`](crate::Warning::UnexpectedSyncError) and ignored.
+    ///
+    /// If none are provided, headers are only checked for proof of work and consensus rules.
+    pub fn checkpoints(mut self, checkpoints: Vec<HashCheckpoint>) -> Self {
+        self.config.checkpoints = checkpoints;
+        self
+    }
+
+    /// Require the compact filter headers at these heights to match, for instance values computed
+    /// by a trusted full node with `getblockfilter`. No filter header checkpoints are built in, as
+    /// each must be verified against a node the application trusts. A peer that sends a conflicting filter header
+    /// is banned, even if other peers agree with it, so a set of dishonest peers cannot feed the
+    /// node a filter header chain that hides transactions.
+    ///
+    /// If none are provided, filter headers are trusted when the required number of peers agree.
+    pub fn filter_header_checkpoints(mut self, checkpoints: Vec<FilterHeaderCheckpoint>) -> Self {
+        self.config.filter_header_checkpoints = checkpoints;
+        self
+    }
+
+    /// Run a callback each time the node enters a [`NodeState`], for instance to start
+    /// reconciling a wallet once filters are synced. The future returned by the callback is
+    /// spawned onto the runtime, so the node keeps running while it completes, and the callback
+    /// may make requests to the node with a [`Requester`](crate::Requester).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use bip157::{Builder, Network, NodeState};
+    ///
+    /// let (node, client) = Builder::new(Network::Signet)
+    ///     .on_state(NodeState::FiltersSynced, || async {
+    ///         println!("Filters synced");
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn on_state<F, Fut>(mut self, state: NodeState, mut callback: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.config
+            .state_callbacks
+            .add(state, Box::new(move || Box::pin(callback())));
+        self
+    }
+
     /// Set the time a peer has to complete the initial TCP handshake. Even on unstable
     /// connections this may be fast.
     ///
@@ -115,6 +318,17 @@ impl Builder {
         self
     }
 
+    /// How long a connection to a peer may go without messages before the peer is sent a ping.
+    /// A peer that does not answer the ping within the response timeout is disconnected. Shorter
+    /// intervals detect dead connections sooner, for instance when a NAT silently drops an idle
+    /// connection on a mobile network.
+    ///
+    /// If none is provided, peers are pinged after two minutes without messages.
+    pub fn ping_interval(mut self, interval: impl Into<Duration>) -> Self {
+        self.config.peer_timeout_config.ping_interval = interval.into();
+        self
+    }
+
     /// The maximum connection time that will be maintained with a remote peer, regardless of
     /// the quality of the peer.
     ///
@@ -130,6 +344,39 @@ impl Builder {
         self
     }
 
+    /// Resolve DNS seeders with a custom [`DnsResolver`], for instance DNS-over-HTTPS or a fixed
+    /// map of hosts when testing. Seeders are only queried when no other peers are known.
+    ///
+    /// If none is provided, the [`DefaultResolver`](crate::DefaultResolver) will query the system
+    /// resolver.
+    pub fn dns_resolver(mut self, resolver: impl DnsResolver + 'static) -> Self {
+        self.config.dns_resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Fetch blocks from a [`BlockSource`], like an Electrum server or a REST API, instead of
+    /// downloading them from peers. Blocks from the source are validated against the header
+    /// chain, and are requested from a peer if the source fails or returns an invalid block.
+    /// Headers and filters are always downloaded from peers.
+    ///
+    /// If none is provided, blocks are downloaded from peers.
+    pub fn block_source(mut self, source: impl BlockSource + 'static) -> Self {
+        self.config.block_source = Some(Arc::new(source));
+        self
+    }
+
+    /// Dial peers at CJDNS addresses, like Bitcoin Core's `-cjdnsreachable`. CJDNS addresses are
+    /// routed like IPv6 addresses, so they are only reachable when a local cjdns interface is
+    /// running. Has no effect when connecting through a proxy.
+    ///
+    /// If none is provided, CJDNS addresses are not dialed.
+    pub fn cjdns_reachable(mut self) -> Self {
+        if let ConnectionType::ClearNet { cjdns_reachable } = &mut self.config.connection_type {
+            *cjdns_reachable = true;
+        }
+        self
+    }
+
     /// Route network traffic through a Tor daemon using a Socks5 proxy. Currently, proxies
     /// must be reachable by IP address.
     pub fn socks5_proxy(mut self, proxy: impl Into<Socks5Proxy>) -> Self {
@@ -151,8 +398,160 @@ impl Builder {
         self
     }
 
+    /// The number of block filter batches and blocks that may be requested before earlier
+    /// requests are answered. Requesting ahead improves throughput on connections where latency
+    /// dominates, such as mobile or satellite links, at the cost of more load on peers. Blocks
+    /// that may be requested together are sent to a peer in a single message.
+    ///
+    /// If none is provided, a single request will be made at a time. The depth will be clamped
+    /// to a range of 1 to 8.
+    pub fn pipeline_depth(mut self, depth: u8) -> Self {
+        self.config.pipeline_depth = depth.clamp(MIN_PIPELINE_DEPTH, MAX_PIPELINE_DEPTH);
+        self
+    }
+
+    /// The number of compact block filters requested in a single message. Larger batches need
+    /// fewer round trips on fast connections, while smaller batches arrive sooner on slow
+    /// connections and leave less work to request again if a peer disconnects.
+    ///
+    /// If none is provided, 1,000 filters are requested at a time. The size will be clamped to a
+    /// range of 1 to 1,000, the most a peer will serve for one request.
+    pub fn filter_batch_size(mut self, size: u32) -> Self {
+        self.config.filter_batch_size = size.clamp(1, MAX_FILTER_BATCH_SIZE);
+        self
+    }
+
+    /// Adjust the number of filters requested at once to how quickly peers respond. The size is
+    /// halved when a peer times out before delivering its filters, and doubled, up to the
+    /// [`Builder::filter_batch_size`], when a batch arrives within a few seconds.
+    ///
+    /// If none is provided, the batch size is fixed.
+    pub fn adaptive_filter_batches(mut self) -> Self {
+        self.config.adaptive_filter_batches = true;
+        self
+    }
+
+    /// The number of blocks that may be requested before earlier requests are answered. Every
+    /// block in flight may be held in memory at once, and blocks on the Bitcoin network may be up
+    /// to four megabytes, so a lower limit bounds the memory used during a rescan at the cost of
+    /// download throughput. A request holds its slot until the block is received.
+    ///
+    /// If none is provided, the [`pipeline_depth`](Builder::pipeline_depth) is used. The limit
+    /// never raises the pipeline depth, and is at least one block.
+    pub fn max_in_flight_blocks(mut self, max_in_flight: usize) -> Self {
+        self.config.max_in_flight_blocks = Some(max_in_flight);
+        self
+    }
+
+    /// Limit the rate blocks are downloaded, in bytes per second, averaged over the last ten
+    /// seconds. New blocks are not requested while recent downloads exceed the limit, which may
+    /// be useful on metered connections. A [`Warning::RateLimited`](crate::Warning::RateLimited)
+    /// is emitted when downloads are slowed.
+    ///
+    /// If none is provided, blocks are downloaded as fast as peers serve them.
+    pub fn max_block_download_rate(mut self, bytes_per_sec: u32) -> Self {
+        self.config.max_block_download_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// The number of peers compact block filters are downloaded from at once. Each peer is sent
+    /// its own batches of filters, up to the [`Builder::pipeline_depth`]. If a peer disconnects
+    /// or stops responding, the filters it did not send are requested from another peer.
+    ///
+    /// If none is provided, filters are downloaded from a single peer. The number of peers will
+    /// be clamped to a range of 1 to 15.
+    pub fn filter_download_peers(mut self, num_peers: u8) -> Self {
+        self.config.filter_download_peers = num_peers.clamp(MIN_PEERS, MAX_PEERS);
+        self
+    }
+
+    /// Peers are scored by their history with the node. A peer gains a point each time it sends
+    /// new headers or completes a batch of compact block filters, and loses a point each time it
+    /// times out or is banned. When finding a new peer, a few addresses are sampled from the
+    /// address book and the highest scoring is preferred. A sampled address scoring below this
+    /// minimum is passed over, unless every sampled address scores below it.
+    ///
+    /// If none is provided, a minimum score of -3 will be used.
+    pub fn min_peer_score(mut self, score: i32) -> Self {
+        self.config.min_peer_score = score;
+        self
+    }
+
+    /// Configure how peers are replaced when no new blocks have been announced for the
+    /// [`Builder::stale_tip_timeout`]. Users with a single reliable connection may prefer to
+    /// rotate one peer at a time.
+    ///
+    /// If none is provided, all peers will be disconnected.
+    pub fn stale_tip_strategy(mut self, strategy: StaleTipStrategy) -> Self {
+        self.config.stale_tip_strategy = strategy;
+        self
+    }
+
+    /// The time without a new block after which the tip is considered stale, and peers are
+    /// replaced according to the [`Builder::stale_tip_strategy`].
+    ///
+    /// ## Note
+    ///
+    /// Blocks on test networks may be found far less regularly than every ten minutes. On signet,
+    /// a timeout of a few hours avoids replacing peers unnecessarily, and on regtest, where blocks
+    /// are only mined on demand, a timeout of days is reasonable.
+    ///
+    /// If none is provided, a timeout of thirty minutes will be used.
+    pub fn stale_tip_timeout(mut self, timeout: impl Into<Duration>) -> Self {
+        self.config.stale_tip_timeout = timeout.into();
+        self
+    }
+
+    /// Wait a random duration, up to this jitter, before each new connection to a peer. Hosts that
+    /// run many nodes may set a jitter so the nodes do not all reconnect to the same peers at
+    /// once after the network recovers from an outage. Each connection, including those made
+    /// when the node starts, is delayed by a new random duration. The jitter is at most five
+    /// minutes.
+    ///
+    /// If none is provided, connections are made without delay.
+    pub fn reconnect_jitter(mut self, jitter: impl Into<Duration>) -> Self {
+        self.config.reconnect_jitter = jitter.into().min(MAX_RECONNECT_JITTER);
+        self
+    }
+
+    /// Configure how long, and to how many peers, transactions are announced. Wallets that cannot
+    /// monitor the mempool may prefer to announce transactions until they are confirmed.
+    ///
+    /// If none is provided, a transaction is announced until a peer requests it.
+    pub fn tx_broadcast_policy(mut self, policy: TxBroadcastPolicy) -> Self {
+        self.config.tx_broadcast_policy = policy;
+        self
+    }
+
+    /// Keep the bytes of each block as they were received from the remote peer. The bytes are
+    /// delivered alongside the parsed block in [`IndexedBlock::raw`](crate::IndexedBlock::raw),
+    /// which avoids encoding the block again when it will be forwarded elsewhere.
+    ///
+    /// If none is provided, only the parsed block will be delivered.
+    pub fn raw_blocks(mut self) -> Self {
+        self.config.raw_blocks = true;
+        self
+    }
+
     /// Consume the node builder and receive a [`Node`] and [`Client`].
     pub fn build(mut self) -> (Node, Client) {
         Node::new(self.network, core::mem::take(&mut self.config))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bitcoin::Network;
+
+    use super::{Builder, MAX_RECONNECT_JITTER};
+
+    #[test]
+    fn test_reconnect_jitter_is_clamped() {
+        let builder = Builder::new(Network::Regtest).reconnect_jitter(Duration::from_secs(10));
+        assert_eq!(builder.config.reconnect_jitter, Duration::from_secs(10));
+        let builder = Builder::new(Network::Regtest).reconnect_jitter(Duration::MAX);
+        assert_eq!(builder.config.reconnect_jitter, MAX_RECONNECT_JITTER);
+    }
+}
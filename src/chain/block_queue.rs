@@ -3,38 +3,94 @@ use std::{
     time::Duration,
 };
 
-use bitcoin::BlockHash;
+use bitcoin::{BlockHash, MerkleBlock, Txid};
 use tokio::{sync::oneshot, time::Instant};
 
-use crate::{error::FetchBlockError, messages::ClientRequest, IndexedBlock};
+use crate::{error::FetchBlockError, messages::ClientRequest, BlockRequestId, IndexedBlock};
 
 const SPAM_LIMIT: Duration = Duration::from_secs(5);
+// Downloads are averaged over this period when enforcing a rate limit
+const RATE_WINDOW: Duration = Duration::from_secs(10);
 
 #[derive(Debug)]
 pub(crate) struct BlockQueue {
     queue: VecDeque<Request>,
-    want: Option<Request>,
+    want: Vec<Request>,
+    depth: usize,
     last_req: Instant,
     completed: HashSet<BlockHash>,
+    rate_limit: Option<RateLimit>,
 }
 
 impl BlockQueue {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(depth: usize) -> Self {
         Self {
             queue: VecDeque::new(),
-            want: None,
+            want: Vec::with_capacity(depth),
+            depth,
             last_req: Instant::now(),
             completed: HashSet::new(),
+            rate_limit: None,
         }
     }
 
+    // Requests that are not yet answered hold a slot until the block arrives, which bounds the
+    // number of blocks that may be buffered at once. The limit only lowers the pipeline depth.
+    pub(crate) fn set_max_in_flight(&mut self, max_in_flight: usize) {
+        self.depth = self.depth.min(max_in_flight).max(1);
+    }
+
+    pub(crate) fn set_max_rate(&mut self, bytes_per_sec: u32) {
+        self.rate_limit = Some(RateLimit::new(bytes_per_sec));
+    }
+
+    pub(crate) fn record_download(&mut self, bytes: usize) {
+        if let Some(rate_limit) = self.rate_limit.as_mut() {
+            rate_limit.record(bytes);
+        }
+    }
+
+    // Blocks are waiting to be requested, but recent downloads exceed the rate limit
+    pub(crate) fn throttled(&mut self) -> bool {
+        if self.queue.is_empty() {
+            return false;
+        }
+        self.rate_limit
+            .as_mut()
+            .is_some_and(|rate_limit| rate_limit.exceeded())
+    }
+
     pub(crate) fn add(&mut self, request: impl Into<Request>) {
         let request: Request = request.into();
         self.queue.push_front(request)
     }
 
+    pub(crate) fn add_with_id(&mut self, id: BlockRequestId, request: impl Into<Request>) {
+        let mut request: Request = request.into();
+        request.id = Some(id);
+        self.queue.push_front(request)
+    }
+
+    // Remove a request that has not been sent to a peer, returning the recipient to notify
+    pub(crate) fn cancel(&mut self, id: BlockRequestId) -> Option<BlockRecipient> {
+        let index = self
+            .queue
+            .iter()
+            .position(|request| request.id == Some(id))?;
+        self.queue.remove(index).map(|request| request.recipient)
+    }
+
     pub(crate) fn pop(&mut self) -> Option<BlockHash> {
-        match self.want.as_mut() {
+        // Request up to `depth` blocks before waiting on responses
+        if self.want.len() < self.depth {
+            if let Some(request) = self.queue.pop_back() {
+                self.last_req = Instant::now();
+                let hash = request.hash;
+                self.want.push(request);
+                return Some(hash);
+            }
+        }
+        match self.want.first() {
             Some(request) => {
                 if self.last_req.elapsed() < SPAM_LIMIT {
                     None
@@ -43,32 +99,29 @@ impl BlockQueue {
                     Some(request.hash)
                 }
             }
-            None => {
-                self.last_req = Instant::now();
-                let request = self.queue.pop_back();
-                let hash = request.as_ref().map(|request| request.hash);
-                self.want = request;
-                hash
+            None => None,
+        }
+    }
+
+    // All blocks that may be requested now, to be sent in a single message
+    pub(crate) fn pop_batch(&mut self) -> Vec<BlockHash> {
+        let mut batch = Vec::new();
+        while let Some(hash) = self.pop() {
+            if batch.contains(&hash) {
+                break;
             }
+            batch.push(hash);
         }
+        batch
     }
 
     pub(crate) fn process_block(&mut self, block: &BlockHash) -> ProcessBlockResponse {
-        if let Some(request) = self.want.take() {
-            if request.hash.eq(block) {
-                self.want = None;
-                self.completed.insert(*block);
-                return ProcessBlockResponse::Accepted {
-                    block_recipient: request.recipient,
-                };
-            // We still need whatever hash is in the queue
-            } else if self.completed.contains(block) {
-                self.want = Some(request);
-                return ProcessBlockResponse::LateResponse;
-            } else {
-                self.want = Some(request);
-                return ProcessBlockResponse::UnknownHash;
-            }
+        if let Some(index) = self.want.iter().position(|request| request.hash.eq(block)) {
+            let request = self.want.remove(index);
+            self.completed.insert(*block);
+            return ProcessBlockResponse::Accepted {
+                block_recipient: request.recipient,
+            };
         }
         if self.completed.contains(block) {
             return ProcessBlockResponse::LateResponse;
@@ -76,17 +129,82 @@ impl BlockQueue {
         ProcessBlockResponse::UnknownHash
     }
 
+    // A peer does not have a block that is waiting on a response. Returns if the block is still
+    // wanted, in which case the request is restarted.
+    pub(crate) fn not_found(&mut self, block: &BlockHash) -> bool {
+        if self.want.iter().any(|request| request.hash.eq(block)) {
+            self.last_req = Instant::now();
+            return true;
+        }
+        false
+    }
+
     #[allow(unused)]
     pub(crate) fn complete(&self) -> bool {
-        self.want.is_none() && self.queue.is_empty()
+        self.want.is_empty() && self.queue.is_empty()
+    }
+
+    // Take every pending request, such that the recipients may be notified
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = BlockRecipient> + '_ {
+        self.queue
+            .drain(..)
+            .chain(self.want.drain(..))
+            .map(|request| request.recipient)
     }
 
     pub(crate) fn remove(&mut self, hashes: &[BlockHash]) {
         self.queue.retain(|request| !hashes.contains(&request.hash));
-        if let Some(want) = self.want.as_ref() {
-            if hashes.contains(&want.hash) {
-                self.want = None;
-            }
+        self.want.retain(|request| !hashes.contains(&request.hash));
+    }
+}
+
+#[derive(Debug)]
+struct RateLimit {
+    bytes_per_sec: u64,
+    received: VecDeque<(Instant, u64)>,
+}
+
+impl RateLimit {
+    fn new(bytes_per_sec: u32) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.into(),
+            received: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, bytes: usize) {
+        self.received.push_back((Instant::now(), bytes as u64));
+    }
+
+    fn exceeded(&mut self) -> bool {
+        while self
+            .received
+            .front()
+            .is_some_and(|(time, _)| time.elapsed() > RATE_WINDOW)
+        {
+            self.received.pop_front();
+        }
+        let total: u64 = self.received.iter().map(|(_, bytes)| bytes).sum();
+        total > self.bytes_per_sec * RATE_WINDOW.as_secs()
+    }
+}
+
+// What to deliver once a block arrives
+#[derive(Debug)]
+pub(crate) enum BlockRecipient {
+    Block(oneshot::Sender<Result<IndexedBlock, FetchBlockError>>),
+    MerkleProof {
+        txid: Txid,
+        recipient: oneshot::Sender<Result<MerkleBlock, FetchBlockError>>,
+    },
+}
+
+impl BlockRecipient {
+    // Resolve the request with an error, returning if the recipient is still listening
+    pub(crate) fn send_error(self, error: FetchBlockError) -> bool {
+        match self {
+            BlockRecipient::Block(recipient) => recipient.send(Err(error)).is_ok(),
+            BlockRecipient::MerkleProof { recipient, .. } => recipient.send(Err(error)).is_ok(),
         }
     }
 }
@@ -94,7 +212,8 @@ impl BlockQueue {
 #[derive(Debug)]
 pub(crate) struct Request {
     hash: BlockHash,
-    recipient: oneshot::Sender<Result<IndexedBlock, FetchBlockError>>,
+    recipient: BlockRecipient,
+    id: Option<BlockRequestId>,
 }
 
 impl Request {
@@ -104,7 +223,8 @@ impl Request {
         let (hash, oneshot) = block_request.into_values();
         Self {
             hash,
-            recipient: oneshot,
+            recipient: BlockRecipient::Block(oneshot),
+            id: None,
         }
     }
 }
@@ -115,11 +235,20 @@ impl From<ClientRequest<BlockHash, Result<IndexedBlock, FetchBlockError>>> for R
     }
 }
 
+impl From<ClientRequest<(BlockHash, Txid), Result<MerkleBlock, FetchBlockError>>> for Request {
+    fn from(value: ClientRequest<(BlockHash, Txid), Result<MerkleBlock, FetchBlockError>>) -> Self {
+        let ((hash, txid), recipient) = value.into_values();
+        Self {
+            hash,
+            recipient: BlockRecipient::MerkleProof { txid, recipient },
+            id: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum ProcessBlockResponse {
-    Accepted {
-        block_recipient: oneshot::Sender<Result<IndexedBlock, FetchBlockError>>,
-    },
+    Accepted { block_recipient: BlockRecipient },
     LateResponse,
     UnknownHash,
 }
@@ -159,7 +288,7 @@ mod test {
     #[test]
     fn test_block_queue() {
         let [hash_1, hash_2, hash_3] = three_block_hashes();
-        let mut queue = BlockQueue::new();
+        let mut queue = BlockQueue::new(1);
         queue.add(hash_1.dummy_request());
         queue.add(hash_2.dummy_request());
         queue.add(hash_3.dummy_request());
@@ -167,17 +296,11 @@ mod test {
         assert_eq!(queue.queue.len(), 4);
         assert_eq!(queue.pop(), Some(hash_1));
         assert_eq!(queue.pop(), None);
-        assert_eq!(
-            queue.want.as_ref().map(|request| request.hash),
-            Some(hash_1)
-        );
+        assert_eq!(queue.want.first().map(|request| request.hash), Some(hash_1));
         queue.process_block(&hash_1);
-        assert_eq!(queue.want.as_ref().map(|request| request.hash), None);
+        assert_eq!(queue.want.first().map(|request| request.hash), None);
         assert_eq!(queue.pop(), Some(hash_2));
-        assert_eq!(
-            queue.want.as_ref().map(|request| request.hash),
-            Some(hash_2)
-        );
+        assert_eq!(queue.want.first().map(|request| request.hash), Some(hash_2));
         queue.process_block(&hash_2);
         assert_eq!(queue.pop(), Some(hash_3));
         assert!(!queue.complete());
@@ -195,7 +318,7 @@ mod test {
     #[tokio::test(start_paused = true)]
     async fn test_laggy_peer() {
         let [hash_1, hash_2, hash_3] = three_block_hashes();
-        let mut queue = BlockQueue::new();
+        let mut queue = BlockQueue::new(1);
         queue.add(hash_1.dummy_request());
         queue.add(hash_2.dummy_request());
         queue.add(hash_3.dummy_request());
@@ -203,17 +326,11 @@ mod test {
         assert_eq!(queue.pop(), Some(hash_1));
         tokio::time::sleep(Duration::from_secs(6)).await;
         assert_eq!(queue.pop(), Some(hash_1));
-        assert_eq!(
-            queue.want.as_ref().map(|request| request.hash),
-            Some(hash_1)
-        );
+        assert_eq!(queue.want.first().map(|request| request.hash), Some(hash_1));
         queue.process_block(&hash_1);
-        assert_eq!(queue.want.as_ref().map(|request| request.hash), None);
+        assert_eq!(queue.want.first().map(|request| request.hash), None);
         assert_eq!(queue.pop(), Some(hash_2));
-        assert_eq!(
-            queue.want.as_ref().map(|request| request.hash),
-            Some(hash_2)
-        );
+        assert_eq!(queue.want.first().map(|request| request.hash), Some(hash_2));
         queue.process_block(&hash_2);
         assert_eq!(queue.pop(), Some(hash_3));
         assert!(!queue.complete());
@@ -232,24 +349,169 @@ mod test {
         assert!(matches!(response, ProcessBlockResponse::LateResponse));
     }
 
+    #[test]
+    fn test_block_not_found() {
+        let [hash_1, hash_2, _] = three_block_hashes();
+        let mut queue = BlockQueue::new(1);
+        queue.add(hash_1.dummy_request());
+        queue.add(hash_2.dummy_request());
+        // Only requests waiting on a response are restarted
+        assert!(!queue.not_found(&hash_1));
+        assert_eq!(queue.pop(), Some(hash_1));
+        assert!(queue.not_found(&hash_1));
+        assert!(!queue.not_found(&hash_2));
+        queue.process_block(&hash_1);
+        assert!(!queue.not_found(&hash_1));
+    }
+
     #[test]
     fn test_blocks_removed() {
         let [hash_1, hash_2, hash_3] = three_block_hashes();
-        let mut queue = BlockQueue::new();
+        let mut queue = BlockQueue::new(1);
         queue.add(hash_1.dummy_request());
         queue.add(hash_2.dummy_request());
         queue.add(hash_3.dummy_request());
         queue.add(hash_1.dummy_request());
         assert_eq!(queue.queue.len(), 4);
         assert_eq!(queue.pop(), Some(hash_1));
-        assert_eq!(
-            queue.want.as_ref().map(|request| request.hash),
-            Some(hash_1)
-        );
+        assert_eq!(queue.want.first().map(|request| request.hash), Some(hash_1));
         queue.remove(&[hash_1]);
-        assert_eq!(queue.want.as_ref().map(|request| request.hash), None);
+        assert_eq!(queue.want.first().map(|request| request.hash), None);
         queue.remove(&[hash_2]);
         assert_eq!(queue.queue.len(), 1);
         assert_eq!(queue.pop(), Some(hash_3));
     }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let [hash_1, hash_2, hash_3] = three_block_hashes();
+        let mut queue = BlockQueue::new(2);
+        queue.add(hash_1.dummy_request());
+        queue.add(hash_2.dummy_request());
+        queue.add(hash_3.dummy_request());
+        assert_eq!(queue.pop(), Some(hash_1));
+        assert_eq!(queue.pop(), Some(hash_2));
+        assert_eq!(queue.pop(), None);
+        // Responses may arrive in any order
+        let response = queue.process_block(&hash_2);
+        assert!(matches!(response, ProcessBlockResponse::Accepted { .. }));
+        assert_eq!(queue.pop(), Some(hash_3));
+        let response = queue.process_block(&hash_1);
+        assert!(matches!(response, ProcessBlockResponse::Accepted { .. }));
+        assert!(!queue.complete());
+        queue.process_block(&hash_3);
+        assert!(queue.complete());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batched_requests() {
+        let [hash_1, hash_2, hash_3] = three_block_hashes();
+        let mut queue = BlockQueue::new(2);
+        queue.add(hash_1.dummy_request());
+        queue.add(hash_2.dummy_request());
+        queue.add(hash_3.dummy_request());
+        assert_eq!(queue.pop_batch(), vec![hash_1, hash_2]);
+        assert!(queue.pop_batch().is_empty());
+        let response = queue.process_block(&hash_2);
+        assert!(matches!(response, ProcessBlockResponse::Accepted { .. }));
+        // A duplicate block from the batch is not delivered twice
+        let response = queue.process_block(&hash_2);
+        assert!(matches!(response, ProcessBlockResponse::LateResponse));
+        assert_eq!(queue.pop_batch(), vec![hash_3]);
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        // Only the oldest outstanding request is repeated
+        assert_eq!(queue.pop_batch(), vec![hash_1]);
+    }
+
+    #[test]
+    fn test_drain_cancels_requests() {
+        let [hash_1, hash_2, _] = three_block_hashes();
+        let mut queue = BlockQueue::new(1);
+        let (tx, mut rx) = oneshot::channel();
+        queue.add(ClientRequest::new(hash_1, tx));
+        queue.add(hash_2.dummy_request());
+        assert_eq!(queue.pop(), Some(hash_1));
+        for recipient in queue.drain() {
+            let _ = recipient.send_error(FetchBlockError::Cancelled);
+        }
+        assert!(queue.complete());
+        assert!(matches!(rx.try_recv(), Ok(Err(FetchBlockError::Cancelled))));
+    }
+
+    #[test]
+    fn test_cancel_request() {
+        let [hash_1, hash_2, hash_3] = three_block_hashes();
+        let mut queue = BlockQueue::new(1);
+        let (tx, mut rx) = oneshot::channel();
+        queue.add_with_id(BlockRequestId(0), ClientRequest::new(hash_1, tx));
+        let (tx, _rx) = oneshot::channel();
+        queue.add_with_id(BlockRequestId(1), ClientRequest::new(hash_2, tx));
+        queue.add(hash_3.dummy_request());
+        // Requests that were sent to a peer may not be cancelled
+        assert_eq!(queue.pop(), Some(hash_1));
+        assert!(queue.cancel(BlockRequestId(0)).is_none());
+        let recipient = queue.cancel(BlockRequestId(1)).unwrap();
+        assert!(recipient.send_error(FetchBlockError::Cancelled));
+        assert!(queue.cancel(BlockRequestId(1)).is_none());
+        // The dispatched request is still waiting on the block
+        assert!(rx.try_recv().is_err());
+        queue.process_block(&hash_1);
+        assert_eq!(queue.pop(), Some(hash_3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit() {
+        let [hash_1, hash_2, _] = three_block_hashes();
+        let mut queue = BlockQueue::new(1);
+        queue.set_max_rate(1_000);
+        queue.add(hash_1.dummy_request());
+        queue.add(hash_2.dummy_request());
+        assert!(!queue.throttled());
+        assert_eq!(queue.pop(), Some(hash_1));
+        queue.record_download(20_000);
+        let response = queue.process_block(&hash_1);
+        assert!(matches!(response, ProcessBlockResponse::Accepted { .. }));
+        assert!(queue.throttled());
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert!(!queue.throttled());
+        assert_eq!(queue.pop(), Some(hash_2));
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        let [hash_1, hash_2, hash_3] = three_block_hashes();
+        let mut queue = BlockQueue::new(8);
+        queue.set_max_in_flight(2);
+        queue.add(hash_1.dummy_request());
+        queue.add(hash_2.dummy_request());
+        queue.add(hash_3.dummy_request());
+        assert_eq!(queue.pop_batch(), vec![hash_1, hash_2]);
+        assert_eq!(queue.pop(), None);
+        // A received block frees a slot for the next request
+        queue.process_block(&hash_2);
+        assert_eq!(queue.pop_batch(), vec![hash_3]);
+    }
+
+    #[test]
+    fn test_max_in_flight_caps_depth() {
+        let [hash_1, hash_2, hash_3] = three_block_hashes();
+        // A limit above the pipeline depth does not raise it
+        let mut queue = BlockQueue::new(1);
+        queue.set_max_in_flight(64);
+        queue.add(hash_1.dummy_request());
+        queue.add(hash_2.dummy_request());
+        assert_eq!(queue.pop_batch(), vec![hash_1]);
+        // A limit below the pipeline depth lowers it
+        let mut queue = BlockQueue::new(3);
+        queue.set_max_in_flight(2);
+        queue.add(hash_1.dummy_request());
+        queue.add(hash_2.dummy_request());
+        queue.add(hash_3.dummy_request());
+        assert_eq!(queue.pop_batch(), vec![hash_1, hash_2]);
+        // Every queue allows at least one block in flight
+        let mut queue = BlockQueue::new(3);
+        queue.set_max_in_flight(0);
+        queue.add(hash_1.dummy_request());
+        assert_eq!(queue.pop_batch(), vec![hash_1]);
+    }
 }
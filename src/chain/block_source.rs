@@ -0,0 +1,53 @@
+use std::fmt::Debug;
+
+use bitcoin::{Block, BlockHash};
+
+use crate::FutureResult;
+
+/// Fetch blocks from a source other than the peer-to-peer network.
+///
+/// Implement this to serve block requests from a service the application already depends on,
+/// like an Electrum server or a REST API. Blocks are validated against the header chain as if
+/// they were received from a peer, and are requested from peers if the source fails or does not
+/// respond within 30 seconds. Filters and headers are always downloaded from peers.
+pub trait BlockSource: Debug + Send + Sync {
+    /// Fetch the block with the given hash.
+    fn get_block<'a>(&'a self, hash: BlockHash) -> FutureResult<'a, Block, BlockSourceError>;
+}
+
+/// Errors that may occur when fetching a block from a [`BlockSource`].
+#[derive(Debug)]
+pub enum BlockSourceError {
+    /// The source does not have the block.
+    NotFound,
+    /// The request could not be completed.
+    Io(std::io::Error),
+    /// An error specific to the source.
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl core::fmt::Display for BlockSourceError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BlockSourceError::NotFound => write!(f, "the source does not have the block."),
+            BlockSourceError::Io(err) => write!(f, "the block request failed: {err}"),
+            BlockSourceError::Other(err) => write!(f, "the block source failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for BlockSourceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BlockSourceError::NotFound => None,
+            BlockSourceError::Io(err) => Some(err),
+            BlockSourceError::Other(err) => Some(err.as_ref()),
+        }
+    }
+}
+
+impl From<std::io::Error> for BlockSourceError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
@@ -1,23 +1,32 @@
 extern crate alloc;
-use std::{collections::BTreeMap, sync::Arc};
+use std::{collections::BTreeMap, ops::RangeInclusive, sync::Arc};
 
 use bitcoin::{
     block::Header,
+    hashes::Hash,
     p2p::message_filter::{CFHeaders, CFilter, GetCFHeaders, GetCFilters},
-    BlockHash, Network,
+    Block, BlockHash, FilterHeader, Network, ScriptBuf, Txid, Work,
 };
 
 use super::{
     error::{CFHeaderSyncError, CFilterSyncError, HeaderSyncError},
     graph::{AcceptHeaderChanges, BlockTree, HeaderRejection},
-    CFHeaderBatch, CFHeaderChanges, ChainState, Filter, FilterCheck, FilterHeaderRequest,
-    FilterRequest, FilterRequestState, HeaderSyncEffect, HeaderValidationExt, PeerId,
+    CFHeaderBatch, CFHeaderChanges, ChainState, Filter, FilterBatch, FilterBatchSize, FilterCheck,
+    FilterHeaderRequest, FilterRequest, FilterRequestState, HeaderSyncEffect, HeaderValidationExt,
+    PeerId, FAST_FILTER_BATCH, MEDIAN_TIME_SPAN,
 };
-use crate::{chain::BlockHeaderChanges, messages::Event, Dialog, Info, Progress};
-use crate::{FilterType, IndexedFilter};
+use super::{filter_cache::FilterCache, tx_index::TxIndex, witness_commitment_valid, ZerolikeExt};
+use crate::{chain::BlockHeaderChanges, messages::Event, Dialog, Info, Progress, SyncStage};
+use crate::{
+    error::{FetchFilterHeadersError, RescanError, TestScriptError},
+    BlockType, FilterType, IndexedFilter,
+};
+use crate::{FilterHeaderCheckpoint, HashCheckpoint};
+use tokio::time::Instant;
 
 const CF_HEADER_BATCH_SIZE: u32 = 1_999;
-const FILTER_BATCH_SIZE: u32 = 999;
+// The number of transactions the node remembers the location of
+const TX_INDEX_CAPACITY: usize = 100_000;
 
 #[derive(Debug)]
 pub(crate) struct Chain {
@@ -26,6 +35,12 @@ pub(crate) struct Chain {
     network: Network,
     dialog: Arc<Dialog>,
     filter_type: FilterType,
+    tx_index: TxIndex,
+    filter_cache: FilterCache,
+    checkpoints: Vec<HashCheckpoint>,
+    filter_header_checkpoints: Vec<FilterHeaderCheckpoint>,
+    filter_batch_size: FilterBatchSize,
+    block_type: BlockType,
 }
 
 impl Chain {
@@ -35,8 +50,10 @@ impl Chain {
         dialog: Arc<Dialog>,
         quorum_required: u8,
         filter_type: FilterType,
+        max_reorg_depth: u32,
+        filter_retention: usize,
     ) -> Self {
-        let header_chain = match chain_state {
+        let mut header_chain = match chain_state {
             ChainState::Snapshot(headers) => {
                 let mut header_iter = headers.into_iter();
                 match header_iter.next() {
@@ -52,13 +69,98 @@ impl Chain {
             }
             ChainState::Checkpoint(cp) => BlockTree::new(cp, network),
         };
+        header_chain.set_max_reorg_depth(max_reorg_depth);
         Chain {
             header_chain,
             request_state: FilterRequestState::new(quorum_required),
             network,
             dialog,
             filter_type,
+            tx_index: TxIndex::new(TX_INDEX_CAPACITY),
+            filter_cache: FilterCache::new(filter_retention),
+            checkpoints: Vec::new(),
+            filter_header_checkpoints: Vec::new(),
+            filter_batch_size: FilterBatchSize::default(),
+            block_type: BlockType::default(),
+        }
+    }
+
+    pub(crate) fn set_block_type(&mut self, block_type: BlockType) {
+        self.block_type = block_type;
+    }
+
+    pub(crate) fn set_filter_batch_size(&mut self, filter_batch_size: FilterBatchSize) {
+        self.filter_batch_size = filter_batch_size;
+    }
+
+    // A peer timed out before delivering its batches, so request fewer filters at once
+    pub(crate) fn filter_batches_timed_out(&mut self) {
+        if self.filter_batch_size.shrink() {
+            crate::debug!(format!(
+                "Filter batch size reduced to {}",
+                self.filter_batch_size.get()
+            ));
+        }
+    }
+
+    // Require headers at the checkpoint heights to match, provided the heights strictly increase
+    pub(crate) fn set_checkpoints(
+        &mut self,
+        checkpoints: Vec<HashCheckpoint>,
+    ) -> Result<(), HeaderSyncError> {
+        if !checkpoints
+            .windows(2)
+            .all(|pair| pair[0].height < pair[1].height)
+        {
+            return Err(HeaderSyncError::InvalidCheckpoint);
+        }
+        self.checkpoints = checkpoints;
+        Ok(())
+    }
+
+    // Require the filter headers at the checkpoint heights to match
+    pub(crate) fn set_filter_header_checkpoints(
+        &mut self,
+        mut checkpoints: Vec<FilterHeaderCheckpoint>,
+    ) {
+        checkpoints.sort_by_key(|checkpoint| checkpoint.height);
+        self.filter_header_checkpoints = checkpoints;
+    }
+
+    // A header at a checkpoint height must have the checkpoint hash, on any fork
+    fn matches_checkpoints(&self, header_batch: &[Header]) -> bool {
+        if self.checkpoints.is_empty() {
+            return true;
         }
+        let first = header_batch.first().expect("non-empty check in sync_chain");
+        let start = self
+            .header_chain
+            .height_of_hash(first.prev_blockhash)
+            .or_else(|| {
+                first
+                    .prev_blockhash
+                    .eq(&self.header_chain.tip_hash())
+                    .then(|| self.header_chain.height())
+            });
+        let Some(start) = start else {
+            return true;
+        };
+        header_batch
+            .iter()
+            .zip(start + 1..)
+            .all(|(header, height)| {
+                match self
+                    .checkpoints
+                    .binary_search_by_key(&height, |checkpoint| checkpoint.height)
+                {
+                    Ok(index) => self.checkpoints[index].hash.eq(&header.block_hash()),
+                    Err(_) => true,
+                }
+            })
+    }
+
+    pub(crate) fn filter_type(&self) -> FilterType {
+        self.filter_type
     }
 
     // The last ten heights and headers in the chain
@@ -101,7 +203,25 @@ impl Chain {
                 }
                 AcceptHeaderChanges::Duplicate => (),
                 AcceptHeaderChanges::ExtendedFork { connected_at } => {
-                    crate::debug!(format!("Fork created or extended {}", connected_at.height));
+                    crate::debug!(
+                        format!("Fork created or extended {}", connected_at.height),
+                        height = connected_at.height
+                    );
+                    let main_tip = self.header_chain.tip_hash();
+                    let fork_tip = connected_at.block_hash();
+                    self.dialog.send_event(Event::ChainForked {
+                        fork_height: self.header_chain.fork_height(fork_tip),
+                        main_tip,
+                        fork_tip,
+                        main_work: self
+                            .header_chain
+                            .accumulated_work(main_tip)
+                            .unwrap_or(Work::zero()),
+                        fork_work: self
+                            .header_chain
+                            .accumulated_work(fork_tip)
+                            .unwrap_or(Work::zero()),
+                    });
                     self.dialog
                         .send_event(Event::ChainUpdate(BlockHeaderChanges::ForkAdded(
                             connected_at,
@@ -119,6 +239,7 @@ impl Chain {
                         .into_iter()
                         .map(|header| header.block_hash())
                         .collect::<Vec<BlockHash>>();
+                    self.tx_index.remove_blocks(&reorgs);
                     self.clear_compact_filter_queue();
                     let disconnected_event = Event::ChainUpdate(BlockHeaderChanges::Reorganized {
                         accepted,
@@ -131,6 +252,9 @@ impl Chain {
                         expected: _,
                         got: _,
                     } => return Err(HeaderSyncError::InvalidBits),
+                    HeaderRejection::DeepReorg { depth } => {
+                        return Err(HeaderSyncError::DeepReorg(depth))
+                    }
                     HeaderRejection::UnknownPrevHash(_) => {
                         crate::debug!("Unknown prevhash does not link to the current header chain");
                         return Err(HeaderSyncError::FloatingHeaders);
@@ -144,17 +268,100 @@ impl Chain {
         Ok(HeaderSyncEffect::Added)
     }
 
+    // Match a script against retained filters, without requesting any data
+    pub(crate) fn test_script(
+        &mut self,
+        script: &ScriptBuf,
+        range: RangeInclusive<u32>,
+    ) -> Result<Vec<BlockHash>, TestScriptError> {
+        let mut matches = Vec::new();
+        for height in range {
+            let block_hash = self
+                .header_chain
+                .block_hash_at_height(height)
+                .ok_or(TestScriptError::FilterNotSynced { height })?;
+            match self.filter_cache.get(&block_hash) {
+                Some(filter) => {
+                    if filter.contains_any(std::iter::once(script)) {
+                        matches.push(block_hash);
+                    }
+                }
+                None if self.header_chain.is_filter_checked(&block_hash) => {
+                    return Err(TestScriptError::FilterDataPurged { height })
+                }
+                None => return Err(TestScriptError::FilterNotSynced { height }),
+            }
+        }
+        Ok(matches)
+    }
+
+    // Segwit blocks must commit to their witness data in the coinbase
+    pub(crate) fn check_witness_commitment(&self, block: &Block, height: u32) -> bool {
+        witness_commitment_valid(block, height, self.network, self.block_type)
+    }
+
+    // Remember where the transactions in a downloaded block were confirmed
+    pub(crate) fn index_transactions(&mut self, block: &Block, height: u32) {
+        self.tx_index.insert(block, height);
+    }
+
+    // The block hash and height of a transaction in a previously downloaded block
+    pub(crate) fn find_transaction(&self, txid: &Txid) -> Option<(BlockHash, u32)> {
+        self.tx_index.get(txid)
+    }
+
+    // Extend the chain with trusted headers that must connect to the tip. The batch passes the
+    // sanity check as a whole, but each header is only checked against the tree as it is
+    // accepted, so the headers before a rejected one remain in the chain.
+    pub(crate) fn preload_headers(&mut self, headers: Vec<Header>) -> Result<(), HeaderSyncError> {
+        let Some(first) = headers.first() else {
+            return Ok(());
+        };
+        if first.prev_blockhash.ne(&self.header_chain.tip_hash()) {
+            return Err(HeaderSyncError::HeadersNotConnected);
+        }
+        self.sanity_check(&headers)?;
+        for header in headers {
+            if let AcceptHeaderChanges::Rejected(rejection) =
+                self.header_chain.accept_header(header)
+            {
+                return Err(match rejection {
+                    HeaderRejection::InvalidPow {
+                        expected: _,
+                        got: _,
+                    } => HeaderSyncError::InvalidBits,
+                    HeaderRejection::DeepReorg { depth } => HeaderSyncError::DeepReorg(depth),
+                    HeaderRejection::UnknownPrevHash(_) => HeaderSyncError::FloatingHeaders,
+                });
+            }
+        }
+        Ok(())
+    }
+
     // These are invariants in all batches of headers we receive
     fn sanity_check(&mut self, header_batch: &[Header]) -> Result<(), HeaderSyncError> {
         if !header_batch.connected() {
             return Err(HeaderSyncError::HeadersNotConnected);
         }
+        if !header_batch.within_pow_limit(self.network) {
+            return Err(HeaderSyncError::TargetExceedsPowLimit);
+        }
         if !header_batch.passes_own_pow() {
             return Err(HeaderSyncError::InvalidHeaderWork);
         }
         if !header_batch.bits_adhere_transition_threshold(self.network) {
             return Err(HeaderSyncError::InvalidBits);
         }
+        let first = header_batch.first().expect("non-empty check in sync_chain");
+        let prior_headers = self
+            .header_chain
+            .ancestors(first.prev_blockhash, MEDIAN_TIME_SPAN);
+        if !header_batch.exceeds_median_time_past(&prior_headers) {
+            return Err(HeaderSyncError::InvalidHeaderTimes);
+        }
+        if !self.matches_checkpoints(header_batch) {
+            return Err(HeaderSyncError::InvalidCheckpoint);
+        }
         Ok(())
     }
 
@@ -171,18 +378,19 @@ impl Chain {
             .ok_or(CFHeaderSyncError::UnexpectedCFHeaderMessage)?;
         if let Some(expected) = request.expected_prev_filter_header {
             if expected.ne(batch.prev_header()) {
-                // This is an older message and we already have the headers corresponding to this
-                // message
-                if self
+                // This is an older message, which may cover headers we already have
+                if self.already_accepted(&batch) {
+                    return Ok(CFHeaderChanges::Duplicate);
+                }
+                let prev_header = self
                     .header_chain
-                    .iter_data()
-                    .filter_map(|node| node.filter_commitment)
-                    .any(|commit| commit.header.eq(batch.prev_header()))
-                {
+                    .height_of_hash(batch.stop_hash())
+                    .and_then(|height| height.checked_sub(batch.len()))
+                    .and_then(|height| self.known_filter_header(height));
+                if prev_header.is_some_and(|header| header.eq(batch.prev_header())) {
                     return Ok(CFHeaderChanges::AddedToQueue);
-                } else {
-                    return Err(CFHeaderSyncError::PrevHeaderMismatch);
                 }
+                return Err(CFHeaderSyncError::PrevHeaderMismatch);
             }
         }
         if request.stop_hash.ne(&batch.stop_hash()) {
@@ -209,10 +417,46 @@ impl Chain {
         if expected_start_height.ne(&request.start_height) {
             return Err(CFHeaderSyncError::StartHeightMisalignment);
         }
+        // The batch must extend the filter header held for the block below it, so a peer cannot
+        // splice in a segment of filter headers that does not connect
+        let prev_header = expected_start_height
+            .checked_sub(1)
+            .and_then(|height| self.known_filter_header(height));
+        if let Some(prev_header) = prev_header {
+            if prev_header.ne(batch.prev_header()) {
+                return Err(CFHeaderSyncError::PrevHeaderMismatch);
+            }
+        }
+        // A peer that contradicts a checkpoint is lying, regardless of what other peers send
+        let contradicts_checkpoint =
+            batch
+                .filter_headers()
+                .zip(expected_start_height..)
+                .any(|(filter_header, height)| {
+                    self.filter_header_checkpoints
+                        .binary_search_by_key(&height, |checkpoint| checkpoint.height)
+                        .is_ok_and(|index| {
+                            self.filter_header_checkpoints[index]
+                                .filter_header
+                                .ne(&filter_header)
+                        })
+                });
+        if contradicts_checkpoint {
+            return Err(CFHeaderSyncError::InvalidCheckpoint);
+        }
+        if self.already_accepted(&batch) {
+            return Ok(CFHeaderChanges::Duplicate);
+        }
 
         match self.request_state.pending_batch.take() {
             Some((id, pending)) => {
+                // A peer resending its own batch does not count towards agreement
                 if peer_id.eq(&id) {
+                    let resent = pending.eq(&batch);
+                    self.request_state.pending_batch = Some((id, pending));
+                    if resent {
+                        return Ok(CFHeaderChanges::Duplicate);
+                    }
                     return Ok(CFHeaderChanges::AddedToQueue);
                 }
                 if pending.ne(&batch) {
@@ -245,6 +489,27 @@ impl Chain {
         }
     }
 
+    // The headers up to the stop hash of this batch were already accepted
+    fn already_accepted(&self, batch: &CFHeaderBatch) -> bool {
+        self.header_chain
+            .filter_commitment(batch.stop_hash())
+            .is_some_and(|commitment| batch.filter_headers().last() == Some(commitment.header))
+    }
+
+    // The filter header committed to at a height, from the synced filter headers or a checkpoint
+    fn known_filter_header(&self, height: u32) -> Option<FilterHeader> {
+        self.header_chain
+            .block_hash_at_height(height)
+            .and_then(|hash| self.header_chain.filter_commitment(hash))
+            .map(|commitment| commitment.header)
+            .or_else(|| {
+                self.filter_header_checkpoints
+                    .binary_search_by_key(&height, |checkpoint| checkpoint.height)
+                    .ok()
+                    .map(|index| self.filter_header_checkpoints[index].filter_header)
+            })
+    }
+
     fn push_cf_header_batch(&mut self, mut batch: CFHeaderBatch, stop_hash: BlockHash) {
         // Start from the stop hash and work backwards
         let cf_header_iter = batch.take_inner().into_iter().rev();
@@ -298,6 +563,60 @@ impl Chain {
         }
     }
 
+    // Build a request for an arbitrary range of filter headers, independent of the sync.
+    pub(crate) fn filter_header_range_message(
+        &self,
+        start_height: u32,
+        stop_hash: BlockHash,
+    ) -> Result<GetCFHeaders, FetchFilterHeadersError> {
+        let stop_height = self
+            .header_chain
+            .height_of_hash_canonical_only(stop_hash)
+            .ok_or(FetchFilterHeadersError::UnknownStopHash)?;
+        match stop_height.checked_sub(start_height) {
+            Some(offset) if offset <= CF_HEADER_BATCH_SIZE => Ok(GetCFHeaders {
+                filter_type: self.filter_type.into(),
+                start_height,
+                stop_hash,
+            }),
+            _ => Err(FetchFilterHeadersError::InvalidRange),
+        }
+    }
+
+    // Check a range of filter headers from a peer against any the node has already synced
+    pub(crate) fn filter_headers_consistent(
+        &self,
+        start_height: u32,
+        cf_headers: &CFHeaders,
+    ) -> bool {
+        let previous = match start_height.checked_sub(1) {
+            Some(height) => self.known_filter_header(height),
+            None => Some(FilterHeader::all_zeros()),
+        };
+        if previous.is_some_and(|previous| previous.ne(&cf_headers.previous_filter_header)) {
+            return false;
+        }
+        let mut header = cf_headers.previous_filter_header;
+        for (height, filter_hash) in (start_height..).zip(&cf_headers.filter_hashes) {
+            header = filter_hash.filter_header(&header);
+            if self
+                .known_filter_header(height)
+                .is_some_and(|known| known.ne(&header))
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Is the sync waiting on filter headers ending in this stop hash
+    pub(crate) fn awaiting_cf_headers(&self, stop_hash: &BlockHash) -> bool {
+        self.request_state
+            .last_filter_header_request
+            .is_some_and(|request| request.stop_hash.eq(stop_hash))
+            || self.request_state.was_recently_requested(stop_hash)
+    }
+
     // Are the compact filter headers caught up to the header chain
     pub(crate) fn is_cf_headers_synced(&self) -> bool {
         self.header_chain.filter_headers_synced()
@@ -306,6 +625,7 @@ impl Chain {
     // Handle a new filter
     pub(crate) fn sync_filter(
         &mut self,
+        peer_id: PeerId,
         filter_message: CFilter,
     ) -> Result<FilterCheck, CFilterSyncError> {
         let filter = Filter::new(filter_message.filter, filter_message.block_hash);
@@ -339,20 +659,147 @@ impl Chain {
             .header_chain
             .header_at_hash(filter_message.block_hash)
             .ok_or(CFilterSyncError::UnknownFilterHash)?;
+        self.filter_cache.insert(&filter);
         let indexed_filter = IndexedFilter::new(height, header, filter);
         self.dialog.send_event(Event::IndexedFilter(indexed_filter));
         self.header_chain.check_filter(filter_message.block_hash);
-        let stop_hash = self
-            .request_state
-            .last_filter_request
-            .ok_or(CFilterSyncError::UnrequestedStophash)?
-            .stop_hash;
-        let was_last_in_batch = filter_message.block_hash.eq(&stop_hash);
+        if self.request_state.last_filter_request.is_none() {
+            return Err(CFilterSyncError::UnrequestedStophash);
+        }
+        let in_flight = &mut self.request_state.filter_batches_in_flight;
+        // The peer that responds to a request takes ownership of the batch
+        if let Some(batch) = in_flight.iter_mut().find(|batch| {
+            batch.peer.is_none() && (batch.start_height..=batch.stop_height).contains(&height)
+        }) {
+            batch.peer = Some(peer_id);
+        }
+        let was_last_in_batch = match in_flight
+            .iter()
+            .position(|batch| batch.stop_hash.eq(&filter_message.block_hash))
+        {
+            Some(index) => {
+                let batch = in_flight.remove(index);
+                if batch.is_some_and(|batch| batch.requested.elapsed() < FAST_FILTER_BATCH)
+                    && self.filter_batch_size.grow()
+                {
+                    crate::debug!(format!(
+                        "Filter batch size increased to {}",
+                        self.filter_batch_size.get()
+                    ));
+                }
+                true
+            }
+            None => false,
+        };
         Ok(FilterCheck { was_last_in_batch })
     }
 
-    // Next filter message, if there is one
-    pub(crate) fn next_filter_message(&mut self) -> GetCFilters {
+    // Start requesting filters from the first filter that has not been checked. Batches already in
+    // flight with other peers are left to complete.
+    pub(crate) fn next_filter_message(&mut self, peer_id: PeerId) -> Option<GetCFilters> {
+        let start_height = self.first_unchecked_filter();
+        crate::debug!(format!(
+            "Requesting filters from height {start_height} in batches of {}",
+            self.filter_batch_size.get()
+        ));
+        self.filter_message_from(start_height, Some(peer_id))
+    }
+
+    // Check the cached filters that are not checked, skipping any that are part of a batch in
+    // flight. Returns the number of filters taken from the cache.
+    pub(crate) fn replay_cached_filters(&mut self) -> u32 {
+        let mut cached: Vec<(u32, BlockHash)> = self
+            .filter_cache
+            .block_hashes()
+            .filter(|block_hash| !self.header_chain.is_filter_checked(block_hash))
+            .filter_map(|block_hash| {
+                let height = self
+                    .header_chain
+                    .height_of_hash_canonical_only(block_hash)?;
+                Some((height, block_hash))
+            })
+            .filter(|(height, _)| !self.is_filter_in_flight(*height))
+            .collect();
+        cached.sort();
+        let mut replayed = 0;
+        for (height, block_hash) in cached {
+            let Some(header) = self.header_chain.header_at_hash(block_hash) else {
+                continue;
+            };
+            let Some(filter) = self.filter_cache.get(&block_hash).cloned() else {
+                continue;
+            };
+            let indexed_filter = IndexedFilter::new(height, header, filter);
+            self.dialog.send_event(Event::IndexedFilter(indexed_filter));
+            self.header_chain.check_filter(block_hash);
+            replayed += 1;
+        }
+        replayed
+    }
+
+    fn is_filter_in_flight(&self, height: u32) -> bool {
+        self.request_state
+            .filter_batches_in_flight
+            .iter()
+            .any(|batch| (batch.start_height..=batch.stop_height).contains(&height))
+    }
+
+    fn is_filter_cached(&self, height: u32) -> bool {
+        self.header_chain
+            .block_hash_at_height(height)
+            .is_some_and(|block_hash| self.filter_cache.contains(&block_hash))
+    }
+
+    // Continue requesting filters from a peer after the batches that are in flight, if any
+    // filters remain.
+    pub(crate) fn pipelined_filter_message(&mut self, peer_id: PeerId) -> Option<GetCFilters> {
+        if self.request_state.filter_batches_in_flight.is_empty() {
+            if self.is_filters_synced() {
+                return None;
+            }
+            self.request_state.filter_batches_in_flight.clear();
+            return self.filter_message_from(self.first_unchecked_filter(), Some(peer_id));
+        }
+        let last_stop_hash = self.request_state.last_filter_request?.stop_hash;
+        let start_height = self.header_chain.height_of_hash(last_stop_hash)? + 1;
+        self.filter_message_from(start_height, Some(peer_id))
+    }
+
+    // The number of filter batches requested but not yet received.
+    #[cfg(test)]
+    pub(crate) fn filter_batches_in_flight(&self) -> usize {
+        self.request_state.filter_batches_in_flight.len()
+    }
+
+    // The number of filter batches requested from a peer but not yet received.
+    pub(crate) fn filter_batches_in_flight_with(&self, peer_id: PeerId) -> usize {
+        self.request_state
+            .filter_batches_in_flight
+            .iter()
+            .filter(|batch| batch.peer.eq(&Some(peer_id)))
+            .count()
+    }
+
+    // A peer disconnected before completing its batches, so the ranges must be requested again.
+    // The batches remain in flight and are taken by the next peer to respond.
+    pub(crate) fn release_filter_batches(&mut self, peer_id: PeerId) -> Vec<GetCFilters> {
+        let filter_type = self.filter_type.into();
+        self.request_state
+            .filter_batches_in_flight
+            .iter_mut()
+            .filter(|batch| batch.peer.eq(&Some(peer_id)))
+            .map(|batch| {
+                batch.peer = None;
+                GetCFilters {
+                    filter_type,
+                    start_height: batch.start_height,
+                    stop_hash: batch.stop_hash,
+                }
+            })
+            .collect()
+    }
+
+    fn first_unchecked_filter(&self) -> u32 {
         let mut last_unchecked_filter = self.header_chain.height();
         for block_data in self.header_chain.iter_data() {
             if block_data.height.eq(&0) {
@@ -363,20 +810,60 @@ impl Chain {
             }
             last_unchecked_filter = block_data.height;
         }
-        let stop_hash_index = last_unchecked_filter + FILTER_BATCH_SIZE;
+        last_unchecked_filter
+    }
+
+    // The lowest height of a block with a filter that has not been checked, if any
+    pub(crate) fn lowest_unchecked_filter(&self) -> Option<u32> {
+        self.header_chain
+            .iter_data()
+            .take_while(|block_data| block_data.height > 0)
+            .filter(|block_data| !block_data.filter_checked)
+            .map(|block_data| block_data.height)
+            .min()
+    }
+
+    // Request the next batch of filters from a height. Cached filters are replayed instead of
+    // downloaded, so the batch starts after and stops before any of them.
+    fn filter_message_from(
+        &mut self,
+        mut start_height: u32,
+        peer: Option<PeerId>,
+    ) -> Option<GetCFilters> {
+        let tip = self.header_chain.height();
+        while start_height <= tip && self.is_filter_cached(start_height) {
+            start_height += 1;
+        }
+        if start_height > tip {
+            return None;
+        }
+        let stop_height = (start_height
+            ..=(start_height + self.filter_batch_size.get() - 1).min(tip))
+            .take_while(|height| !self.is_filter_cached(*height))
+            .last()
+            .unwrap_or(start_height);
         let stop_hash = self
             .header_chain
-            .block_hash_at_height(stop_hash_index)
+            .block_hash_at_height(stop_height)
             .unwrap_or(self.header_chain.tip_hash());
         self.request_state.last_filter_request = Some(FilterRequest {
             stop_hash,
-            start_height: last_unchecked_filter,
+            start_height,
         });
-        GetCFilters {
+        self.request_state
+            .filter_batches_in_flight
+            .push_back(FilterBatch {
+                start_height,
+                stop_hash,
+                stop_height,
+                peer,
+                requested: Instant::now(),
+            });
+        Some(GetCFilters {
             filter_type: self.filter_type.into(),
-            start_height: last_unchecked_filter,
+            start_height,
             stop_hash,
-        }
+        })
     }
 
     // Are we synced with filters
@@ -391,26 +878,66 @@ impl Chain {
         self.request_state.pending_batch = None;
     }
 
+    // The height of the chain state the tree was built from, below which no filters are known
+    pub(crate) fn chain_state_height(&self) -> u32 {
+        self.header_chain
+            .iter_data()
+            .last()
+            .map(|block_data| block_data.height.saturating_sub(1))
+            .unwrap_or(self.header_chain.height())
+    }
+
+    // Rescan the filters after a height, which must be between the chain state and the tip
+    pub(crate) fn clear_filters_from(&mut self, height: u32) -> Result<(), RescanError> {
+        let chain_state = self.chain_state_height();
+        if height < chain_state {
+            return Err(RescanError::BelowChainState { chain_state });
+        }
+        let tip = self.header_chain.height();
+        if height > tip {
+            return Err(RescanError::AboveTip { tip });
+        }
+        self.header_chain.reset_filters_above(height);
+        Ok(())
+    }
+
+    // Headers are only pruned once every filter is checked, so none are dropped before they are
+    // scanned
+    pub(crate) fn prune_headers(&mut self, depth: u32) -> usize {
+        if !self.is_filters_synced() {
+            return 0;
+        }
+        let pruned = self.header_chain.prune_below_depth(depth);
+        if let Some(root) = self.header_chain.root_height() {
+            self.tx_index.prune_below(root);
+        }
+        pruned
+    }
+
     // Clear the filter header cache to rescan the filters for new scripts.
     pub(crate) fn clear_filters(&mut self) {
         self.header_chain.reset_all_filters();
     }
 
-    pub(crate) fn send_chain_update(&self) {
+    pub(crate) fn send_chain_update(&self, stage: SyncStage) {
         self.dialog.send_info(Info::Progress(Progress::new(
+            stage,
             self.header_chain.total_filter_headers_synced(),
             self.header_chain.total_filters_synced(),
             self.header_chain.internal_chain_len() as u32,
             self.header_chain.height(),
         )));
-        crate::debug!(format!(
-            "Headers: {} CFHeaders: ({}/{}) CFilters: ({}/{})",
-            self.header_chain.height(),
-            self.header_chain.total_filter_headers_synced(),
-            self.header_chain.internal_chain_len() as u32,
-            self.header_chain.total_filters_synced(),
-            self.header_chain.internal_chain_len() as u32,
-        ));
+        crate::debug!(
+            format!(
+                "Headers: {} CFHeaders: ({}/{}) CFilters: ({}/{})",
+                self.header_chain.height(),
+                self.header_chain.total_filter_headers_synced(),
+                self.header_chain.internal_chain_len() as u32,
+                self.header_chain.total_filters_synced(),
+                self.header_chain.internal_chain_len() as u32,
+            ),
+            height = self.header_chain.height()
+        );
     }
 }
 
@@ -422,33 +949,44 @@ mod tests {
     use bitcoin::hashes::sha256d;
     use bitcoin::hashes::Hash;
     use bitcoin::{
+        absolute::LockTime,
         block::Header,
         consensus::deserialize,
         p2p::message_filter::{CFHeaders, CFilter},
-        BlockHash, FilterHash, FilterHeader,
+        transaction::Version,
+        Block, BlockHash, FilterHash, FilterHeader, Transaction,
     };
     use corepc_node::serde_json;
 
     use crate::chain::ChainState;
+    use crate::error::FetchFilterHeadersError;
     use crate::FilterType;
     use crate::{
-        chain::checkpoints::HashCheckpoint,
+        chain::checkpoints::{FilterHeaderCheckpoint, HashCheckpoint},
         messages::{Event, Info, Warning},
         Dialog,
     };
 
     use super::{CFHeaderChanges, Chain};
+    use crate::chain::graph::MAX_REORG_DEPTH;
 
     fn new_regtest(anchor: HashCheckpoint, peers: u8) -> Chain {
         let (info_tx, _) = tokio::sync::mpsc::channel::<Info>(1);
         let (warn_tx, _) = tokio::sync::mpsc::unbounded_channel::<Warning>();
-        let (event_tx, _) = tokio::sync::mpsc::unbounded_channel::<Event>();
+        let (event_tx, _) = tokio::sync::mpsc::channel::<Event>(1);
         Chain::new(
             bitcoin::Network::Regtest,
             ChainState::Checkpoint(anchor),
-            Arc::new(Dialog::new(info_tx, warn_tx, event_tx)),
+            Arc::new(Dialog::new(
+                info_tx,
+                warn_tx,
+                event_tx,
+                tokio::sync::broadcast::channel(1).0,
+            )),
             peers,
             FilterType::Basic,
+            MAX_REORG_DEPTH,
+            0,
         )
     }
 
@@ -616,13 +1154,43 @@ mod tests {
         let append_attempt = cf_header_sync_res.unwrap();
         assert_eq!(CFHeaderChanges::Extended, append_attempt);
         assert!(chain.is_cf_headers_synced());
-        chain.next_filter_message();
-        for filter in scenario.filters().into_iter().rev() {
-            assert!(chain.sync_filter(filter).is_ok())
+        chain.next_filter_message(0.into());
+        assert_eq!(chain.lowest_unchecked_filter(), Some(2497));
+        let mut filters = scenario.filters();
+        let first = filters.remove(0);
+        for filter in filters.into_iter().rev() {
+            assert!(chain.sync_filter(0.into(), filter).is_ok())
         }
+        // Resuming must not skip the filter that was not checked
+        assert_eq!(chain.lowest_unchecked_filter(), Some(2497));
+        assert!(chain.sync_filter(0.into(), first).is_ok());
+        assert_eq!(chain.lowest_unchecked_filter(), None);
         assert!(chain.is_filters_synced());
     }
 
+    #[tokio::test]
+    async fn test_filter_headers_consistent() {
+        let gen = base_block();
+        let mut chain = new_regtest(gen, 1);
+        let scenario = load_scenario();
+        chain.sync_chain(scenario.most_work_headers()).unwrap();
+        let cf_headers = CFHeaders {
+            filter_type: 0x00,
+            stop_hash: scenario.last_block_hash(),
+            previous_filter_header: scenario.prev_header(),
+            filter_hashes: scenario.n_most_work_filter_hashes(5),
+        };
+        chain.next_cf_header_message();
+        chain.sync_cf_headers(0.into(), cf_headers.clone()).unwrap();
+        assert!(chain.filter_headers_consistent(2497, &cf_headers));
+        let mut wrong_prev = cf_headers.clone();
+        wrong_prev.previous_filter_header = FilterHeader::all_zeros();
+        assert!(!chain.filter_headers_consistent(2497, &wrong_prev));
+        let mut wrong_hash = cf_headers.clone();
+        wrong_hash.filter_hashes[2] = FilterHash::all_zeros();
+        assert!(!chain.filter_headers_consistent(2497, &wrong_hash));
+    }
+
     #[tokio::test]
     async fn test_bad_filter() {
         let gen = base_block();
@@ -644,16 +1212,19 @@ mod tests {
         let append_attempt = cf_header_sync_res.unwrap();
         assert_eq!(CFHeaderChanges::Extended, append_attempt);
         assert!(chain.is_cf_headers_synced());
-        chain.next_filter_message();
+        chain.next_filter_message(0.into());
         let mismatch_filter = scenario.filters().first().unwrap().filter.clone();
-        let sync_filter_1 = chain.sync_filter(CFilter {
-            filter_type: 0x00,
-            block_hash: scenario.last_block_hash(),
-            filter: mismatch_filter,
-        });
+        let sync_filter_1 = chain.sync_filter(
+            0.into(),
+            CFilter {
+                filter_type: 0x00,
+                block_hash: scenario.last_block_hash(),
+                filter: mismatch_filter,
+            },
+        );
         assert!(sync_filter_1.is_err());
         let good_filter = scenario.filters().last().unwrap().clone();
-        let sync_filter_1 = chain.sync_filter(good_filter);
+        let sync_filter_1 = chain.sync_filter(0.into(), good_filter);
         assert!(sync_filter_1.is_ok());
     }
 
@@ -811,16 +1382,71 @@ mod tests {
         let cf_header_sync_res = chain.sync_cf_headers(2.into(), cf_headers);
         assert!(cf_header_sync_res.is_ok());
         assert_eq!(cf_header_sync_res.unwrap(), CFHeaderChanges::Extended);
-        chain.next_filter_message();
+        chain.next_filter_message(0.into());
         let filters = scenario.filters();
-        let sync_filter_1 = chain.sync_filter(filters[0].clone());
+        let sync_filter_1 = chain.sync_filter(0.into(), filters[0].clone());
         assert!(sync_filter_1.is_ok());
-        let sync_filter_2 = chain.sync_filter(filters[1].clone());
+        let sync_filter_2 = chain.sync_filter(0.into(), filters[1].clone());
         assert!(sync_filter_2.is_ok());
-        let sync_filter_4 = chain.sync_filter(filters[3].clone());
+        let sync_filter_4 = chain.sync_filter(0.into(), filters[3].clone());
         assert!(sync_filter_4.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_tx_index_invalidated_on_reorg() {
+        let gen = base_block();
+        let mut chain = new_regtest(gen, 2);
+        let scenario = load_scenario();
+        let mut stale_headers = scenario.n_most_work_headers(3);
+        let stale_block_data = scenario.stale_chain.first().unwrap();
+        stale_headers.push(stale_block_data.header.0);
+        chain.sync_chain(stale_headers).unwrap();
+        let tx = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: Vec::new(),
+            output: Vec::new(),
+        };
+        let txid = tx.compute_txid();
+        let block = Block {
+            header: stale_block_data.header.0,
+            txdata: vec![tx],
+        };
+        chain.index_transactions(&block, 2500);
+        assert_eq!(
+            chain.find_transaction(&txid),
+            Some((block.block_hash(), 2500))
+        );
+        let most_work = scenario.most_work_headers();
+        chain.sync_chain(vec![most_work[3], most_work[4]]).unwrap();
+        assert_eq!(chain.find_transaction(&txid), None);
+    }
+
+    #[tokio::test]
+    async fn test_tx_index_keeps_deep_blocks() {
+        let gen = base_block();
+        let mut chain = new_regtest(gen, 2);
+        let scenario = load_scenario();
+        chain.sync_chain(scenario.n_most_work_headers(4)).unwrap();
+        let tx = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: Vec::new(),
+            output: Vec::new(),
+        };
+        let txid = tx.compute_txid();
+        let block = Block {
+            header: scenario.most_work_headers()[3],
+            txdata: vec![tx],
+        };
+        // Blocks found by a rescan may be arbitrarily deep
+        let tip = chain.header_chain.height();
+        chain.index_transactions(&block, 1);
+        assert!(chain.find_transaction(&txid).is_some());
+        chain.tx_index.prune_below(tip);
+        assert_eq!(chain.find_transaction(&txid), None);
+    }
+
     #[tokio::test]
     async fn test_reorg_with_queue() {
         let gen = base_block();
@@ -941,12 +1567,15 @@ mod tests {
         let cf_header_sync_res = chain.sync_cf_headers(0.into(), cf_headers);
         assert!(cf_header_sync_res.is_ok());
         assert_eq!(cf_header_sync_res.unwrap(), CFHeaderChanges::Extended);
-        chain.next_filter_message();
-        let sync_filter_1 = chain.sync_filter(CFilter {
-            filter_type: 0x00,
-            block_hash: block_1.block_hash(),
-            filter: filter_1,
-        });
+        chain.next_filter_message(0.into());
+        let sync_filter_1 = chain.sync_filter(
+            0.into(),
+            CFilter {
+                filter_type: 0x00,
+                block_hash: block_1.block_hash(),
+                filter: filter_1,
+            },
+        );
         assert!(sync_filter_1.is_ok());
         // Reorganize the blocks
         let header_batch = vec![new_block_4, block_5];
@@ -980,17 +1609,23 @@ mod tests {
         let cf_header_sync_res = chain.sync_cf_headers(1.into(), cf_headers);
         assert!(cf_header_sync_res.is_ok());
         assert_eq!(cf_header_sync_res.unwrap(), CFHeaderChanges::Extended);
-        let sync_filter_4 = chain.sync_filter(CFilter {
-            filter_type: 0x00,
-            block_hash: block_4.block_hash(),
-            filter: filter_4,
-        });
+        let sync_filter_4 = chain.sync_filter(
+            0.into(),
+            CFilter {
+                filter_type: 0x00,
+                block_hash: block_4.block_hash(),
+                filter: filter_4,
+            },
+        );
         assert!(sync_filter_4.is_err());
-        let sync_filter_4 = chain.sync_filter(CFilter {
-            filter_type: 0x00,
-            block_hash: new_block_4.block_hash(),
-            filter: new_filter_4,
-        });
+        let sync_filter_4 = chain.sync_filter(
+            0.into(),
+            CFilter {
+                filter_type: 0x00,
+                block_hash: new_block_4.block_hash(),
+                filter: new_filter_4,
+            },
+        );
         assert!(sync_filter_4.is_ok());
     }
 
@@ -1078,4 +1713,409 @@ mod tests {
         assert_eq!(cf_header_sync_res.unwrap(), CFHeaderChanges::AddedToQueue);
         assert!(!chain.is_cf_headers_synced());
     }
+
+    #[tokio::test]
+    async fn test_filter_header_range_request() {
+        let gen = base_block();
+        let mut chain = new_regtest(gen, 1);
+        let scenario = load_scenario();
+        chain.sync_chain(scenario.most_work_headers()).unwrap();
+        let stop_hash = scenario.last_block_hash();
+        let message = chain.filter_header_range_message(2497, stop_hash).unwrap();
+        assert_eq!(message.start_height, 2497);
+        assert_eq!(message.stop_hash, stop_hash);
+        assert!(matches!(
+            chain.filter_header_range_message(2502, stop_hash),
+            Err(FetchFilterHeadersError::InvalidRange)
+        ));
+        assert!(matches!(
+            chain.filter_header_range_message(2497, BlockHash::all_zeros()),
+            Err(FetchFilterHeadersError::UnknownStopHash)
+        ));
+        // A user request does not interfere with the sync
+        assert!(!chain.awaiting_cf_headers(&stop_hash));
+        chain.next_cf_header_message();
+        assert!(chain.awaiting_cf_headers(&stop_hash));
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_filter_requests() {
+        let gen = base_block();
+        let mut chain = new_regtest(gen, 1);
+        let scenario = load_scenario();
+        chain.sync_chain(scenario.most_work_headers()).unwrap();
+        chain.next_cf_header_message();
+        let cf_headers = CFHeaders {
+            filter_type: 0x00,
+            stop_hash: scenario.last_block_hash(),
+            previous_filter_header: scenario.prev_header(),
+            filter_hashes: scenario.n_most_work_filter_hashes(5),
+        };
+        chain.sync_cf_headers(0.into(), cf_headers).unwrap();
+        let message = chain.next_filter_message(0.into()).unwrap();
+        assert_eq!(message.start_height, 2497);
+        assert_eq!(chain.filter_batches_in_flight(), 1);
+        // The batch in flight already covers the tip
+        assert!(chain.pipelined_filter_message(0.into()).is_none());
+        let mut filters = scenario.filters().into_iter();
+        let check = chain
+            .sync_filter(0.into(), filters.next().unwrap())
+            .unwrap();
+        assert!(!check.was_last_in_batch);
+        for filter in filters {
+            chain.sync_filter(0.into(), filter).unwrap();
+        }
+        assert_eq!(chain.filter_batches_in_flight(), 0);
+        assert!(chain.is_filters_synced());
+        assert!(chain.pipelined_filter_message(0.into()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_filter_batches_reassigned() {
+        let gen = base_block();
+        let mut chain = new_regtest(gen, 1);
+        let scenario = load_scenario();
+        chain.sync_chain(scenario.most_work_headers()).unwrap();
+        chain.next_cf_header_message();
+        let cf_headers = CFHeaders {
+            filter_type: 0x00,
+            stop_hash: scenario.last_block_hash(),
+            previous_filter_header: scenario.prev_header(),
+            filter_hashes: scenario.n_most_work_filter_hashes(5),
+        };
+        chain.sync_cf_headers(0.into(), cf_headers).unwrap();
+        // The peer the first batch is sent to owns it before responding
+        chain.next_filter_message(1.into());
+        assert_eq!(chain.filter_batches_in_flight_with(1.into()), 1);
+        let mut filters = scenario.filters().into_iter();
+        chain
+            .sync_filter(1.into(), filters.next().unwrap())
+            .unwrap();
+        let reassigned = chain.release_filter_batches(1.into());
+        assert_eq!(reassigned.len(), 1);
+        assert_eq!(reassigned[0].start_height, 2497);
+        assert_eq!(reassigned[0].stop_hash, scenario.last_block_hash());
+        assert_eq!(chain.filter_batches_in_flight_with(1.into()), 0);
+        // A released batch is owned by the next peer to respond
+        chain
+            .sync_filter(2.into(), filters.next().unwrap())
+            .unwrap();
+        assert_eq!(chain.filter_batches_in_flight_with(2.into()), 1);
+        // Filters already checked are not emitted again
+        for filter in scenario.filters() {
+            chain.sync_filter(2.into(), filter).unwrap();
+        }
+        assert!(chain.is_filters_synced());
+        assert_eq!(chain.filter_batches_in_flight(), 0);
+    }
+
+    #[test]
+    fn test_preload_headers() {
+        let chain_scenario = load_scenario();
+        let headers = chain_scenario.most_work_headers();
+        // Headers that skip a block do not connect to the checkpoint
+        let mut chain = new_regtest(base_block(), 1);
+        assert!(chain.preload_headers(headers[1..].to_vec()).is_err());
+        assert_eq!(chain.header_chain.height(), 2496);
+        let mut chain = new_regtest(base_block(), 1);
+        assert!(chain.preload_headers(headers.clone()).is_ok());
+        assert_eq!(chain.header_chain.height(), 2496 + headers.len() as u32);
+        assert_eq!(
+            chain.header_chain.tip_hash(),
+            chain_scenario.last_block_hash()
+        );
+    }
+
+    #[test]
+    fn test_regtest_sync_from_genesis() {
+        let genesis = bitcoin::constants::genesis_block(bitcoin::Network::Regtest).header;
+        let mut chain = new_regtest(HashCheckpoint::from_genesis(bitcoin::Network::Regtest), 1);
+        // Regtest headers are mined at the minimum difficulty and never retarget
+        let mut headers = Vec::new();
+        let mut prev_blockhash = genesis.block_hash();
+        for i in 1..=2020u32 {
+            let mut header = Header {
+                version: genesis.version,
+                prev_blockhash,
+                merkle_root: genesis.merkle_root,
+                time: genesis.time + i,
+                bits: genesis.bits,
+                nonce: 0,
+            };
+            while header.validate_pow(header.target()).is_err() {
+                header.nonce += 1;
+            }
+            prev_blockhash = header.block_hash();
+            headers.push(header);
+        }
+        let (first, second) = headers.split_at(2000);
+        assert!(chain.sync_chain(first.to_vec()).is_ok());
+        assert!(chain.sync_chain(second.to_vec()).is_ok());
+        assert_eq!(chain.header_chain.height(), 2020);
+        assert_eq!(chain.header_chain.tip_hash(), prev_blockhash);
+    }
+
+    #[test]
+    fn test_user_checkpoints() {
+        let chain_scenario = load_scenario();
+        let headers = chain_scenario.most_work_headers();
+        let mut chain = new_regtest(base_block(), 1);
+        // Heights must strictly increase
+        let out_of_order = vec![
+            HashCheckpoint::new(2498, headers[1].block_hash()),
+            HashCheckpoint::new(2497, headers[0].block_hash()),
+        ];
+        assert!(chain.set_checkpoints(out_of_order).is_err());
+        // A conflicting header at a checkpoint height is rejected
+        let conflicting = vec![HashCheckpoint::new(2498, headers[0].block_hash())];
+        assert!(chain.set_checkpoints(conflicting).is_ok());
+        assert!(matches!(
+            chain.sync_chain(headers.clone()),
+            Err(super::HeaderSyncError::InvalidCheckpoint)
+        ));
+        assert_eq!(chain.header_chain.height(), 2496);
+        // Matching checkpoints allow the sync
+        let matching = vec![
+            HashCheckpoint::new(2497, headers[0].block_hash()),
+            HashCheckpoint::new(2498, headers[1].block_hash()),
+        ];
+        assert!(chain.set_checkpoints(matching).is_ok());
+        assert!(chain.sync_chain(headers.clone()).is_ok());
+        assert_eq!(chain.header_chain.height(), 2496 + headers.len() as u32);
+    }
+
+    #[tokio::test]
+    async fn test_rescan_from_cached_filters() {
+        let scenario = load_scenario();
+        let mut chain = new_regtest(base_block(), 1);
+        chain.filter_cache = super::FilterCache::new(3);
+        chain.sync_chain(scenario.most_work_headers()).unwrap();
+        chain.next_cf_header_message();
+        let cf_headers = CFHeaders {
+            filter_type: 0x00,
+            stop_hash: scenario.last_block_hash(),
+            previous_filter_header: scenario.prev_header(),
+            filter_hashes: scenario.n_most_work_filter_hashes(5),
+        };
+        chain.sync_cf_headers(0.into(), cf_headers).unwrap();
+        chain.next_filter_message(0.into());
+        for filter in scenario.filters() {
+            chain.sync_filter(0.into(), filter).unwrap();
+        }
+        assert!(chain.is_filters_synced());
+        // The first filters were evicted, so a full rescan must download only those
+        chain.clear_filters();
+        let message = chain.next_filter_message(0.into()).unwrap();
+        assert_eq!(message.start_height, 2497);
+        assert_eq!(
+            message.stop_hash,
+            scenario.most_work_headers()[1].block_hash()
+        );
+        assert!(chain.pipelined_filter_message(0.into()).is_none());
+        // The filters after the first miss are still served from the cache
+        assert_eq!(chain.replay_cached_filters(), 3);
+        assert!(!chain.is_filters_synced());
+        for filter in scenario.filters().into_iter().take(2) {
+            chain.sync_filter(0.into(), filter).unwrap();
+        }
+        assert!(chain.is_filters_synced());
+    }
+
+    #[tokio::test]
+    async fn test_clear_filters_from() {
+        let scenario = load_scenario();
+        let mut chain = new_regtest(base_block(), 1);
+        chain.filter_cache = super::FilterCache::new(5);
+        chain.sync_chain(scenario.most_work_headers()).unwrap();
+        chain.next_cf_header_message();
+        let cf_headers = CFHeaders {
+            filter_type: 0x00,
+            stop_hash: scenario.last_block_hash(),
+            previous_filter_header: scenario.prev_header(),
+            filter_hashes: scenario.n_most_work_filter_hashes(5),
+        };
+        chain.sync_cf_headers(0.into(), cf_headers).unwrap();
+        chain.next_filter_message(0.into());
+        for filter in scenario.filters() {
+            chain.sync_filter(0.into(), filter).unwrap();
+        }
+        assert!(chain.is_filters_synced());
+        let chain_state = chain.chain_state_height();
+        let tip = chain.header_chain.height();
+        assert!(matches!(
+            chain.clear_filters_from(chain_state - 1),
+            Err(super::RescanError::BelowChainState { .. })
+        ));
+        assert!(matches!(
+            chain.clear_filters_from(tip + 1),
+            Err(super::RescanError::AboveTip { .. })
+        ));
+        assert!(chain.is_filters_synced());
+        // Only the filters above the height are rescanned
+        chain.clear_filters_from(tip - 2).unwrap();
+        assert_eq!(chain.first_unchecked_filter(), tip - 1);
+        assert_eq!(chain.replay_cached_filters(), 2);
+        assert!(chain.is_filters_synced());
+    }
+
+    #[tokio::test]
+    async fn test_filter_header_checkpoints() {
+        let scenario = load_scenario();
+        let filter_hashes = scenario.n_most_work_filter_hashes(5);
+        let second_filter_header = filter_hashes[1]
+            .filter_header(&filter_hashes[0].filter_header(&scenario.prev_header()));
+        let cf_headers = CFHeaders {
+            filter_type: 0x00,
+            stop_hash: scenario.last_block_hash(),
+            previous_filter_header: scenario.prev_header(),
+            filter_hashes,
+        };
+        // A batch that contradicts a checkpoint is rejected
+        let mut chain = new_regtest(base_block(), 1);
+        chain.sync_chain(scenario.most_work_headers()).unwrap();
+        chain.set_filter_header_checkpoints(vec![FilterHeaderCheckpoint::new(
+            2498,
+            scenario.prev_header(),
+        )]);
+        chain.next_cf_header_message();
+        assert!(matches!(
+            chain.sync_cf_headers(0.into(), cf_headers.clone()),
+            Err(super::CFHeaderSyncError::InvalidCheckpoint)
+        ));
+        assert!(!chain.is_cf_headers_synced());
+        // A batch that agrees with a checkpoint is accepted
+        let mut chain = new_regtest(base_block(), 1);
+        chain.sync_chain(scenario.most_work_headers()).unwrap();
+        chain.set_filter_header_checkpoints(vec![FilterHeaderCheckpoint::new(
+            2498,
+            second_filter_header,
+        )]);
+        chain.next_cf_header_message();
+        assert_eq!(
+            chain.sync_cf_headers(0.into(), cf_headers).unwrap(),
+            CFHeaderChanges::Extended
+        );
+        assert!(chain.is_cf_headers_synced());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_cf_headers() {
+        let scenario = load_scenario();
+        let cf_headers = CFHeaders {
+            filter_type: 0x00,
+            stop_hash: scenario.last_block_hash(),
+            previous_filter_header: scenario.prev_header(),
+            filter_hashes: scenario.n_most_work_filter_hashes(5),
+        };
+        let mut conflict_hashes = scenario.n_most_work_filter_hashes(4);
+        conflict_hashes.push(
+            scenario
+                .stale_chain
+                .first()
+                .map(|data| data.filter_hash())
+                .unwrap(),
+        );
+        let conflicting = CFHeaders {
+            filter_type: 0x00,
+            stop_hash: scenario.last_block_hash(),
+            previous_filter_header: scenario.prev_header(),
+            filter_hashes: conflict_hashes,
+        };
+        let mut chain = new_regtest(base_block(), 2);
+        chain.sync_chain(scenario.most_work_headers()).unwrap();
+        chain.next_cf_header_message();
+        assert_eq!(
+            chain.sync_cf_headers(0.into(), cf_headers.clone()).unwrap(),
+            CFHeaderChanges::AddedToQueue
+        );
+        // A resend from the same peer is not progress, and the batch stays queued
+        assert_eq!(
+            chain.sync_cf_headers(0.into(), cf_headers.clone()).unwrap(),
+            CFHeaderChanges::Duplicate
+        );
+        assert!(chain.request_state.pending_batch.is_some());
+        // A disagreement with the queued batch is still a conflict
+        assert_eq!(
+            chain.sync_cf_headers(1.into(), conflicting).unwrap(),
+            CFHeaderChanges::Conflict
+        );
+        // Headers that were already accepted are a duplicate
+        let mut chain = new_regtest(base_block(), 1);
+        chain.sync_chain(scenario.most_work_headers()).unwrap();
+        chain.next_cf_header_message();
+        assert_eq!(
+            chain.sync_cf_headers(0.into(), cf_headers.clone()).unwrap(),
+            CFHeaderChanges::Extended
+        );
+        assert_eq!(
+            chain.sync_cf_headers(1.into(), cf_headers).unwrap(),
+            CFHeaderChanges::Duplicate
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_requests_below_checkpoint() {
+        let scenario = load_scenario();
+        let checkpoint = base_block();
+        let mut chain = new_regtest(checkpoint, 1);
+        chain.sync_chain(scenario.most_work_headers()).unwrap();
+        let cf_header_message = chain.next_cf_header_message();
+        assert_eq!(cf_header_message.start_height, checkpoint.height + 1);
+        let cf_headers = CFHeaders {
+            filter_type: 0x00,
+            stop_hash: scenario.last_block_hash(),
+            previous_filter_header: scenario.prev_header(),
+            filter_hashes: scenario.n_most_work_filter_hashes(5),
+        };
+        chain.sync_cf_headers(0.into(), cf_headers).unwrap();
+        assert_eq!(chain.lowest_unchecked_filter(), Some(checkpoint.height + 1));
+        assert_eq!(
+            chain.next_filter_message(0.into()).unwrap().start_height,
+            checkpoint.height + 1
+        );
+        // A rescan of every filter still starts above the checkpoint
+        chain.clear_filters();
+        assert_eq!(
+            chain.next_filter_message(0.into()).unwrap().start_height,
+            checkpoint.height + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prev_filter_header_must_connect() {
+        let scenario = load_scenario();
+        let filter_hashes = scenario.n_most_work_filter_hashes(5);
+        let spliced = CFHeaders {
+            filter_type: 0x00,
+            stop_hash: scenario.last_block_hash(),
+            previous_filter_header: FilterHeader::from_byte_array([1; 32]),
+            filter_hashes: filter_hashes.clone(),
+        };
+        // The chain state is a checkpoint, so the previous filter header is only known from a
+        // filter header checkpoint at the height of the chain state
+        let mut chain = new_regtest(base_block(), 1);
+        chain.sync_chain(scenario.most_work_headers()).unwrap();
+        chain.set_filter_header_checkpoints(vec![FilterHeaderCheckpoint::new(
+            chain.chain_state_height(),
+            scenario.prev_header(),
+        )]);
+        let request = chain.next_cf_header_message();
+        assert_eq!(request.start_height, chain.chain_state_height() + 1);
+        assert!(matches!(
+            chain.sync_cf_headers(0.into(), spliced),
+            Err(super::CFHeaderSyncError::PrevHeaderMismatch)
+        ));
+        assert!(!chain.is_cf_headers_synced());
+        let connected = CFHeaders {
+            filter_type: 0x00,
+            stop_hash: scenario.last_block_hash(),
+            previous_filter_header: scenario.prev_header(),
+            filter_hashes,
+        };
+        assert_eq!(
+            chain.sync_cf_headers(0.into(), connected).unwrap(),
+            CFHeaderChanges::Extended
+        );
+        assert!(chain.is_cf_headers_synced());
+    }
 }
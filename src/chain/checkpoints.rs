@@ -1,6 +1,6 @@
 use std::str::FromStr;
 
-use bitcoin::{constants::genesis_block, params::Params, BlockHash};
+use bitcoin::{bip158::FilterHeader, constants::genesis_block, params::Params, BlockHash};
 
 type Height = u32;
 
@@ -53,6 +53,28 @@ impl HashCheckpoint {
     }
 }
 
+/// A known compact block filter header in the chain of most work.
+///
+/// No filter header checkpoints are built in. The filter header at a height may be found with
+/// `bitcoin-cli getblockfilter <block hash>` on a trusted node with `-blockfilterindex` enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterHeaderCheckpoint {
+    /// The index of the block the filter header commits to.
+    pub height: Height,
+    /// The filter header expected at this height.
+    pub filter_header: FilterHeader,
+}
+
+impl FilterHeaderCheckpoint {
+    /// Create a new checkpoint from a filter header computed by a trusted source.
+    pub fn new(height: Height, filter_header: FilterHeader) -> Self {
+        FilterHeaderCheckpoint {
+            height,
+            filter_header,
+        }
+    }
+}
+
 impl std::cmp::PartialOrd for HashCheckpoint {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
@@ -88,3 +110,27 @@ impl TryFrom<(u32, &str)> for HashCheckpoint {
         Ok(HashCheckpoint::new(value.0, hash))
     }
 }
+
+impl From<(u32, FilterHeader)> for FilterHeaderCheckpoint {
+    fn from(value: (u32, FilterHeader)) -> Self {
+        FilterHeaderCheckpoint::new(value.0, value.1)
+    }
+}
+
+impl TryFrom<(u32, String)> for FilterHeaderCheckpoint {
+    type Error = <FilterHeader as FromStr>::Err;
+
+    fn try_from(value: (u32, String)) -> Result<Self, Self::Error> {
+        let filter_header = FilterHeader::from_str(&value.1)?;
+        Ok(FilterHeaderCheckpoint::new(value.0, filter_header))
+    }
+}
+
+impl TryFrom<(u32, &str)> for FilterHeaderCheckpoint {
+    type Error = <FilterHeader as FromStr>::Err;
+
+    fn try_from(value: (u32, &str)) -> Result<Self, Self::Error> {
+        let filter_header = FilterHeader::from_str(value.1)?;
+        Ok(FilterHeaderCheckpoint::new(value.0, filter_header))
+    }
+}
@@ -6,11 +6,13 @@ use std::fmt::Debug;
 pub(crate) enum HeaderSyncError {
     HeadersNotConnected,
     InvalidHeaderWork,
+    TargetExceedsPowLimit,
     InvalidHeaderTimes,
     InvalidCheckpoint,
     MiscalculatedDifficulty,
     InvalidBits,
     FloatingHeaders,
+    DeepReorg(u32),
 }
 
 impl Display for HeaderSyncError {
@@ -22,6 +24,12 @@ impl Display for HeaderSyncError {
             HeaderSyncError::InvalidHeaderWork => {
                 write!(f, "one or more headers does not match its own PoW target.")
             }
+            HeaderSyncError::TargetExceedsPowLimit => {
+                write!(
+                    f,
+                    "one or more headers has a target easier than the network minimum difficulty."
+                )
+            }
             HeaderSyncError::InvalidHeaderTimes => {
                 write!(f, "one or more headers does not have a valid block time.")
             }
@@ -35,6 +43,10 @@ impl Display for HeaderSyncError {
                 f,
                 "the peer sent us a chain that does not connect to any header of ours."
             ),
+            HeaderSyncError::DeepReorg(depth) => write!(
+                f,
+                "the peer proposed a reorganization of {depth} blocks, which exceeds the limit."
+            ),
             HeaderSyncError::InvalidBits => write!(
                 f,
                 "the target work does not adhere to basic transition requirements."
@@ -55,6 +67,7 @@ pub enum CFHeaderSyncError {
     HeaderChainIndexOverflow,
     UnexpectedCFHeaderMessage,
     StartHeightMisalignment,
+    InvalidCheckpoint,
 }
 
 impl core::fmt::Display for CFHeaderSyncError {
@@ -83,6 +96,9 @@ impl core::fmt::Display for CFHeaderSyncError {
                 f,
                 "the size of the batch and the requested start height do not align"
             ),
+            CFHeaderSyncError::InvalidCheckpoint => {
+                write!(f, "a filter header checkpoint did not match.")
+            }
         }
     }
 }
@@ -0,0 +1,102 @@
+use std::collections::{BTreeMap, HashMap};
+
+use bitcoin::BlockHash;
+
+use super::Filter;
+
+// Compact block filters retained after they are checked, so they may be matched again or replayed
+// during a rescan without downloading them. Memory is bounded by the number of filters, and the
+// least recently used filter is evicted once the capacity is reached.
+#[derive(Debug)]
+pub(crate) struct FilterCache {
+    capacity: usize,
+    filters: HashMap<BlockHash, (Filter, u64)>,
+    recency: BTreeMap<u64, BlockHash>,
+    clock: u64,
+}
+
+impl FilterCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            filters: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, filter: &Filter) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.touch(&filter.block_hash()) {
+            return;
+        }
+        if self.filters.len() >= self.capacity {
+            if let Some((_, evicted)) = self.recency.pop_first() {
+                self.filters.remove(&evicted);
+            }
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, filter.block_hash());
+        self.filters
+            .insert(filter.block_hash(), (filter.clone(), self.clock));
+    }
+
+    pub(crate) fn get(&mut self, block_hash: &BlockHash) -> Option<&Filter> {
+        self.touch(block_hash);
+        self.filters.get(block_hash).map(|(filter, _)| filter)
+    }
+
+    pub(crate) fn contains(&self, block_hash: &BlockHash) -> bool {
+        self.filters.contains_key(block_hash)
+    }
+
+    pub(crate) fn block_hashes(&self) -> impl Iterator<Item = BlockHash> + '_ {
+        self.filters.keys().copied()
+    }
+
+    // Mark a filter as the most recently used, if it is cached
+    fn touch(&mut self, block_hash: &BlockHash) -> bool {
+        let Some((_, last_used)) = self.filters.get_mut(block_hash) else {
+            return false;
+        };
+        self.recency.remove(last_used);
+        self.clock += 1;
+        *last_used = self.clock;
+        self.recency.insert(self.clock, *block_hash);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{hashes::Hash, BlockHash};
+
+    use crate::chain::Filter;
+
+    use super::FilterCache;
+
+    #[test]
+    fn test_least_recent_filters_evicted() {
+        let filters = (0..3u8)
+            .map(|i| Filter::new(vec![0x00], BlockHash::from_byte_array([i; 32])))
+            .collect::<Vec<Filter>>();
+        let mut cache = FilterCache::new(2);
+        for filter in &filters {
+            cache.insert(filter);
+        }
+        assert!(cache.get(&filters[0].block_hash()).is_none());
+        assert!(cache.get(&filters[1].block_hash()).is_some());
+        assert!(cache.get(&filters[2].block_hash()).is_some());
+        // Reading a filter protects it from the next eviction
+        assert!(cache.get(&filters[1].block_hash()).is_some());
+        cache.insert(&filters[0]);
+        assert!(cache.get(&filters[1].block_hash()).is_some());
+        assert!(cache.get(&filters[2].block_hash()).is_none());
+        // Nothing is retained without capacity
+        let mut cache = FilterCache::new(0);
+        cache.insert(&filters[0]);
+        assert!(cache.get(&filters[0].block_hash()).is_none());
+    }
+}
@@ -10,7 +10,11 @@ use super::{FilterCommitment, HeightExt, IndexedHeader, ZerolikeExt};
 
 type Height = u32;
 
+// Reorganizations deeper than this are refused unless configured otherwise
+pub(crate) const MAX_REORG_DEPTH: Height = 100;
 const LOCATOR_INDEX: &[Height] = &[1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024];
+// Headers are never pruned closer to the tip than a difficulty period past the reorg limit
+pub(crate) const MIN_PRUNE_DEPTH: Height = 2_016 + MAX_REORG_DEPTH;
 
 #[derive(Debug, Clone)]
 pub(crate) enum AcceptHeaderChanges {
@@ -35,6 +39,9 @@ pub(crate) enum HeaderRejection {
         got: CompactTarget,
     },
     UnknownPrevHash(BlockHash),
+    DeepReorg {
+        depth: u32,
+    },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -95,6 +102,7 @@ pub struct BlockTree {
     active_tip: Tip,
     candidate_forks: Vec<Tip>,
     network: Network,
+    max_reorg_depth: u32,
 }
 
 #[allow(unused)]
@@ -107,6 +115,7 @@ impl BlockTree {
             active_tip: tip,
             candidate_forks: Vec::with_capacity(2),
             network,
+            max_reorg_depth: MAX_REORG_DEPTH,
         }
     }
 
@@ -126,9 +135,14 @@ impl BlockTree {
             active_tip: tip,
             candidate_forks: Vec::with_capacity(2),
             network,
+            max_reorg_depth: MAX_REORG_DEPTH,
         }
     }
 
+    pub(crate) fn set_max_reorg_depth(&mut self, depth: u32) {
+        self.max_reorg_depth = depth;
+    }
+
     pub(crate) fn accept_header(&mut self, new_header: Header) -> AcceptHeaderChanges {
         let new_hash = new_header.block_hash();
         let prev_hash = new_header.prev_blockhash;
@@ -140,7 +154,7 @@ impl BlockTree {
                 && !params.allow_min_difficulty_blocks
                 && new_height.is_adjustment_multiple(self.network)
             {
-                self.compute_next_work_required(new_height)
+                self.compute_next_work_required(prev_hash, new_height)
             } else {
                 self.active_tip.next_work_required
             };
@@ -189,7 +203,7 @@ impl BlockTree {
                     && !params.allow_min_difficulty_blocks
                     && new_height.is_adjustment_multiple(self.network)
                 {
-                    self.compute_next_work_required(new_height)
+                    self.compute_next_work_required(prev_hash, new_height)
                 } else {
                     fork.next_work_required
                 };
@@ -207,15 +221,21 @@ impl BlockTree {
                     height: new_height,
                     next_work_required: next_work,
                 };
-                let new_block_node = BlockNode::new(new_height, new_header, acc_work);
-                self.headers.insert(new_hash, new_block_node);
-                if acc_work
+                let more_work = acc_work
                     > self
                         .headers
                         .get(&self.active_tip.hash)
                         .map(|node| node.acc_work)
-                        .unwrap_or(Work::zero())
-                {
+                        .unwrap_or(Work::zero());
+                if more_work {
+                    let depth = self.fork_depth(fork.hash);
+                    if depth > self.max_reorg_depth {
+                        return AcceptHeaderChanges::Rejected(HeaderRejection::DeepReorg { depth });
+                    }
+                }
+                let new_block_node = BlockNode::new(new_height, new_header, acc_work);
+                self.headers.insert(new_hash, new_block_node);
+                if more_work {
                     self.candidate_forks.push(self.active_tip);
                     self.active_tip = new_tip;
                     let (accepted, disconnected) = self.switch_to_fork(&new_tip);
@@ -241,7 +261,7 @@ impl BlockTree {
                     && !params.allow_min_difficulty_blocks
                     && new_height.is_adjustment_multiple(self.network)
                 {
-                    self.compute_next_work_required(new_height)
+                    self.compute_next_work_required(prev_hash, new_height)
                 } else {
                     Some(node.header.bits)
                 };
@@ -271,6 +291,43 @@ impl BlockTree {
         }
     }
 
+    // The number of blocks that would be disconnected from the active chain to switch to a fork
+    fn fork_depth(&self, fork_hash: BlockHash) -> u32 {
+        let mut curr_hash = fork_hash;
+        let mut fork_height = self.active_tip.height;
+        while let Some(node) = self.headers.get(&curr_hash) {
+            if self
+                .canonical_hashes
+                .get(&node.height)
+                .is_some_and(|hash| hash.eq(&curr_hash))
+            {
+                return self.active_tip.height.saturating_sub(node.height);
+            }
+            // Otherwise the fork may connect to the root of the tree
+            fork_height = node.height.saturating_sub(1);
+            curr_hash = node.header.prev_blockhash;
+        }
+        self.active_tip.height.saturating_sub(fork_height)
+    }
+
+    // The height of the last block the fork shares with the active chain
+    pub(crate) fn fork_height(&self, fork_hash: BlockHash) -> Height {
+        self.active_tip
+            .height
+            .saturating_sub(self.fork_depth(fork_hash))
+    }
+
+    pub(crate) fn accumulated_work(&self, hash: BlockHash) -> Option<Work> {
+        self.headers.get(&hash).map(|node| node.acc_work)
+    }
+
+    // The work accumulated by the chain of most work since the root of the tree. Headers below the
+    // root are not known, so this is not the total work of the chain unless the root is genesis.
+    pub(crate) fn work_since_root(&self) -> Work {
+        self.accumulated_work(self.tip_hash())
+            .unwrap_or(Work::zero())
+    }
+
     fn switch_to_fork(&mut self, new_best: &Tip) -> (Vec<IndexedHeader>, Vec<IndexedHeader>) {
         let mut curr_hash = new_best.hash;
         let mut connections = Vec::new();
@@ -306,7 +363,13 @@ impl BlockTree {
         }
     }
 
-    fn compute_next_work_required(&self, new_height: Height) -> Option<CompactTarget> {
+    // The retarget is computed from the epoch that ends with the previous header, which may be on
+    // a fork, so the timestamps of the fork are used rather than those of the canonical chain.
+    fn compute_next_work_required(
+        &self,
+        prev_hash: BlockHash,
+        new_height: Height,
+    ) -> Option<CompactTarget> {
         // Do not audit the diffulty for `Testnet`. Auditing the difficulty properly for a testnet
         // will result in convoluted logic. This is a critical code block for mainnet and should be
         // as readable as possible
@@ -316,11 +379,12 @@ impl BlockTree {
         let adjustment_period =
             Height::from_u64_checked(self.network.params().difficulty_adjustment_interval())?;
         let epoch_start = new_height.checked_sub(adjustment_period)?;
-        let epoch_end = new_height.checked_sub(1)?;
-        let epoch_start_hash = self.canonical_hashes.get(&epoch_start)?;
-        let epoch_end_hash = self.canonical_hashes.get(&epoch_end)?;
-        let epoch_start_header = self.headers.get(epoch_start_hash).map(|node| node.header)?;
-        let epoch_end_header = self.headers.get(epoch_end_hash).map(|node| node.header)?;
+        let epoch_start_hash = self.ancestor_at_height(prev_hash, epoch_start)?;
+        let epoch_start_header = self
+            .headers
+            .get(&epoch_start_hash)
+            .map(|node| node.header)?;
+        let epoch_end_header = self.headers.get(&prev_hash).map(|node| node.header)?;
         let new_target = CompactTarget::from_header_difficulty_adjustment(
             epoch_start_header,
             epoch_end_header,
@@ -329,6 +393,25 @@ impl BlockTree {
         Some(new_target)
     }
 
+    // Walk back from a header until it joins the canonical chain, after which the ancestor may be
+    // looked up by height.
+    fn ancestor_at_height(&self, hash: BlockHash, height: Height) -> Option<BlockHash> {
+        let mut curr_hash = hash;
+        loop {
+            let node = self.headers.get(&curr_hash)?;
+            if node.height.eq(&height) {
+                return Some(curr_hash);
+            }
+            if node.height < height {
+                return None;
+            }
+            if self.canonical_hashes.get(&node.height) == Some(&curr_hash) {
+                return self.canonical_hashes.get(&height).copied();
+            }
+            curr_hash = node.header.prev_blockhash;
+        }
+    }
+
     pub(crate) fn block_hash_at_height(&self, height: Height) -> Option<BlockHash> {
         if self.active_tip.height.eq(&height) {
             return Some(self.active_tip.hash);
@@ -353,6 +436,23 @@ impl BlockTree {
         self.headers.get(&hash).map(|node| node.height)
     }
 
+    // Up to `count` headers ending with `hash`, in chronological order
+    pub(crate) fn ancestors(&self, hash: BlockHash, count: usize) -> Vec<Header> {
+        let mut ancestors = Vec::with_capacity(count);
+        let mut curr_hash = hash;
+        while ancestors.len() < count {
+            match self.headers.get(&curr_hash) {
+                Some(node) => {
+                    ancestors.push(node.header);
+                    curr_hash = node.header.prev_blockhash;
+                }
+                None => break,
+            }
+        }
+        ancestors.reverse();
+        ancestors
+    }
+
     pub(crate) fn header_at_hash(&self, hash: BlockHash) -> Option<Header> {
         self.headers.get(&hash).map(|node| node.header)
     }
@@ -417,6 +517,18 @@ impl BlockTree {
         false
     }
 
+    // Mark the filters above a height as unchecked, leaving those at or below it untouched
+    pub(crate) fn reset_filters_above(&mut self, height: Height) {
+        let mut curr = self.tip_hash();
+        while let Some(node) = self.headers.get_mut(&curr) {
+            if node.height <= height {
+                break;
+            }
+            node.filter_checked = false;
+            curr = node.header.prev_blockhash;
+        }
+    }
+
     pub(crate) fn reset_all_filters(&mut self) {
         let mut curr = self.tip_hash();
         while self.headers.get_mut(&curr).is_some() {
@@ -481,6 +593,37 @@ impl BlockTree {
         locators.into_iter().rev().collect()
     }
 
+    // Drop the headers more than `depth` blocks below the tip. A difficulty period of headers
+    // below the deepest accepted reorganization is always kept, so the retarget of any fork that
+    // may still be accepted can be verified. Returns the number of headers removed.
+    pub(crate) fn prune_below_depth(&mut self, depth: Height) -> usize {
+        let interval =
+            Height::from_u64_checked(self.network.params().difficulty_adjustment_interval())
+                .unwrap_or(Height::MAX);
+        let depth = depth
+            .max(MIN_PRUNE_DEPTH)
+            .max(interval.saturating_add(self.max_reorg_depth));
+        let Some(prune_height) = self.active_tip.height.checked_sub(depth) else {
+            return 0;
+        };
+        // Forks that branch off below the new root could never connect again
+        let candidate_forks = core::mem::take(&mut self.candidate_forks);
+        self.candidate_forks = candidate_forks
+            .into_iter()
+            .filter(|fork| self.fork_height(fork.hash) >= prune_height)
+            .collect();
+        let retained = self.canonical_hashes.split_off(&prune_height);
+        self.canonical_hashes = retained;
+        let before = self.headers.len();
+        self.headers.retain(|_, node| node.height >= prune_height);
+        before - self.headers.len()
+    }
+
+    // The lowest height still held in memory after pruning
+    pub(crate) fn root_height(&self) -> Option<Height> {
+        self.canonical_hashes.keys().next().copied()
+    }
+
     pub(crate) fn internal_chain_len(&self) -> usize {
         self.canonical_hashes.len()
     }
@@ -533,6 +676,7 @@ impl<'a> Iterator for BlockNodeIterator<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bitcoin::hashes::Hash;
     use corepc_node::serde_json;
     use std::fs::File;
     use std::str::FromStr;
@@ -597,6 +741,12 @@ mod tests {
         assert_eq!(chain.height(), 10);
         let old_block_10 = stale.first().unwrap().0;
         assert_eq!(chain.header_at_height(10), Some(old_block_10));
+        // The fork branches off at height 9 with equal work
+        assert_eq!(chain.fork_height(new_block_10.block_hash()), 9);
+        assert_eq!(
+            chain.accumulated_work(new_block_10.block_hash()),
+            chain.accumulated_work(old_block_10.block_hash())
+        );
         let block_11 = new_header_iter.next().unwrap();
         let accept_11 = chain.accept_header(block_11);
         match accept_11 {
@@ -638,6 +788,35 @@ mod tests {
         assert_eq!(chain.height_of_hash(unknown), None);
     }
 
+    #[test]
+    fn test_work_since_root() {
+        let GraphScenario { base, stale, new } = get_graph_scenario(0);
+        let tip = Tip::from_checkpoint(
+            7,
+            BlockHash::from_str("62c28f380692524a3a8f1fc66252bc0eb31d6b6a127d2263bdcbee172529fe16")
+                .unwrap(),
+        );
+        let mut chain = BlockTree::new(tip, Network::Regtest);
+        assert_eq!(chain.work_since_root(), Work::zero());
+        for header in base.iter().chain(&stale) {
+            chain.accept_header(header.0);
+        }
+        let base_work = base
+            .iter()
+            .map(|hex| hex.0.work())
+            .fold(Work::zero(), |acc, work| acc + work);
+        assert_eq!(chain.work_since_root(), base_work + stale[0].0.work());
+        // The work follows the chain of most work after a reorganization
+        for header in &new {
+            chain.accept_header(header.0);
+        }
+        let new_work = new
+            .iter()
+            .map(|hex| hex.0.work())
+            .fold(Work::zero(), |acc, work| acc + work);
+        assert_eq!(chain.work_since_root(), base_work + new_work);
+    }
+
     #[test]
     fn test_depth_two_reorg() {
         let GraphScenario { base, stale, new } = get_graph_scenario(1);
@@ -686,6 +865,63 @@ mod tests {
         assert_eq!(chain.header_at_height(1), Some(base[0].0));
     }
 
+    #[test]
+    fn test_deep_reorg_rejected() {
+        let GraphScenario { base, stale, new } = get_graph_scenario(0);
+        let tip = Tip::from_checkpoint(
+            7,
+            BlockHash::from_str("62c28f380692524a3a8f1fc66252bc0eb31d6b6a127d2263bdcbee172529fe16")
+                .unwrap(),
+        );
+        let mut chain = BlockTree::new(tip, Network::Regtest);
+        chain.set_max_reorg_depth(0);
+        for header in base.iter().chain(&stale) {
+            chain.accept_header(header.0);
+        }
+        let height = chain.height();
+        let mut new_header_iter = new.into_iter().map(|hex| hex.0);
+        let fork = chain.accept_header(new_header_iter.next().unwrap());
+        assert!(matches!(fork, AcceptHeaderChanges::ExtendedFork { .. }));
+        let reorg = chain.accept_header(new_header_iter.next().unwrap());
+        assert!(matches!(
+            reorg,
+            AcceptHeaderChanges::Rejected(HeaderRejection::DeepReorg { depth: 1 })
+        ));
+        assert_eq!(chain.height(), height);
+        assert_eq!(chain.header_at_height(height), Some(stale[0].0));
+    }
+
+    #[test]
+    fn test_prune_below_depth() {
+        let mut chain = BlockTree::from_genesis(Network::Regtest);
+        let mut prev_hash = chain.tip_hash();
+        for time in 0..MIN_PRUNE_DEPTH + 5 {
+            let header = Header {
+                version: bitcoin::block::Version::TWO,
+                prev_blockhash: prev_hash,
+                merkle_root: bitcoin::TxMerkleNode::all_zeros(),
+                time,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            };
+            prev_hash = header.block_hash();
+            assert!(matches!(
+                chain.accept_header(header),
+                AcceptHeaderChanges::Accepted { .. }
+            ));
+        }
+        let locators = chain.locators();
+        // Depths below the floor are raised
+        assert_eq!(chain.prune_below_depth(1), 4);
+        assert_eq!(chain.height(), MIN_PRUNE_DEPTH + 5);
+        assert_eq!(chain.iter_data().last().unwrap().height, 5);
+        assert!(chain.header_at_height(4).is_none());
+        assert!(chain.header_at_height(5).is_some());
+        assert_eq!(chain.locators(), locators);
+        // Pruning again without new headers has no effect
+        assert_eq!(chain.prune_below_depth(MIN_PRUNE_DEPTH), 0);
+    }
+
     #[test]
     fn test_assumed_checked() {
         let GraphScenario {
@@ -702,4 +938,63 @@ mod tests {
         chain.assume_checked_to(4);
         assert!(chain.filters_synced());
     }
+
+    #[test]
+    fn test_fork_retarget_uses_fork_timestamps() {
+        // Proof of work is not checked by the tree, so headers with arbitrary bits suffice
+        let bits = CompactTarget::from_consensus(0x1d00ffff);
+        let next_header = |prev: &Header, spacing: u32| Header {
+            version: bitcoin::block::Version::ONE,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: prev.merkle_root,
+            time: prev.time + spacing,
+            bits,
+            nonce: 0,
+        };
+        let root = genesis_block(Network::Bitcoin).header;
+        let mut chain =
+            BlockTree::new(Tip::from_checkpoint(0, root.block_hash()), Network::Bitcoin);
+        let mut canonical = vec![root];
+        for _ in 1..4032 {
+            let header = next_header(canonical.last().unwrap(), 600);
+            assert!(matches!(
+                chain.accept_header(header),
+                AcceptHeaderChanges::Accepted { .. }
+            ));
+            canonical.push(header);
+        }
+        // A fork from height 4000 is mined faster until the retarget at 4032
+        let mut fork = vec![canonical[4000]];
+        for _ in 4001..4032 {
+            let header = next_header(fork.last().unwrap(), 60);
+            chain.accept_header(header);
+            fork.push(header);
+        }
+        let fork_tip = *fork.last().unwrap();
+        let fork_target = CompactTarget::from_header_difficulty_adjustment(
+            canonical[2016],
+            fork_tip,
+            Network::Bitcoin,
+        );
+        let canonical_target = CompactTarget::from_header_difficulty_adjustment(
+            canonical[2016],
+            canonical[4031],
+            Network::Bitcoin,
+        );
+        assert_ne!(fork_target, canonical_target);
+        // The bits of the canonical chain do not apply to the fork
+        let mut wrong_bits = next_header(&fork_tip, 60);
+        wrong_bits.bits = canonical_target;
+        assert!(matches!(
+            chain.accept_header(wrong_bits),
+            AcceptHeaderChanges::Rejected(HeaderRejection::InvalidPow { .. })
+        ));
+        let mut retarget = next_header(&fork_tip, 60);
+        retarget.bits = fork_target;
+        assert!(!matches!(
+            chain.accept_header(retarget),
+            AcceptHeaderChanges::Rejected(_)
+        ));
+        assert!(chain.contains(retarget.block_hash()));
+    }
 }
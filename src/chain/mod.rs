@@ -2,6 +2,7 @@
 //!
 //! Notably, [`checkpoints`] contains known Bitcoin block hashes and heights with significant work, so Kyoto nodes do not have to sync from genesis.
 pub(crate) mod block_queue;
+pub(crate) mod block_source;
 #[allow(clippy::module_inception)]
 pub(crate) mod chain;
 /// Expected block header checkpoints and corresponding structure.
@@ -9,22 +10,34 @@ pub mod checkpoints;
 /// Errors associated with the blockchain representation.
 #[allow(dead_code)]
 pub(crate) mod error;
+pub(crate) mod filter_cache;
 pub(crate) mod graph;
+pub(crate) mod tx_index;
 
 use std::collections::VecDeque;
+use std::time::Duration;
 
 use bitcoin::constants::SUBSIDY_HALVING_INTERVAL;
 use bitcoin::hashes::{sha256d, Hash};
-use bitcoin::Amount;
 use bitcoin::{
     bip158::BlockFilter, block::Header, p2p::message_filter::CFHeaders, params::Params, BlockHash,
     FilterHash, FilterHeader, ScriptBuf, Target, Work,
 };
+use bitcoin::{Amount, Block, FeeRate, Network};
+
+use tokio::time::Instant;
 
 use crate::network::PeerId;
-use crate::HashCheckpoint;
+use crate::{BlockType, HashCheckpoint};
 
+// The most filters a peer will serve for a single request
+pub(crate) const MAX_FILTER_BATCH_SIZE: u32 = 1_000;
+const MIN_FILTER_BATCH_SIZE: u32 = 100;
+// A batch that arrives faster than this allows a larger batch size, when adaptive
+pub(crate) const FAST_FILTER_BATCH: Duration = Duration::from_secs(5);
 const MAX_PREV_STOP_HASHES: usize = 3;
+// The number of recently downloaded blocks averaged in the fee estimate
+const FEE_ESTIMATE_BLOCKS: usize = 6;
 
 /// A block header with associated height.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -108,6 +121,7 @@ pub(crate) struct FilterCommitment {
 #[derive(Debug, Clone)]
 pub(crate) struct FilterRequestState {
     pub last_filter_request: Option<FilterRequest>,
+    pub filter_batches_in_flight: VecDeque<FilterBatch>,
     pub last_filter_header_request: Option<FilterHeaderRequest>,
     pub pending_batch: Option<(PeerId, CFHeaderBatch)>,
     pub agreement_state: FilterHeaderAgreements,
@@ -118,6 +132,7 @@ impl FilterRequestState {
     pub(crate) fn new(required: u8) -> Self {
         Self {
             last_filter_request: None,
+            filter_batches_in_flight: VecDeque::new(),
             last_filter_header_request: None,
             pending_batch: None,
             agreement_state: FilterHeaderAgreements::new(required),
@@ -147,6 +162,65 @@ pub(crate) struct FilterRequest {
     pub stop_hash: BlockHash,
 }
 
+// A range of filters that was requested. The peer is unknown when the request was sent to
+// whichever peer the node was handling, until that peer responds.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FilterBatch {
+    pub start_height: u32,
+    pub stop_hash: BlockHash,
+    pub stop_height: u32,
+    pub peer: Option<PeerId>,
+    pub requested: Instant,
+}
+
+// The number of filters requested at once. When adaptive, the size is halved after a peer times
+// out on a batch, and doubled, up to the configured size, after a batch arrives quickly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FilterBatchSize {
+    current: u32,
+    max: u32,
+    adaptive: bool,
+}
+
+impl FilterBatchSize {
+    pub(crate) fn new(max: u32, adaptive: bool) -> Self {
+        let max = max.clamp(1, MAX_FILTER_BATCH_SIZE);
+        Self {
+            current: max,
+            max,
+            adaptive,
+        }
+    }
+
+    pub(crate) fn get(&self) -> u32 {
+        self.current
+    }
+
+    // Returns if the size changed
+    pub(crate) fn grow(&mut self) -> bool {
+        let previous = self.current;
+        if self.adaptive {
+            self.current = self.current.saturating_mul(2).min(self.max);
+        }
+        previous.ne(&self.current)
+    }
+
+    // Returns if the size changed
+    pub(crate) fn shrink(&mut self) -> bool {
+        let previous = self.current;
+        if self.adaptive {
+            self.current = (self.current / 2).max(MIN_FILTER_BATCH_SIZE.min(self.max));
+        }
+        previous.ne(&self.current)
+    }
+}
+
+impl Default for FilterBatchSize {
+    fn default() -> Self {
+        Self::new(MAX_FILTER_BATCH_SIZE, false)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct FilterHeaderRequest {
     pub start_height: u32,
@@ -185,6 +259,8 @@ impl FilterHeaderAgreements {
 pub(crate) enum CFHeaderChanges {
     AddedToQueue,
     Extended,
+    // A resend of filter headers that were already queued or accepted
+    Duplicate,
     // Unfortunately, auditing each peer by reconstruction the filter would be costly in network
     // and compute. Instead it is easier to disconnect from all peers and try again.
     Conflict,
@@ -228,6 +304,10 @@ impl CFHeaderBatch {
         self.inner.len() as u32
     }
 
+    fn filter_headers(&self) -> impl Iterator<Item = FilterHeader> + '_ {
+        self.inner.iter().map(|commitment| commitment.header)
+    }
+
     fn take_inner(&mut self) -> Vec<FilterCommitment> {
         core::mem::take(&mut self.inner)
     }
@@ -321,13 +401,20 @@ impl ZerolikeExt for Work {
     }
 }
 
+// The number of previous blocks used to compute the median time past.
+const MEDIAN_TIME_SPAN: usize = 11;
+
 trait HeaderValidationExt {
     // Headers are logically connected.
     fn connected(&self) -> bool;
     // Each header passes its own work target.
     fn passes_own_pow(&self) -> bool;
+    // No target is easier than the minimum difficulty of the network.
+    fn within_pow_limit(&self, params: impl AsRef<Params>) -> bool;
     // Targets do not change out of the acceptable range.
     fn bits_adhere_transition_threshold(&self, params: impl AsRef<Params>) -> bool;
+    // Each header has a time greater than the median of the previous eleven, per BIP-113.
+    fn exceeds_median_time_past(&self, prior_headers: &[Header]) -> bool;
 }
 
 impl HeaderValidationExt for &[Header] {
@@ -338,11 +425,15 @@ impl HeaderValidationExt for &[Header] {
     }
 
     fn passes_own_pow(&self) -> bool {
-        !self.iter().any(|header| {
-            let target = header.target();
-            let valid_pow = header.validate_pow(target);
-            valid_pow.is_err()
-        })
+        #[cfg(feature = "parallel-pow")]
+        return parallel_passes_own_pow(self);
+        #[cfg(not(feature = "parallel-pow"))]
+        return serial_passes_own_pow(self);
+    }
+
+    fn within_pow_limit(&self, params: impl AsRef<Params>) -> bool {
+        let limit = params.as_ref().max_attainable_target;
+        self.iter().all(|header| header.target().le(&limit))
     }
 
     fn bits_adhere_transition_threshold(&self, params: impl AsRef<Params>) -> bool {
@@ -355,6 +446,54 @@ impl HeaderValidationExt for &[Header] {
             Target::from_compact(second.bits).le(&transition)
         })
     }
+
+    fn exceeds_median_time_past(&self, prior_headers: &[Header]) -> bool {
+        let times: Vec<u32> = prior_headers
+            .iter()
+            .chain(self.iter())
+            .map(|header| header.time)
+            .collect();
+        // Headers without a full window of history, such as those following a checkpoint, are
+        // not checked.
+        (prior_headers.len()..times.len())
+            .filter(|index| *index >= MEDIAN_TIME_SPAN)
+            .all(|index| {
+                let mut window = times[index - MEDIAN_TIME_SPAN..index].to_vec();
+                window.sort_unstable();
+                times[index] > window[MEDIAN_TIME_SPAN / 2]
+            })
+    }
+}
+
+fn serial_passes_own_pow(headers: &[Header]) -> bool {
+    !headers.iter().any(|header| {
+        let target = header.target();
+        let valid_pow = header.validate_pow(target);
+        valid_pow.is_err()
+    })
+}
+
+// Each header commits to its own target, so the checks are independent and may be split across
+// threads. Small batches are not worth the cost of spawning threads.
+#[cfg(feature = "parallel-pow")]
+fn parallel_passes_own_pow(headers: &[Header]) -> bool {
+    const MIN_PARALLEL_BATCH: usize = 100;
+    let threads = std::thread::available_parallelism()
+        .map(|threads| threads.get())
+        .unwrap_or(1);
+    if threads < 2 || headers.len() < MIN_PARALLEL_BATCH {
+        return serial_passes_own_pow(headers);
+    }
+    let chunk_size = headers.len().div_ceil(threads);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = headers
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| serial_passes_own_pow(chunk)))
+            .collect();
+        handles
+            .into_iter()
+            .all(|handle| handle.join().unwrap_or(false))
+    })
 }
 
 // Emulation of `GetBlockSubsidy` in Bitcoin Core: https://github.com/bitcoin/bitcoin/blob/master/src/validation.cpp#L1944
@@ -368,10 +507,184 @@ pub(crate) fn block_subsidy(height: u32) -> Amount {
     Amount::from_sat(subsidy)
 }
 
+// The fees collected by the coinbase over the weight of the block. Fee rates of individual
+// transactions cannot be computed without the outputs they spend.
+pub(crate) fn block_fee_rate(block: &Block, height: u32) -> FeeRate {
+    let subsidy = block_subsidy(height);
+    let weight = block.weight();
+    let revenue = block
+        .txdata
+        .first()
+        .map(|tx| tx.output.iter().map(|txout| txout.value).sum())
+        .unwrap_or(Amount::ZERO);
+    let block_fees = revenue.checked_sub(subsidy).unwrap_or(Amount::ZERO);
+    let fee_rate = block_fees
+        .to_sat()
+        .checked_div(weight.to_kwu_floor())
+        .unwrap_or(0);
+    FeeRate::from_sat_per_kwu(fee_rate)
+}
+
+// A rolling average of the fee rates of the blocks most recently downloaded
+#[derive(Debug, Default)]
+pub(crate) struct FeeEstimates {
+    recent: VecDeque<(BlockHash, FeeRate)>,
+}
+
+impl FeeEstimates {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn add(&mut self, block: &Block, height: u32) {
+        let hash = block.block_hash();
+        if self.recent.iter().any(|(known, _)| known.eq(&hash)) {
+            return;
+        }
+        if self.recent.len() >= FEE_ESTIMATE_BLOCKS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((hash, block_fee_rate(block, height)));
+    }
+
+    pub(crate) fn estimate(&self) -> Option<FeeRate> {
+        let total = self
+            .recent
+            .iter()
+            .map(|(_, fee_rate)| fee_rate.to_sat_per_kwu())
+            .sum::<u64>();
+        total
+            .checked_div(self.recent.len() as u64)
+            .map(FeeRate::from_sat_per_kwu)
+    }
+}
+
+// `OP_RETURN OP_PUSHBYTES_36` followed by the witness commitment header
+const WITNESS_COMMITMENT_MAGIC: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+// The first height the segwit softfork is enforced, from `SegwitHeight` in Bitcoin Core
+fn segwit_height(network: Network) -> u32 {
+    match network {
+        Network::Bitcoin => 481_824,
+        Network::Testnet => 834_624,
+        Network::Testnet4 | Network::Signet => 1,
+        Network::Regtest => 0,
+    }
+}
+
+// Blocks before segwit activation may not have a witness commitment, and blocks requested
+// without witness data cannot be checked against one
+pub(crate) fn witness_commitment_valid(
+    block: &Block,
+    height: u32,
+    network: Network,
+    block_type: BlockType,
+) -> bool {
+    if matches!(block_type, BlockType::Legacy) || height < segwit_height(network) {
+        return true;
+    }
+    let Some(coinbase) = block.txdata.first() else {
+        return false;
+    };
+    let has_commitment = coinbase.output.iter().any(|output| {
+        output.script_pubkey.len() >= 38
+            && output.script_pubkey.as_bytes()[0..6] == WITNESS_COMMITMENT_MAGIC
+    });
+    if !has_commitment {
+        return block
+            .txdata
+            .iter()
+            .all(|tx| tx.input.iter().all(|input| input.witness.is_empty()));
+    }
+    // A block stripped of its witnesses still carries the commitment, but not the reserved value
+    let has_reserved_value = coinbase.input.first().is_some_and(|input| {
+        input.witness.len() == 1 && input.witness.nth(0).is_some_and(|value| value.len() == 32)
+    });
+    has_reserved_value && block.check_witness_commitment()
+}
+
 #[cfg(test)]
 mod tests {
+    use bitcoin::{block::Version, CompactTarget, TxMerkleNode};
+
     use super::*;
 
+    // Mine a chain of headers at the minimum regtest difficulty
+    fn mine_headers(count: usize) -> Vec<Header> {
+        let mut prev_blockhash = BlockHash::all_zeros();
+        let mut headers = Vec::with_capacity(count);
+        for time in 0..count as u32 {
+            let mut header = Header {
+                version: Version::TWO,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            };
+            while header.validate_pow(header.target()).is_err() {
+                header.nonce += 1;
+            }
+            prev_blockhash = header.block_hash();
+            headers.push(header);
+        }
+        headers
+    }
+
+    #[test]
+    fn test_passes_own_pow() {
+        let mut headers = mine_headers(500);
+        assert!(headers.as_slice().passes_own_pow());
+        let last = headers.last_mut().unwrap();
+        while last.validate_pow(last.target()).is_ok() {
+            last.nonce += 1;
+        }
+        assert!(!headers.as_slice().passes_own_pow());
+    }
+
+    #[test]
+    fn test_within_pow_limit() {
+        // Headers that are valid on regtest are far too easy for the Bitcoin network
+        let headers = mine_headers(5);
+        assert!(headers.as_slice().passes_own_pow());
+        assert!(headers.as_slice().within_pow_limit(Network::Regtest));
+        assert!(!headers.as_slice().within_pow_limit(Network::Bitcoin));
+        let genesis = bitcoin::constants::genesis_block(Network::Bitcoin).header;
+        assert!([genesis].as_slice().within_pow_limit(Network::Bitcoin));
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        let headers = mine_headers(14);
+        let (prior, batch) = headers.split_at(11);
+        assert!(batch.exceeds_median_time_past(prior));
+        // The median of the times 0 through 10 is 5
+        let mut early = batch.to_vec();
+        early[0].time = 6;
+        assert!(early.as_slice().exceeds_median_time_past(prior));
+        early[0].time = 5;
+        assert!(!early.as_slice().exceeds_median_time_past(prior));
+        // Without a full window of history, the time is not checked
+        assert!(early.as_slice().exceeds_median_time_past(&prior[1..]));
+    }
+
+    // Run with `cargo test --release --features parallel-pow -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_passes_own_pow() {
+        let headers = mine_headers(2000);
+        let start = std::time::Instant::now();
+        for _ in 0..100 {
+            assert!(serial_passes_own_pow(&headers));
+        }
+        println!("Serial: {:?} per batch", start.elapsed() / 100);
+        let start = std::time::Instant::now();
+        for _ in 0..100 {
+            assert!(headers.as_slice().passes_own_pow());
+        }
+        println!("Configured: {:?} per batch", start.elapsed() / 100);
+    }
+
     #[test]
     fn test_subsidy_calculation() {
         let first_subsidy = block_subsidy(2);
@@ -385,4 +698,238 @@ mod tests {
         let now = block_subsidy(902_000);
         assert_eq!(now, Amount::from_btc(3.125).unwrap());
     }
+
+    #[test]
+    fn test_witness_commitment_valid() {
+        use bitcoin::{
+            absolute::LockTime, transaction, OutPoint, Sequence, Transaction, TxIn, TxOut, Witness,
+        };
+        let coinbase = Transaction {
+            version: transaction::Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[[0u8; 32]]),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_btc(50.0).unwrap(),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let spend = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(coinbase.compute_txid(), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[[1u8; 64]]),
+            }],
+            output: Vec::new(),
+        };
+        let header = Header {
+            version: Version::TWO,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        };
+        // The witness data is not committed to in the coinbase
+        let block = Block {
+            header,
+            txdata: vec![coinbase, spend],
+        };
+        let witness = BlockType::Witness;
+        assert!(witness_commitment_valid(
+            &block,
+            481_823,
+            Network::Bitcoin,
+            witness
+        ));
+        assert!(!witness_commitment_valid(
+            &block,
+            481_824,
+            Network::Bitcoin,
+            witness
+        ));
+        assert!(!witness_commitment_valid(
+            &block,
+            0,
+            Network::Regtest,
+            witness
+        ));
+        // Blocks requested without witness data are not checked
+        assert!(witness_commitment_valid(
+            &block,
+            0,
+            Network::Regtest,
+            BlockType::Legacy
+        ));
+        // Blocks without witness data do not require a commitment
+        let mut stripped = block.clone();
+        stripped.txdata[0].input[0].witness = Witness::new();
+        stripped.txdata[1].input[0].witness = Witness::new();
+        assert!(witness_commitment_valid(
+            &stripped,
+            0,
+            Network::Regtest,
+            witness
+        ));
+        // Commit to the witness data in the coinbase
+        let mut committed = block.clone();
+        let witness_root = committed.witness_root().unwrap();
+        let commitment = Block::compute_witness_commitment(&witness_root, &[0u8; 32]);
+        let mut script = WITNESS_COMMITMENT_MAGIC.to_vec();
+        script.extend_from_slice(commitment.as_byte_array());
+        committed.txdata[0].output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::from_bytes(script),
+        });
+        assert!(witness_commitment_valid(
+            &committed,
+            0,
+            Network::Regtest,
+            witness
+        ));
+        // Stripping the witnesses leaves the commitment without the data it commits to
+        let mut stripped = committed.clone();
+        stripped.txdata[0].input[0].witness = Witness::new();
+        stripped.txdata[1].input[0].witness = Witness::new();
+        assert!(!witness_commitment_valid(
+            &stripped,
+            0,
+            Network::Regtest,
+            witness
+        ));
+    }
+
+    #[test]
+    fn test_fee_estimates() {
+        use bitcoin::{absolute::LockTime, transaction, Transaction, TxIn, TxOut};
+        let block = |nonce: u32, fees: u64| {
+            let coinbase = Transaction {
+                version: transaction::Version::ONE,
+                lock_time: LockTime::ZERO,
+                input: vec![TxIn::default()],
+                output: vec![TxOut {
+                    value: block_subsidy(1) + Amount::from_sat(fees),
+                    script_pubkey: ScriptBuf::new(),
+                }],
+            };
+            let header = Header {
+                version: Version::TWO,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce,
+            };
+            Block {
+                header,
+                txdata: vec![coinbase],
+            }
+        };
+        let mut estimates = FeeEstimates::new();
+        assert!(estimates.estimate().is_none());
+        let first = block(0, 1_000_000);
+        estimates.add(&first, 1);
+        estimates.add(&first, 1);
+        assert_eq!(estimates.estimate(), Some(block_fee_rate(&first, 1)));
+        // Only the most recent blocks are averaged
+        for nonce in 1..=FEE_ESTIMATE_BLOCKS as u32 {
+            estimates.add(&block(nonce, 0), 1);
+        }
+        assert_eq!(estimates.estimate(), Some(FeeRate::ZERO));
+    }
+
+    #[test]
+    fn test_filter_batch_size() {
+        let mut fixed = FilterBatchSize::new(5_000, false);
+        assert_eq!(fixed.get(), MAX_FILTER_BATCH_SIZE);
+        assert!(!fixed.shrink());
+        assert_eq!(fixed.get(), MAX_FILTER_BATCH_SIZE);
+        let mut adaptive = FilterBatchSize::new(800, true);
+        assert!(!adaptive.grow());
+        assert!(adaptive.shrink());
+        assert_eq!(adaptive.get(), 400);
+        assert!(adaptive.shrink());
+        assert!(adaptive.shrink());
+        assert_eq!(adaptive.get(), MIN_FILTER_BATCH_SIZE);
+        assert!(!adaptive.shrink());
+        assert!(adaptive.grow());
+        assert_eq!(adaptive.get(), 200);
+        assert!(adaptive.grow());
+        assert!(adaptive.grow());
+        assert_eq!(adaptive.get(), 800);
+        // Configured sizes below the minimum are never shrunk further
+        let mut small = FilterBatchSize::new(10, true);
+        assert!(!small.shrink());
+        assert_eq!(small.get(), 10);
+    }
+
+    #[test]
+    fn test_filter_matches_taproot_scripts() {
+        use bitcoin::{
+            absolute::LockTime, transaction, OutPoint, Transaction, TxIn, TxOut, Txid,
+            WitnessProgram, WitnessVersion,
+        };
+        let taproot = |key: [u8; 32]| {
+            ScriptBuf::new_witness_program(&WitnessProgram::new(WitnessVersion::V1, &key).unwrap())
+        };
+        let received = taproot([1; 32]);
+        let spent = taproot([2; 32]);
+        let coinbase = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: Amount::from_sat(50_000),
+                script_pubkey: received.clone(),
+            }],
+        };
+        let prevout = OutPoint::new(Txid::from_byte_array([3; 32]), 0);
+        let spend = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: prevout,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(10_000),
+                script_pubkey: ScriptBuf::new_op_return([4; 8]),
+            }],
+        };
+        let block = Block {
+            header: Header {
+                version: Version::TWO,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: vec![coinbase, spend],
+        };
+        let block_filter = BlockFilter::new_script_filter(&block, |outpoint| {
+            assert_eq!(*outpoint, prevout);
+            Ok(spent.clone())
+        })
+        .unwrap();
+        let filter = Filter::new(block_filter.content, block.block_hash());
+        // Both outputs paid to and outputs spent from taproot scripts are matched
+        assert_eq!(received.as_bytes()[0], 0x51);
+        assert!(filter.contains_any([received.clone()].iter()));
+        assert!(filter.contains_any([spent].iter()));
+        // The raw script is committed to, so the same program under another witness version, or
+        // another taproot key, does not match
+        let v0 = ScriptBuf::new_witness_program(
+            &WitnessProgram::new(WitnessVersion::V0, &[1; 32]).unwrap(),
+        );
+        assert!(!filter.contains_any([v0].iter()));
+        assert!(!filter.contains_any([taproot([5; 32])].iter()));
+    }
 }
@@ -0,0 +1,149 @@
+use std::collections::{BTreeMap, HashMap};
+
+use bitcoin::{hashes::Hash, Block, BlockHash, Txid};
+
+// The location of the transactions in blocks downloaded by the node. Memory is bounded by the
+// number of transactions, and the blocks indexed least recently are evicted once the capacity is
+// reached. Blocks are also ordered by height, so pruning only visits the blocks it removes.
+#[derive(Debug)]
+pub(crate) struct TxIndex {
+    capacity: usize,
+    transactions: HashMap<Txid, (BlockHash, u32)>,
+    blocks: BTreeMap<(u32, BlockHash), (Vec<Txid>, u64)>,
+    recency: BTreeMap<u64, (u32, BlockHash)>,
+    clock: u64,
+}
+
+impl TxIndex {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            transactions: HashMap::new(),
+            blocks: BTreeMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, block: &Block, height: u32) {
+        let block_hash = block.block_hash();
+        let key = (height, block_hash);
+        if self.blocks.contains_key(&key) {
+            return;
+        }
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.compute_txid()).collect();
+        for txid in &txids {
+            self.transactions.insert(*txid, (block_hash, height));
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, key);
+        self.blocks.insert(key, (txids, self.clock));
+        while self.transactions.len() > self.capacity {
+            let Some((_, evicted)) = self.recency.pop_first() else {
+                break;
+            };
+            self.remove_block(evicted);
+        }
+    }
+
+    pub(crate) fn get(&self, txid: &Txid) -> Option<(BlockHash, u32)> {
+        self.transactions.get(txid).copied()
+    }
+
+    // Forget the transactions in blocks that are no longer in the chain of most work
+    pub(crate) fn remove_blocks(&mut self, disconnected: &[BlockHash]) {
+        let removed: Vec<(u32, BlockHash)> = self
+            .blocks
+            .keys()
+            .filter(|(_, block_hash)| disconnected.contains(block_hash))
+            .copied()
+            .collect();
+        for key in removed {
+            self.remove_block(key);
+        }
+    }
+
+    // Forget the transactions in blocks below this height
+    pub(crate) fn prune_below(&mut self, height: u32) {
+        let retained = self.blocks.split_off(&(height, BlockHash::all_zeros()));
+        let pruned = core::mem::replace(&mut self.blocks, retained);
+        for ((_, block_hash), (txids, last_indexed)) in pruned {
+            self.recency.remove(&last_indexed);
+            self.forget(block_hash, txids);
+        }
+    }
+
+    fn remove_block(&mut self, key: (u32, BlockHash)) {
+        if let Some((txids, last_indexed)) = self.blocks.remove(&key) {
+            self.recency.remove(&last_indexed);
+            self.forget(key.1, txids);
+        }
+    }
+
+    // A transaction may be confirmed in more than one block across a reorganization, so only
+    // entries that point to this block are removed
+    fn forget(&mut self, block_hash: BlockHash, txids: Vec<Txid>) {
+        for txid in txids {
+            if self
+                .transactions
+                .get(&txid)
+                .is_some_and(|(indexed, _)| indexed.eq(&block_hash))
+            {
+                self.transactions.remove(&txid);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        absolute::LockTime, block::Header, block::Version, transaction, CompactTarget, Transaction,
+        TxMerkleNode,
+    };
+
+    use super::{Block, BlockHash, Hash, TxIndex};
+
+    fn block_with_transaction(nonce: u32) -> Block {
+        let header = Header {
+            version: Version::TWO,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce,
+        };
+        let tx = Transaction {
+            version: transaction::Version::ONE,
+            lock_time: LockTime::from_consensus(nonce),
+            input: Vec::new(),
+            output: Vec::new(),
+        };
+        Block {
+            header,
+            txdata: vec![tx],
+        }
+    }
+
+    #[test]
+    fn test_tx_index_bounded_and_pruned() {
+        let blocks: Vec<Block> = (0..3).map(block_with_transaction).collect();
+        let txid = |index: usize| blocks[index].txdata[0].compute_txid();
+        let mut index = TxIndex::new(2);
+        // Blocks deep in the chain are indexed, as a rescan may find them
+        index.insert(&blocks[0], 100);
+        index.insert(&blocks[1], 10);
+        assert_eq!(index.get(&txid(1)), Some((blocks[1].block_hash(), 10)));
+        // The block indexed least recently is evicted, regardless of height
+        index.insert(&blocks[2], 200);
+        assert!(index.get(&txid(0)).is_none());
+        assert!(index.get(&txid(1)).is_some());
+        assert!(index.get(&txid(2)).is_some());
+        index.prune_below(11);
+        assert!(index.get(&txid(1)).is_none());
+        assert!(index.get(&txid(2)).is_some());
+        index.remove_blocks(&[blocks[2].block_hash()]);
+        assert!(index.get(&txid(2)).is_none());
+        assert!(index.blocks.is_empty() && index.recency.is_empty());
+    }
+}
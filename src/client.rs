@@ -1,18 +1,36 @@
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
 use bitcoin::p2p::address::AddrV2;
+use bitcoin::p2p::message_filter::CFHeaders;
 use bitcoin::p2p::ServiceFlags;
-use bitcoin::{Amount, Wtxid};
-use bitcoin::{BlockHash, FeeRate};
+use bitcoin::{BlockHash, FeeRate, MerkleBlock};
+use bitcoin::{ScriptBuf, Txid, Work, Wtxid};
+use tokio::runtime::Runtime;
+use tokio::sync::broadcast;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 
-use crate::chain::block_subsidy;
+use crate::chain::block_fee_rate;
 use crate::chain::IndexedHeader;
 use crate::messages::ClientRequest;
-use crate::{Event, HashCheckpoint, Info, Package, TrustedPeer, Warning};
+use crate::network::median_height;
+use crate::{
+    Event, HashCheckpoint, Info, Node, NodeError, NodeHealth, Package, PeerId, PeerInfo,
+    StoredPeer, SyncUpdate, TrustedPeer, Warning,
+};
 
 use super::{error::ClientError, messages::ClientMessage};
-use super::{error::FetchBlockError, IndexedBlock};
+use super::{
+    error::{BroadcastError, FetchBlockError, RescanError, TestScriptError},
+    IndexedBlock,
+};
 
 /// A [`Client`] allows for communication with a running node.
 #[derive(Debug)]
@@ -24,34 +42,254 @@ pub struct Client {
     /// Receive warning messages from a node.
     pub warn_rx: mpsc::UnboundedReceiver<Warning>,
     /// Receive [`Event`] from a node to act on.
-    pub event_rx: mpsc::UnboundedReceiver<Event>,
+    pub event_rx: mpsc::Receiver<Event>,
 }
 
 impl Client {
     pub(crate) fn new(
         info_rx: mpsc::Receiver<Info>,
         warn_rx: mpsc::UnboundedReceiver<Warning>,
-        event_rx: mpsc::UnboundedReceiver<Event>,
+        event_rx: mpsc::Receiver<Event>,
+        event_broadcast: broadcast::Sender<Event>,
+        tip_rx: watch::Receiver<HashCheckpoint>,
         ntx: UnboundedSender<ClientMessage>,
     ) -> Self {
         Self {
-            requester: Requester::new(ntx),
+            requester: Requester::new(ntx, event_broadcast, tip_rx),
             info_rx,
             warn_rx,
             event_rx,
         }
     }
+
+    /// Receive a new stream of [`Event`], independent of [`Client::event_rx`] and of any other
+    /// subscription. Every subscriber receives each event sent after it subscribed, so separate
+    /// tasks may each react to events without forwarding them manually.
+    ///
+    /// A subscription holds up to the configured
+    /// [`Builder::event_buffer`](crate::Builder::event_buffer) of events. Unlike
+    /// [`Client::event_rx`], the node does not wait for a subscriber that falls behind. Once the
+    /// buffer overflows, the oldest events are dropped and the next call to `recv` returns
+    /// [`RecvError::Lagged`](tokio::sync::broadcast::error::RecvError::Lagged) with the number of
+    /// events that were skipped, after which the subscriber continues with the oldest event still
+    /// held. Subscribers that must not miss an event should use [`Client::event_rx`].
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Event> {
+        self.requester.subscribe_events()
+    }
+
+    /// Separate the [`Requester`] from the channels that receive messages from the node, so each
+    /// half may be moved into a different task.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use bip157::{Builder, Network};
+    ///
+    /// # async fn example() {
+    /// let (node, client) = Builder::new(Network::Signet).build();
+    /// let (requester, mut receivers) = client.split();
+    /// tokio::task::spawn(async move {
+    ///     while let Some(event) = receivers.event_rx.recv().await {
+    ///         // Handle events
+    ///     }
+    /// });
+    /// # }
+    /// ```
+    pub fn split(self) -> (Requester, ClientReceivers) {
+        let Client {
+            requester,
+            info_rx,
+            warn_rx,
+            event_rx,
+        } = self;
+        (
+            requester,
+            ClientReceivers {
+                info_rx,
+                warn_rx,
+                event_rx,
+            },
+        )
+    }
+}
+
+/// The channels of a [`Client`] that receive messages from a node.
+#[derive(Debug)]
+pub struct ClientReceivers {
+    /// Receive informational messages from the node.
+    pub info_rx: mpsc::Receiver<Info>,
+    /// Receive warning messages from a node.
+    pub warn_rx: mpsc::UnboundedReceiver<Warning>,
+    /// Receive [`Event`] from a node to act on.
+    pub event_rx: mpsc::Receiver<Event>,
+}
+
+/// A [`Client`] for applications that do not run an async runtime, such as GUI frameworks or
+/// FFI bindings. The wrapper owns a dedicated runtime, on which the [`Node`] runs in the
+/// background. Each blocking call drives the runtime until the result is ready, so methods must
+/// not be called from within another async runtime.
+///
+/// # Examples
+///
+/// ```no_run
+/// use bip157::{BlockingClient, Builder, Network};
+///
+/// let (node, client) = Builder::new(Network::Signet).build();
+/// let mut client = BlockingClient::new(node, client).unwrap();
+/// while let Some(event) = client.next_event() {
+///     // Handle events
+/// }
+/// client.shutdown().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct BlockingClient {
+    client: Client,
+    node: Option<JoinHandle<Result<(), NodeError>>>,
+    runtime: Runtime,
+}
+
+impl BlockingClient {
+    /// Start a runtime and run the node on it.
+    ///
+    /// # Errors
+    ///
+    /// If the runtime could not be created.
+    pub fn new(node: Node, client: Client) -> Result<Self, std::io::Error> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let node = runtime.spawn(node.run());
+        Ok(Self {
+            client,
+            node: Some(node),
+            runtime,
+        })
+    }
+
+    /// The [`Requester`] of the node, for requests that do not have a blocking version. Async
+    /// requests may be completed with [`block_on`](BlockingClient::block_on).
+    pub fn requester(&self) -> &Requester {
+        &self.client.requester
+    }
+
+    /// Drive the internal runtime until the future completes.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    /// Wait for the next [`Event`], returning `None` once the node has stopped.
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.runtime.block_on(self.client.event_rx.recv())
+    }
+
+    /// Wait for the next [`Info`] message, returning `None` once the node has stopped.
+    pub fn next_info(&mut self) -> Option<Info> {
+        self.runtime.block_on(self.client.info_rx.recv())
+    }
+
+    /// Wait for the next [`Warning`], returning `None` once the node has stopped.
+    pub fn next_warning(&mut self) -> Option<Warning> {
+        self.runtime.block_on(self.client.warn_rx.recv())
+    }
+
+    /// Submit a package of transactions to the network, returning when transaction data was sent
+    /// to at least one peer. See [`Requester::submit_package`].
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub fn submit_package(&self, package: impl Into<Package>) -> Result<Wtxid, ClientError> {
+        self.runtime
+            .block_on(self.client.requester.submit_package(package))
+    }
+
+    /// Fetch a block from a peer. See [`Requester::get_block`].
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub fn get_block(&self, block_hash: BlockHash) -> Result<IndexedBlock, ClientError> {
+        self.runtime
+            .block_on(self.client.requester.get_block(block_hash))
+    }
+
+    /// Tell the node to shut down and wait for it to stop. The runtime is dropped afterwards.
+    ///
+    /// # Errors
+    ///
+    /// If the node stopped with an error.
+    pub fn shutdown(mut self) -> Result<(), NodeError> {
+        let _ = self.client.requester.shutdown();
+        let Some(node) = self.node.take() else {
+            return Ok(());
+        };
+        match self.runtime.block_on(node) {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+/// An identifier for a block request made with [`Requester::fetch_block`], unique for the
+/// lifetime of the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequestId(pub(crate) u64);
+
+/// A block that was requested with [`Requester::fetch_block`] and may still be cancelled.
+#[derive(Debug)]
+pub struct PendingBlock {
+    id: BlockRequestId,
+    rx: oneshot::Receiver<Result<IndexedBlock, FetchBlockError>>,
+}
+
+impl PendingBlock {
+    /// The identifier of the request, to cancel it with [`Requester::cancel_block`].
+    pub fn id(&self) -> BlockRequestId {
+        self.id
+    }
+
+    /// Wait for the block to be downloaded.
+    ///
+    /// # Errors
+    ///
+    /// If the request was cancelled, the block is not in the chain of most work, or the node has
+    /// stopped running.
+    pub async fn wait(self) -> Result<IndexedBlock, ClientError> {
+        Ok(self.rx.await.map_err(|_| ClientError::NodeShutdown)??)
+    }
 }
 
 /// Send messages to a node that is running so the node may complete a task.
+///
+/// Every request returns a [`ClientError`]. A request fails with [`ClientError::NodeShutdown`]
+/// once the node has stopped, instead of waiting on a response that will never arrive.
 #[derive(Debug, Clone)]
 pub struct Requester {
     ntx: UnboundedSender<ClientMessage>,
+    next_block_request: Arc<AtomicU64>,
+    event_broadcast: broadcast::Sender<Event>,
+    tip_rx: watch::Receiver<HashCheckpoint>,
 }
 
 impl Requester {
-    fn new(ntx: UnboundedSender<ClientMessage>) -> Self {
-        Self { ntx }
+    fn new(
+        ntx: UnboundedSender<ClientMessage>,
+        event_broadcast: broadcast::Sender<Event>,
+        tip_rx: watch::Receiver<HashCheckpoint>,
+    ) -> Self {
+        Self {
+            ntx,
+            next_block_request: Arc::new(AtomicU64::new(0)),
+            event_broadcast,
+            tip_rx,
+        }
+    }
+
+    /// Receive a new stream of [`Event`]. See [`Client::subscribe_events`] for how subscribers
+    /// that fall behind are handled.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<Event> {
+        self.event_broadcast.subscribe()
     }
 
     /// Tell the node to shut down.
@@ -62,7 +300,7 @@ impl Requester {
     pub fn shutdown(&self) -> Result<(), ClientError> {
         self.ntx
             .send(ClientMessage::Shutdown)
-            .map_err(|_| ClientError::SendError)
+            .map_err(|_| ClientError::NodeShutdown)
     }
 
     /// Submit a package of transactions to the network, returning when transaction data was sent
@@ -83,8 +321,51 @@ impl Requester {
         let client_request = ClientRequest::new(package, tx);
         self.ntx
             .send(ClientMessage::Broadcast(client_request))
-            .map_err(|_| ClientError::SendError)?;
-        rx.await.map_err(|_| ClientError::RecvError)
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
+    }
+
+    /// Broadcast a new transaction or package to the network, waiting for a peer to either request
+    /// or reject it. Unlike [`Requester::submit_package`], a rejection removes the package from
+    /// the broadcast queue.
+    ///
+    /// # Returns
+    ///
+    /// The `Wtxid` of the child or singleton transaction.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running, a peer rejected the transaction, or no peer requested the
+    /// transaction within the `timeout`. After a timeout, the package remains queued and may
+    /// still be sent to a peer.
+    pub async fn broadcast_with_confirmation(
+        &self,
+        package: impl Into<Package>,
+        timeout: Duration,
+    ) -> Result<Wtxid, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Result<Wtxid, BroadcastError>>();
+        let client_request = ClientRequest::new(package.into(), tx);
+        self.ntx
+            .send(ClientMessage::BroadcastWithConfirmation(client_request))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        let wtxid = tokio::time::timeout(timeout, rx)
+            .await
+            .map_err(|_| ClientError::RequestTimeout)?
+            .map_err(|_| ClientError::NodeShutdown)??;
+        Ok(wtxid)
+    }
+
+    /// Stop announcing a transaction to peers, for instance one that is announced persistently
+    /// with [`TxBroadcastPolicy::Persistent`](crate::TxBroadcastPolicy::Persistent). If the
+    /// transaction is the child of a package, the parent is removed as well.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub fn cancel_broadcast(&self, wtxid: Wtxid) -> Result<(), ClientError> {
+        self.ntx
+            .send(ClientMessage::CancelBroadcast(wtxid))
+            .map_err(|_| ClientError::NodeShutdown)
     }
 
     /// A connection has a minimum transaction fee requirement to enter its mempool. For proper transaction propagation,
@@ -101,8 +382,8 @@ impl Requester {
         let request = ClientRequest::new((), tx);
         self.ntx
             .send(ClientMessage::GetBroadcastMinFeeRate(request))
-            .map_err(|_| ClientError::SendError)?;
-        rx.await.map_err(|_| ClientError::RecvError)
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
     }
 
     /// Request a block be fetched. Note that this method will request a block
@@ -112,13 +393,82 @@ impl Requester {
     /// # Errors
     ///
     /// If the node has stopped running.
-    pub async fn get_block(&self, block_hash: BlockHash) -> Result<IndexedBlock, FetchBlockError> {
+    pub async fn get_block(&self, block_hash: BlockHash) -> Result<IndexedBlock, ClientError> {
         let (tx, rx) = tokio::sync::oneshot::channel::<Result<IndexedBlock, FetchBlockError>>();
         let message = ClientRequest::new(block_hash, tx);
         self.ntx
             .send(ClientMessage::GetBlock(message))
-            .map_err(|_| FetchBlockError::SendError)?;
-        rx.await.map_err(|_| FetchBlockError::RecvError)?
+            .map_err(|_| ClientError::NodeShutdown)?;
+        Ok(rx.await.map_err(|_| ClientError::NodeShutdown)??)
+    }
+
+    /// Request a block be fetched, like [`Requester::get_block`], returning a handle that
+    /// identifies the request. Requests that were not yet sent to a peer may be cancelled with
+    /// [`Requester::cancel_block`], which saves downloading a block that is no longer needed.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub fn fetch_block(&self, block_hash: BlockHash) -> Result<PendingBlock, ClientError> {
+        let id = BlockRequestId(self.next_block_request.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = tokio::sync::oneshot::channel::<Result<IndexedBlock, FetchBlockError>>();
+        let message = ClientRequest::new(block_hash, tx);
+        self.ntx
+            .send(ClientMessage::FetchBlock(id, message))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        Ok(PendingBlock { id, rx })
+    }
+
+    /// Cancel a block request made with [`Requester::fetch_block`]. If the block was not yet
+    /// requested from a peer, the request is removed and resolves with
+    /// [`FetchBlockError::Cancelled`]. Otherwise, the block is delivered as usual.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub fn cancel_block(&self, id: BlockRequestId) -> Result<(), ClientError> {
+        self.ntx
+            .send(ClientMessage::CancelBlock(id))
+            .map_err(|_| ClientError::NodeShutdown)
+    }
+
+    /// Prove that a transaction is included in a block of the chain of most work. The block is
+    /// downloaded and validated against the header chain, and only the merkle branch of the
+    /// transaction is returned, alongside the block header. The proof may be verified with
+    /// [`MerkleBlock::extract_matches`], which checks the branch against the merkle root in the
+    /// header.
+    ///
+    /// # Errors
+    ///
+    /// If the block is not in the chain of most work, the block does not contain the transaction,
+    /// or the node has stopped running.
+    pub async fn get_merkle_proof(
+        &self,
+        block_hash: BlockHash,
+        txid: Txid,
+    ) -> Result<MerkleBlock, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Result<MerkleBlock, FetchBlockError>>();
+        let message = ClientRequest::new((block_hash, txid), tx);
+        self.ntx
+            .send(ClientMessage::GetMerkleProof(message))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        Ok(rx.await.map_err(|_| ClientError::NodeShutdown)??)
+    }
+
+    /// Request the block at a height in the chain of most work be fetched. Like
+    /// [`Requester::get_block`], this may take an indefinite amount of time, until a peer
+    /// responds.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running, or the height is not in the chain of most work.
+    pub async fn get_block_by_height(&self, height: u32) -> Result<IndexedBlock, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Result<IndexedBlock, FetchBlockError>>();
+        let message = ClientRequest::new(height, tx);
+        self.ntx
+            .send(ClientMessage::GetBlockByHeight(message))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        Ok(rx.await.map_err(|_| ClientError::NodeShutdown)??)
     }
 
     /// Request a block be fetched and receive a [`tokio::sync::oneshot::Receiver`]
@@ -130,59 +480,143 @@ impl Requester {
     pub fn request_block(
         &self,
         block_hash: BlockHash,
-    ) -> Result<oneshot::Receiver<Result<IndexedBlock, FetchBlockError>>, FetchBlockError> {
+    ) -> Result<oneshot::Receiver<Result<IndexedBlock, FetchBlockError>>, ClientError> {
         let (tx, rx) = tokio::sync::oneshot::channel::<Result<IndexedBlock, FetchBlockError>>();
         let message = ClientRequest::new(block_hash, tx);
         self.ntx
             .send(ClientMessage::GetBlock(message))
-            .map_err(|_| FetchBlockError::SendError)?;
+            .map_err(|_| ClientError::NodeShutdown)?;
         Ok(rx)
     }
 
+    /// Request the compact filter headers from `start_height` up to and including the block with
+    /// `stop_hash` from a connected peer. This request is independent of the headers the node
+    /// syncs automatically, which makes it useful for auditing the filter headers served by
+    /// different peers. The response is checked to align with the requested range, and to match
+    /// any filter headers the node has already synced in that range.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running, the stop hash is unknown, or the range is invalid. The
+    /// request also fails if the peer does not respond within 30 seconds, disconnects, or sends
+    /// filter headers that conflict with those synced by the node.
+    pub async fn get_filter_headers(
+        &self,
+        start_height: u32,
+        stop_hash: BlockHash,
+    ) -> Result<CFHeaders, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Result<CFHeaders, ClientError>>();
+        let request = ClientRequest::new((start_height, stop_hash), tx);
+        self.ntx
+            .send(ClientMessage::GetFilterHeaders(request))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)?
+    }
+
     /// Fetch the average fee rate for the given block hash.
     ///
     /// Computed by taking (`coinbase output amount` - `block subsidy`) / `block weight`. Note that
     /// this value may provide skewed estimates, as averages are more effected by outliers than
     /// medians. For a rudimentary estimation of the fee rate required to enter the next block,
     /// this method may suffice.
-    pub async fn average_fee_rate(
-        &self,
-        block_hash: BlockHash,
-    ) -> Result<FeeRate, FetchBlockError> {
+    pub async fn average_fee_rate(&self, block_hash: BlockHash) -> Result<FeeRate, ClientError> {
         let (tx, rx) = tokio::sync::oneshot::channel::<Result<IndexedBlock, FetchBlockError>>();
         let message = ClientRequest::new(block_hash, tx);
         self.ntx
             .send(ClientMessage::GetBlock(message))
-            .map_err(|_| FetchBlockError::SendError)?;
-        let indexed_block = rx.await.map_err(|_| FetchBlockError::RecvError)??;
-        let subsidy = block_subsidy(indexed_block.height);
-        let weight = indexed_block.block.weight();
-        let revenue = indexed_block
-            .block
-            .txdata
-            .first()
-            .map(|tx| tx.output.iter().map(|txout| txout.value).sum())
-            .unwrap_or(Amount::ZERO);
-        let block_fees = revenue.checked_sub(subsidy).unwrap_or(Amount::ZERO);
-        let fee_rate = block_fees
-            .to_sat()
-            .checked_div(weight.to_kwu_floor())
-            .unwrap_or(0);
-        Ok(FeeRate::from_sat_per_kwu(fee_rate))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        let indexed_block = rx.await.map_err(|_| ClientError::NodeShutdown)??;
+        Ok(block_fee_rate(&indexed_block.block, indexed_block.height))
     }
 
-    /// Get the current peer connections.
+    /// A rough estimate of the fee rate to enter a block, averaged over the last six blocks
+    /// the node downloaded. Each block contributes the fees collected by the coinbase divided by
+    /// the block weight, so the estimate is only as recent as the blocks fetched, and is
+    /// unrelated to the fee filters of peers reported by
+    /// [`broadcast_min_feerate`](Requester::broadcast_min_feerate). Returns `None` if no blocks
+    /// have been downloaded.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub async fn fee_estimate(&self) -> Result<Option<FeeRate>, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Option<FeeRate>>();
+        let request = ClientRequest::new((), tx);
+        self.ntx
+            .send(ClientMessage::GetFeeEstimate(request))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
+    }
+
+    /// Get the address and services of the current peer connections.
     ///
     /// # Errors
     ///
     /// If the node has stopped running.
     pub async fn peer_info(&self) -> Result<Vec<(AddrV2, ServiceFlags)>, ClientError> {
-        let (tx, rx) = tokio::sync::oneshot::channel::<Vec<(AddrV2, ServiceFlags)>>();
+        let peers = self.get_peer_info().await?;
+        Ok(peers
+            .into_iter()
+            .map(|peer| (peer.address, peer.services))
+            .collect())
+    }
+
+    /// Get details about the current peer connections. Peers that have not completed the version
+    /// handshake are not included.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub async fn get_peer_info(&self) -> Result<Vec<PeerInfo>, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Vec<PeerInfo>>();
         let request = ClientRequest::new((), tx);
         self.ntx
             .send(ClientMessage::GetPeerInfo(request))
-            .map_err(|_| ClientError::SendError)?;
-        rx.await.map_err(|_| ClientError::RecvError)
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
+    }
+
+    /// Get the best height known for each connected peer, either reported when the connection was
+    /// opened or announced since.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub async fn peer_heights(&self) -> Result<Vec<(PeerId, u32)>, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Vec<(PeerId, u32)>>();
+        let request = ClientRequest::new((), tx);
+        self.ntx
+            .send(ClientMessage::GetPeerHeights(request))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
+    }
+
+    /// Estimate the height of the tip of the network as the median of the heights in
+    /// [`Requester::peer_heights`]. Returns `None` if no peers are connected. Compare against the
+    /// height of [`Requester::chain_tip`] to tell if the node is behind the network.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub async fn network_tip_estimate(&self) -> Result<Option<u32>, ClientError> {
+        let heights = self.peer_heights().await?;
+        Ok(median_height(heights.into_iter().map(|(_, height)| height)))
+    }
+
+    /// Get the peers the node has discovered and stored in its address book, including those that
+    /// were banned. Peers configured with [`Builder::add_peer`](crate::Builder::add_peer) are
+    /// only included once a connection to them was made.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub async fn known_peers(&self) -> Result<Vec<StoredPeer>, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Vec<StoredPeer>>();
+        let request = ClientRequest::new((), tx);
+        self.ntx
+            .send(ClientMessage::GetKnownPeers(request))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
     }
 
     /// Starting after the configured checkpoint, re-emit all block filters.
@@ -192,19 +626,25 @@ impl Requester {
     /// If the node has stopped running.
     pub fn rescan(&self) -> Result<(), ClientError> {
         self.ntx
-            .send(ClientMessage::Rescan(None))
-            .map_err(|_| ClientError::SendError)
+            .send(ClientMessage::Rescan)
+            .map_err(|_| ClientError::NodeShutdown)
     }
 
-    /// Re-emit block filters _after_ the specified height.
+    /// Re-emit block filters _after_ the specified height. Filters at or below the height are left
+    /// as they are, and filters above it are replayed from the cache or downloaded again.
     ///
     /// # Errors
     ///
-    /// If the node has stopped running.
-    pub fn rescan_from(&self, height: u32) -> Result<(), ClientError> {
+    /// If the height is below the chain state the node started from, or below the headers pruned
+    /// with [`Builder::prune_headers_below_depth`](crate::Builder::prune_headers_below_depth), if
+    /// it is above the tip, or if the node has stopped running.
+    pub async fn rescan_from(&self, height: u32) -> Result<(), ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Result<(), RescanError>>();
+        let request = ClientRequest::new(height, tx);
         self.ntx
-            .send(ClientMessage::Rescan(Some(height)))
-            .map_err(|_| ClientError::SendError)
+            .send(ClientMessage::RescanFrom(request))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        Ok(rx.await.map_err(|_| ClientError::NodeShutdown)??)
     }
 
     /// Add another known peer to connect to.
@@ -215,7 +655,41 @@ impl Requester {
     pub fn add_peer(&self, peer: impl Into<TrustedPeer>) -> Result<(), ClientError> {
         self.ntx
             .send(ClientMessage::AddPeer(peer.into()))
-            .map_err(|_| ClientError::SendError)
+            .map_err(|_| ClientError::NodeShutdown)
+    }
+
+    /// Disconnect from a peer, for instance one returned by [`Requester::get_peer_info`]. If the
+    /// peer is not connected, a [`Warning::UnknownPeer`] is issued.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub fn disconnect_peer(&self, id: PeerId) -> Result<(), ClientError> {
+        self.ntx
+            .send(ClientMessage::DisconnectPeer(id))
+            .map_err(|_| ClientError::NodeShutdown)
+    }
+
+    /// Disconnect from every peer and connect to new peers right away. Connections that were
+    /// lost, for instance when a device switches from one network to another, are otherwise only
+    /// replaced once they time out or the tip is considered stale. Call this when the platform
+    /// reports a change in connectivity.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub fn force_reconnect_all(&self) -> Result<(), ClientError> {
+        self.ntx
+            .send(ClientMessage::ReconnectAll)
+            .map_err(|_| ClientError::NodeShutdown)
+    }
+
+    /// The height and hash of the block in the chain of most work, as last published by the
+    /// node. The tip is read without sending a request to the node, so this is suitable for
+    /// frequent reads, like refreshing a display. After the node stops, the last tip it
+    /// published is returned. Use [`Requester::chain_tip`] to wait for the node to answer.
+    pub fn tip(&self) -> HashCheckpoint {
+        *self.tip_rx.borrow()
     }
 
     /// The height and hash of the block in the chain of most work.
@@ -228,8 +702,8 @@ impl Requester {
         let request = ClientRequest::new((), tx);
         self.ntx
             .send(ClientMessage::BestBlock(request))
-            .map_err(|_| ClientError::SendError)?;
-        rx.await.map_err(|_| ClientError::RecvError)
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
     }
 
     /// Look up a header at a specific height in the locally synced chain of most work.
@@ -243,8 +717,8 @@ impl Requester {
         let request = ClientRequest::new(height, tx);
         self.ntx
             .send(ClientMessage::GetHeader(request))
-            .map_err(|_| ClientError::SendError)?;
-        rx.await.map_err(|_| ClientError::RecvError)
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
     }
 
     /// Look up the height of a block hash in the locally synced chain of most work.
@@ -258,8 +732,124 @@ impl Requester {
         let request = ClientRequest::new(hash, tx);
         self.ntx
             .send(ClientMessage::HeightOfHash(request))
-            .map_err(|_| ClientError::SendError)?;
-        rx.await.map_err(|_| ClientError::RecvError)
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
+    }
+
+    /// Test which blocks in a range of heights may contain a script, using filters the node has
+    /// already checked and retained. No filters or blocks are downloaded. Filters are only retained
+    /// if configured with [`Builder::retain_filters`](crate::Builder::retain_filters).
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running, a filter in the range is no longer retained, or a filter
+    /// in the range has not been checked.
+    pub async fn test_script(
+        &self,
+        script: ScriptBuf,
+        range: RangeInclusive<u32>,
+    ) -> Result<Vec<BlockHash>, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Result<Vec<BlockHash>, TestScriptError>>();
+        let request = ClientRequest::new((script, range), tx);
+        self.ntx
+            .send(ClientMessage::TestScript(request))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        Ok(rx.await.map_err(|_| ClientError::NodeShutdown)??)
+    }
+
+    /// Get the block locators of the locally synced chain of most work. The locators step back
+    /// exponentially from the tip, and are ordered from the oldest block to the tip. Locators may
+    /// be used to construct `getheaders` style queries to a peer, and headers found with
+    /// [`get_header`](Requester::get_header) may be used to verify merkle proofs sourced
+    /// elsewhere.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub async fn get_block_locators(&self) -> Result<Vec<BlockHash>, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Vec<BlockHash>>();
+        let request = ClientRequest::new((), tx);
+        self.ntx
+            .send(ClientMessage::GetBlockLocators(request))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
+    }
+
+    /// Wait until the node has synced compact block filters to the tip of the chain, resolving
+    /// immediately if the node is already synced. This does not consume any
+    /// [`Event`], so the event receiver may be handled by another task as usual.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn wait(requester: bip157::Requester) {
+    /// let update = requester.wait_until_synced().await.unwrap();
+    /// println!("Synced to height {}", update.tip().height);
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub async fn wait_until_synced(&self) -> Result<SyncUpdate, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<SyncUpdate>();
+        let request = ClientRequest::new((), tx);
+        self.ntx
+            .send(ClientMessage::WaitUntilSynced(request))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
+    }
+
+    /// Get the work of the locally synced chain of most work, for instance to compare the chain
+    /// against an external source. The work is relative, accumulated from the block after the
+    /// configured [`ChainState`](crate::chain::ChainState), so it is not the `chainwork` reported by
+    /// Bitcoin Core unless the node started from genesis. Chains compared this way should share a
+    /// starting point.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub async fn relative_chain_work(&self) -> Result<Work, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Work>();
+        let request = ClientRequest::new((), tx);
+        self.ntx
+            .send(ClientMessage::GetRelativeChainWork(request))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
+    }
+
+    /// Find the block hash and height of a transaction that was confirmed in a block previously
+    /// downloaded by the node. Transactions in blocks that were reorganized out of the chain are
+    /// forgotten, as are the least recently downloaded blocks once 100,000 transactions are indexed.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub async fn find_transaction(
+        &self,
+        txid: Txid,
+    ) -> Result<Option<(BlockHash, u32)>, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<Option<(BlockHash, u32)>>();
+        let request = ClientRequest::new(txid, tx);
+        self.ntx
+            .send(ClientMessage::FindTransaction(request))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
+    }
+
+    /// Get the sync state of the node and the number of times its main loop has run. A
+    /// supervisor may call this with a timeout to detect a node that has stalled.
+    ///
+    /// # Errors
+    ///
+    /// If the node has stopped running.
+    pub async fn health(&self) -> Result<NodeHealth, ClientError> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<NodeHealth>();
+        let request = ClientRequest::new((), tx);
+        self.ntx
+            .send(ClientMessage::Health(request))
+            .map_err(|_| ClientError::NodeShutdown)?;
+        rx.await.map_err(|_| ClientError::NodeShutdown)
     }
 
     /// Check if the node is running.
@@ -270,6 +860,6 @@ impl Requester {
 
 impl<T> From<mpsc::error::SendError<T>> for ClientError {
     fn from(_: mpsc::error::SendError<T>) -> Self {
-        ClientError::SendError
+        ClientError::NodeShutdown
     }
 }
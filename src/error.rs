@@ -1,6 +1,6 @@
 use std::fmt::Debug;
 
-use crate::impl_sourceless_error;
+use crate::{impl_sourceless_error, messages::RejectPayload};
 
 /// Errors that prevent the node from running.
 #[derive(Debug)]
@@ -22,60 +22,248 @@ impl core::fmt::Display for NodeError {
 impl_sourceless_error!(NodeError);
 
 /// Errors occurring when the client is talking to the node.
+///
+/// Every request to the node fails with a [`ClientError`]. Failures specific to a request, such
+/// as fetching a block that is not in the chain, are wrapped by the variant for that request.
 #[derive(Debug)]
 pub enum ClientError {
-    /// The channel to the node was likely closed and dropped from memory.
-    SendError,
-    /// A channel was dropped before sending its value back.
-    RecvError,
+    /// The node stopped running before the request was answered.
+    NodeShutdown,
+    /// The request was not answered in time.
+    RequestTimeout,
+    /// A block could not be fetched.
+    FetchBlock(FetchBlockError),
+    /// Compact filter headers could not be fetched.
+    FetchFilterHeaders(FetchFilterHeadersError),
+    /// A transaction was not accepted by a peer.
+    Broadcast(BroadcastError),
+    /// A rescan could not be started.
+    Rescan(RescanError),
+    /// A script could not be tested against the filters.
+    TestScript(TestScriptError),
 }
 
 impl core::fmt::Display for ClientError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ClientError::SendError => {
-                write!(f, "the receiver of this message was dropped from memory.")
-            }
-            ClientError::RecvError => {
-                write!(f, "the sender of data was dropped from memory.")
+            ClientError::NodeShutdown => {
+                write!(
+                    f,
+                    "the node stopped running before the request was answered."
+                )
             }
+            ClientError::RequestTimeout => write!(f, "the request was not answered in time."),
+            ClientError::FetchBlock(e) => write!(f, "{e}"),
+            ClientError::FetchFilterHeaders(e) => write!(f, "{e}"),
+            ClientError::Broadcast(e) => write!(f, "{e}"),
+            ClientError::Rescan(e) => write!(f, "{e}"),
+            ClientError::TestScript(e) => write!(f, "{e}"),
         }
     }
 }
 
-impl_sourceless_error!(ClientError);
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::NodeShutdown | ClientError::RequestTimeout => None,
+            ClientError::FetchBlock(e) => Some(e),
+            ClientError::FetchFilterHeaders(e) => Some(e),
+            ClientError::Broadcast(e) => Some(e),
+            ClientError::Rescan(e) => Some(e),
+            ClientError::TestScript(e) => Some(e),
+        }
+    }
+}
+
+impl From<FetchBlockError> for ClientError {
+    fn from(value: FetchBlockError) -> Self {
+        ClientError::FetchBlock(value)
+    }
+}
+
+impl From<FetchFilterHeadersError> for ClientError {
+    fn from(value: FetchFilterHeadersError) -> Self {
+        ClientError::FetchFilterHeaders(value)
+    }
+}
+
+impl From<BroadcastError> for ClientError {
+    fn from(value: BroadcastError) -> Self {
+        ClientError::Broadcast(value)
+    }
+}
+
+impl From<RescanError> for ClientError {
+    fn from(value: RescanError) -> Self {
+        ClientError::Rescan(value)
+    }
+}
+
+impl From<TestScriptError> for ClientError {
+    fn from(value: TestScriptError) -> Self {
+        ClientError::TestScript(value)
+    }
+}
 
 /// Errors occurring when the client is fetching blocks from the node.
 #[derive(Debug)]
 pub enum FetchBlockError {
-    /// The channel to the node was likely closed and dropped from memory.
-    /// This implies the node is not running.
-    SendError,
-    /// The channel to the client was likely closed by the node and dropped from memory.
-    RecvError,
     /// The hash is not a member of the chain of most work.
     UnknownHash,
+    /// The height is beyond the tip of the chain of most work, or before the checkpoint the node
+    /// started from.
+    UnknownHeight,
+    /// The node was stopped before the block was received.
+    Cancelled,
+    /// The block does not contain the transaction a merkle proof was requested for.
+    UnknownTransaction,
 }
 
 impl core::fmt::Display for FetchBlockError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            FetchBlockError::SendError => {
-                write!(f, "the receiver of this message was dropped from memory.")
-            }
-            FetchBlockError::RecvError => write!(
-                f,
-                "the channel to the client was likely closed by the node and dropped from memory."
-            ),
             FetchBlockError::UnknownHash => {
                 write!(f, "the hash is not a member of the chain of most work.")
             }
+            FetchBlockError::UnknownHeight => {
+                write!(f, "the height is not in the chain of most work.")
+            }
+            FetchBlockError::Cancelled => {
+                write!(f, "the node was stopped before the block was received.")
+            }
+            FetchBlockError::UnknownTransaction => {
+                write!(f, "the block does not contain the transaction.")
+            }
         }
     }
 }
 
 impl_sourceless_error!(FetchBlockError);
 
+/// Errors occurring when the client is fetching compact filter headers from the node.
+#[derive(Debug)]
+pub enum FetchFilterHeadersError {
+    /// The stop hash is not a member of the chain of most work.
+    UnknownStopHash,
+    /// The start height is after the stop hash, or the range exceeds the 2,000 headers a peer may
+    /// respond with.
+    InvalidRange,
+    /// No peer was connected, or the peer disconnected before responding.
+    NoResponse,
+    /// The filter headers sent by the peer conflict with those synced by the node.
+    InvalidResponse,
+}
+
+impl core::fmt::Display for FetchFilterHeadersError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchFilterHeadersError::UnknownStopHash => {
+                write!(
+                    f,
+                    "the stop hash is not a member of the chain of most work."
+                )
+            }
+            FetchFilterHeadersError::InvalidRange => {
+                write!(f, "the requested range of filter headers is invalid.")
+            }
+            FetchFilterHeadersError::NoResponse => {
+                write!(f, "no peer responded with the filter headers.")
+            }
+            FetchFilterHeadersError::InvalidResponse => {
+                write!(
+                    f,
+                    "the filter headers conflict with those synced by the node."
+                )
+            }
+        }
+    }
+}
+
+impl_sourceless_error!(FetchFilterHeadersError);
+
+/// Errors occurring when the client tests a script against previously checked filters.
+#[derive(Debug)]
+pub enum TestScriptError {
+    /// The filter at this height was checked, but is no longer retained. A rescan is required.
+    FilterDataPurged {
+        /// The height of the filter.
+        height: u32,
+    },
+    /// The filter at this height has not been checked yet, or the height is not in the chain of
+    /// most work.
+    FilterNotSynced {
+        /// The height of the filter.
+        height: u32,
+    },
+}
+
+impl core::fmt::Display for TestScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TestScriptError::FilterDataPurged { height } => {
+                write!(f, "the filter at height {height} is no longer retained.")
+            }
+            TestScriptError::FilterNotSynced { height } => {
+                write!(f, "the filter at height {height} has not been checked.")
+            }
+        }
+    }
+}
+
+impl_sourceless_error!(TestScriptError);
+
+/// Errors occurring when the client requests a rescan from a height.
+#[derive(Debug)]
+pub enum RescanError {
+    /// The height is below the chain state the node was started from, so no filters are known.
+    BelowChainState {
+        /// The height of the chain state.
+        chain_state: u32,
+    },
+    /// The height is above the tip of the chain of most work.
+    AboveTip {
+        /// The height of the tip.
+        tip: u32,
+    },
+}
+
+impl core::fmt::Display for RescanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RescanError::BelowChainState { chain_state } => {
+                write!(
+                    f,
+                    "the height is below the chain state at height {chain_state}."
+                )
+            }
+            RescanError::AboveTip { tip } => {
+                write!(f, "the height is above the tip at height {tip}.")
+            }
+        }
+    }
+}
+
+impl_sourceless_error!(RescanError);
+
+/// Errors occurring when the client is waiting for a peer to accept a transaction.
+#[derive(Debug)]
+pub enum BroadcastError {
+    /// A peer rejected the transaction.
+    Rejected(RejectPayload),
+}
+
+impl core::fmt::Display for BroadcastError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BroadcastError::Rejected(payload) => {
+                write!(f, "a peer rejected transaction {}", payload.wtxid)
+            }
+        }
+    }
+}
+
+impl_sourceless_error!(BroadcastError);
+
 /// Errors when constructing transaction packages.
 #[derive(Debug)]
 pub enum PackageError {
@@ -44,7 +44,7 @@
 #![warn(missing_docs)]
 pub mod chain;
 
-use crate::network::{ConnectionType, PeerTimeoutConfig};
+use crate::network::{ConnectionType, LocalVersion, PeerTimeoutConfig};
 
 mod network;
 
@@ -63,13 +63,22 @@ pub mod node;
 use bitcoin::OutPoint;
 use chain::Filter;
 
+use std::collections::VecDeque;
+use std::future::Future;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 // Re-exports
 #[doc(inline)]
-pub use chain::checkpoints::HashCheckpoint;
+pub use chain::block_source::{BlockSource, BlockSourceError};
+#[doc(inline)]
+pub use chain::checkpoints::{FilterHeaderCheckpoint, HashCheckpoint};
 
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::error::TrySendError;
 #[doc(inline)]
 pub use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
@@ -82,16 +91,25 @@ use tokio::sync::mpsc::UnboundedSender;
 pub use {
     crate::builder::Builder,
     crate::chain::ChainState,
-    crate::client::{Client, Requester},
+    crate::client::{
+        BlockRequestId, BlockingClient, Client, ClientReceivers, PendingBlock, Requester,
+    },
     crate::error::{ClientError, NodeError},
-    crate::messages::{Event, Info, Progress, RejectPayload, SyncUpdate, Warning},
+    crate::messages::{
+        Event, Info, NodeHealth, Progress, RejectPayload, SyncStage, SyncUpdate, Warning,
+    },
+    crate::network::dns::{DefaultResolver, DnsError, DnsResolver, FutureResult},
+    crate::network::{
+        DisconnectReason, PeerId, PeerInfo, StoredPeer, TimeSensitiveKind, TransportVersion,
+    },
     crate::node::Node,
 };
 
 #[doc(inline)]
 pub use bitcoin::{
     bip158::BlockFilter, block::Header, p2p::address::AddrV2, p2p::message_network::RejectReason,
-    p2p::ServiceFlags, Address, Block, BlockHash, FeeRate, Network, ScriptBuf, Transaction, Wtxid,
+    p2p::Magic, p2p::ServiceFlags, Address, Block, BlockHash, FeeRate, MerkleBlock, Network,
+    ScriptBuf, Transaction, Txid, Work, Wtxid,
 };
 
 pub extern crate tokio;
@@ -103,11 +121,14 @@ pub struct IndexedBlock {
     pub height: u32,
     /// The Bitcoin block with some matching script.
     pub block: Block,
+    /// The consensus encoding of the block as it was received from the remote peer. Only
+    /// present when requested with [`Builder::raw_blocks`].
+    pub raw: Option<Vec<u8>>,
 }
 
 impl IndexedBlock {
-    pub(crate) fn new(height: u32, block: Block) -> Self {
-        Self { height, block }
+    pub(crate) fn new(height: u32, block: Block, raw: Option<Vec<u8>>) -> Self {
+        Self { height, block, raw }
     }
 }
 
@@ -120,6 +141,36 @@ pub enum FilterType {
     Basic,
 }
 
+/// How the node should find new peers when no new blocks have been announced for a long time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StaleTipStrategy {
+    /// Disconnect from all peers at once.
+    #[default]
+    RotateAll,
+    /// Disconnect from a single random peer and find a replacement. If the tip remains stale,
+    /// all peers will be disconnected.
+    RotateOne,
+}
+
+/// How long the node should continue to announce a transaction to peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TxBroadcastPolicy {
+    /// Announce the transaction until a peer requests it.
+    #[default]
+    UntilSent,
+    /// Announce the transaction to every newly connected peer until it is found in a block
+    /// downloaded by the node, or the broadcast is cancelled with
+    /// [`Requester::cancel_broadcast`]. This guards against peers disconnecting before relaying
+    /// the transaction.
+    Persistent,
+    /// Announce the transaction to this many randomly chosen peers at once, until a peer requests
+    /// it. Announcing to more peers is more reliable, while announcing to fewer reveals the
+    /// transaction to fewer peers. If fewer peers are connected, the transaction is announced to
+    /// all of them and a [`Warning::ReducedBroadcast`] is emitted. A count of zero is treated as
+    /// one.
+    NPeers(usize),
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 enum BlockType {
     #[default]
@@ -337,52 +388,122 @@ impl From<SocketAddr> for TrustedPeer {
 
 /// Route network traffic through a Socks5 proxy, typically used by a Tor daemon.
 #[derive(Debug, Clone)]
-pub struct Socks5Proxy(SocketAddr);
+pub struct Socks5Proxy {
+    addr: SocketAddr,
+    credentials: Option<(String, String)>,
+}
 
 impl Socks5Proxy {
     /// Define a non-standard Socks5 proxy to connect to.
     pub fn new(socket_addr: impl Into<SocketAddr>) -> Self {
-        Self(socket_addr.into())
+        Self {
+            addr: socket_addr.into(),
+            credentials: None,
+        }
     }
 
     /// Connect to the default local Socks5 proxy hosted at `127.0.0.1:9050`.
     pub const fn local() -> Self {
-        Socks5Proxy(SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            9050,
-        ))
+        Socks5Proxy {
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 9050),
+            credentials: None,
+        }
+    }
+
+    /// Authenticate with the proxy using a username and password. Tor will isolate the circuits
+    /// of connections that use different credentials. Each value must be at most 255 bytes.
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
     }
 }
 
 impl From<SocketAddr> for Socks5Proxy {
     fn from(value: SocketAddr) -> Self {
-        Self(value)
+        Self::new(value)
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum NodeState {
-    // We are behind on block headers according to our peers.
+/// The stage of the sync the node has reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NodeState {
+    /// We are behind on block headers according to our peers.
     Behind,
-    // We may start downloading compact block filter headers.
+    /// We may start downloading compact block filter headers.
     HeadersSynced,
-    // We may start scanning compact block filters.
+    /// We may start scanning compact block filters.
     FilterHeadersSynced,
-    // We may start asking for blocks with matches.
+    /// We may start asking for blocks with matches.
     FiltersSynced,
 }
 
+/// A callback run when the node enters a [`NodeState`].
+pub type StateCallback = Box<dyn FnMut() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+#[derive(Default)]
+struct StateCallbacks(Vec<(NodeState, StateCallback)>);
+
+impl StateCallbacks {
+    fn add(&mut self, state: NodeState, callback: StateCallback) {
+        self.0.push((state, callback));
+    }
+
+    // Callbacks are spawned so they may use the client without waiting on the node
+    fn run(&mut self, entered: NodeState) {
+        for (state, callback) in self.0.iter_mut() {
+            if entered.eq(state) {
+                tokio::spawn(callback());
+            }
+        }
+    }
+}
+
+impl core::fmt::Debug for StateCallbacks {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(state, _)| state))
+            .finish()
+    }
+}
+
 #[derive(Debug)]
 struct Config {
     required_peers: u8,
     white_list: Vec<TrustedPeer>,
     whitelist_only: bool,
+    peers_only: bool,
     data_path: Option<PathBuf>,
     chain_state: Option<ChainState>,
     connection_type: ConnectionType,
     peer_timeout_config: PeerTimeoutConfig,
     filter_type: FilterType,
     block_type: BlockType,
+    raw_blocks: bool,
+    pipeline_depth: u8,
+    filter_download_peers: u8,
+    stale_tip_strategy: StaleTipStrategy,
+    stale_tip_timeout: Duration,
+    tx_broadcast_policy: TxBroadcastPolicy,
+    min_peer_score: i32,
+    tip_confirmations: u8,
+    max_reorg_depth: u32,
+    max_block_download_rate: Option<u32>,
+    max_in_flight_blocks: Option<usize>,
+    dns_resolver: Arc<dyn DnsResolver>,
+    preload_headers: Vec<Header>,
+    event_buffer: usize,
+    filter_retention: usize,
+    checkpoints: Vec<HashCheckpoint>,
+    filter_header_checkpoints: Vec<FilterHeaderCheckpoint>,
+    require_v2_transport: bool,
+    local_version: LocalVersion,
+    block_source: Option<Arc<dyn BlockSource>>,
+    prune_depth: Option<u32>,
+    filter_batch_size: u32,
+    adaptive_filter_batches: bool,
+    network_magic: Option<Magic>,
+    reconnect_jitter: Duration,
+    state_callbacks: StateCallbacks,
 }
 
 impl Default for Config {
@@ -391,12 +512,39 @@ impl Default for Config {
             required_peers: 1,
             white_list: Default::default(),
             whitelist_only: Default::default(),
+            peers_only: Default::default(),
             data_path: Default::default(),
             chain_state: Default::default(),
             connection_type: Default::default(),
             peer_timeout_config: PeerTimeoutConfig::default(),
             filter_type: FilterType::default(),
             block_type: BlockType::default(),
+            raw_blocks: false,
+            pipeline_depth: 1,
+            filter_download_peers: 1,
+            stale_tip_strategy: StaleTipStrategy::default(),
+            stale_tip_timeout: network::THIRTY_MINS,
+            tx_broadcast_policy: TxBroadcastPolicy::default(),
+            min_peer_score: -3,
+            tip_confirmations: 1,
+            max_reorg_depth: chain::graph::MAX_REORG_DEPTH,
+            max_block_download_rate: None,
+            max_in_flight_blocks: None,
+            dns_resolver: Arc::new(DefaultResolver),
+            preload_headers: Vec::new(),
+            event_buffer: 1024,
+            filter_retention: 0,
+            checkpoints: Vec::new(),
+            filter_header_checkpoints: Vec::new(),
+            require_v2_transport: false,
+            local_version: LocalVersion::default(),
+            block_source: None,
+            prune_depth: None,
+            filter_batch_size: chain::MAX_FILTER_BATCH_SIZE,
+            adaptive_filter_batches: false,
+            network_magic: None,
+            reconnect_jitter: Duration::ZERO,
+            state_callbacks: StateCallbacks::default(),
         }
     }
 }
@@ -432,36 +580,100 @@ fn default_port_from_network(network: &Network) -> u16 {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct Dialog {
     info_tx: Sender<Info>,
     warn_tx: UnboundedSender<Warning>,
-    event_tx: UnboundedSender<Event>,
+    event_tx: Sender<Event>,
+    // Events published to subscribers, which each receive their own copy
+    event_broadcast: broadcast::Sender<Event>,
+    // Events that did not fit in the channel, delivered in order as the client catches up
+    pending_events: Mutex<VecDeque<Event>>,
 }
 
 impl Dialog {
     fn new(
         info_tx: Sender<Info>,
         warn_tx: UnboundedSender<Warning>,
-        event_tx: UnboundedSender<Event>,
+        event_tx: Sender<Event>,
+        event_broadcast: broadcast::Sender<Event>,
     ) -> Self {
         Self {
             info_tx,
             warn_tx,
             event_tx,
+            event_broadcast,
+            pending_events: Mutex::new(VecDeque::new()),
         }
     }
 
     fn send_warning(&self, warning: Warning) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(%warning);
         let _ = self.warn_tx.send(warning);
     }
 
     fn send_info(&self, info: Info) {
+        #[cfg(feature = "tracing")]
+        tracing::info!(%info);
         let _ = self.info_tx.try_send(info);
     }
 
     fn send_event(&self, message: Event) {
-        let _ = self.event_tx.send(message);
+        if self.event_broadcast.receiver_count() > 0 {
+            let _ = self.event_broadcast.send(message.clone());
+        }
+        let mut pending = self
+            .pending_events
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !pending.is_empty() {
+            pending.push_back(message);
+            return;
+        }
+        if let Err(TrySendError::Full(message)) = self.event_tx.try_send(message) {
+            pending.push_back(message);
+        }
+    }
+
+    // Move pending events into the channel, returning if any are still waiting
+    fn flush_events(&self) -> bool {
+        let mut pending = self
+            .pending_events
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        while let Some(message) = pending.pop_front() {
+            match self.event_tx.try_send(message) {
+                Ok(()) => (),
+                Err(TrySendError::Full(message)) => {
+                    pending.push_front(message);
+                    return true;
+                }
+                Err(TrySendError::Closed(_)) => pending.clear(),
+            }
+        }
+        false
+    }
+
+    // Deliver any remaining events after the node stops, without waiting on the client
+    fn deliver_pending_events(&self) {
+        let pending = core::mem::take(
+            &mut *self
+                .pending_events
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        );
+        if pending.is_empty() {
+            return;
+        }
+        let event_tx = self.event_tx.clone();
+        tokio::spawn(async move {
+            for message in pending {
+                if event_tx.send(message).await.is_err() {
+                    return;
+                }
+            }
+        });
     }
 }
 
@@ -581,9 +793,18 @@ macro_rules! impl_sourceless_error {
 
 pub(crate) use impl_sourceless_error;
 
+// Log a message, with optional structured fields when using the `tracing` facade
 macro_rules! debug {
     ($expr:expr) => {
-        #[cfg(debug_assertions)]
+        #[cfg(feature = "tracing")]
+        tracing::debug!("{}", $expr);
+        #[cfg(all(debug_assertions, not(feature = "tracing")))]
+        println!("{}", $expr)
+    };
+    ($expr:expr, $($key:ident = $value:expr),+ $(,)?) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($key = %$value),+, "{}", $expr);
+        #[cfg(all(debug_assertions, not(feature = "tracing")))]
         println!("{}", $expr)
     };
 }
@@ -610,3 +831,65 @@ macro_rules! impl_deserialize {
 
 #[cfg(test)]
 pub(crate) use impl_deserialize;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    };
+
+    use crate::{Dialog, Event, Info, NodeState, StateCallbacks, Warning};
+
+    #[test]
+    fn test_events_wait_for_capacity() {
+        let (info_tx, _) = tokio::sync::mpsc::channel::<Info>(1);
+        let (warn_tx, _) = tokio::sync::mpsc::unbounded_channel::<Warning>();
+        let (event_tx, mut event_rx) = tokio::sync::mpsc::channel::<Event>(2);
+        let (event_broadcast, mut subscriber) = tokio::sync::broadcast::channel::<Event>(8);
+        let dialog = Dialog::new(info_tx, warn_tx, event_tx, event_broadcast);
+        for next_height in 0..5 {
+            dialog.send_event(Event::RescanIncomplete { next_height });
+        }
+        assert!(dialog.flush_events());
+        let mut received = Vec::new();
+        while received.len() < 5 {
+            while let Ok(Event::RescanIncomplete { next_height }) = event_rx.try_recv() {
+                received.push(next_height);
+            }
+            dialog.flush_events();
+        }
+        // No events are lost or reordered
+        assert_eq!(received, vec![0, 1, 2, 3, 4]);
+        assert!(!dialog.flush_events());
+        // Subscribers receive every event as it is sent
+        for next_height in 0..5 {
+            assert!(matches!(
+                subscriber.try_recv(),
+                Ok(Event::RescanIncomplete { next_height: height }) if height == next_height
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_state_callbacks() {
+        let entered = Arc::new(AtomicU8::new(0));
+        let mut callbacks = StateCallbacks::default();
+        let counter = Arc::clone(&entered);
+        callbacks.add(
+            NodeState::FiltersSynced,
+            Box::new(move || {
+                let counter = Arc::clone(&counter);
+                Box::pin(async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                })
+            }),
+        );
+        callbacks.run(NodeState::HeadersSynced);
+        callbacks.run(NodeState::FiltersSynced);
+        callbacks.run(NodeState::FiltersSynced);
+        tokio::task::yield_now().await;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert_eq!(entered.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,15 +1,20 @@
 use std::collections::BTreeMap;
-use std::ops::Div;
+use std::ops::{Div, RangeInclusive};
 
-use bitcoin::p2p::address::AddrV2;
-use bitcoin::p2p::ServiceFlags;
-use bitcoin::{block::Header, p2p::message_network::RejectReason, BlockHash, FeeRate, Wtxid};
+use bitcoin::p2p::message_filter::CFHeaders;
+use bitcoin::{
+    block::Header, p2p::message_network::RejectReason, BlockHash, FeeRate, MerkleBlock, ScriptBuf,
+    Txid, Work, Wtxid,
+};
 
 use crate::chain::{BlockHeaderChanges, IndexedHeader};
 use crate::{chain::checkpoints::HashCheckpoint, IndexedBlock, TrustedPeer};
-use crate::{IndexedFilter, Package};
+use crate::{
+    BlockRequestId, DisconnectReason, IndexedFilter, NodeState, Package, PeerId, PeerInfo,
+    StoredPeer, TimeSensitiveKind,
+};
 
-use super::error::FetchBlockError;
+use super::error::{BroadcastError, ClientError, FetchBlockError, RescanError, TestScriptError};
 
 /// Informational messages emitted by a node
 #[derive(Debug, Clone)]
@@ -18,10 +23,35 @@ pub enum Info {
     SuccessfulHandshake,
     /// The node is connected to all required peers.
     ConnectionsMet,
+    /// The node was connected to all required peers, but peers have since disconnected.
+    ConnectionsLost {
+        /// The number of live connections.
+        connected: usize,
+        /// The configured requirement.
+        required: usize,
+    },
     /// The progress of the node during the block filter download process.
     Progress(Progress),
     /// A requested block has been received and is being processed.
     BlockReceived(BlockHash),
+    /// Counts of the compact block filters checked and blocks downloaded since the node started,
+    /// sent after each batch of filters. Filters may match blocks that contain none of the
+    /// scripts of interest, so comparing these counts with the blocks that were relevant gives
+    /// an estimate of the false positive rate.
+    FilterStats {
+        /// The number of compact block filters received and checked against the filter headers.
+        filters_checked: u32,
+        /// The number of blocks requested by the client.
+        blocks_requested: u32,
+        /// The number of requested blocks that were received.
+        blocks_received: u32,
+    },
+    /// Compact block filters are about to be checked, starting at this height. Filters below the
+    /// configured [`ChainState`](crate::ChainState) are never requested.
+    ScanStart {
+        /// The height of the first filter to be checked.
+        height: u32,
+    },
 }
 
 impl core::fmt::Display for Info {
@@ -29,11 +59,27 @@ impl core::fmt::Display for Info {
         match self {
             Info::SuccessfulHandshake => write!(f, "Successful version handshake with a peer"),
             Info::ConnectionsMet => write!(f, "Required connections met"),
+            Info::ConnectionsLost {
+                connected,
+                required,
+            } => write!(
+                f,
+                "Connections lost, connected to {connected} of {required} required peers"
+            ),
             Info::Progress(p) => {
                 let progress_percent = p.percentage_complete();
                 write!(f, "Percent complete: {progress_percent}")
             }
             Info::BlockReceived(hash) => write!(f, "Received block {hash}"),
+            Info::FilterStats {
+                filters_checked,
+                blocks_requested,
+                blocks_received,
+            } => write!(
+                f,
+                "Filters checked: {filters_checked}, blocks requested: {blocks_requested}, blocks received: {blocks_received}"
+            ),
+            Info::ScanStart { height } => write!(f, "Scanning filters from height {height}"),
         }
     }
 }
@@ -47,6 +93,57 @@ pub enum Event {
     FiltersSynced(SyncUpdate),
     /// A compact block filter with associated height and block hash.
     IndexedFilter(IndexedFilter),
+    /// A connection to a peer completed the version handshake.
+    PeerConnected(PeerInfo),
+    /// A connection to a peer that completed the version handshake ended.
+    PeerDisconnected {
+        /// The identifier of the connection.
+        id: PeerId,
+        /// Why the connection ended.
+        reason: DisconnectReason,
+    },
+    /// The node was stopped before all compact block filters were checked. A subsequent rescan
+    /// may resume from this height.
+    RescanIncomplete {
+        /// The height of the first block with a filter that was not checked.
+        next_height: u32,
+    },
+    /// A downloaded block contains a transaction that spends the same inputs as a transaction
+    /// pending broadcast. The pending transaction can no longer be mined, and it will no longer be
+    /// announced to peers.
+    BroadcastConflicted {
+        /// The pending transaction that was replaced.
+        wtxid: Wtxid,
+        /// The transaction in the block that conflicts with the pending transaction.
+        conflicting_txid: Txid,
+        /// The height of the block containing the conflicting transaction.
+        height: u32,
+    },
+    /// A transaction pending broadcast was found in a downloaded block, and it will no longer be
+    /// announced to peers.
+    TxConfirmed {
+        /// The pending transaction that was confirmed.
+        wtxid: Wtxid,
+        /// The height of the block containing the transaction.
+        height: u32,
+    },
+    /// A competing chain of headers branched off the chain of most work. The fork did not
+    /// accumulate enough work to reorganize the chain, but may indicate a chain split.
+    ChainForked {
+        /// The height of the last block shared by both branches.
+        fork_height: u32,
+        /// The tip of the chain of most work.
+        main_tip: BlockHash,
+        /// The tip of the competing branch.
+        fork_tip: BlockHash,
+        /// The accumulated work of the chain of most work.
+        main_work: Work,
+        /// The accumulated work of the competing branch.
+        fork_work: Work,
+    },
+    /// The node has stopped running. Any blocks that were requested and not yet received have
+    /// been cancelled. The chain is reported as it was when the node stopped.
+    Shutdown(SyncUpdate),
 }
 
 /// The node has synced to a new tip of the chain.
@@ -77,9 +174,32 @@ impl SyncUpdate {
     }
 }
 
+/// A snapshot of the node's main loop, useful to detect if the node has stalled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeHealth {
+    /// The stage of the sync the node has reached.
+    pub state: NodeState,
+    /// The number of times the main loop has run. This increases with each iteration, so a
+    /// value that does not change between checks indicates the node is stuck.
+    pub iterations: u64,
+}
+
+/// The stage of the sync the node is working on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SyncStage {
+    /// Block headers are being downloaded.
+    Headers,
+    /// Compact filter headers are being downloaded.
+    FilterHeaders,
+    /// Compact block filters are being downloaded and checked.
+    Filters,
+}
+
 /// The progress of the node during the block filter download process.
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub struct Progress {
+    // The stage of the sync that triggered this update.
+    stage: SyncStage,
     // The number of filter headers that have been assumed checked and downloaded.
     filter_headers: u32,
     // The number of block filters that have been assumed checked and downloaded.
@@ -92,12 +212,14 @@ pub struct Progress {
 
 impl Progress {
     pub(crate) fn new(
+        stage: SyncStage,
         filter_headers: u32,
         filters: u32,
         total_to_check: u32,
         chain_height: u32,
     ) -> Self {
         Self {
+            stage,
             filter_headers,
             filters,
             total_to_check,
@@ -110,6 +232,25 @@ impl Progress {
         self.chain_height
     }
 
+    /// The stage of the sync the node is working on.
+    pub fn stage(&self) -> SyncStage {
+        self.stage
+    }
+
+    /// The progress of the current stage represented as a fraction. The final height of the chain
+    /// is not known while block headers are downloaded, so this is zero for [`SyncStage::Headers`].
+    pub fn stage_fraction(&self) -> f32 {
+        let processed = match self.stage {
+            SyncStage::Headers => return 0.0,
+            SyncStage::FilterHeaders => self.filter_headers,
+            SyncStage::Filters => self.filters,
+        };
+        if self.total_to_check == 0 {
+            return 1.0;
+        }
+        (processed as f32).div(self.total_to_check as f32)
+    }
+
     /// The total progress represented as a percent.
     pub fn percentage_complete(&self) -> f32 {
         self.fraction_complete() * 100.0
@@ -139,22 +280,65 @@ pub(crate) enum ClientMessage {
     Shutdown,
     /// Broadcast a [`crate::Transaction`] with a [`crate::TxBroadcastPolicy`].
     Broadcast(ClientRequest<Package, Wtxid>),
+    /// Broadcast a package, resolving with the result of the first peer to respond.
+    BroadcastWithConfirmation(ClientRequest<Package, Result<Wtxid, BroadcastError>>),
     /// Starting at the configured anchor checkpoint, re-emit all filters.
-    Rescan(Option<u32>),
+    Rescan,
+    /// Re-emit all filters after the height, if it is between the chain state and the tip.
+    RescanFrom(ClientRequest<u32, Result<(), RescanError>>),
     /// Explicitly request a block from the node.
     GetBlock(ClientRequest<BlockHash, Result<IndexedBlock, FetchBlockError>>),
+    /// Request a block that may be cancelled before it is sent to a peer.
+    FetchBlock(
+        BlockRequestId,
+        ClientRequest<BlockHash, Result<IndexedBlock, FetchBlockError>>,
+    ),
+    /// Cancel a block request that has not been sent to a peer.
+    CancelBlock(BlockRequestId),
+    /// Download a block and prove the inclusion of a transaction in it.
+    GetMerkleProof(ClientRequest<(BlockHash, Txid), Result<MerkleBlock, FetchBlockError>>),
+    /// Request the block at a height in the chain of most work.
+    GetBlockByHeight(ClientRequest<u32, Result<IndexedBlock, FetchBlockError>>),
     /// Get the chain tip.
     BestBlock(ClientRequest<(), HashCheckpoint>),
     /// Add another known peer to connect to.
     AddPeer(TrustedPeer),
+    /// Disconnect from a connected peer.
+    DisconnectPeer(PeerId),
+    /// Disconnect from every peer and connect to new peers.
+    ReconnectAll,
+    /// Stop announcing a pending transaction.
+    CancelBroadcast(Wtxid),
     /// Request the broadcast minimum fee rate.
     GetBroadcastMinFeeRate(ClientRequest<(), FeeRate>),
+    /// Request a fee rate estimate from recently downloaded blocks.
+    GetFeeEstimate(ClientRequest<(), Option<FeeRate>>),
     /// Get info on connections
-    GetPeerInfo(ClientRequest<(), Vec<(AddrV2, ServiceFlags)>>),
+    GetPeerInfo(ClientRequest<(), Vec<PeerInfo>>),
+    /// Get the best height known for each connected peer.
+    GetPeerHeights(ClientRequest<(), Vec<(PeerId, u32)>>),
+    /// Get the peers stored in the address book.
+    GetKnownPeers(ClientRequest<(), Vec<StoredPeer>>),
     /// Look up a header at a specific height in the chain of most work.
     GetHeader(ClientRequest<u32, Option<IndexedHeader>>),
     /// Look up the height of a block hash in the chain of most work.
     HeightOfHash(ClientRequest<BlockHash, Option<u32>>),
+    /// Match a script against retained filters in a range of heights.
+    TestScript(
+        ClientRequest<(ScriptBuf, RangeInclusive<u32>), Result<Vec<BlockHash>, TestScriptError>>,
+    ),
+    /// Get the block locators of the chain of most work.
+    GetBlockLocators(ClientRequest<(), Vec<BlockHash>>),
+    /// Wait until the filters are synced to the tip of the chain.
+    WaitUntilSynced(ClientRequest<(), SyncUpdate>),
+    /// Get the work accumulated by the chain of most work since the chain state.
+    GetRelativeChainWork(ClientRequest<(), Work>),
+    /// Request a range of compact filter headers from a peer.
+    GetFilterHeaders(ClientRequest<(u32, BlockHash), Result<CFHeaders, ClientError>>),
+    /// Look up the block hash and height of a transaction in a downloaded block.
+    FindTransaction(ClientRequest<Txid, Option<(BlockHash, u32)>>),
+    /// Report the state of the node and the number of loop iterations.
+    Health(ClientRequest<(), NodeHealth>),
     /// Send an empty message to see if the node is running.
     NoOp,
 }
@@ -189,8 +373,13 @@ pub enum Warning {
         /// The configured requirement.
         required: usize,
     },
-    /// A connection to a peer timed out.
-    PeerTimedOut,
+    /// A peer did not respond to a request in time, and the connection will be closed.
+    PeerTimedOut {
+        /// The identifier of the connection.
+        id: PeerId,
+        /// The kind of request that was not answered.
+        kind: TimeSensitiveKind,
+    },
     /// The node was unable to connect to a peer in the database.
     CouldNotConnect,
     /// A connection was maintained, but the peer does not signal for compact block filers.
@@ -206,6 +395,33 @@ pub enum Warning {
     },
     /// The peer sent us a potential fork.
     EvaluatingFork,
+    /// A peer sent headers that do not connect to the chain of headers and was banned.
+    InvalidHeaderConnection {
+        /// The identifier of the connection.
+        peer: PeerId,
+    },
+    /// A peer sent headers with insufficient or miscalculated proof of work and was banned.
+    InvalidPow {
+        /// The identifier of the connection.
+        peer: PeerId,
+    },
+    /// A peer sent compact filter headers that do not match the filter header chain, a
+    /// checkpoint, or the headers sent by other peers.
+    FilterHeaderMismatch {
+        /// The identifier of the connection.
+        peer: PeerId,
+    },
+    /// A peer resent compact filter headers that were already received.
+    DuplicateFilterHeader {
+        /// The identifier of the connection.
+        peer: PeerId,
+    },
+    /// A peer sent a compact filter that does not match the committed filter header and was
+    /// banned.
+    InvalidFilter {
+        /// The identifier of the connection.
+        peer: PeerId,
+    },
     /// An unexpected error occurred processing a peer-to-peer message.
     UnexpectedSyncError {
         /// Additional context as to why block syncing failed.
@@ -213,6 +429,61 @@ pub enum Warning {
     },
     /// A channel that was supposed to receive a message was dropped.
     ChannelDropped,
+    /// A peer proposed a reorganization deeper than the configured limit and was banned.
+    DeepReorg {
+        /// The number of blocks that would have been disconnected.
+        depth: u32,
+    },
+    /// A peer responded with a compact filter type other than the one requested and was
+    /// disconnected.
+    UnsupportedFilterType {
+        /// The filter type the peer sent.
+        received: u8,
+    },
+    /// A request referred to a peer that is not connected.
+    UnknownPeer(PeerId),
+    /// Block downloads are being slowed to respect the configured rate limit.
+    RateLimited,
+    /// The connected peers agree with each other, but not with the chain of headers the node has
+    /// synced, or all peers belong to the same network group. The node may be connected to peers
+    /// controlled by a single party.
+    PossibleEclipse,
+    /// A peer served a block with witness data that does not match the commitment in the
+    /// coinbase. The peer was banned.
+    InvalidWitnessCommitment {
+        /// The height of the block.
+        height: u32,
+    },
+    /// Fewer peers are connected than a transaction was configured to be announced to, so the
+    /// transaction was announced to every connected peer.
+    ReducedBroadcast {
+        /// The number of peers the transaction was configured to be announced to.
+        requested: usize,
+        /// The number of peers the transaction was announced to.
+        sent: usize,
+    },
+    /// The connected peers report a tip well ahead of the headers the node considers synced. The
+    /// node may be connected to peers that withhold blocks.
+    NodeBehind {
+        /// The height of the local chain of most work.
+        local: u32,
+        /// The median height reported by the connected peers.
+        network: u32,
+    },
+    /// A peer repeatedly responded to requests for headers with no headers, while the connected
+    /// peers report a tip well ahead of the node. The connection will be closed.
+    PeerStalling {
+        /// The identifier of the connection.
+        id: PeerId,
+    },
+    /// A peer does not have a requested block, likely because it was pruned. The block is
+    /// requested from another peer.
+    BlockUnavailable {
+        /// The hash of the block.
+        hash: BlockHash,
+        /// The identifier of the connection.
+        peer: PeerId,
+    },
 }
 
 impl core::fmt::Display for Warning {
@@ -243,11 +514,38 @@ impl core::fmt::Display for Warning {
                 write!(f, "A transaction got rejected: WTXID {}", payload.wtxid)
             }
             Warning::EvaluatingFork => write!(f, "Peer sent us a potential fork."),
+            Warning::InvalidHeaderConnection { peer } => {
+                write!(f, "{peer} sent headers that do not connect to the chain.")
+            }
+            Warning::InvalidPow { peer } => {
+                write!(f, "{peer} sent headers with invalid proof of work.")
+            }
+            Warning::FilterHeaderMismatch { peer } => {
+                write!(f, "{peer} sent filter headers that do not match the chain.")
+            }
+            Warning::DuplicateFilterHeader { peer } => {
+                write!(
+                    f,
+                    "{peer} resent filter headers that were already received."
+                )
+            }
+            Warning::InvalidFilter { peer } => {
+                write!(
+                    f,
+                    "{peer} sent a filter that does not match its filter header."
+                )
+            }
             Warning::UnexpectedSyncError { warning } => {
                 write!(f, "Error handling a P2P message: {warning}")
             }
-            Warning::PeerTimedOut => {
-                write!(f, "A connection to a peer timed out.")
+            Warning::PeerStalling { id } => {
+                write!(
+                    f,
+                    "{id} sent no headers while the network is ahead of the node."
+                )
+            }
+            Warning::PeerTimedOut { id, kind } => {
+                write!(f, "{id} did not respond to a {kind} request in time.")
             }
             Warning::UnsolicitedMessage => {
                 write!(
@@ -261,6 +559,51 @@ impl core::fmt::Display for Warning {
                     "A channel that was supposed to receive a message was dropped."
                 )
             }
+            Warning::DeepReorg { depth } => {
+                write!(f, "A peer proposed a reorganization of {depth} blocks.")
+            }
+            Warning::UnsupportedFilterType { received } => {
+                write!(
+                    f,
+                    "A peer does not serve the requested filter type. Received type: {received}"
+                )
+            }
+            Warning::UnknownPeer(id) => {
+                write!(f, "{id} is not connected.")
+            }
+            Warning::RateLimited => {
+                write!(
+                    f,
+                    "Block downloads are being slowed to respect the rate limit."
+                )
+            }
+            Warning::PossibleEclipse => {
+                write!(
+                    f,
+                    "The connected peers may be controlled by a single party."
+                )
+            }
+            Warning::InvalidWitnessCommitment { height } => {
+                write!(
+                    f,
+                    "A peer served a block at height {height} with an invalid witness commitment."
+                )
+            }
+            Warning::ReducedBroadcast { requested, sent } => {
+                write!(
+                    f,
+                    "A transaction was announced to {sent} peers, fewer than the {requested} configured."
+                )
+            }
+            Warning::NodeBehind { local, network } => {
+                write!(
+                    f,
+                    "Peers report a tip at height {network}, but the local tip is at height {local}."
+                )
+            }
+            Warning::BlockUnavailable { hash, peer } => {
+                write!(f, "{peer} does not have block {hash}.")
+            }
         }
     }
 }
@@ -1,6 +1,6 @@
 extern crate alloc;
 use bitcoin::Network;
-use std::net::IpAddr;
+use std::{fmt::Debug, future::Future, net::IpAddr, pin::Pin};
 
 const SIGNET_SEEDS: &[&str; 3] = &[
     "seed.dlsouza.lol",
@@ -39,7 +39,68 @@ const SERVICE_BITS_PREFIX: &[&str; 2] = &[CBF_SERVICE_BIT_PREFIX, CBF_V2T_SERVIC
 
 pub(crate) const DNS_RESOLVER_PORT: u16 = 53;
 
-pub(crate) async fn bootstrap_dns(network: Network) -> Vec<IpAddr> {
+/// A boxed future that resolves to a result, used to keep [`DnsResolver`] object safe.
+pub type FutureResult<'a, T, E> = Pin<Box<dyn Future<Output = Result<T, E>> + Send + 'a>>;
+
+/// Resolve the peers advertised by a DNS seeder.
+///
+/// Implement this to source peers from an alternative resolver, like DNS-over-HTTPS or a fixed
+/// map of hosts for testing. The [`DefaultResolver`] queries the system resolver.
+pub trait DnsResolver: Debug + Send + Sync {
+    /// Resolve a seed hostname to the IP addresses of potential peers.
+    fn resolve<'a>(&'a self, seed: &'a str) -> FutureResult<'a, Vec<IpAddr>, DnsError>;
+}
+
+/// Query the system resolver for the seeder hostname and the service bit filtered subdomains.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultResolver;
+
+impl DnsResolver for DefaultResolver {
+    fn resolve<'a>(&'a self, seed: &'a str) -> FutureResult<'a, Vec<IpAddr>, DnsError> {
+        Box::pin(async move {
+            let ip_addrs = lookup_hostname(seed).await;
+            if ip_addrs.is_empty() {
+                return Err(DnsError::NoRecords);
+            }
+            Ok(ip_addrs)
+        })
+    }
+}
+
+/// Errors that may occur when resolving a DNS seeder.
+#[derive(Debug)]
+pub enum DnsError {
+    /// The seeder did not return any addresses.
+    NoRecords,
+    /// The query could not be completed.
+    Io(std::io::Error),
+}
+
+impl core::fmt::Display for DnsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DnsError::NoRecords => write!(f, "the seeder did not return any addresses."),
+            DnsError::Io(err) => write!(f, "the DNS query failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DnsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DnsError::NoRecords => None,
+            DnsError::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for DnsError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+pub(crate) async fn bootstrap_dns(network: Network, resolver: &dyn DnsResolver) -> Vec<IpAddr> {
     let seeds = match network {
         Network::Bitcoin => MAINNET_SEEDS.to_vec(),
         Network::Testnet => TESTNET_SEEDS.to_vec(),
@@ -49,8 +110,12 @@ pub(crate) async fn bootstrap_dns(network: Network) -> Vec<IpAddr> {
     };
     let mut ip_addrs: Vec<IpAddr> = vec![];
     for host in seeds {
-        let hosts = lookup_hostname(host).await;
-        ip_addrs.extend(hosts);
+        match resolver.resolve(host).await {
+            Ok(hosts) => ip_addrs.extend(hosts),
+            Err(_e) => {
+                crate::debug!(format!("Resolving {host} failed: {_e}"));
+            }
+        }
     }
     ip_addrs
 }
@@ -136,6 +136,8 @@ impl From<serde::Error> for PeerError {
 pub(crate) enum Socks5Error {
     WrongVersion,
     AuthRequired,
+    AuthFailed,
+    InvalidCredentials,
     ConnectionTimeout,
     ConnectionFailed,
     Io(io::Error),
@@ -146,6 +148,10 @@ impl core::fmt::Display for Socks5Error {
         match self {
             Socks5Error::WrongVersion => write!(f, "server responded with an unsupported version."),
             Socks5Error::AuthRequired => write!(f, "server requires authentication."),
+            Socks5Error::AuthFailed => write!(f, "server rejected the username and password."),
+            Socks5Error::InvalidCredentials => {
+                write!(f, "username or password is longer than 255 bytes.")
+            }
             Socks5Error::ConnectionTimeout => write!(f, "connection to server timed out."),
             Socks5Error::ConnectionFailed => write!(
                 f,
@@ -2,21 +2,33 @@ use bip324::serde::NetworkMessage;
 use bip324::{PacketReader, PacketType};
 use bitcoin::consensus::{deserialize, deserialize_partial};
 use bitcoin::p2p::message::RawNetworkMessage;
-use bitcoin::Network;
+use bitcoin::p2p::Magic;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt};
 
 use super::error::ReaderError;
 use super::V1Header;
 
 const MAX_MESSAGE_BYTES: u32 = 1024 * 1024 * 32;
+// The BIP-324 short ID for a `block` message
+const V2_BLOCK_ID: u8 = 2;
+const V1_BLOCK_COMMAND: &str = "block";
+
+// A message read from the remote peer. The payload of a block is retained if it was requested.
+pub(crate) struct InboundMessage {
+    pub(crate) message: NetworkMessage,
+    pub(crate) raw_block: Option<Vec<u8>>,
+}
 
 pub(crate) enum MessageParser<R: AsyncBufReadExt + Send + Sync + Unpin> {
     V2(R, PacketReader),
-    V1(R, Network),
+    V1(R, Magic),
 }
 
 impl<R: AsyncBufReadExt + Send + Sync + Unpin> MessageParser<R> {
-    pub async fn read_message(&mut self) -> Result<Option<NetworkMessage>, ReaderError> {
+    pub async fn read_message(
+        &mut self,
+        keep_raw_block: bool,
+    ) -> Result<Option<InboundMessage>, ReaderError> {
         match self {
             MessageParser::V2(stream, decryptor) => {
                 let mut len_buf = [0; 3];
@@ -30,18 +42,22 @@ impl<R: AsyncBufReadExt + Send + Sync + Unpin> MessageParser<R> {
                 let msg = decryptor.decrypt_payload(&response_message, None)?;
                 match msg.packet_type() {
                     PacketType::Genuine => {
-                        let parsed = bip324::serde::deserialize(msg.contents())?;
-                        Ok(Some(parsed))
+                        let contents = msg.contents();
+                        let message = bip324::serde::deserialize(contents)?;
+                        let raw_block = (keep_raw_block
+                            && contents.first().eq(&Some(&V2_BLOCK_ID)))
+                        .then(|| contents[1..].to_vec());
+                        Ok(Some(InboundMessage { message, raw_block }))
                     }
                     PacketType::Decoy => Ok(None),
                 }
             }
-            MessageParser::V1(stream, network) => {
+            MessageParser::V1(stream, magic) => {
                 let mut message_buf = vec![0_u8; 24];
                 let _ = stream.read_exact(&mut message_buf).await?;
                 let header: V1Header = deserialize_partial(&message_buf)?.0;
                 // Nonsense for our network
-                if header.magic != network.magic() {
+                if header.magic.ne(magic) {
                     return Err(ReaderError::InvalidDeserialization);
                 }
                 // Message is too long
@@ -52,8 +68,58 @@ impl<R: AsyncBufReadExt + Send + Sync + Unpin> MessageParser<R> {
                 let _ = stream.read_exact(&mut contents_buf).await?;
                 message_buf.extend_from_slice(&contents_buf);
                 let message: RawNetworkMessage = deserialize(&message_buf)?;
-                Ok(Some(message.into_payload()))
+                let raw_block = (keep_raw_block && header.command.as_ref() == V1_BLOCK_COMMAND)
+                    .then_some(contents_buf);
+                Ok(Some(InboundMessage {
+                    message: message.into_payload(),
+                    raw_block,
+                }))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        consensus::serialize,
+        p2p::message::{NetworkMessage, RawNetworkMessage},
+        Network,
+    };
+
+    use super::MessageParser;
+
+    #[tokio::test]
+    async fn v1_parser_keeps_raw_blocks() {
+        let network = Network::Regtest;
+        let block = bitcoin::constants::genesis_block(network);
+        let message = RawNetworkMessage::new(network.magic(), NetworkMessage::Block(block.clone()));
+        let bytes = serialize(&message);
+        let mut parser = MessageParser::V1(bytes.as_slice(), network.magic());
+        let inbound = parser.read_message(true).await.unwrap().unwrap();
+        assert!(matches!(inbound.message, NetworkMessage::Block(ref b) if b.eq(&block)));
+        assert_eq!(inbound.raw_block, Some(serialize(&block)));
+        let mut parser = MessageParser::V1(bytes.as_slice(), network.magic());
+        let inbound = parser.read_message(false).await.unwrap().unwrap();
+        assert!(inbound.raw_block.is_none());
+        // Only blocks are retained
+        let ping = serialize(&RawNetworkMessage::new(
+            network.magic(),
+            NetworkMessage::Ping(42),
+        ));
+        let mut parser = MessageParser::V1(ping.as_slice(), network.magic());
+        let inbound = parser.read_message(true).await.unwrap().unwrap();
+        assert!(inbound.raw_block.is_none());
+    }
+
+    #[tokio::test]
+    async fn v1_parser_checks_magic() {
+        let custom = bitcoin::p2p::Magic::from_bytes([0xfa, 0xce, 0xb0, 0x0c]);
+        let ping = serialize(&RawNetworkMessage::new(custom, NetworkMessage::Ping(42)));
+        let mut parser = MessageParser::V1(ping.as_slice(), custom);
+        let inbound = parser.read_message(false).await.unwrap().unwrap();
+        assert!(matches!(inbound.message, NetworkMessage::Ping(42)));
+        let mut parser = MessageParser::V1(ping.as_slice(), Network::Signet.magic());
+        assert!(parser.read_message(false).await.is_err());
+    }
+}
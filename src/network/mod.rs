@@ -1,9 +1,9 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     fs::{self, File},
     net::IpAddr,
     path::PathBuf,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use addrman::{io::FileExt, Record, Table};
@@ -19,7 +19,7 @@ use bitcoin::{
         message_blockdata::GetHeadersMessage,
         message_filter::{CFHeaders, CFilter, GetCFHeaders, GetCFilters},
         message_network::VersionMessage,
-        Magic,
+        Magic, ServiceFlags,
     },
     Block, BlockHash, FeeRate, Wtxid,
 };
@@ -43,15 +43,20 @@ pub const PROTOCOL_VERSION: u32 = 70016;
 pub const KYOTO_VERSION: &str = "0.6.3";
 pub const RUST_BITCOIN_VERSION: &str = "0.32.8";
 
-const THIRTY_MINS: Duration = Duration::from_secs(60 * 30);
+// Peers disconnect when the user agent is longer than this many bytes
+pub(crate) const MAX_USER_AGENT_LENGTH: usize = 256;
+
+pub(crate) const THIRTY_MINS: Duration = Duration::from_secs(60 * 30);
 const MESSAGE_TIMEOUT_SECS: Duration = Duration::from_secs(5);
 //                                            sec  min  hour
 const TWO_HOUR: Duration = Duration::from_secs(60 * 60 * 2);
 const TCP_CONNECTION_TIMEOUT: Duration = Duration::from_secs(2);
-// Ping the peer if we have not exchanged messages for two minutes
+// By default, ping the peer if we have not exchanged messages for two minutes
 const SEND_PING: Duration = Duration::from_secs(60 * 2);
 // An absolute maximum timeout to respond to a batch filter request
 const MAX_FILTER_RESPONSE_TIME_SEC: Duration = Duration::from_secs(20);
+// Repeated announcements of the same block within this window are ignored
+const ANNOUNCEMENT_WINDOW: Duration = Duration::from_secs(10);
 
 // These are the parameters of the "tried" and "new" tables
 const B_TRIED: usize = 4;
@@ -69,8 +74,9 @@ const MAX_ATTEMPS: u8 = 2;
 // If it has been less than a week, only allow a single fail
 const MAX_WEEKLY_ATTEMPTS: u8 = 1;
 
+/// An identifier for a connection to a peer, unique for the lifetime of the node.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub(crate) struct PeerId(pub(crate) u32);
+pub struct PeerId(pub(crate) u32);
 
 impl PeerId {
     fn increment(&mut self) {
@@ -90,6 +96,118 @@ impl std::fmt::Display for PeerId {
     }
 }
 
+/// The transport protocol used to communicate with a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportVersion {
+    /// Messages are sent in plaintext.
+    V1,
+    /// Messages are encrypted as described in BIP-324.
+    V2,
+}
+
+/// The kind of request a peer did not respond to in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimeSensitiveKind {
+    /// The version handshake.
+    Handshake,
+    /// A request for block headers.
+    Headers,
+    /// A request for compact filter headers.
+    FilterHeaders,
+    /// A request for compact block filters, or filters that arrived too slowly.
+    Filters,
+    /// A request for a block.
+    Block,
+    /// A ping.
+    Ping,
+}
+
+impl core::fmt::Display for TimeSensitiveKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Handshake => write!(f, "version handshake"),
+            Self::Headers => write!(f, "headers"),
+            Self::FilterHeaders => write!(f, "filter headers"),
+            Self::Filters => write!(f, "filters"),
+            Self::Block => write!(f, "block"),
+            Self::Ping => write!(f, "ping"),
+        }
+    }
+}
+
+/// Why the connection to a peer ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    /// The peer was banned for sending invalid data.
+    Banned,
+    /// The peer sent a message that violates the peer-to-peer protocol.
+    ProtocolViolation,
+    /// The peer did not respond in time.
+    Timeout,
+    /// The connection was closed by either side, or was rotated for a new peer.
+    Closed,
+}
+
+/// A connection to a peer that has completed the version handshake.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    /// The identifier of the connection.
+    pub id: PeerId,
+    /// The network address of the peer.
+    pub address: AddrV2,
+    /// The port of the peer.
+    pub port: u16,
+    /// The services the peer advertised during the version handshake.
+    pub services: ServiceFlags,
+    /// The height of the peer's chain when the connection was opened.
+    pub start_height: u32,
+    /// The transport protocol used with the peer.
+    pub transport: TransportVersion,
+    /// If the node is syncing from this peer, which is the last peer to deliver headers, filter
+    /// headers or filters that advanced the sync.
+    pub is_sync_peer: bool,
+}
+
+/// A peer the node learned about and stored in its address book.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredPeer {
+    /// The network address of the peer.
+    pub address: AddrV2,
+    /// The port of the peer.
+    pub port: u16,
+    /// The services the peer was advertised with, or advertised during the last connection.
+    pub services: ServiceFlags,
+    /// The last time the peer was known to be online, if any.
+    pub last_seen: Option<SystemTime>,
+    /// If the peer was banned for misbehavior. Banned peers are never selected for connections.
+    pub banned: bool,
+}
+
+// The median of the heights reported by peers, taking the higher of the two middle heights
+pub(crate) fn median_height(heights: impl Iterator<Item = u32>) -> Option<u32> {
+    let mut heights: Vec<u32> = heights.collect();
+    heights.sort_unstable();
+    heights.get(heights.len() / 2).copied()
+}
+
+// How the node describes itself in the version handshake
+#[derive(Debug, Clone)]
+pub(crate) struct LocalVersion {
+    pub(crate) user_agent: String,
+    pub(crate) services: ServiceFlags,
+}
+
+impl Default for LocalVersion {
+    fn default() -> Self {
+        Self {
+            user_agent: format!(
+                "/Rust BIP-157:{KYOTO_VERSION}/rust-bitcoin:{RUST_BITCOIN_VERSION}/"
+            ),
+            services: ServiceFlags::NONE,
+        }
+    }
+}
+
 // Configuration for peer connection timeouts
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub(crate) struct PeerTimeoutConfig {
@@ -99,6 +217,8 @@ pub(crate) struct PeerTimeoutConfig {
     pub(crate) max_connection_time: Duration,
     // How much time does the peer have to make the initial TCP handshake
     pub(crate) handshake_timeout: Duration,
+    // How long a connection may be idle before the peer is sent a ping
+    pub(crate) ping_interval: Duration,
 }
 
 impl Default for PeerTimeoutConfig {
@@ -107,17 +227,22 @@ impl Default for PeerTimeoutConfig {
             response_timeout: MESSAGE_TIMEOUT_SECS,
             max_connection_time: TWO_HOUR,
             handshake_timeout: TCP_CONNECTION_TIMEOUT,
+            ping_interval: SEND_PING,
         }
     }
 }
 
 pub(crate) struct LastBlockMonitor {
     last_block: Option<Instant>,
+    timeout: Duration,
 }
 
 impl LastBlockMonitor {
-    pub(crate) fn new() -> Self {
-        Self { last_block: None }
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            last_block: None,
+            timeout,
+        }
     }
 
     pub(crate) fn reset(&mut self) {
@@ -126,23 +251,62 @@ impl LastBlockMonitor {
 
     pub(crate) fn stale(&self) -> bool {
         if let Some(time) = self.last_block {
-            return time.elapsed() > THIRTY_MINS;
+            return time.elapsed() > self.timeout;
         }
         false
     }
 }
 
-#[derive(Debug, Clone, Default)]
+// Many peers announce the same block at nearly the same time. Only the first announcement should
+// result in a header request.
+#[derive(Debug, Default)]
+pub(crate) struct AnnouncementCache {
+    seen: HashMap<BlockHash, Instant>,
+}
+
+impl AnnouncementCache {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    // Record an announced block, returning if it has not been announced recently.
+    pub(crate) fn first_announcement(&mut self, block_hash: BlockHash) -> bool {
+        self.seen
+            .retain(|_, then| then.elapsed() < ANNOUNCEMENT_WINDOW);
+        match self.seen.entry(block_hash) {
+            std::collections::hash_map::Entry::Occupied(_) => false,
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(Instant::now());
+                true
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub(crate) enum ConnectionType {
-    #[default]
-    ClearNet,
+    ClearNet { cjdns_reachable: bool },
     Socks5Proxy(Socks5Proxy),
 }
 
+impl Default for ConnectionType {
+    fn default() -> Self {
+        Self::ClearNet {
+            cjdns_reachable: false,
+        }
+    }
+}
+
 impl ConnectionType {
     pub(crate) fn can_connect(&self, addr: &AddrV2) -> bool {
         match &self {
-            Self::ClearNet => matches!(addr, AddrV2::Ipv4(_) | AddrV2::Ipv6(_)),
+            Self::ClearNet { cjdns_reachable } => match addr {
+                AddrV2::Ipv4(_) | AddrV2::Ipv6(_) => true,
+                // CJDNS addresses are routed like IPv6, but only reach peers through a local cjdns
+                // interface
+                AddrV2::Cjdns(_) => *cjdns_reachable,
+                _ => false,
+            },
             Self::Socks5Proxy(_) => {
                 matches!(addr, AddrV2::Ipv4(_) | AddrV2::Ipv6(_) | AddrV2::TorV3(_))
             }
@@ -156,10 +320,10 @@ impl ConnectionType {
         handshake_timeout: Duration,
     ) -> Result<TcpStream, PeerError> {
         match &self {
-            Self::ClearNet => {
+            Self::ClearNet { .. } => {
                 let socket_addr = match addr {
                     AddrV2::Ipv4(ip) => IpAddr::V4(ip),
-                    AddrV2::Ipv6(ip) => IpAddr::V6(ip),
+                    AddrV2::Ipv6(ip) | AddrV2::Cjdns(ip) => IpAddr::V6(ip),
                     _ => return Err(PeerError::UnreachableSocketAddr),
                 };
                 let timeout = tokio::time::timeout(
@@ -179,7 +343,7 @@ impl ConnectionType {
                     _ => return Err(PeerError::UnreachableSocketAddr),
                 };
                 let socks5_timeout =
-                    tokio::time::timeout(handshake_timeout, create_socks5(proxy.0, addr, port))
+                    tokio::time::timeout(handshake_timeout, create_socks5(proxy, addr, port))
                         .await
                         .map_err(|_| PeerError::ConnectionFailed)?;
                 let tcp_stream = socks5_timeout.map_err(PeerError::Socks5)?;
@@ -196,18 +360,20 @@ impl ConnectionType {
 #[derive(Debug, Clone)]
 struct MessageState {
     general_timeout: Duration,
+    ping_interval: Duration,
     version_handshake: VersionHandshakeState,
     verack: VerackState,
     sent_txs: HashSet<Wtxid>,
-    timed_message_state: HashMap<TimeSensitiveId, Instant>,
+    timed_message_state: HashMap<TimeSensitiveId, VecDeque<Instant>>,
     ping_state: PingState,
     filter_rate: FilterRate,
 }
 
 impl MessageState {
-    fn new(general_timeout: Duration) -> Self {
+    fn new(general_timeout: Duration, ping_interval: Duration) -> Self {
         Self {
             general_timeout,
+            ping_interval,
             version_handshake: Default::default(),
             verack: Default::default(),
             sent_txs: Default::default(),
@@ -217,6 +383,11 @@ impl MessageState {
         }
     }
 
+    // Ping the peer if the connection has been idle for the interval
+    fn send_ping(&mut self) -> Option<u64> {
+        self.ping_state.send_ping(self.ping_interval)
+    }
+
     fn start_version_handshake(&mut self) {
         self.version_handshake = self.version_handshake.start();
     }
@@ -233,11 +404,41 @@ impl MessageState {
         !self.sent_txs.remove(&wtxid)
     }
 
+    // Multiple requests of the same kind may be in flight, each with their own deadline.
+    fn request_sent(&mut self, id: TimeSensitiveId, time: Instant) {
+        self.timed_message_state
+            .entry(id)
+            .or_default()
+            .push_back(time);
+    }
+
+    fn response_received(&mut self, id: &TimeSensitiveId) {
+        if let Some(deadlines) = self.timed_message_state.get_mut(id) {
+            deadlines.pop_front();
+            if deadlines.is_empty() {
+                self.timed_message_state.remove(id);
+            }
+        }
+    }
+
+    #[cfg(test)]
     fn unresponsive(&self) -> bool {
+        self.overdue().is_some()
+    }
+
+    // The kind of the first request found that was not answered in time
+    fn overdue(&self) -> Option<TimeSensitiveKind> {
+        if self.version_handshake.is_unresponsive(self.general_timeout) {
+            return Some(TimeSensitiveKind::Handshake);
+        }
         self.timed_message_state
-            .values()
-            .any(|time| time.elapsed() > self.general_timeout)
-            || self.version_handshake.is_unresponsive(self.general_timeout)
+            .iter()
+            .find(|(_, deadlines)| {
+                deadlines
+                    .front()
+                    .is_some_and(|time| time.elapsed() > self.general_timeout)
+            })
+            .map(|(id, _)| id.kind())
     }
 }
 
@@ -301,11 +502,11 @@ enum PingState {
 }
 
 impl PingState {
-    fn send_ping(&mut self) -> Option<u64> {
+    fn send_ping(&mut self, interval: Duration) -> Option<u64> {
         match self {
             Self::WaitingFor { nonce: _ } => None,
             Self::LastMessageReceied { then } => {
-                if then.elapsed() > SEND_PING {
+                if then.elapsed() > interval {
                     let nonce = rand::random();
                     *self = Self::WaitingFor { nonce };
                     Some(nonce)
@@ -354,30 +555,22 @@ impl Default for PingState {
 
 #[derive(Debug, Clone, Default)]
 struct FilterRate {
-    waiting_for: Option<(BlockHash, Instant)>,
+    waiting_for: HashMap<BlockHash, Instant>,
 }
 
 impl FilterRate {
     fn batch_requested(&mut self, stop_hash: BlockHash) {
-        self.waiting_for = Some((stop_hash, Instant::now()))
+        self.waiting_for.insert(stop_hash, Instant::now());
     }
 
     fn filter_received(&mut self, block_hash: BlockHash) {
-        if let Some((hash, _)) = self.waiting_for {
-            if hash.eq(&block_hash) {
-                self.waiting_for = None;
-            }
-        }
+        self.waiting_for.remove(&block_hash);
     }
 
     fn slow_peer(&self) -> bool {
-        if let Some((_, then)) = self.waiting_for {
-            let elapsed = then.elapsed();
-            if elapsed > MAX_FILTER_RESPONSE_TIME_SEC {
-                return true;
-            }
-        }
-        false
+        self.waiting_for
+            .values()
+            .any(|then| then.elapsed() > MAX_FILTER_RESPONSE_TIME_SEC)
     }
 }
 
@@ -396,6 +589,17 @@ impl TimeSensitiveId {
     fn from_slice(slice: [u8; 32]) -> Self {
         Self(slice)
     }
+
+    // Any identifier that is not a known message is the hash of a block
+    fn kind(&self) -> TimeSensitiveKind {
+        match *self {
+            Self::HEADER_MSG => TimeSensitiveKind::Headers,
+            Self::CF_HEADER_MSG => TimeSensitiveKind::FilterHeaders,
+            Self::C_FILTER_MSG => TimeSensitiveKind::Filters,
+            Self::PING => TimeSensitiveKind::Ping,
+            _ => TimeSensitiveKind::Block,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -408,7 +612,7 @@ pub(crate) enum MainThreadMessage {
     GetHeaders(GetHeadersMessage),
     GetFilterHeaders(GetCFHeaders),
     GetFilters(GetCFilters),
-    GetBlock(BlockHash),
+    GetBlocks(Vec<BlockHash>),
     Disconnect,
     BroadcastPending,
     Verack,
@@ -417,20 +621,24 @@ pub(crate) enum MainThreadMessage {
 impl MainThreadMessage {
     pub(in crate::network) fn time_sensitive_message_start(
         &self,
-    ) -> Option<(TimeSensitiveId, Instant)> {
+    ) -> Vec<(TimeSensitiveId, Instant)> {
         match self {
-            MainThreadMessage::GetHeaders(_) => Some((TimeSensitiveId::HEADER_MSG, Instant::now())),
+            MainThreadMessage::GetHeaders(_) => vec![(TimeSensitiveId::HEADER_MSG, Instant::now())],
             MainThreadMessage::GetFilterHeaders(_) => {
-                Some((TimeSensitiveId::CF_HEADER_MSG, Instant::now()))
+                vec![(TimeSensitiveId::CF_HEADER_MSG, Instant::now())]
             }
             MainThreadMessage::GetFilters(_) => {
-                Some((TimeSensitiveId::C_FILTER_MSG, Instant::now()))
+                vec![(TimeSensitiveId::C_FILTER_MSG, Instant::now())]
             }
-            MainThreadMessage::GetBlock(hash) => {
-                let id = hash.to_raw_hash().to_byte_array();
-                Some((TimeSensitiveId::from_slice(id), Instant::now()))
-            }
-            _ => None,
+            // Each block in the batch is expected to arrive as a separate message
+            MainThreadMessage::GetBlocks(hashes) => hashes
+                .iter()
+                .map(|hash| {
+                    let id = hash.to_raw_hash().to_byte_array();
+                    (TimeSensitiveId::from_slice(id), Instant::now())
+                })
+                .collect(),
+            _ => Vec::new(),
         }
     }
 }
@@ -443,19 +651,20 @@ pub(crate) struct PeerThreadMessage {
 
 #[derive(Debug)]
 pub(crate) enum PeerMessage {
-    Version(VersionMessage),
+    Version(VersionMessage, TransportVersion),
     Headers(Vec<Header>),
     FilterHeaders(CFHeaders),
     Filter(CFilter),
-    Block(Block),
+    Block(Block, Option<Vec<u8>>),
     NewBlocks(Vec<BlockHash>),
+    BlocksNotFound(Vec<BlockHash>),
     FeeFilter(FeeRate),
 }
 
 #[derive(Debug)]
 struct V1Header {
     magic: Magic,
-    _command: CommandString,
+    command: CommandString,
     length: u32,
     _checksum: u32,
 }
@@ -465,12 +674,12 @@ impl Decodable for V1Header {
         reader: &mut R,
     ) -> Result<Self, bitcoin::consensus::encode::Error> {
         let magic = Magic::consensus_decode(reader)?;
-        let _command = CommandString::consensus_decode(reader)?;
+        let command = CommandString::consensus_decode(reader)?;
         let length = u32::consensus_decode(reader)?;
         let _checksum = u32::consensus_decode(reader)?;
         Ok(Self {
             magic,
-            _command,
+            command,
             length,
             _checksum,
         })
@@ -481,6 +690,8 @@ impl Decodable for V1Header {
 pub(crate) struct AddressBook {
     new: Table<B_NEW, S_NEW, W_NEW>,
     tried: Table<B_TRIED, S_TRIED, W_TRIED>,
+    // The tables may not be iterated, so the stored peers are mirrored for inspection
+    stored: HashMap<(AddrV2, u16), StoredPeer>,
 }
 
 impl AddressBook {
@@ -488,23 +699,53 @@ impl AddressBook {
         Self {
             new: Table::new(),
             tried: Table::new(),
+            stored: HashMap::new(),
+        }
+    }
+
+    fn store(&mut self, record: &Record, last_seen: Option<SystemTime>) {
+        let (address, port) = record.network_addr();
+        let stored = self
+            .stored
+            .entry((address.clone(), port))
+            .or_insert(StoredPeer {
+                address,
+                port,
+                services: record.service_flags(),
+                last_seen: None,
+                banned: false,
+            });
+        stored.services = record.service_flags();
+        if last_seen > stored.last_seen {
+            stored.last_seen = last_seen;
         }
     }
 
+    fn forget(&mut self, record: &Record) {
+        self.stored.remove(&record.network_addr());
+    }
+
     pub(crate) fn add_gossiped(
         &mut self,
         gossip: impl Iterator<Item = AddrV2Message>,
         source: &AddrV2,
     ) {
         for addr in gossip {
+            let last_seen =
+                (addr.time > 0).then(|| UNIX_EPOCH + Duration::from_secs(u64::from(addr.time)));
             let record =
                 Record::new_from_addrv2_source(addr.addr, addr.port, addr.services, source);
             if self.new.count(&record) < MAX_ADDR {
-                if let Some(conflict) = self.new.add(&record) {
-                    if conflict.is_terrible(MAX_ATTEMPS, MAX_WEEKLY_ATTEMPTS) {
-                        self.new.remove(&conflict);
-                        self.new.add(&record);
+                match self.new.add(&record) {
+                    Some(conflict) => {
+                        if conflict.is_terrible(MAX_ATTEMPS, MAX_WEEKLY_ATTEMPTS) {
+                            self.new.remove(&conflict);
+                            self.forget(&conflict);
+                            self.new.add(&record);
+                            self.store(&record, last_seen);
+                        }
                     }
+                    None => self.store(&record, last_seen),
                 }
             }
         }
@@ -533,14 +774,24 @@ impl AddressBook {
         self.new.remove(record);
         if let Some(conflict) = self.tried.add(record) {
             self.tried.remove(&conflict);
+            self.forget(&conflict);
             self.tried.add(record);
         }
         self.tried.successful_connection(record);
+        self.store(record, Some(SystemTime::now()));
     }
 
     pub(crate) fn ban(&mut self, record: &Record) {
         self.new.remove(record);
         self.tried.remove(record);
+        self.store(record, None);
+        if let Some(stored) = self.stored.get_mut(&record.network_addr()) {
+            stored.banned = true;
+        }
+    }
+
+    pub(crate) fn stored_peers(&self) -> Vec<StoredPeer> {
+        self.stored.values().cloned().collect()
     }
 
     #[allow(unused)]
@@ -565,22 +816,31 @@ mod tests {
     use std::time::Duration;
 
     use bitcoin::{consensus::deserialize, hashes::Hash, BlockHash, Transaction};
+    use tokio::time::Instant;
+
+    use bitcoin::p2p::{
+        address::{AddrV2, AddrV2Message},
+        ServiceFlags,
+    };
 
-    use crate::network::{LastBlockMonitor, MessageState, PingState};
+    use crate::network::{
+        median_height, AddressBook, AnnouncementCache, ConnectionType, LastBlockMonitor,
+        MessageState, PingState, TimeSensitiveId, TimeSensitiveKind, THIRTY_MINS,
+    };
 
-    use super::FilterRate;
+    use super::{FilterRate, SEND_PING};
 
     #[tokio::test(start_paused = true)]
     async fn test_version_message_state() {
         let timeout = Duration::from_secs(1);
-        let mut message_state = MessageState::new(timeout);
+        let mut message_state = MessageState::new(timeout, SEND_PING);
         assert!(!message_state.unresponsive());
         tokio::time::sleep(Duration::from_secs(2)).await;
         assert!(!message_state.unresponsive());
         message_state.start_version_handshake();
         tokio::time::sleep(Duration::from_secs(2)).await;
         assert!(message_state.unresponsive());
-        let mut message_state = MessageState::new(timeout);
+        let mut message_state = MessageState::new(timeout, SEND_PING);
         message_state.start_version_handshake();
         message_state.finish_version_handshake();
         tokio::time::sleep(Duration::from_secs(2)).await;
@@ -588,10 +848,32 @@ mod tests {
         assert!(message_state.version_handshake.is_complete());
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_concurrent_deadlines() {
+        let timeout = Duration::from_secs(5);
+        let mut message_state = MessageState::new(timeout, SEND_PING);
+        message_state.request_sent(TimeSensitiveId::C_FILTER_MSG, Instant::now());
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        message_state.request_sent(TimeSensitiveId::C_FILTER_MSG, Instant::now());
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        // The first request is overdue
+        assert!(message_state.unresponsive());
+        message_state.response_received(&TimeSensitiveId::C_FILTER_MSG);
+        assert!(!message_state.unresponsive());
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(message_state.unresponsive());
+        message_state.response_received(&TimeSensitiveId::C_FILTER_MSG);
+        assert!(!message_state.unresponsive());
+        assert!(message_state.timed_message_state.is_empty());
+        message_state.request_sent(TimeSensitiveId::PING, Instant::now());
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        assert_eq!(message_state.overdue(), Some(TimeSensitiveKind::Ping));
+    }
+
     #[test]
     fn test_verack_state() {
         let timeout = Duration::from_secs(1);
-        let mut messsage_state = MessageState::new(timeout);
+        let mut messsage_state = MessageState::new(timeout, SEND_PING);
         messsage_state.version_handshake.start();
         messsage_state.verack.got_ack();
         assert!(!messsage_state.verack.both_acks());
@@ -603,7 +885,7 @@ mod tests {
     fn test_tx_reject_state() {
         let transaction: Transaction = deserialize(&hex::decode("0200000000010158e87a21b56daf0c23be8e7070456c336f7cbaa5c8757924f545887bb2abdd7501000000171600145f275f436b09a8cc9a2eb2a2f528485c68a56323feffffff02d8231f1b0100000017a914aed962d6654f9a2b36608eb9d64d2b260db4f1118700c2eb0b0000000017a914b7f5faf40e3d40a5a459b1db3535f2b72fa921e88702483045022100a22edcc6e5bc511af4cc4ae0de0fcd75c7e04d8c1c3a8aa9d820ed4b967384ec02200642963597b9b1bc22c75e9f3e117284a962188bf5e8a74c895089046a20ad770121035509a48eb623e10aace8bfd0212fdb8a8e5af3c94b0b133b95e114cab89e4f7965000000").unwrap()).unwrap();
         let wtxid = transaction.compute_wtxid();
-        let mut message_state = MessageState::new(Duration::from_secs(2));
+        let mut message_state = MessageState::new(Duration::from_secs(2), SEND_PING);
         message_state.sent_tx(wtxid);
         assert!(!message_state.unknown_rejection(wtxid));
         assert!(message_state.unknown_rejection(wtxid));
@@ -613,42 +895,47 @@ mod tests {
     async fn test_ping_state() {
         // Detect we need a ping
         let mut ping_state = PingState::default();
-        assert!(ping_state.send_ping().is_none());
+        assert!(ping_state.send_ping(SEND_PING).is_none());
         tokio::time::sleep(Duration::from_secs(60)).await;
-        assert!(ping_state.send_ping().is_none());
+        assert!(ping_state.send_ping(SEND_PING).is_none());
         tokio::time::sleep(Duration::from_secs(70)).await;
-        assert!(ping_state.send_ping().is_some());
+        assert!(ping_state.send_ping(SEND_PING).is_some());
         // Do not spam
-        assert!(ping_state.send_ping().is_none());
+        assert!(ping_state.send_ping(SEND_PING).is_none());
         // We match pings and update the state correctly
         let mut ping_state = PingState::default();
         tokio::time::sleep(Duration::from_secs(60 * 3)).await;
-        let ping = ping_state.send_ping().unwrap();
+        let ping = ping_state.send_ping(SEND_PING).unwrap();
         tokio::time::sleep(Duration::from_secs(60 * 3)).await;
         assert!(ping_state.check_pong(ping));
         assert!(!ping_state.check_pong(ping));
-        assert!(ping_state.send_ping().is_none());
+        assert!(ping_state.send_ping(SEND_PING).is_none());
         tokio::time::sleep(Duration::from_secs(60 * 3)).await;
-        assert!(ping_state.send_ping().is_some());
+        assert!(ping_state.send_ping(SEND_PING).is_some());
         // Receiving a message without a `Pong` does not update the state
         let mut ping_state = PingState::default();
         tokio::time::sleep(Duration::from_secs(60 * 3)).await;
-        let ping = ping_state.send_ping().unwrap();
+        let ping = ping_state.send_ping(SEND_PING).unwrap();
         ping_state.update_last_message();
         assert!(ping_state.check_pong(ping));
         // Time updates properly
         let mut ping_state = PingState::default();
-        assert!(ping_state.send_ping().is_none());
+        assert!(ping_state.send_ping(SEND_PING).is_none());
         tokio::time::sleep(Duration::from_secs(60)).await;
-        assert!(ping_state.send_ping().is_none());
+        assert!(ping_state.send_ping(SEND_PING).is_none());
         ping_state.update_last_message();
         tokio::time::sleep(Duration::from_secs(70)).await;
-        assert!(ping_state.send_ping().is_none());
+        assert!(ping_state.send_ping(SEND_PING).is_none());
+        // Shorter intervals are respected
+        let mut ping_state = PingState::default();
+        tokio::time::sleep(Duration::from_secs(15)).await;
+        assert!(ping_state.send_ping(Duration::from_secs(20)).is_none());
+        assert!(ping_state.send_ping(Duration::from_secs(10)).is_some());
     }
 
     #[tokio::test(start_paused = true)]
     async fn test_block_detected_stale() {
-        let mut last_block = LastBlockMonitor::new();
+        let mut last_block = LastBlockMonitor::new(THIRTY_MINS);
         tokio::time::sleep(Duration::from_secs(60 * 40)).await;
         // No blocks received yet.
         assert!(!last_block.stale());
@@ -663,6 +950,21 @@ mod tests {
         assert!(!last_block.stale());
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_duplicate_announcements_ignored() {
+        let mut announcements = AnnouncementCache::new();
+        let block_hash = BlockHash::from_byte_array([1; 32]);
+        let other_hash = BlockHash::from_byte_array([2; 32]);
+        assert!(announcements.first_announcement(block_hash));
+        assert!(!announcements.first_announcement(block_hash));
+        assert!(announcements.first_announcement(other_hash));
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(!announcements.first_announcement(block_hash));
+        // The window is measured from the first announcement
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        assert!(announcements.first_announcement(block_hash));
+    }
+
     #[tokio::test(start_paused = true)]
     async fn test_filter_rate_stale() {
         let mut filter_rate = FilterRate::default();
@@ -675,4 +977,96 @@ mod tests {
         tokio::time::sleep(Duration::from_secs(21)).await;
         assert!(filter_rate.slow_peer());
     }
+
+    #[tokio::test]
+    async fn test_ipv6_peer_dialable() {
+        // Not every host has an IPv6 loopback interface
+        let Ok(listener) = tokio::net::TcpListener::bind("[::1]:0").await else {
+            return;
+        };
+        let port = listener.local_addr().unwrap().port();
+        let addr = AddrV2::Ipv6(std::net::Ipv6Addr::LOCALHOST);
+        let mut address_book = AddressBook::new();
+        let gossip = AddrV2Message {
+            time: 0,
+            services: ServiceFlags::COMPACT_FILTERS | ServiceFlags::NETWORK,
+            addr: addr.clone(),
+            port,
+        };
+        let source = AddrV2::Ipv6("2001:db8::1".parse().unwrap());
+        address_book.add_gossiped([gossip].into_iter(), &source);
+        let record = address_book.select().unwrap();
+        let (selected, selected_port) = record.network_addr();
+        assert_eq!(selected, addr);
+        assert_eq!(selected_port, port);
+        let connector = ConnectionType::default();
+        assert!(connector.can_connect(&selected));
+        assert!(connector
+            .connect(selected, selected_port, Duration::from_secs(1))
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn test_cjdns_reachable() {
+        let cjdns = AddrV2::Cjdns("fc00::1".parse().unwrap());
+        assert!(!ConnectionType::default().can_connect(&cjdns));
+        let connector = ConnectionType::ClearNet {
+            cjdns_reachable: true,
+        };
+        assert!(connector.can_connect(&cjdns));
+    }
+
+    #[test]
+    fn test_median_height() {
+        assert_eq!(median_height(std::iter::empty()), None);
+        assert_eq!(median_height([100].into_iter()), Some(100));
+        assert_eq!(median_height([102, 100, 101].into_iter()), Some(101));
+        assert_eq!(median_height([100, 200].into_iter()), Some(200));
+        assert_eq!(median_height([5, 900, 100, 101, 99].into_iter()), Some(100));
+    }
+
+    #[test]
+    fn test_stored_peers() {
+        let mut address_book = AddressBook::new();
+        let source = AddrV2::Ipv4("1.1.1.1".parse().unwrap());
+        let gossip = [
+            AddrV2Message {
+                time: 1_700_000_000,
+                services: ServiceFlags::COMPACT_FILTERS | ServiceFlags::NETWORK,
+                addr: AddrV2::Ipv4("2.2.2.2".parse().unwrap()),
+                port: 8333,
+            },
+            AddrV2Message {
+                time: 0,
+                services: ServiceFlags::NETWORK,
+                addr: AddrV2::Ipv4("3.3.3.3".parse().unwrap()),
+                port: 8333,
+            },
+        ];
+        address_book.add_gossiped(gossip.into_iter(), &source);
+        let mut stored = address_book.stored_peers();
+        stored.sort_by_key(|peer| peer.last_seen);
+        assert_eq!(stored.len(), 2);
+        assert!(stored[0].last_seen.is_none());
+        assert_eq!(
+            stored[1].last_seen,
+            Some(std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        assert!(stored.iter().all(|peer| !peer.banned));
+        // A banned peer is kept for inspection, but can no longer be selected
+        let record = address_book.select().unwrap();
+        let banned = record.network_addr();
+        address_book.tried(&record);
+        address_book.ban(&record);
+        let stored = address_book.stored_peers();
+        assert_eq!(stored.len(), 2);
+        let peer = stored
+            .iter()
+            .find(|peer| (peer.address.clone(), peer.port) == banned)
+            .unwrap();
+        assert!(peer.banned);
+        assert!(peer.last_seen.is_some());
+        assert_ne!(address_book.select().unwrap().network_addr(), banned);
+    }
 }
@@ -10,18 +10,19 @@ use bitcoin::{
         message::{NetworkMessage, RawNetworkMessage},
         message_blockdata::Inventory,
         message_network::VersionMessage,
-        Address, ServiceFlags,
+        Address, Magic, ServiceFlags,
     },
     BlockHash, Network, Transaction, Wtxid,
 };
 
 use crate::{default_port_from_network, BlockType};
 
-use super::{KYOTO_VERSION, PROTOCOL_VERSION, RUST_BITCOIN_VERSION};
+use super::{LocalVersion, TransportVersion, PROTOCOL_VERSION};
 
 // Responsible for serializing messages to write over the wire, either encrypted or plaintext.
 pub(in crate::network) struct MessageGenerator {
     pub network: Network,
+    pub magic: Magic,
     pub transport: Transport,
     pub block_type: BlockType,
 }
@@ -32,10 +33,17 @@ pub(in crate::network) enum Transport {
 }
 
 impl MessageGenerator {
+    pub(in crate::network) fn transport_version(&self) -> TransportVersion {
+        match self.transport {
+            Transport::V1 => TransportVersion::V1,
+            Transport::V2 { .. } => TransportVersion::V2,
+        }
+    }
+
     pub(in crate::network) fn serialize(&mut self, msg: NetworkMessage) -> Vec<u8> {
         match &mut self.transport {
             Transport::V1 => {
-                let data = RawNetworkMessage::new(self.network.magic(), msg);
+                let data = RawNetworkMessage::new(self.magic, msg);
                 serialize(&data)
             }
             Transport::V2 { encryptor } => {
@@ -45,17 +53,24 @@ impl MessageGenerator {
         }
     }
 
-    pub(in crate::network) fn version_message(&mut self, port: Option<u16>) -> Vec<u8> {
-        let msg = NetworkMessage::Version(make_version(port, &self.network));
+    pub(in crate::network) fn version_message(
+        &mut self,
+        port: Option<u16>,
+        local_version: &LocalVersion,
+    ) -> Vec<u8> {
+        let msg = NetworkMessage::Version(make_version(port, &self.network, local_version));
         self.serialize(msg)
     }
 
-    pub(in crate::network) fn block(&mut self, hash: BlockHash) -> Vec<u8> {
-        let inv = match self.block_type {
-            BlockType::Legacy => Inventory::Block(hash),
-            BlockType::Witness => Inventory::WitnessBlock(hash),
-        };
-        let msg = NetworkMessage::GetData(vec![inv]);
+    pub(in crate::network) fn blocks(&mut self, hashes: Vec<BlockHash>) -> Vec<u8> {
+        let inv = hashes
+            .into_iter()
+            .map(|hash| match self.block_type {
+                BlockType::Legacy => Inventory::Block(hash),
+                BlockType::Witness => Inventory::WitnessBlock(hash),
+            })
+            .collect();
+        let msg = NetworkMessage::GetData(inv);
         self.serialize(msg)
     }
 
@@ -71,6 +86,11 @@ impl MessageGenerator {
         let msg = NetworkMessage::Tx(transaction);
         self.serialize(msg)
     }
+
+    pub(in crate::network) fn not_found(&mut self, inventory: Vec<Inventory>) -> Vec<u8> {
+        let msg = NetworkMessage::NotFound(inventory);
+        self.serialize(msg)
+    }
 }
 
 fn serialize_network_message(message: NetworkMessage) -> Vec<u8> {
@@ -83,7 +103,11 @@ fn encrypt_plaintext(encryptor: &mut PacketWriter, plaintext: Vec<u8>) -> Vec<u8
         .expect("encryption to in memory buffer cannot fail.")
 }
 
-pub(in crate::network) fn make_version(port: Option<u16>, network: &Network) -> VersionMessage {
+pub(in crate::network) fn make_version(
+    port: Option<u16>,
+    network: &Network,
+    local_version: &LocalVersion,
+) -> VersionMessage {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("time went backwards")
@@ -96,12 +120,12 @@ pub(in crate::network) fn make_version(port: Option<u16>, network: &Network) ->
     let from_and_recv = Address::new(&ip, ServiceFlags::NONE);
     VersionMessage {
         version: PROTOCOL_VERSION,
-        services: ServiceFlags::NONE,
+        services: local_version.services,
         timestamp: now as i64,
         receiver: from_and_recv.clone(),
         sender: from_and_recv,
         nonce: 1,
-        user_agent: format!("/Rust BIP-157:{KYOTO_VERSION}/rust-bitcoin:{RUST_BITCOIN_VERSION}/"),
+        user_agent: local_version.user_agent.clone(),
         start_height: 0,
         relay: false,
     }
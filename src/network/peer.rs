@@ -4,7 +4,8 @@ use std::{sync::Arc, time::Duration};
 use addrman::Record;
 use bip324::{AsyncProtocol, PacketReader, PacketWriter, Role};
 use bitcoin::{
-    p2p::{message::NetworkMessage, message_blockdata::Inventory, ServiceFlags},
+    hashes::Hash,
+    p2p::{message::NetworkMessage, Magic, ServiceFlags},
     Network,
 };
 use tokio::{
@@ -25,8 +26,8 @@ use super::{
     inbound::MessageParser,
     outbound::{MessageGenerator, Transport},
     reader::{Reader, ReaderMessage},
-    AddressBook, MainThreadMessage, MessageState, PeerId, PeerMessage, PeerThreadMessage,
-    PeerTimeoutConfig, TimeSensitiveId,
+    AddressBook, DisconnectReason, LocalVersion, MainThreadMessage, MessageState, PeerId,
+    PeerMessage, PeerThreadMessage, PeerTimeoutConfig, TimeSensitiveId, TimeSensitiveKind,
 };
 
 const LOOP_TIMEOUT: Duration = Duration::from_millis(500);
@@ -39,11 +40,15 @@ pub(crate) struct Peer {
     main_thread_recv: Receiver<MainThreadMessage>,
     network: Network,
     block_type: BlockType,
+    raw_blocks: bool,
     dialog: Arc<Dialog>,
     db: Arc<Mutex<AddressBook>>,
     timeout_config: PeerTimeoutConfig,
     message_state: MessageState,
     tx_queue: Arc<Mutex<BroadcastQueue>>,
+    require_v2_transport: bool,
+    local_version: LocalVersion,
+    magic: Magic,
 }
 
 impl Peer {
@@ -53,12 +58,16 @@ impl Peer {
         source: Record,
         network: Network,
         block_type: BlockType,
+        raw_blocks: bool,
         main_thread_sender: Sender<PeerThreadMessage>,
         main_thread_recv: Receiver<MainThreadMessage>,
         dialog: Arc<Dialog>,
         db: Arc<Mutex<AddressBook>>,
         timeout_config: PeerTimeoutConfig,
         tx_queue: Arc<Mutex<BroadcastQueue>>,
+        require_v2_transport: bool,
+        local_version: LocalVersion,
+        magic: Magic,
     ) -> Self {
         Self {
             nonce,
@@ -67,11 +76,18 @@ impl Peer {
             main_thread_recv,
             network,
             block_type,
+            raw_blocks,
             dialog,
             db,
             timeout_config,
-            message_state: MessageState::new(timeout_config.response_timeout),
+            message_state: MessageState::new(
+                timeout_config.response_timeout,
+                timeout_config.ping_interval,
+            ),
             tx_queue,
+            require_v2_transport,
+            local_version,
+            magic,
         }
     }
 
@@ -79,42 +95,52 @@ impl Peer {
         &mut self,
         connection: TcpStream,
         is_proxy_connection: bool,
-    ) -> Result<(), PeerError> {
+    ) -> Result<DisconnectReason, PeerError> {
         let start_time = Instant::now();
         let (tx, mut rx) = mpsc::channel(32);
         let (reader, mut writer) = connection.into_split();
         let mut reader = BufReader::new(reader);
-        // If a peer signals for V2 we will use it, otherwise just use plaintext.
-        let (mut outbound_messages, mut peer_reader) =
-            if self.source.service_flags().has(ServiceFlags::P2P_V2) && !is_proxy_connection {
-                let handshake_result = tokio::time::timeout(
-                    V2_HANDSHAKE_TIMEOUT,
-                    self.try_handshake(&mut writer, &mut reader),
-                )
-                .await
-                .map_err(|_| PeerError::HandshakeFailed)?;
-                if handshake_result.is_err() {
-                    self.dialog.send_warning(Warning::CouldNotConnect);
-                }
-                let (decryptor, encryptor) = handshake_result?;
-                let outbound_messages = MessageGenerator {
-                    network: self.network,
-                    transport: Transport::V2 { encryptor },
-                    block_type: self.block_type,
-                };
-                let reader = Reader::new(MessageParser::V2(reader, decryptor), tx);
-                (outbound_messages, reader)
-            } else {
-                let outbound_messages = MessageGenerator {
-                    network: self.network,
-                    transport: Transport::V1,
-                    block_type: self.block_type,
-                };
-                let reader = Reader::new(MessageParser::V1(reader, self.network), tx);
-                (outbound_messages, reader)
+        // If a peer signals for V2 we will use it, otherwise just use plaintext. When encryption is
+        // required, the handshake is attempted regardless and the connection fails without it.
+        let attempt_v2 = self.require_v2_transport
+            || (self.source.service_flags().has(ServiceFlags::P2P_V2) && !is_proxy_connection);
+        // The V2 handshake derives its keys from the magic of a standard network
+        let custom_magic = self.magic.ne(&self.network.magic());
+        if attempt_v2 && custom_magic && self.require_v2_transport {
+            return Err(PeerError::HandshakeFailed);
+        }
+        let attempt_v2 = attempt_v2 && !custom_magic;
+        let (mut outbound_messages, mut peer_reader) = if attempt_v2 {
+            let handshake_result = tokio::time::timeout(
+                V2_HANDSHAKE_TIMEOUT,
+                self.try_handshake(&mut writer, &mut reader),
+            )
+            .await
+            .map_err(|_| PeerError::HandshakeFailed)?;
+            if handshake_result.is_err() {
+                self.dialog.send_warning(Warning::CouldNotConnect);
+            }
+            let (decryptor, encryptor) = handshake_result?;
+            let outbound_messages = MessageGenerator {
+                network: self.network,
+                magic: self.magic,
+                transport: Transport::V2 { encryptor },
+                block_type: self.block_type,
             };
+            let reader = Reader::new(MessageParser::V2(reader, decryptor), tx, self.raw_blocks);
+            (outbound_messages, reader)
+        } else {
+            let outbound_messages = MessageGenerator {
+                network: self.network,
+                magic: self.magic,
+                transport: Transport::V1,
+                block_type: self.block_type,
+            };
+            let reader = Reader::new(MessageParser::V1(reader, self.magic), tx, self.raw_blocks);
+            (outbound_messages, reader)
+        };
 
-        let message = outbound_messages.version_message(None);
+        let message = outbound_messages.version_message(None, &self.local_version);
         self.write_bytes(&mut writer, message).await?;
         self.message_state.start_version_handshake();
         let read_handle = tokio::spawn(async move { peer_reader.read_from_remote().await });
@@ -122,30 +148,34 @@ impl Peer {
         interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
         loop {
             if read_handle.is_finished() {
-                return Ok(());
+                return Ok(DisconnectReason::Closed);
             }
-            if let Some(nonce) = self.message_state.ping_state.send_ping() {
+            if let Some(nonce) = self.message_state.send_ping() {
                 let msg = outbound_messages.serialize(NetworkMessage::Ping(nonce));
                 self.write_bytes(&mut writer, msg).await?;
-                let msg_id = TimeSensitiveId::PING;
                 self.message_state
-                    .timed_message_state
-                    .insert(msg_id, Instant::now());
+                    .request_sent(TimeSensitiveId::PING, Instant::now());
             }
-            if self.message_state.unresponsive() {
-                self.dialog.send_warning(Warning::PeerTimedOut);
-                return Ok(());
+            if let Some(kind) = self.message_state.overdue() {
+                self.dialog.send_warning(Warning::PeerTimedOut {
+                    id: self.nonce,
+                    kind,
+                });
+                return Ok(DisconnectReason::Timeout);
             }
             if self.message_state.filter_rate.slow_peer() {
-                self.dialog.send_warning(Warning::PeerTimedOut);
-                return Ok(());
+                self.dialog.send_warning(Warning::PeerTimedOut {
+                    id: self.nonce,
+                    kind: TimeSensitiveKind::Filters,
+                });
+                return Ok(DisconnectReason::Timeout);
             }
             if Instant::now().duration_since(start_time) > self.timeout_config.max_connection_time {
                 crate::debug!(format!(
                     "The connection to peer {} has been maintained for over {} seconds, finding a new peer",
                     self.nonce, self.timeout_config.max_connection_time.as_secs(),
                 ));
-                return Ok(());
+                return Ok(DisconnectReason::Closed);
             }
             select! {
                 // The peer sent us a message
@@ -157,7 +187,7 @@ impl Peer {
                                 Err(e) => {
                                     match e {
                                         // We were told by the reader thread to disconnect from this peer
-                                        PeerError::DisconnectCommand => return Ok(()),
+                                        PeerError::DisconnectCommand => return Ok(DisconnectReason::ProtocolViolation),
                                         _ => continue,
                                     }
                                 },
@@ -175,7 +205,7 @@ impl Peer {
                                 Err(e) => {
                                     match e {
                                         // We were told by the main thread to disconnect from this peer
-                                        PeerError::DisconnectCommand => return Ok(()),
+                                        PeerError::DisconnectCommand => return Ok(DisconnectReason::Closed),
                                         _ => continue,
                                     }
                                 },
@@ -200,7 +230,7 @@ impl Peer {
     {
         self.message_state.ping_state.update_last_message();
         if let Some(msg_id) = message.time_sensitive_message_received() {
-            self.message_state.timed_message_state.remove(&msg_id);
+            self.message_state.response_received(&msg_id);
         }
         match message {
             ReaderMessage::Version(version) => {
@@ -210,7 +240,10 @@ impl Peer {
                 self.main_thread_sender
                     .send(PeerThreadMessage {
                         nonce: self.nonce,
-                        message: PeerMessage::Version(version),
+                        message: PeerMessage::Version(
+                            version,
+                            message_generator.transport_version(),
+                        ),
                     })
                     .await?;
                 Ok(())
@@ -250,11 +283,11 @@ impl Peer {
                     .await?;
                 Ok(())
             }
-            ReaderMessage::Block(block) => {
+            ReaderMessage::Block(block, raw_block) => {
                 self.main_thread_sender
                     .send(PeerThreadMessage {
                         nonce: self.nonce,
-                        message: PeerMessage::Block(block),
+                        message: PeerMessage::Block(block, raw_block),
                     })
                     .await?;
                 Ok(())
@@ -268,41 +301,31 @@ impl Peer {
                     .await?;
                 Ok(())
             }
+            ReaderMessage::NotFound(block_hashes) => {
+                // The peer will not send these blocks, so stop waiting on them
+                for hash in &block_hashes {
+                    let msg_id = TimeSensitiveId::from_slice(hash.to_raw_hash().to_byte_array());
+                    self.message_state.response_received(&msg_id);
+                }
+                self.main_thread_sender
+                    .send(PeerThreadMessage {
+                        nonce: self.nonce,
+                        message: PeerMessage::BlocksNotFound(block_hashes),
+                    })
+                    .await?;
+                Ok(())
+            }
             ReaderMessage::GetData(requests) => {
-                let mut tx_queue = self.tx_queue.lock().await;
-                for inv in requests {
-                    match inv {
-                        Inventory::WTx(wtxid) => {
-                            let transaction = tx_queue.fetch_tx(wtxid);
-                            if let Some(transaction) = transaction {
-                                let msg = message_generator.broadcast_transaction(transaction);
-                                self.write_bytes(writer, msg).await?;
-                                self.message_state.sent_tx(wtxid);
-                                tx_queue.sent_transaction_payload(wtxid);
-                            }
-                        }
-                        Inventory::Transaction(txid) => {
-                            let transaction = tx_queue.fetch_tx(txid);
-                            if let Some(transaction) = transaction {
-                                let wtxid = transaction.compute_wtxid();
-                                let msg = message_generator.broadcast_transaction(transaction);
-                                self.write_bytes(writer, msg).await?;
-                                self.message_state.sent_tx(wtxid);
-                                tx_queue.sent_transaction_payload(wtxid);
-                            }
-                        }
-                        Inventory::WitnessTransaction(txid) => {
-                            let transaction = tx_queue.fetch_tx(txid);
-                            if let Some(transaction) = transaction {
-                                let wtxid = transaction.compute_wtxid();
-                                let msg = message_generator.broadcast_transaction(transaction);
-                                self.write_bytes(writer, msg).await?;
-                                self.message_state.sent_tx(wtxid);
-                                tx_queue.sent_transaction_payload(wtxid);
-                            }
-                        }
-                        _ => (),
-                    }
+                let (transactions, not_found) = self.tx_queue.lock().await.answer_getdata(requests);
+                for transaction in transactions {
+                    let wtxid = transaction.compute_wtxid();
+                    let msg = message_generator.broadcast_transaction(transaction);
+                    self.write_bytes(writer, msg).await?;
+                    self.message_state.sent_tx(wtxid);
+                }
+                if !not_found.is_empty() {
+                    let msg = message_generator.not_found(not_found);
+                    self.write_bytes(writer, msg).await?;
                 }
                 Ok(())
             }
@@ -347,6 +370,7 @@ impl Peer {
                     self.dialog.send_warning(Warning::UnsolicitedMessage);
                     return Err(PeerError::DisconnectCommand);
                 }
+                self.tx_queue.lock().await.rejected(payload);
                 self.dialog
                     .send_warning(Warning::TransactionRejected { payload });
                 Ok(())
@@ -364,9 +388,8 @@ impl Peer {
     where
         W: AsyncWrite + Send + Unpin,
     {
-        let time_sensitive = request.time_sensitive_message_start();
-        if let Some((msg_id, time)) = time_sensitive {
-            self.message_state.timed_message_state.insert(msg_id, time);
+        for (msg_id, time) in request.time_sensitive_message_start() {
+            self.message_state.request_sent(msg_id, time);
         }
         match request {
             MainThreadMessage::GetAddr => {
@@ -400,8 +423,8 @@ impl Peer {
                 let message = message_generator.serialize(NetworkMessage::GetCFilters(config));
                 self.write_bytes(writer, message).await?;
             }
-            MainThreadMessage::GetBlock(message) => {
-                let message = message_generator.block(message);
+            MainThreadMessage::GetBlocks(hashes) => {
+                let message = message_generator.blocks(hashes);
                 self.write_bytes(writer, message).await?;
             }
             MainThreadMessage::BroadcastPending => {
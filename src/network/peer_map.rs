@@ -1,14 +1,15 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Debug,
     net::{IpAddr, Ipv4Addr},
     sync::Arc,
+    time::Duration,
 };
 
 use addrman::Record;
 use bitcoin::{
     key::rand,
-    p2p::{address::AddrV2, ServiceFlags},
+    p2p::{address::AddrV2, Magic, ServiceFlags},
     FeeRate, Network,
 };
 use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
@@ -18,29 +19,73 @@ use tokio::{
         Mutex,
     },
     task::JoinHandle,
+    time::Instant,
 };
 
 use crate::{
     broadcaster::BroadcastQueue,
     default_port_from_network,
-    network::{dns::bootstrap_dns, error::PeerError, peer::Peer, PeerId, PeerTimeoutConfig},
-    BlockType, Dialog, TrustedPeer, TrustedPeerInner,
+    network::{
+        dns::{bootstrap_dns, DnsResolver},
+        error::PeerError,
+        peer::Peer,
+        PeerId, PeerTimeoutConfig,
+    },
+    BlockType, Dialog, Event, TrustedPeer, TrustedPeerInner, TxBroadcastPolicy,
 };
 
-use super::{AddressBook, ConnectionType, MainThreadMessage, PeerThreadMessage};
+use super::{
+    median_height, AddressBook, ConnectionType, DisconnectReason, LocalVersion, MainThreadMessage,
+    PeerInfo, PeerThreadMessage, StoredPeer, TransportVersion,
+};
 
 const LOCAL_HOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+// Peers reporting a height within this many blocks of our tip are considered in agreement
+const HEIGHT_TOLERANCE: u32 = 6;
+// The number of connections required to reason about an eclipse
+const MIN_ECLIPSE_PEERS: usize = 2;
+// The number of addresses compared by score when selecting a peer to connect to
+const SCORE_CANDIDATES: usize = 4;
+// The number of addresses the node remembers a score for
+const MAX_SCORED_ADDRESSES: usize = 1_000;
+// The wait before dialing an address again after a failed connection, doubled with each failure
+const BASE_DIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_DIAL_BACKOFF: Duration = Duration::from_secs(60 * 5);
 
 // Preferred peers to connect to based on the user configuration
 type Whitelist = Vec<TrustedPeer>;
 
+// An address that recently failed to connect
+#[derive(Debug, Clone, Copy)]
+struct DialBackoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+impl DialBackoff {
+    fn failed(failures: u32) -> Self {
+        let delay = BASE_DIAL_BACKOFF
+            .checked_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .unwrap_or(MAX_DIAL_BACKOFF)
+            .min(MAX_DIAL_BACKOFF);
+        Self {
+            failures,
+            retry_at: Instant::now() + delay,
+        }
+    }
+}
+
 // A peer that is or was connected to the node
 #[derive(Debug)]
 pub(crate) struct ManagedPeer {
     record: Record,
     broadcast_min: FeeRate,
+    height: Option<u32>,
+    announced_height: Option<u32>,
+    transport: Option<TransportVersion>,
+    banned: bool,
     ptx: Sender<MainThreadMessage>,
-    handle: JoinHandle<Result<(), PeerError>>,
+    handle: JoinHandle<Result<DisconnectReason, PeerError>>,
 }
 
 // The `PeerMap` manages connections with peers, adds and bans peers, and manages the peer database
@@ -51,13 +96,23 @@ pub(crate) struct PeerMap {
     current_id: PeerId,
     network: Network,
     block_type: BlockType,
+    raw_blocks: bool,
     mtx: Sender<PeerThreadMessage>,
     map: HashMap<PeerId, ManagedPeer>,
     db: Arc<Mutex<AddressBook>>,
     connector: ConnectionType,
     whitelist: Whitelist,
+    static_peers: Option<Whitelist>,
+    banned: HashSet<AddrV2>,
+    scores: HashMap<AddrV2, i32>,
+    min_score: i32,
+    backoff: HashMap<AddrV2, DialBackoff>,
     dialog: Arc<Dialog>,
     timeout_config: PeerTimeoutConfig,
+    dns_resolver: Arc<dyn DnsResolver>,
+    require_v2_transport: bool,
+    local_version: LocalVersion,
+    magic: Magic,
 }
 
 impl PeerMap {
@@ -66,31 +121,76 @@ impl PeerMap {
         mtx: Sender<PeerThreadMessage>,
         network: Network,
         block_type: BlockType,
+        raw_blocks: bool,
         whitelist: Whitelist,
         whitelist_only: bool,
+        peers_only: bool,
+        tx_broadcast_policy: TxBroadcastPolicy,
+        min_score: i32,
         dialog: Arc<Dialog>,
         connection_type: ConnectionType,
         timeout_config: PeerTimeoutConfig,
+        dns_resolver: Arc<dyn DnsResolver>,
+        require_v2_transport: bool,
+        local_version: LocalVersion,
+        magic: Magic,
     ) -> Self {
+        let mut tx_queue = BroadcastQueue::new();
+        tx_queue.set_policy(tx_broadcast_policy);
         Self {
-            tx_queue: Arc::new(Mutex::new(BroadcastQueue::new())),
+            tx_queue: Arc::new(Mutex::new(tx_queue)),
             whitelist_only,
             current_id: PeerId(0),
             network,
             block_type,
+            raw_blocks,
             mtx,
             map: HashMap::new(),
             db: Arc::new(Mutex::new(AddressBook::new())),
             connector: connection_type,
+            static_peers: peers_only.then(|| whitelist.clone()),
             whitelist,
+            banned: HashSet::new(),
+            scores: HashMap::new(),
+            min_score,
+            backoff: HashMap::new(),
             dialog,
             timeout_config,
+            dns_resolver,
+            require_v2_transport,
+            local_version,
+            magic,
         }
     }
 
-    // Remove any finished connections
-    pub async fn clean(&mut self) {
-        self.map.retain(|_, peer| !peer.handle.is_finished());
+    // Remove peers that are no longer connected, returning the reason for any peers that completed
+    // the version handshake
+    pub async fn clean(&mut self) -> Vec<(PeerId, DisconnectReason)> {
+        let finished: Vec<PeerId> = self
+            .map
+            .iter()
+            .filter(|(_, peer)| peer.handle.is_finished())
+            .map(|(id, _)| *id)
+            .collect();
+        let mut disconnected = Vec::new();
+        for id in finished {
+            let Some(peer) = self.map.remove(&id) else {
+                continue;
+            };
+            if peer.transport.is_none() {
+                continue;
+            }
+            let reason = match peer.handle.await {
+                _ if peer.banned => DisconnectReason::Banned,
+                Ok(Ok(reason)) => reason,
+                Ok(Err(_)) | Err(_) => DisconnectReason::Closed,
+            };
+            if reason == DisconnectReason::Timeout {
+                self.adjust_score(peer.record.network_addr().0, -1);
+            }
+            disconnected.push((id, reason));
+        }
+        disconnected
     }
 
     // The number of peers with live connections
@@ -113,6 +213,8 @@ impl PeerMap {
         if !self.connector.can_connect(&addr) {
             let mut db_lock = self.db.lock().await;
             db_lock.failed(&loaded_peer);
+            drop(db_lock);
+            self.dial_failed(addr);
             return Err(PeerError::UnreachableSocketAddr);
         }
         crate::debug!(format!("Connecting to {:?}:{}", addr, port));
@@ -122,25 +224,32 @@ impl PeerMap {
             loaded_peer.clone(),
             self.network,
             self.block_type,
+            self.raw_blocks,
             self.mtx.clone(),
             prx,
             Arc::clone(&self.dialog),
             Arc::clone(&self.db),
             self.timeout_config,
             Arc::clone(&self.tx_queue),
+            self.require_v2_transport,
+            self.local_version.clone(),
+            self.magic,
         );
         let connection = self
             .connector
-            .connect(addr, port, self.timeout_config.handshake_timeout)
+            .connect(addr.clone(), port, self.timeout_config.handshake_timeout)
             .await;
         let connection = match connection {
             Ok(conn) => conn,
             Err(e) => {
                 let mut db_lock = self.db.lock().await;
                 db_lock.failed(&loaded_peer);
+                drop(db_lock);
+                self.dial_failed(addr);
                 return Err(e);
             }
         };
+        self.backoff.remove(&addr);
         let is_proxy = self.connector.is_proxy();
         let handle = tokio::spawn(async move { peer.run(connection, is_proxy).await });
         self.map.insert(
@@ -148,6 +257,10 @@ impl PeerMap {
             ManagedPeer {
                 record: loaded_peer,
                 broadcast_min: FeeRate::BROADCAST_MIN,
+                height: None,
+                announced_height: None,
+                transport: None,
+                banned: false,
                 ptx,
                 handle,
             },
@@ -169,6 +282,73 @@ impl PeerMap {
         }
     }
 
+    // Set the height the peer reported when connecting
+    pub fn set_height(&mut self, nonce: PeerId, height: i32) {
+        if let Some(peer) = self.map.get_mut(&nonce) {
+            peer.height = u32::try_from(height).ok();
+        }
+    }
+
+    // Record the height of a block the peer announced after connecting
+    pub fn set_announced_height(&mut self, nonce: PeerId, height: u32) {
+        if let Some(peer) = self.map.get_mut(&nonce) {
+            peer.announced_height = peer.announced_height.max(Some(height));
+        }
+    }
+
+    // The best height known for each live peer, either reported when connecting or announced since
+    pub fn peer_heights(&self) -> Vec<(PeerId, u32)> {
+        self.map
+            .iter()
+            .filter(|(_, peer)| !peer.handle.is_finished())
+            .filter_map(|(id, peer)| Some((*id, peer.height.max(peer.announced_height)?)))
+            .collect()
+    }
+
+    // Check if the peers agree on a tip that is far ahead of our own, returning that tip
+    pub fn network_ahead_of(&self, local_height: u32) -> Option<u32> {
+        let network = median_height(self.peer_heights().into_iter().map(|(_, height)| height))?;
+        (network > local_height.saturating_add(HEIGHT_TOLERANCE)).then_some(network)
+    }
+
+    // Set the transport negotiated with the peer, completing the version handshake
+    pub fn set_transport(&mut self, nonce: PeerId, transport: TransportVersion) {
+        if let Some(peer) = self.map.get_mut(&nonce) {
+            peer.transport = Some(transport);
+        }
+    }
+
+    // Check if the connected peers look like they are controlled by a single party. Either every
+    // peer reports a height far from our own, or all peers are in the same network group.
+    pub fn possible_eclipse(&self, local_height: u32) -> bool {
+        let peers: Vec<&ManagedPeer> = self
+            .map
+            .values()
+            .filter(|peer| !peer.handle.is_finished() && peer.height.is_some())
+            .collect();
+        if peers.len() < MIN_ECLIPSE_PEERS {
+            return false;
+        }
+        // The height reported when connecting goes stale, so prefer any tip announced since
+        let heights = peers
+            .iter()
+            .filter_map(|peer| peer.height.max(peer.announced_height));
+        if heights_disagree(heights, local_height) {
+            return true;
+        }
+        // Users that configure their own peers have chosen them deliberately
+        if self.whitelist_only {
+            return false;
+        }
+        let mut groups = peers
+            .iter()
+            .map(|peer| netgroup(&peer.record.network_addr().0));
+        match groups.next().flatten() {
+            Some(first) => groups.all(|group| group.eq(&Some(first))),
+            None => false,
+        }
+    }
+
     // The minimum fee rate to successfully broadcast a transaction to all peers
     pub fn broadcast_min(&self) -> FeeRate {
         self.map
@@ -178,11 +358,55 @@ impl PeerMap {
             .unwrap_or(FeeRate::BROADCAST_MIN)
     }
 
-    pub fn peer_info(&self) -> Vec<(AddrV2, ServiceFlags)> {
-        self.map
-            .values()
-            .map(|peer| (peer.record.network_addr().0, peer.record.service_flags()))
-            .collect()
+    pub async fn stored_peers(&self) -> Vec<StoredPeer> {
+        let db = self.db.lock().await;
+        db.stored_peers()
+    }
+
+    pub fn peer_info(&self) -> Vec<PeerInfo> {
+        self.map.keys().filter_map(|id| self.info(*id)).collect()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn local_version(&self) -> &LocalVersion {
+        &self.local_version
+    }
+
+    // Track a connection to a peer that stays open for the duration of a test
+    #[cfg(test)]
+    pub(crate) fn insert_live_peer(&mut self, id: u32, ip: std::net::Ipv4Addr, height: u32) {
+        let (ptx, _) = mpsc::channel(1);
+        let source = std::net::Ipv4Addr::LOCALHOST.into();
+        self.map.insert(
+            PeerId(id),
+            ManagedPeer {
+                record: Record::new(AddrV2::Ipv4(ip), 18444, ServiceFlags::NONE, &source),
+                broadcast_min: bitcoin::FeeRate::BROADCAST_MIN,
+                height: Some(height),
+                announced_height: None,
+                transport: None,
+                banned: false,
+                ptx,
+                handle: tokio::spawn(std::future::pending()),
+            },
+        );
+    }
+
+    // Details of a peer that has completed the version handshake
+    pub fn info(&self, nonce: PeerId) -> Option<PeerInfo> {
+        let peer = self.map.get(&nonce)?;
+        let transport = peer.transport?;
+        let (address, port) = peer.record.network_addr();
+        Some(PeerInfo {
+            id: nonce,
+            address,
+            port,
+            services: peer.record.service_flags(),
+            start_height: peer.height.unwrap_or_default(),
+            transport,
+            // Only the node knows which peer it is syncing from
+            is_sync_peer: false,
+        })
     }
 
     // Send a message to the specified peer
@@ -192,6 +416,23 @@ impl PeerMap {
         }
     }
 
+    // Disconnect from a single peer, returning if the peer was connected
+    pub async fn disconnect(&mut self, nonce: PeerId) -> bool {
+        match self.map.remove(&nonce) {
+            Some(peer) => {
+                let _ = peer.ptx.send(MainThreadMessage::Disconnect).await;
+                if peer.transport.is_some() {
+                    self.dialog.send_event(Event::PeerDisconnected {
+                        id: nonce,
+                        reason: DisconnectReason::Closed,
+                    });
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
     // Broadcast to all connected peers, returning if at least one peer received the message.
     pub async fn broadcast(&self, message: MainThreadMessage) -> bool {
         let active = self.map.values().filter(|peer| !peer.handle.is_finished());
@@ -203,20 +444,78 @@ impl PeerMap {
         sends.into_iter().any(|res| res)
     }
 
+    // Close every connection, and forget recent dial failures so new connections are made
+    // immediately, for instance after the local network changed.
+    pub async fn reconnect_all(&mut self) {
+        self.backoff.clear();
+        self.broadcast(MainThreadMessage::Disconnect).await;
+    }
+
+    // A random peer that is still connected, if any
+    pub fn random_peer(&self) -> Option<PeerId> {
+        let mut rng = StdRng::from_entropy();
+        self.map
+            .iter()
+            .filter(|(_, peer)| !peer.handle.is_finished())
+            .map(|(id, _)| *id)
+            .choose(&mut rng)
+    }
+
     // Send to a random peer, returning true if the message was sent.
     pub async fn send_random(&self, message: MainThreadMessage) -> bool {
+        self.send_random_peer(message).await.is_some()
+    }
+
+    // Send to a random peer, returning the peer the message was sent to.
+    pub async fn send_random_peer(&self, message: MainThreadMessage) -> Option<PeerId> {
         let mut rng = StdRng::from_entropy();
-        if let Some((_, peer)) = self.map.iter().choose(&mut rng) {
+        let (id, peer) = self.map.iter().choose(&mut rng)?;
+        peer.ptx.send(message).await.ok().map(|_| *id)
+    }
+
+    // Send to a random peer other than the one provided, returning true if the message was sent.
+    pub async fn send_random_excluding(&self, nonce: PeerId, message: MainThreadMessage) -> bool {
+        let mut rng = StdRng::from_entropy();
+        if let Some((_, peer)) = self
+            .map
+            .iter()
+            .filter(|(id, _)| **id != nonce)
+            .choose(&mut rng)
+        {
             let res = peer.ptx.send(message).await;
             return res.is_ok();
         }
         false
     }
 
+    // Send to up to `count` distinct random peers, returning the number of peers sent to.
+    pub async fn send_random_peers(&self, message: MainThreadMessage, count: usize) -> usize {
+        let mut rng = StdRng::from_entropy();
+        let active = self
+            .map
+            .values()
+            .filter(|peer| !peer.handle.is_finished())
+            .choose_multiple(&mut rng, count);
+        let mut sent = 0;
+        for peer in active {
+            if peer.ptx.send(message.clone()).await.is_ok() {
+                sent += 1;
+            }
+        }
+        sent
+    }
+
     // Pull a peer from the configuration if we have one. If not, select a random peer from the database,
     // as long as it is not from the same netgroup. If there are no peers in the database, try DNS.
-    // When `whitelist_only` is set, only whitelist peers are used.
+    // When `whitelist_only` is set, only whitelist peers are used. When pinned to a static set of
+    // peers, the set is cycled through, skipping any that were banned or are still connected.
     pub async fn next_peer(&mut self) -> Option<Record> {
+        if self.whitelist.is_empty() {
+            if let Some(static_peers) = &self.static_peers {
+                crate::debug!("Cycling through the configured peers");
+                self.whitelist.clone_from(static_peers);
+            }
+        }
         while let Some(peer) = self.whitelist.pop() {
             let port = peer
                 .port
@@ -262,6 +561,9 @@ impl PeerMap {
                     }
                 }
             };
+            if self.banned.contains(&addr) || self.is_connected(&addr, port) {
+                continue;
+            }
             crate::debug!("Using a configured peer");
             return Some(Record::new(addr, port, peer.known_services, &LOCAL_HOST));
         }
@@ -271,7 +573,7 @@ impl PeerMap {
         let mut db_lock = self.db.lock().await;
         if db_lock.is_empty() {
             crate::debug!("Bootstrapping peers with DNS");
-            let new_peers = bootstrap_dns(self.network)
+            let new_peers = bootstrap_dns(self.network, self.dns_resolver.as_ref())
                 .await
                 .into_iter()
                 .map(|ip| match ip {
@@ -291,7 +593,42 @@ impl PeerMap {
             let source = AddrV2::Ipv4(Ipv4Addr::new(1, 1, 1, 1));
             db_lock.add_gossiped(addr_iter, &source);
         }
-        db_lock.select()
+        // Prefer the address with the best history of a few candidates
+        let candidates = (0..SCORE_CANDIDATES).filter_map(|_| db_lock.select());
+        prefer_scored(candidates, &self.scores, self.min_score)
+    }
+
+    fn is_connected(&self, addr: &AddrV2, port: u16) -> bool {
+        self.map.values().any(|peer| {
+            !peer.handle.is_finished() && peer.record.network_addr().eq(&(addr.clone(), port))
+        })
+    }
+
+    // Wait longer before dialing an address each time it fails to connect
+    fn dial_failed(&mut self, addr: AddrV2) {
+        let failures = self
+            .backoff
+            .get(&addr)
+            .map_or(1, |backoff| backoff.failures.saturating_add(1));
+        self.backoff.insert(addr, DialBackoff::failed(failures));
+    }
+
+    // The address failed to connect recently and should not be dialed yet
+    pub fn backing_off(&self, record: &Record) -> bool {
+        self.backoff
+            .get(&record.network_addr().0)
+            .is_some_and(|backoff| Instant::now() < backoff.retry_at)
+    }
+
+    // This peer contributed to the sync.
+    pub fn reward(&mut self, nonce: PeerId) {
+        if let Some(addr) = self
+            .map
+            .get(&nonce)
+            .map(|peer| peer.record.network_addr().0)
+        {
+            self.adjust_score(addr, 1);
+        }
     }
 
     // We tried this peer and successfully connected.
@@ -304,9 +641,320 @@ impl PeerMap {
 
     // This peer misbehaved in some way.
     pub async fn ban(&mut self, nonce: PeerId) {
-        if let Some(peer) = self.map.get(&nonce) {
+        if let Some(peer) = self.map.get_mut(&nonce) {
+            peer.banned = true;
+            let addr = peer.record.network_addr().0;
+            self.banned.insert(addr.clone());
             let mut db = self.db.lock().await;
             db.ban(&peer.record);
+            drop(db);
+            self.adjust_score(addr, -1);
+        }
+    }
+
+    // Addresses with a neutral score are forgotten, and once too many addresses are scored, the
+    // score nearest to neutral is dropped, as it says the least about the peer
+    fn adjust_score(&mut self, addr: AddrV2, change: i32) {
+        let score = self.scores.entry(addr.clone()).or_default();
+        *score = score.saturating_add(change);
+        if *score == 0 {
+            self.scores.remove(&addr);
+        }
+        if self.scores.len() > MAX_SCORED_ADDRESSES {
+            let least_known = self
+                .scores
+                .iter()
+                .filter(|(scored, _)| scored.ne(&&addr))
+                .min_by_key(|(_, score)| score.unsigned_abs())
+                .map(|(scored, _)| scored.clone());
+            if let Some(least_known) = least_known {
+                self.scores.remove(&least_known);
+            }
+        }
+    }
+}
+
+// The highest scoring candidate, excluding those below the minimum score unless no other
+// candidate is available. Addresses without a history have a score of zero.
+fn prefer_scored(
+    candidates: impl Iterator<Item = Record>,
+    scores: &HashMap<AddrV2, i32>,
+    min_score: i32,
+) -> Option<Record> {
+    let score = |record: &Record| {
+        scores
+            .get(&record.network_addr().0)
+            .copied()
+            .unwrap_or_default()
+    };
+    let candidates: Vec<Record> = candidates.collect();
+    let best = candidates
+        .iter()
+        .filter(|record| score(record) >= min_score)
+        .max_by_key(|record| score(record))
+        .cloned();
+    best.or_else(|| candidates.into_iter().next())
+}
+
+// All peers report a height that is either well ahead or well behind our own.
+fn heights_disagree(mut heights: impl Iterator<Item = u32> + Clone, local_height: u32) -> bool {
+    let all_ahead = heights
+        .clone()
+        .all(|height| height > local_height.saturating_add(HEIGHT_TOLERANCE));
+    let all_behind = heights.all(|height| height.saturating_add(HEIGHT_TOLERANCE) < local_height);
+    all_ahead || all_behind
+}
+
+// A coarse grouping of routable addresses, following the /16 and /32 groups used by Bitcoin Core.
+fn netgroup(addr: &AddrV2) -> Option<[u8; 5]> {
+    match addr {
+        AddrV2::Ipv4(ip) if !ip.is_loopback() && !ip.is_private() => {
+            let octets = ip.octets();
+            Some([4, octets[0], octets[1], 0, 0])
+        }
+        AddrV2::Ipv6(ip) if !ip.is_loopback() => {
+            let octets = ip.octets();
+            Some([6, octets[0], octets[1], octets[2], octets[3]])
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use addrman::Record;
+    use bitcoin::{
+        p2p::{address::AddrV2, ServiceFlags},
+        Network,
+    };
+
+    use crate::{
+        messages::{Event, Info, Warning},
+        network::dns::{DefaultResolver, DnsError, DnsResolver, FutureResult},
+        BlockType, Dialog, TrustedPeer,
+    };
+
+    use super::{
+        heights_disagree, netgroup, prefer_scored, PeerId, PeerMap, MAX_DIAL_BACKOFF,
+        MAX_SCORED_ADDRESSES,
+    };
+
+    fn pinned_peer_map(peers: Vec<TrustedPeer>) -> PeerMap {
+        let (mtx, _) = tokio::sync::mpsc::channel(1);
+        let (info_tx, _) = tokio::sync::mpsc::channel::<Info>(1);
+        let (warn_tx, _) = tokio::sync::mpsc::unbounded_channel::<Warning>();
+        let (event_tx, _) = tokio::sync::mpsc::channel::<Event>(1);
+        PeerMap::new(
+            mtx,
+            Network::Regtest,
+            BlockType::default(),
+            false,
+            peers,
+            true,
+            true,
+            Default::default(),
+            0,
+            Arc::new(Dialog::new(
+                info_tx,
+                warn_tx,
+                event_tx,
+                tokio::sync::broadcast::channel(1).0,
+            )),
+            Default::default(),
+            Default::default(),
+            Arc::new(DefaultResolver),
+            false,
+            Default::default(),
+            Network::Regtest.magic(),
+        )
+    }
+
+    #[derive(Debug)]
+    struct FixedResolver(Ipv4Addr);
+
+    impl DnsResolver for FixedResolver {
+        fn resolve<'a>(&'a self, _seed: &'a str) -> FutureResult<'a, Vec<IpAddr>, DnsError> {
+            Box::pin(async move { Ok(vec![IpAddr::V4(self.0)]) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_dns_resolver() {
+        let ip = Ipv4Addr::new(10, 0, 0, 9);
+        let (mtx, _) = tokio::sync::mpsc::channel(1);
+        let (info_tx, _) = tokio::sync::mpsc::channel::<Info>(1);
+        let (warn_tx, _) = tokio::sync::mpsc::unbounded_channel::<Warning>();
+        let (event_tx, _) = tokio::sync::mpsc::channel::<Event>(1);
+        let mut peer_map = PeerMap::new(
+            mtx,
+            Network::Signet,
+            BlockType::default(),
+            false,
+            Vec::new(),
+            false,
+            false,
+            Default::default(),
+            0,
+            Arc::new(Dialog::new(
+                info_tx,
+                warn_tx,
+                event_tx,
+                tokio::sync::broadcast::channel(1).0,
+            )),
+            Default::default(),
+            Default::default(),
+            Arc::new(FixedResolver(ip)),
+            false,
+            Default::default(),
+            Network::Signet.magic(),
+        );
+        let record = peer_map.next_peer().await.unwrap();
+        assert_eq!(record.network_addr().0, AddrV2::Ipv4(ip));
+    }
+
+    #[tokio::test]
+    async fn test_peers_only_cycles() {
+        let a = AddrV2::Ipv4(Ipv4Addr::new(10, 0, 0, 1));
+        let b = AddrV2::Ipv4(Ipv4Addr::new(10, 0, 0, 2));
+        let mut peer_map = pinned_peer_map(vec![
+            TrustedPeer::from_ip(Ipv4Addr::new(10, 0, 0, 1)),
+            TrustedPeer::from_ip(Ipv4Addr::new(10, 0, 0, 2)),
+        ]);
+        assert_eq!(peer_map.next_peer().await.unwrap().network_addr().0, b);
+        assert_eq!(peer_map.next_peer().await.unwrap().network_addr().0, a);
+        assert_eq!(peer_map.next_peer().await.unwrap().network_addr().0, b);
+        peer_map.banned.insert(a.clone());
+        peer_map.banned.insert(b);
+        assert!(peer_map.next_peer().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connected_static_peers_skipped() {
+        let a = AddrV2::Ipv4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut peer_map = pinned_peer_map(vec![
+            TrustedPeer::from_ip(Ipv4Addr::new(10, 0, 0, 1)),
+            TrustedPeer::from_ip(Ipv4Addr::new(10, 0, 0, 2)),
+        ]);
+        peer_map.insert_live_peer(1, Ipv4Addr::new(10, 0, 0, 2), 0);
+        assert_eq!(peer_map.next_peer().await.unwrap().network_addr().0, a);
+        assert_eq!(peer_map.next_peer().await.unwrap().network_addr().0, a);
+        peer_map.insert_live_peer(2, Ipv4Addr::new(10, 0, 0, 1), 0);
+        assert!(peer_map.next_peer().await.is_none());
+    }
+
+    #[test]
+    fn test_heights_disagree() {
+        assert!(!heights_disagree([100, 103, 98].into_iter(), 100));
+        assert!(!heights_disagree([100, 200].into_iter(), 100));
+        assert!(heights_disagree([200, 201].into_iter(), 100));
+        assert!(heights_disagree([10, 12].into_iter(), 100));
+        assert!(!heights_disagree([10, 200].into_iter(), 100));
+    }
+
+    #[tokio::test]
+    async fn test_announced_heights_prevent_eclipse() {
+        let mut peer_map = pinned_peer_map(Vec::new());
+        peer_map.insert_live_peer(1, Ipv4Addr::new(8, 8, 1, 1), 100);
+        peer_map.insert_live_peer(2, Ipv4Addr::new(9, 9, 1, 1), 100);
+        // Long lived peers connected when the tip was lower
+        assert!(peer_map.possible_eclipse(120));
+        peer_map.set_announced_height(PeerId(1), 120);
+        peer_map.set_announced_height(PeerId(2), 119);
+        assert!(!peer_map.possible_eclipse(120));
+    }
+
+    #[test]
+    fn test_netgroup() {
+        let a = AddrV2::Ipv4(Ipv4Addr::new(8, 8, 1, 1));
+        let b = AddrV2::Ipv4(Ipv4Addr::new(8, 8, 200, 3));
+        let c = AddrV2::Ipv4(Ipv4Addr::new(8, 9, 1, 1));
+        assert_eq!(netgroup(&a), netgroup(&b));
+        assert_ne!(netgroup(&a), netgroup(&c));
+        assert!(netgroup(&AddrV2::Ipv4(Ipv4Addr::LOCALHOST)).is_none());
+        assert!(netgroup(&AddrV2::Ipv4(Ipv4Addr::new(192, 168, 0, 1))).is_none());
+        assert!(netgroup(&AddrV2::Ipv6(Ipv6Addr::LOCALHOST)).is_none());
+        assert_ne!(
+            netgroup(&AddrV2::Ipv6(Ipv6Addr::new(
+                0x2001, 0xdb8, 0, 0, 0, 0, 0, 1
+            ))),
+            netgroup(&AddrV2::Ipv6(Ipv6Addr::new(
+                0x2001, 0xdb9, 0, 0, 0, 0, 0, 1
+            )))
+        );
+    }
+
+    #[test]
+    fn test_prefer_scored() {
+        let source = Ipv4Addr::LOCALHOST.into();
+        let a = AddrV2::Ipv4(Ipv4Addr::new(8, 8, 1, 1));
+        let b = AddrV2::Ipv4(Ipv4Addr::new(9, 9, 1, 1));
+        let records = || {
+            [a.clone(), b.clone()]
+                .into_iter()
+                .map(|addr| Record::new(addr, 8333, ServiceFlags::NONE, &source))
+        };
+        let mut scores = HashMap::new();
+        scores.insert(b.clone(), 2);
+        let best = prefer_scored(records(), &scores, -3).unwrap();
+        assert_eq!(best.network_addr().0, b);
+        scores.insert(a.clone(), -5);
+        scores.insert(b.clone(), -4);
+        // Every candidate is below the minimum, so the first is used
+        let best = prefer_scored(records(), &scores, -3).unwrap();
+        assert_eq!(best.network_addr().0, a);
+        assert!(prefer_scored(std::iter::empty(), &scores, -3).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_scores_bounded() {
+        let mut peer_map = pinned_peer_map(Vec::new());
+        let addr = |i: u32| AddrV2::Ipv4(Ipv4Addr::from(i));
+        peer_map.adjust_score(addr(0), 1);
+        peer_map.adjust_score(addr(0), -1);
+        assert!(peer_map.scores.is_empty());
+        peer_map.adjust_score(addr(0), -5);
+        for i in 1..=MAX_SCORED_ADDRESSES as u32 {
+            peer_map.adjust_score(addr(i), 1);
+        }
+        assert_eq!(peer_map.scores.len(), MAX_SCORED_ADDRESSES);
+        // The address with a history of misbehavior is remembered over those with little history
+        assert_eq!(peer_map.scores.get(&addr(0)), Some(&-5));
+        assert_eq!(
+            peer_map.scores.get(&addr(MAX_SCORED_ADDRESSES as u32)),
+            Some(&1)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dial_backoff() {
+        let addr = AddrV2::Ipv4(Ipv4Addr::new(10, 0, 0, 1));
+        let record = Record::new(
+            addr.clone(),
+            8333,
+            ServiceFlags::NONE,
+            &Ipv4Addr::LOCALHOST.into(),
+        );
+        let mut peer_map = pinned_peer_map(Vec::new());
+        assert!(!peer_map.backing_off(&record));
+        peer_map.dial_failed(addr.clone());
+        assert!(peer_map.backing_off(&record));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!peer_map.backing_off(&record));
+        // Each failure doubles the wait
+        peer_map.dial_failed(addr.clone());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(peer_map.backing_off(&record));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!peer_map.backing_off(&record));
+        // The wait is bounded
+        for _ in 0..40 {
+            peer_map.dial_failed(addr.clone());
         }
+        tokio::time::sleep(MAX_DIAL_BACKOFF).await;
+        assert!(!peer_map.backing_off(&record));
     }
 }
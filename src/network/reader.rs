@@ -29,17 +29,27 @@ const MAX_HEADERS: usize = 2_000;
 pub(in crate::network) struct Reader<R: AsyncBufReadExt + Send + Sync + Unpin> {
     parser: MessageParser<R>,
     tx: Sender<ReaderMessage>,
+    raw_blocks: bool,
 }
 
 impl<R: AsyncBufReadExt + Send + Sync + Unpin> Reader<R> {
-    pub fn new(parser: MessageParser<R>, tx: Sender<ReaderMessage>) -> Self {
-        Self { parser, tx }
+    pub fn new(parser: MessageParser<R>, tx: Sender<ReaderMessage>, raw_blocks: bool) -> Self {
+        Self {
+            parser,
+            tx,
+            raw_blocks,
+        }
     }
 
     pub(in crate::network) async fn read_from_remote(&mut self) -> Result<(), ReaderError> {
         loop {
-            if let Some(message) = self.parser.read_message().await? {
-                let cleaned_message = self.parse_message(message);
+            if let Some(inbound) = self.parser.read_message(self.raw_blocks).await? {
+                let cleaned_message = match self.parse_message(inbound.message) {
+                    Some(ReaderMessage::Block(block, _)) => {
+                        Some(ReaderMessage::Block(block, inbound.raw_block))
+                    }
+                    message => message,
+                };
                 match cleaned_message {
                     Some(message) => self.tx.send(message).await?,
                     None => continue,
@@ -74,12 +84,24 @@ impl<R: AsyncBufReadExt + Send + Sync + Unpin> Reader<R> {
                 Some(ReaderMessage::NewBlocks(blocks))
             }
             NetworkMessage::GetData(inventory) => Some(ReaderMessage::GetData(inventory)),
-            NetworkMessage::NotFound(_) => None,
+            NetworkMessage::NotFound(inventory) => {
+                let blocks: Vec<BlockHash> = inventory
+                    .into_iter()
+                    .filter_map(|inv| match inv {
+                        Inventory::Block(hash) | Inventory::WitnessBlock(hash) => Some(hash),
+                        _ => None,
+                    })
+                    .collect();
+                if blocks.is_empty() {
+                    return None;
+                }
+                Some(ReaderMessage::NotFound(blocks))
+            }
             NetworkMessage::GetBlocks(_) => None,
             NetworkMessage::GetHeaders(_) => None,
             NetworkMessage::MemPool => None,
             NetworkMessage::Tx(_) => None,
-            NetworkMessage::Block(block) => Some(ReaderMessage::Block(block)),
+            NetworkMessage::Block(block) => Some(ReaderMessage::Block(block, None)),
             NetworkMessage::Headers(headers) => {
                 if headers.len() > MAX_HEADERS {
                     return Some(ReaderMessage::Disconnect);
@@ -157,7 +179,7 @@ pub(in crate::network) enum ReaderMessage {
     Headers(Vec<Header>),
     FilterHeaders(CFHeaders),
     Filter(CFilter),
-    Block(Block),
+    Block(Block, Option<Vec<u8>>),
     NewBlocks(Vec<BlockHash>),
     Reject(RejectPayload),
     Disconnect,
@@ -167,6 +189,7 @@ pub(in crate::network) enum ReaderMessage {
     Pong(u64),
     FeeFilter(FeeRate),
     GetData(Vec<Inventory>),
+    NotFound(Vec<BlockHash>),
 }
 
 impl ReaderMessage {
@@ -176,7 +199,7 @@ impl ReaderMessage {
             ReaderMessage::FilterHeaders(_) => Some(TimeSensitiveId::CF_HEADER_MSG),
             ReaderMessage::Filter(_) => Some(TimeSensitiveId::C_FILTER_MSG),
             ReaderMessage::Pong(_) => Some(TimeSensitiveId::PING),
-            ReaderMessage::Block(b) => {
+            ReaderMessage::Block(b, _) => {
                 let hash = *b.block_hash().to_raw_hash().as_byte_array();
                 Some(TimeSensitiveId::from_slice(hash))
             }
@@ -192,8 +215,9 @@ mod tests {
     fn test_reader() -> Reader<tokio::io::Empty> {
         let (tx, _rx) = tokio::sync::mpsc::channel(1);
         Reader::new(
-            MessageParser::V1(tokio::io::empty(), bitcoin::Network::Regtest),
+            MessageParser::V1(tokio::io::empty(), bitcoin::Network::Regtest.magic()),
             tx,
+            false,
         )
     }
 
@@ -220,4 +244,19 @@ mod tests {
         let parsed = reader.parse_message(NetworkMessage::Inv(oversized));
         assert!(matches!(parsed, Some(ReaderMessage::Disconnect)));
     }
+
+    #[test]
+    fn not_found_surfaces_block_hashes() {
+        let reader = test_reader();
+        let block = BlockHash::from_byte_array([1; 32]);
+        let txid = bitcoin::Txid::from_byte_array([3; 32]);
+        let parsed = reader.parse_message(NetworkMessage::NotFound(vec![
+            Inventory::Transaction(txid),
+            Inventory::WitnessBlock(block),
+        ]));
+        assert!(matches!(parsed, Some(ReaderMessage::NotFound(hashes)) if hashes == vec![block]));
+        let parsed =
+            reader.parse_message(NetworkMessage::NotFound(vec![Inventory::Transaction(txid)]));
+        assert!(parsed.is_none());
+    }
 }
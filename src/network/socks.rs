@@ -1,10 +1,9 @@
 // Partial implementation of RFC 1928, Socks5 protocol
 // ref: https://datatracker.ietf.org/doc/html/rfc1928#section-1
+// Username and password authentication, RFC 1929
+// ref: https://datatracker.ietf.org/doc/html/rfc1929
 
-use std::{
-    net::{IpAddr, SocketAddr},
-    time::Duration,
-};
+use std::{net::IpAddr, time::Duration};
 
 use hashes::sha3_256;
 use tokio::{
@@ -12,12 +11,17 @@ use tokio::{
     net::TcpStream,
 };
 
+use crate::Socks5Proxy;
+
 use super::error::Socks5Error;
 
 const CONNECTION_TIMEOUT: Duration = Duration::from_secs(2);
 const VERSION: u8 = 5;
 const NOAUTH: u8 = 0;
-const METHODS: u8 = 1;
+const USERPASS: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+const USERPASS_VERSION: u8 = 1;
+const AUTH_SUCCESS: u8 = 0;
 const CMD_CONNECT: u8 = 1;
 const RESPONSE_SUCCESS: u8 = 0;
 const RSV: u8 = 0;
@@ -118,29 +122,50 @@ impl From<[u8; 32]> for SocksConnection {
 }
 
 pub(crate) async fn create_socks5(
-    proxy: SocketAddr,
+    proxy: &Socks5Proxy,
     addr: SocksConnection,
     port: u16,
 ) -> Result<TcpStream, Socks5Error> {
     // Connect to the proxy, likely a local Tor daemon.
-    let timeout = tokio::time::timeout(CONNECTION_TIMEOUT, TcpStream::connect(proxy))
+    let timeout = tokio::time::timeout(CONNECTION_TIMEOUT, TcpStream::connect(proxy.addr))
         .await
         .map_err(|_| Socks5Error::ConnectionTimeout)?;
+    let tcp_stream = timeout.map_err(|_| Socks5Error::ConnectionFailed)?;
+    handshake(tcp_stream, proxy.credentials.as_ref(), addr, port).await
+}
+
+async fn handshake(
+    mut tcp_stream: TcpStream,
+    credentials: Option<&(String, String)>,
+    addr: SocksConnection,
+    port: u16,
+) -> Result<TcpStream, Socks5Error> {
     // Format the destination IP address and port according to the Socks5 spec
     let dest_ip_bytes = addr.encode();
     let dest_port_bytes = port.to_be_bytes();
     let ip_type_byte = addr.type_byte();
-    // Begin the handshake by requesting a connection to the proxy.
-    let mut tcp_stream = timeout.map_err(|_| Socks5Error::ConnectionFailed)?;
-    tcp_stream.write_all(&[VERSION, METHODS, NOAUTH]).await?;
-    // Read the response from the proxy
+    // Begin the handshake by offering the supported authentication methods.
+    match credentials {
+        Some(_) => {
+            tcp_stream
+                .write_all(&[VERSION, 2, NOAUTH, USERPASS])
+                .await?
+        }
+        None => tcp_stream.write_all(&[VERSION, 1, NOAUTH]).await?,
+    }
+    // Read the method selected by the proxy
     let mut buf = [0_u8; 2];
     tcp_stream.read_exact(&mut buf).await?;
     if buf[0] != VERSION {
         return Err(Socks5Error::WrongVersion);
     }
-    if buf[1] != NOAUTH {
-        return Err(Socks5Error::AuthRequired);
+    match (buf[1], credentials) {
+        (NOAUTH, _) => (),
+        (USERPASS, Some((username, password))) => {
+            authenticate(&mut tcp_stream, username, password).await?
+        }
+        (NO_ACCEPTABLE_METHODS, _) | (_, None) => return Err(Socks5Error::AuthRequired),
+        _ => return Err(Socks5Error::WrongVersion),
     }
     // Write the request to the proxy to connect to our destination
     tcp_stream
@@ -182,9 +207,42 @@ pub(crate) async fn create_socks5(
     Ok(tcp_stream)
 }
 
+async fn authenticate(
+    tcp_stream: &mut TcpStream,
+    username: &str,
+    password: &str,
+) -> Result<(), Socks5Error> {
+    let username_len = u8::try_from(username.len()).map_err(|_| Socks5Error::InvalidCredentials)?;
+    let password_len = u8::try_from(password.len()).map_err(|_| Socks5Error::InvalidCredentials)?;
+    let mut request = Vec::with_capacity(3 + username.len() + password.len());
+    request.push(USERPASS_VERSION);
+    request.push(username_len);
+    request.extend_from_slice(username.as_bytes());
+    request.push(password_len);
+    request.extend_from_slice(password.as_bytes());
+    tcp_stream.write_all(&request).await?;
+    // The response is the subnegotiation version and a status, where zero indicates success
+    let mut buf = [0_u8; 2];
+    tcp_stream.read_exact(&mut buf).await?;
+    if buf[0] != USERPASS_VERSION {
+        return Err(Socks5Error::WrongVersion);
+    }
+    if buf[1] != AUTH_SUCCESS {
+        return Err(Socks5Error::AuthFailed);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use super::pubkey_to_service;
+    use std::net::Ipv4Addr;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{handshake, pubkey_to_service, SocksConnection};
 
     #[test]
     fn public_key_to_service() {
@@ -196,4 +254,35 @@ mod tests {
             service
         );
     }
+
+    #[tokio::test]
+    async fn username_password_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0_u8; 4];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 2, 0, 2]);
+            stream.write_all(&[5, 2]).await.unwrap();
+            let mut auth = [0_u8; 11];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            stream.write_all(&[1, 0]).await.unwrap();
+            let mut request = [0_u8; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [5, 1, 0, 1, 127, 0, 0, 1, 0x20, 0x8d]);
+            stream
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+        let stream = TcpStream::connect(proxy).await.unwrap();
+        let credentials = ("user".to_string(), "pass".to_string());
+        let addr = SocksConnection::ClearNet(Ipv4Addr::LOCALHOST.into());
+        assert!(handshake(stream, Some(&credentials), addr, 8333)
+            .await
+            .is_ok());
+        server.await.unwrap();
+    }
 }
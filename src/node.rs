@@ -1,52 +1,86 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use bitcoin::{
     block::Header,
     hashes::Hash,
+    key::rand::{rngs::StdRng, Rng, SeedableRng},
     p2p::{
         message_blockdata::GetHeadersMessage,
         message_filter::{CFHeaders, CFilter},
         message_network::VersionMessage,
         ServiceFlags,
     },
-    Block, BlockHash, Network, Wtxid,
+    Block, BlockHash, MerkleBlock, Network,
 };
 use tokio::{
     select,
-    sync::mpsc::{self},
+    sync::{
+        broadcast,
+        mpsc::{self},
+        oneshot, watch,
+    },
 };
 use tokio::{
-    sync::mpsc::{Receiver, UnboundedReceiver},
-    time::MissedTickBehavior,
+    sync::mpsc::{Receiver, UnboundedReceiver, UnboundedSender},
+    time::{Instant, MissedTickBehavior},
 };
 
 use crate::{
+    broadcaster::BroadcastCallback,
     chain::{
-        block_queue::{BlockQueue, ProcessBlockResponse},
+        block_queue::{BlockQueue, BlockRecipient, ProcessBlockResponse},
         chain::Chain,
         checkpoints::HashCheckpoint,
-        CFHeaderChanges, ChainState, FilterCheck, HeaderSyncEffect, IndexedHeader,
+        error::{CFHeaderSyncError, CFilterSyncError, HeaderSyncError},
+        CFHeaderChanges, ChainState, FeeEstimates, FilterBatchSize, FilterCheck, HeaderSyncEffect,
+        IndexedHeader,
     },
-    error::FetchBlockError,
+    error::{ClientError, FetchBlockError, FetchFilterHeadersError},
     messages::ClientRequest,
     network::{
-        peer_map::PeerMap, LastBlockMonitor, MainThreadMessage, PeerId, PeerMessage,
-        PeerThreadMessage,
+        peer_map::PeerMap, AnnouncementCache, DisconnectReason, LastBlockMonitor, LocalVersion,
+        MainThreadMessage, PeerId, PeerInfo, PeerMessage, PeerThreadMessage, TransportVersion,
+        MAX_USER_AGENT_LENGTH,
     },
-    Config, IndexedBlock, NodeState, Package,
+    BlockSource, BlockSourceError, Config, IndexedBlock, NodeState, Package, StaleTipStrategy,
+    StateCallbacks, TxBroadcastPolicy,
 };
 
 use super::{
     client::Client,
     error::NodeError,
-    messages::{ClientMessage, Event, Info, SyncUpdate, Warning},
+    messages::{ClientMessage, Event, Info, NodeHealth, SyncStage, SyncUpdate, Warning},
     Dialog,
 };
 
 pub(crate) const WTXID_VERSION: u32 = 70016;
 const LOOP_TIMEOUT: Duration = Duration::from_millis(10);
+// From Bitcoin Core, the number of header announcements that do not connect before a peer is
+// considered to be misbehaving
+const MAX_UNCONNECTING_HEADERS: u8 = 10;
+// The number of empty `headers` responses a peer may send while the network is ahead of the node
+// before it is considered to be stalling the sync
+const MAX_EMPTY_HEADERS: u8 = 3;
+// How long a peer has to respond to a client request for filter headers
+const FILTER_HEADERS_TIMEOUT: Duration = Duration::from_secs(30);
+// How long the block source has to return a block before it is requested from a peer
+const BLOCK_SOURCE_TIMEOUT: Duration = Duration::from_secs(30);
 
 type PeerRequirement = usize;
+type FilterHeadersRequest = ClientRequest<(u32, BlockHash), Result<CFHeaders, ClientError>>;
+type SourcedBlock = (BlockHash, Result<Block, BlockSourceError>);
+
+// A client request for filter headers, waiting on the peer it was sent to
+#[derive(Debug)]
+struct PendingFilterHeaders {
+    request: FilterHeadersRequest,
+    peer: PeerId,
+    deadline: Instant,
+}
 
 /// A compact block filter node. Nodes download Bitcoin block headers, block filters, and blocks to send relevant events to a client.
 #[derive(Debug)]
@@ -55,8 +89,37 @@ pub struct Node {
     chain: Chain,
     peer_map: PeerMap,
     required_peers: PeerRequirement,
+    pipeline_depth: usize,
+    filter_download_peers: usize,
+    stale_tip_strategy: StaleTipStrategy,
+    stale_tip_timeout: Duration,
+    rotated_one: bool,
+    rate_limited: bool,
+    connections_met: bool,
+    tip_confirmations: usize,
+    tip_confirmed_by: HashSet<PeerId>,
+    unconnecting_headers: HashMap<PeerId, u8>,
+    empty_headers: HashMap<PeerId, u8>,
+    sync_peer: Option<PeerId>,
+    iterations: u64,
+    filters_checked: u32,
+    blocks_requested: u32,
+    blocks_received: u32,
     dialog: Arc<Dialog>,
     block_queue: BlockQueue,
+    state_callbacks: StateCallbacks,
+    fee_estimates: FeeEstimates,
+    announcements: AnnouncementCache,
+    filter_header_requests: Vec<PendingFilterHeaders>,
+    sync_waiters: Vec<oneshot::Sender<SyncUpdate>>,
+    block_source: Option<Arc<dyn BlockSource>>,
+    sourcing: HashSet<BlockHash>,
+    sourced_tx: UnboundedSender<SourcedBlock>,
+    sourced_recv: UnboundedReceiver<SourcedBlock>,
+    prune_depth: Option<u32>,
+    tip_tx: watch::Sender<HashCheckpoint>,
+    reconnect_jitter: Duration,
+    next_dial: Option<Instant>,
     client_recv: UnboundedReceiver<ClientMessage>,
     peer_recv: Receiver<PeerThreadMessage>,
 }
@@ -67,54 +130,171 @@ impl Node {
             required_peers,
             white_list,
             whitelist_only,
+            peers_only,
             data_path: _,
             chain_state,
             connection_type,
             peer_timeout_config,
             filter_type,
             block_type,
+            raw_blocks,
+            pipeline_depth,
+            filter_download_peers,
+            stale_tip_strategy,
+            stale_tip_timeout,
+            tx_broadcast_policy,
+            min_peer_score,
+            tip_confirmations,
+            max_reorg_depth,
+            max_block_download_rate,
+            max_in_flight_blocks,
+            dns_resolver,
+            preload_headers,
+            event_buffer,
+            filter_retention,
+            checkpoints,
+            filter_header_checkpoints,
+            require_v2_transport,
+            local_version,
+            block_source,
+            prune_depth,
+            filter_batch_size,
+            adaptive_filter_batches,
+            network_magic,
+            reconnect_jitter,
+            state_callbacks,
         } = config;
         // Set up a communication channel between the node and client
         let (info_tx, info_rx) = mpsc::channel::<Info>(32);
         let (warn_tx, warn_rx) = mpsc::unbounded_channel::<Warning>();
-        let (event_tx, event_rx) = mpsc::unbounded_channel::<Event>();
+        let (event_tx, event_rx) = mpsc::channel::<Event>(event_buffer);
         let (ctx, crx) = mpsc::unbounded_channel::<ClientMessage>();
-        let client = Client::new(info_rx, warn_rx, event_rx, ctx);
+        let (event_broadcast, _) = broadcast::channel::<Event>(event_buffer);
+        let (tip_tx, tip_rx) = watch::channel(HashCheckpoint::from_genesis(network));
+        let client = Client::new(
+            info_rx,
+            warn_rx,
+            event_rx,
+            event_broadcast.clone(),
+            tip_rx,
+            ctx,
+        );
         // A structured way to talk to the client
-        let dialog = Arc::new(Dialog::new(info_tx, warn_tx, event_tx));
+        let dialog = Arc::new(Dialog::new(info_tx, warn_tx, event_tx, event_broadcast));
         // We always assume we are behind
         let state = NodeState::Behind;
+        // Peers disconnect from a node with an over-long user agent, so the default is used instead
+        let local_version = if local_version.user_agent.len() > MAX_USER_AGENT_LENGTH {
+            dialog.send_warning(Warning::UnexpectedSyncError {
+                warning: format!(
+                    "The user agent was rejected, it must be at most {MAX_USER_AGENT_LENGTH} bytes"
+                ),
+            });
+            LocalVersion {
+                services: local_version.services,
+                ..Default::default()
+            }
+        } else {
+            local_version
+        };
         // Configure the peer manager
         let (mtx, mrx) = mpsc::channel::<PeerThreadMessage>(32);
         let peer_map = PeerMap::new(
             mtx,
             network,
             block_type,
+            raw_blocks,
             white_list,
             whitelist_only,
+            peers_only,
+            tx_broadcast_policy,
+            min_peer_score,
             Arc::clone(&dialog),
             connection_type,
             peer_timeout_config,
+            dns_resolver,
+            require_v2_transport,
+            local_version,
+            network_magic.unwrap_or(network.magic()),
         );
         // Build the chain
         let chain_state = chain_state.unwrap_or(ChainState::Checkpoint(
             HashCheckpoint::from_genesis(network),
         ));
-        let chain = Chain::new(
+        let (sourced_tx, sourced_recv) = mpsc::unbounded_channel::<SourcedBlock>();
+        let mut block_queue = BlockQueue::new(pipeline_depth.into());
+        if let Some(bytes_per_sec) = max_block_download_rate {
+            block_queue.set_max_rate(bytes_per_sec);
+        }
+        if let Some(max_in_flight) = max_in_flight_blocks {
+            block_queue.set_max_in_flight(max_in_flight);
+        }
+        let mut chain = Chain::new(
             network,
             chain_state,
             Arc::clone(&dialog),
             required_peers,
             filter_type,
+            max_reorg_depth,
+            filter_retention,
         );
+        if let Err(e) = chain.set_checkpoints(checkpoints) {
+            dialog.send_warning(Warning::UnexpectedSyncError {
+                warning: format!("Checkpoints were rejected, heights must strictly increase: {e}"),
+            });
+        }
+        chain.set_filter_header_checkpoints(filter_header_checkpoints);
+        chain.set_block_type(block_type);
+        chain.set_filter_batch_size(FilterBatchSize::new(
+            filter_batch_size,
+            adaptive_filter_batches,
+        ));
+        if let Err(e) = chain.preload_headers(preload_headers) {
+            dialog.send_warning(Warning::UnexpectedSyncError {
+                warning: format!("Preloaded headers were rejected: {e}"),
+            });
+        }
+        let _ = tip_tx.send(HashCheckpoint::new(
+            chain.header_chain.height(),
+            chain.header_chain.tip_hash(),
+        ));
         (
             Self {
                 state,
                 chain,
                 peer_map,
                 required_peers: required_peers.into(),
+                pipeline_depth: pipeline_depth.into(),
+                filter_download_peers: filter_download_peers.into(),
+                stale_tip_strategy,
+                stale_tip_timeout,
+                rotated_one: false,
+                rate_limited: false,
+                connections_met: false,
+                tip_confirmations: tip_confirmations.min(required_peers).into(),
+                tip_confirmed_by: HashSet::new(),
+                unconnecting_headers: HashMap::new(),
+                empty_headers: HashMap::new(),
+                sync_peer: None,
+                iterations: 0,
+                filters_checked: 0,
+                blocks_requested: 0,
+                blocks_received: 0,
                 dialog,
-                block_queue: BlockQueue::new(),
+                block_queue,
+                fee_estimates: FeeEstimates::new(),
+                state_callbacks,
+                announcements: AnnouncementCache::new(),
+                filter_header_requests: Vec::new(),
+                sync_waiters: Vec::new(),
+                block_source,
+                sourcing: HashSet::new(),
+                sourced_tx,
+                sourced_recv,
+                prune_depth,
+                tip_tx,
+                reconnect_jitter,
+                next_dial: None,
                 client_recv: crx,
                 peer_recv: mrx,
             },
@@ -133,31 +313,41 @@ impl Node {
             "Configured connection requirement: {} peers",
             self.required_peers
         ));
-        let mut last_block = LastBlockMonitor::new();
+        let mut last_block = LastBlockMonitor::new(self.stale_tip_timeout);
         let mut interval = tokio::time::interval(LOOP_TIMEOUT);
         interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
         loop {
+            self.iterations = self.iterations.wrapping_add(1);
             // Try to advance the state of the node
             self.advance_state(&mut last_block).await;
             // Connect to more peers if we need them and remove old connections
             self.dispatch().await?;
             // If there are blocks we need in the queue, we should request them of a random peer
             self.get_blocks().await;
+            let now = Instant::now();
+            self.fail_filter_header_requests(
+                |pending| pending.deadline <= now,
+                || ClientError::RequestTimeout,
+            );
+            self.publish_tip();
+            // Stop reading from peers until the client catches up on events
+            let events_backlogged = self.dialog.flush_events();
             // Either handle a message from a remote peer or from our client
             select! {
-                peer = self.peer_recv.recv() => {
+                peer = self.peer_recv.recv(), if !events_backlogged => {
                     match peer {
                         Some(peer_thread) => {
                             match peer_thread.message {
-                                PeerMessage::Version(version) => {
+                                PeerMessage::Version(version, transport) => {
                                     self.peer_map.set_services(peer_thread.nonce, version.services);
-                                    let response = self.handle_version(peer_thread.nonce, version).await?;
+                                    self.peer_map.set_height(peer_thread.nonce, version.start_height);
+                                    let response = self.handle_version(peer_thread.nonce, version, transport).await?;
                                     self.peer_map.send_message(peer_thread.nonce, response).await;
-                                    crate::debug!(format!("[{}]: version", peer_thread.nonce));
+                                    crate::debug!(format!("[{}]: version", peer_thread.nonce), peer = peer_thread.nonce, message = "version");
                                 }
                                 PeerMessage::Headers(headers) => {
                                     last_block.reset();
-                                    crate::debug!(format!("[{}]: headers", peer_thread.nonce));
+                                    crate::debug!(format!("[{}]: headers", peer_thread.nonce), peer = peer_thread.nonce, message = "headers");
                                     match self.handle_headers(peer_thread.nonce, headers).await {
                                         Some(response) => {
                                             self.peer_map.send_message(peer_thread.nonce, response).await;
@@ -166,7 +356,7 @@ impl Node {
                                     }
                                 }
                                 PeerMessage::FilterHeaders(cf_headers) => {
-                                    crate::debug!(format!("[{}]: filter headers", peer_thread.nonce));
+                                    crate::debug!(format!("[{}]: filter headers", peer_thread.nonce), peer = peer_thread.nonce, message = "cfheaders");
                                     match self.handle_cf_headers(peer_thread.nonce, cf_headers).await {
                                         Some(response) => {
                                             self.peer_map.broadcast(response).await;
@@ -182,14 +372,20 @@ impl Node {
                                         None => continue,
                                     }
                                 }
-                                PeerMessage::Block(block) => match self.handle_block(peer_thread.nonce, block).await {
+                                PeerMessage::Block(block, raw_block) => match self.handle_block(peer_thread.nonce, block, raw_block).await {
                                     Some(response) => {
                                         self.peer_map.send_message(peer_thread.nonce, response).await;
                                     }
                                     None => continue,
                                 },
+                                PeerMessage::BlocksNotFound(blocks) => {
+                                    crate::debug!(format!("[{}]: notfound", peer_thread.nonce), peer = peer_thread.nonce, message = "notfound");
+                                    if let Some(response) = self.handle_blocks_not_found(peer_thread.nonce, blocks) {
+                                        self.peer_map.send_random_excluding(peer_thread.nonce, response).await;
+                                    }
+                                }
                                 PeerMessage::NewBlocks(blocks) => {
-                                    crate::debug!(format!("[{}]: inv", peer_thread.nonce));
+                                    crate::debug!(format!("[{}]: inv", peer_thread.nonce), peer = peer_thread.nonce, message = "inv");
                                     match self.handle_inventory_blocks(blocks) {
                                         Some(response) => {
                                             self.peer_map.send_message(peer_thread.nonce, response).await;
@@ -208,13 +404,29 @@ impl Node {
                 message = self.client_recv.recv() => {
                     if let Some(message) = message {
                         match message {
-                            ClientMessage::Shutdown => return Ok(()),
+                            ClientMessage::Shutdown => {
+                                self.shutdown();
+                                return Ok(());
+                            }
                             ClientMessage::Broadcast(transaction) => {
                                 self.broadcast_transaction(transaction).await;
                             },
-                            ClientMessage::Rescan(height_opt) => {
-                                if let Some(response) = self.rescan(height_opt) {
-                                    self.peer_map.broadcast(response).await;
+                            ClientMessage::BroadcastWithConfirmation(transaction) => {
+                                self.broadcast_transaction(transaction).await;
+                            },
+                            ClientMessage::Rescan => {
+                                self.chain.clear_filters();
+                                self.rescan().await;
+                            },
+                            ClientMessage::RescanFrom(request) => {
+                                let (height, oneshot) = request.into_values();
+                                let result = self.chain.clear_filters_from(height);
+                                let accepted = result.is_ok();
+                                if oneshot.send(result).is_err() {
+                                    self.dialog.send_warning(Warning::ChannelDropped);
+                                }
+                                if accepted {
+                                    self.rescan().await;
                                 }
                             },
                             ClientMessage::GetBlock(request) => {
@@ -230,6 +442,60 @@ impl Node {
                                         format!("Adding block {} to queue", request.data())
                                     );
                                     self.block_queue.add(request);
+                                    self.blocks_requested += 1;
+                                }
+                            },
+                            ClientMessage::FetchBlock(id, request) => {
+                                if self.chain.header_chain.height_of_hash(request.data()).is_none() {
+                                    let (_, oneshot) = request.into_values();
+                                    if oneshot.send(Err(FetchBlockError::UnknownHash)).is_err() {
+                                        self.dialog.send_warning(Warning::ChannelDropped);
+                                    }
+                                } else {
+                                    crate::debug!(
+                                        format!("Adding block {} to queue", request.data())
+                                    );
+                                    self.block_queue.add_with_id(id, request);
+                                    self.blocks_requested += 1;
+                                }
+                            },
+                            ClientMessage::CancelBlock(id) => {
+                                if let Some(recipient) = self.block_queue.cancel(id) {
+                                    // The client may have dropped the request before cancelling it
+                                    let _ = recipient.send_error(FetchBlockError::Cancelled);
+                                }
+                            },
+                            ClientMessage::GetMerkleProof(request) => {
+                                let (block_hash, _) = request.data();
+                                if self.chain.header_chain.height_of_hash(block_hash).is_none() {
+                                    let (_, oneshot) = request.into_values();
+                                    if oneshot.send(Err(FetchBlockError::UnknownHash)).is_err() {
+                                        self.dialog.send_warning(Warning::ChannelDropped);
+                                    }
+                                } else {
+                                    crate::debug!(
+                                        format!("Adding block {} to queue", block_hash)
+                                    );
+                                    self.block_queue.add(request);
+                                    self.blocks_requested += 1;
+                                }
+                            },
+                            ClientMessage::GetBlockByHeight(request) => {
+                                let (height, oneshot) = request.into_values();
+                                match self.chain.header_chain.block_hash_at_height(height) {
+                                    Some(hash) => {
+                                        crate::debug!(
+                                            format!("Adding block {} to queue", hash)
+                                        );
+                                        self.block_queue.add(ClientRequest::new(hash, oneshot));
+                                        self.blocks_requested += 1;
+                                    }
+                                    None => {
+                                        let err_reponse = oneshot.send(Err(FetchBlockError::UnknownHeight));
+                                        if err_reponse.is_err() {
+                                            self.dialog.send_warning(Warning::ChannelDropped);
+                                        }
+                                    }
                                 }
                             },
                             ClientMessage::BestBlock(request) => {
@@ -246,6 +512,22 @@ impl Node {
                             ClientMessage::AddPeer(peer) => {
                                 self.peer_map.add_trusted_peer(peer);
                             },
+                            ClientMessage::CancelBroadcast(wtxid) => {
+                                let cancelled = self.peer_map.tx_queue.lock().await.cancel(wtxid);
+                                if !cancelled {
+                                    crate::debug!(format!("No pending broadcast for {wtxid}"));
+                                }
+                            },
+                            ClientMessage::DisconnectPeer(id) => {
+                                if !self.peer_map.disconnect(id).await {
+                                    self.dialog.send_warning(Warning::UnknownPeer(id));
+                                }
+                            },
+                            ClientMessage::ReconnectAll => {
+                                crate::debug!("Reconnecting to new peers at the request of the client");
+                                self.peer_map.reconnect_all().await;
+                                self.rotated_one = false;
+                            },
                             ClientMessage::GetBroadcastMinFeeRate(request) => {
                                 let (_, oneshot) = request.into_values();
                                 let fee_rate = self.peer_map.broadcast_min();
@@ -254,14 +536,33 @@ impl Node {
                                     self.dialog.send_warning(Warning::ChannelDropped);
                                 };
                             }
+                            ClientMessage::GetFeeEstimate(request) => {
+                                let (_, oneshot) = request.into_values();
+                                let send_result = oneshot.send(self.fee_estimates.estimate());
+                                if send_result.is_err() {
+                                    self.dialog.send_warning(Warning::ChannelDropped);
+                                };
+                            }
                             ClientMessage::GetPeerInfo(request) => {
                                 let (_, oneshot) = request.into_values();
-                                let peers = self.peer_map.peer_info();
-                                let send_result = oneshot.send(peers);
+                                let send_result = oneshot.send(self.peer_info());
                                 if send_result.is_err() {
                                     self.dialog.send_warning(Warning::ChannelDropped);
                                 };
                             }
+                            ClientMessage::GetPeerHeights(request) => {
+                                let (_, oneshot) = request.into_values();
+                                if oneshot.send(self.peer_map.peer_heights()).is_err() {
+                                    self.dialog.send_warning(Warning::ChannelDropped);
+                                };
+                            }
+                            ClientMessage::GetKnownPeers(request) => {
+                                let (_, oneshot) = request.into_values();
+                                let peers = self.peer_map.stored_peers().await;
+                                if oneshot.send(peers).is_err() {
+                                    self.dialog.send_warning(Warning::ChannelDropped);
+                                };
+                            }
                             ClientMessage::GetHeader(request) => {
                                 let (height, oneshot) = request.into_values();
                                 let header = self
@@ -281,10 +582,77 @@ impl Node {
                                     self.dialog.send_warning(Warning::ChannelDropped);
                                 };
                             }
+                            ClientMessage::TestScript(request) => {
+                                let ((script, range), oneshot) = request.into_values();
+                                let matches = self.chain.test_script(&script, range);
+                                if oneshot.send(matches).is_err() {
+                                    self.dialog.send_warning(Warning::ChannelDropped);
+                                };
+                            }
+                            ClientMessage::WaitUntilSynced(request) => {
+                                let (_, oneshot) = request.into_values();
+                                if self.state == NodeState::FiltersSynced {
+                                    if oneshot.send(self.sync_update()).is_err() {
+                                        self.dialog.send_warning(Warning::ChannelDropped);
+                                    };
+                                } else {
+                                    self.sync_waiters.push(oneshot);
+                                }
+                            }
+                            ClientMessage::GetRelativeChainWork(request) => {
+                                let (_, oneshot) = request.into_values();
+                                let work = self.chain.header_chain.work_since_root();
+                                if oneshot.send(work).is_err() {
+                                    self.dialog.send_warning(Warning::ChannelDropped);
+                                };
+                            }
+                            ClientMessage::GetBlockLocators(request) => {
+                                let (_, oneshot) = request.into_values();
+                                let locators = self.chain.header_chain.locators();
+                                if oneshot.send(locators).is_err() {
+                                    self.dialog.send_warning(Warning::ChannelDropped);
+                                };
+                            }
+                            ClientMessage::FindTransaction(request) => {
+                                let (txid, oneshot) = request.into_values();
+                                let location = self.chain.find_transaction(&txid);
+                                if oneshot.send(location).is_err() {
+                                    self.dialog.send_warning(Warning::ChannelDropped);
+                                };
+                            }
+                            ClientMessage::GetFilterHeaders(request) => {
+                                let (start_height, stop_hash) = request.data();
+                                match self.chain.filter_header_range_message(start_height, stop_hash) {
+                                    Ok(message) => match self.peer_map.send_random_peer(MainThreadMessage::GetFilterHeaders(message)).await {
+                                        Some(peer) => self.filter_header_requests.push(PendingFilterHeaders {
+                                            request,
+                                            peer,
+                                            deadline: Instant::now() + FILTER_HEADERS_TIMEOUT,
+                                        }),
+                                        None => self.resolve_filter_headers(request, Err(FetchFilterHeadersError::NoResponse.into())),
+                                    },
+                                    Err(e) => self.resolve_filter_headers(request, Err(e.into())),
+                                }
+                            }
+                            ClientMessage::Health(request) => {
+                                let (_, oneshot) = request.into_values();
+                                let health = NodeHealth {
+                                    state: self.state,
+                                    iterations: self.iterations,
+                                };
+                                if oneshot.send(health).is_err() {
+                                    self.dialog.send_warning(Warning::ChannelDropped);
+                                };
+                            }
                             ClientMessage::NoOp => (),
                         }
                     }
                 }
+                sourced = self.sourced_recv.recv() => {
+                    if let Some((hash, result)) = sourced {
+                        self.handle_sourced_block(hash, result).await;
+                    }
+                }
                 _ = interval.tick() => (),
             }
         }
@@ -292,20 +660,56 @@ impl Node {
 
     // Connect to a new peer if we are not connected to enough
     async fn dispatch(&mut self) -> Result<(), NodeError> {
-        self.peer_map.clean().await;
+        for (id, reason) in self.peer_map.clean().await {
+            self.forget_peer(id);
+            self.fail_filter_header_requests(
+                |pending| pending.peer.eq(&id),
+                || FetchFilterHeadersError::NoResponse.into(),
+            );
+            self.dialog
+                .send_event(Event::PeerDisconnected { id, reason });
+            // Filters the peer did not deliver are requested from another peer
+            let released = self.chain.release_filter_batches(id);
+            if !released.is_empty() && reason.eq(&DisconnectReason::Timeout) {
+                self.chain.filter_batches_timed_out();
+            }
+            for filters in released {
+                self.peer_map
+                    .send_random(MainThreadMessage::GetFilters(filters))
+                    .await;
+            }
+        }
         let live = self.peer_map.live();
+        // Only report a loss of connections after they were met
+        if self.connections_met && live < self.required_peers {
+            self.connections_met = false;
+            self.dialog.send_info(Info::ConnectionsLost {
+                connected: live,
+                required: self.required_peers,
+            });
+        }
         let required = self.next_required_peers();
         // Find more peers when lower than the desired threshold.
         if live < required {
+            if self.dial_delayed() {
+                return Ok(());
+            }
             self.dialog.send_warning(Warning::NeedConnections {
                 connected: live,
                 required,
             });
-            let address = self
-                .peer_map
-                .next_peer()
-                .await
-                .ok_or(NodeError::NoReachablePeers)?;
+            let Some(address) = self.peer_map.next_peer().await else {
+                // Every configured peer may already be connected
+                if live > 0 {
+                    return Ok(());
+                }
+                self.dialog.send_warning(Warning::CouldNotConnect);
+                return Err(NodeError::NoReachablePeers);
+            };
+            // Another address is selected on the next iteration
+            if self.peer_map.backing_off(&address) {
+                return Ok(());
+            }
             if self.peer_map.dispatch(address).await.is_err() {
                 self.dialog.send_warning(Warning::CouldNotConnect);
             }
@@ -313,20 +717,112 @@ impl Node {
         Ok(())
     }
 
-    // If there are blocks in the queue, we should request them of a random peer
+    // Wait a random delay, up to the configured jitter, before each new connection, so nodes that
+    // lost their connections at the same time do not all dial at once
+    fn dial_delayed(&mut self) -> bool {
+        if self.reconnect_jitter.is_zero() {
+            return false;
+        }
+        let dial_at = *self.next_dial.get_or_insert_with(|| {
+            let mut rng = StdRng::from_entropy();
+            Instant::now() + rng.gen_range(Duration::ZERO..=self.reconnect_jitter)
+        });
+        if Instant::now() < dial_at {
+            return true;
+        }
+        self.next_dial = None;
+        false
+    }
+
+    // If there are blocks in the queue, we should request them of the block source or a random peer
     async fn get_blocks(&mut self) {
-        if let Some(block_request) = self.pop_block_queue() {
-            crate::debug!("Sending block request to random peer");
-            self.peer_map.send_random(block_request).await;
+        let Some(block_request) = self.pop_block_queue() else {
+            return;
+        };
+        if let (Some(source), MainThreadMessage::GetBlocks(hashes)) =
+            (&self.block_source, &block_request)
+        {
+            for hash in hashes.iter().copied() {
+                // Requests are repeated while waiting, but the source is only asked once at a time
+                if !self.sourcing.insert(hash) {
+                    continue;
+                }
+                crate::debug!(format!("Fetching block {hash} from the block source"));
+                let source = Arc::clone(source);
+                let sourced_tx = self.sourced_tx.clone();
+                tokio::spawn(async move {
+                    let result = tokio::time::timeout(BLOCK_SOURCE_TIMEOUT, source.get_block(hash))
+                        .await
+                        .unwrap_or_else(|_| {
+                            Err(BlockSourceError::Io(std::io::ErrorKind::TimedOut.into()))
+                        });
+                    let _ = sourced_tx.send((hash, result));
+                });
+            }
+            return;
+        }
+        crate::debug!("Sending block request to random peer");
+        self.peer_map.send_random(block_request).await;
+    }
+
+    // Accept a block from the block source, or fall back to requesting it from a peer
+    async fn handle_sourced_block(
+        &mut self,
+        block_hash: BlockHash,
+        result: Result<Block, BlockSourceError>,
+    ) {
+        self.sourcing.remove(&block_hash);
+        let warning = match result {
+            Ok(block) if block.block_hash().eq(&block_hash) => {
+                match self.chain.header_chain.height_of_hash(block_hash) {
+                    Some(height) => match self.check_block(&block, height) {
+                        Ok(()) => {
+                            self.accept_block(block, None, height).await;
+                            return;
+                        }
+                        Err(warning) => warning,
+                    },
+                    // The block was reorganized out of the chain while it was fetched
+                    None => return,
+                }
+            }
+            Ok(_) => Warning::UnexpectedSyncError {
+                warning: format!("The block source returned a different block for {block_hash}"),
+            },
+            Err(e) => Warning::UnexpectedSyncError {
+                warning: format!("The block source could not fetch {block_hash}: {e}"),
+            },
+        };
+        self.dialog.send_warning(warning);
+        if self.block_queue.not_found(&block_hash) {
+            self.peer_map
+                .send_random(MainThreadMessage::GetBlocks(vec![block_hash]))
+                .await;
         }
     }
 
     // Broadcast transactions according to the configured policy
-    async fn broadcast_transaction(&self, broadcast: ClientRequest<Package, Wtxid>) {
+    async fn broadcast_transaction<U>(&self, broadcast: ClientRequest<Package, U>)
+    where
+        oneshot::Sender<U>: Into<BroadcastCallback>,
+    {
         let mut queue = self.peer_map.tx_queue.lock().await;
         let (transaction, oneshot) = broadcast.into_values();
         queue.add_to_queue(transaction, oneshot);
+        let policy = queue.policy();
         drop(queue);
+        if let TxBroadcastPolicy::NPeers(requested) = policy {
+            crate::debug!(format!("Sending transaction to {requested} random peers"));
+            let sent = self
+                .peer_map
+                .send_random_peers(MainThreadMessage::BroadcastPending, requested)
+                .await;
+            if sent < requested {
+                self.dialog
+                    .send_warning(Warning::ReducedBroadcast { requested, sent });
+            }
+            return;
+        }
         crate::debug!("Sending transaction to a random peer");
         self.peer_map
             .send_random(MainThreadMessage::BroadcastPending)
@@ -340,44 +836,73 @@ impl Node {
             NodeState::Behind => (),
             NodeState::HeadersSynced => {
                 if self.chain.is_cf_headers_synced() {
-                    self.state = NodeState::FilterHeadersSynced;
+                    self.set_state(NodeState::FilterHeadersSynced);
+                    self.report_scan_start();
                 }
             }
             NodeState::FilterHeadersSynced => {
                 if self.chain.is_filters_synced() {
-                    self.state = NodeState::FiltersSynced;
-                    let update = SyncUpdate::new(
-                        HashCheckpoint::new(
-                            self.chain.header_chain.height(),
-                            self.chain.header_chain.tip_hash(),
-                        ),
-                        self.chain.last_ten(),
-                    );
+                    self.set_state(NodeState::FiltersSynced);
+                    let update = self.sync_update();
+                    for waiter in self.sync_waiters.drain(..) {
+                        let _ = waiter.send(update.clone());
+                    }
                     self.dialog.send_event(Event::FiltersSynced(update));
+                    self.prune_headers();
                 }
             }
             NodeState::FiltersSynced => {
                 if last_block.stale() {
+                    self.check_eclipse();
                     self.dialog.send_warning(Warning::PotentialStaleTip);
-                    crate::debug!("Disconnecting from remote nodes to find new connections");
-                    self.peer_map.broadcast(MainThreadMessage::Disconnect).await;
+                    match self.stale_tip_strategy {
+                        // Try replacing a single peer before escalating
+                        StaleTipStrategy::RotateOne if !self.rotated_one => {
+                            crate::debug!(
+                                "Disconnecting from a remote node to find a new connection"
+                            );
+                            self.peer_map
+                                .send_random(MainThreadMessage::Disconnect)
+                                .await;
+                            self.rotated_one = true;
+                        }
+                        _ => {
+                            crate::debug!(
+                                "Disconnecting from remote nodes to find new connections"
+                            );
+                            self.peer_map.broadcast(MainThreadMessage::Disconnect).await;
+                            self.rotated_one = false;
+                        }
+                    }
                     last_block.reset();
                 }
             }
         }
     }
 
+    // Compare what our peers report against the headers we have synced
+    fn check_eclipse(&self) {
+        let local = self.chain.header_chain.height();
+        if self.peer_map.possible_eclipse(local) {
+            self.dialog.send_warning(Warning::PossibleEclipse);
+        }
+        if let Some(network) = self.peer_map.network_ahead_of(local) {
+            self.dialog
+                .send_warning(Warning::NodeBehind { local, network });
+        }
+    }
+
     // When syncing headers we are only interested in one peer to start
     fn next_required_peers(&self) -> PeerRequirement {
         match self.state {
-            NodeState::Behind => 1,
+            NodeState::Behind => self.tip_confirmations,
             _ => self.required_peers,
         }
     }
 
     // After we receiving some chain-syncing message, we decide what chain of data needs to be
     // requested next.
-    async fn next_stateful_message(&mut self) -> Option<MainThreadMessage> {
+    async fn next_stateful_message(&mut self, peer_id: PeerId) -> Option<MainThreadMessage> {
         if self.state == NodeState::Behind {
             let headers = GetHeadersMessage {
                 version: WTXID_VERSION,
@@ -390,9 +915,14 @@ impl Node {
                 self.chain.next_cf_header_message(),
             ));
         } else if !self.chain.is_filters_synced() {
-            return Some(MainThreadMessage::GetFilters(
-                self.chain.next_filter_message(),
-            ));
+            self.filters_checked += self.chain.replay_cached_filters();
+            if self.chain.is_filters_synced() {
+                return None;
+            }
+            return self
+                .chain
+                .pipelined_filter_message(peer_id)
+                .map(MainThreadMessage::GetFilters);
         }
         None
     }
@@ -402,6 +932,7 @@ impl Node {
         &mut self,
         nonce: PeerId,
         version_message: VersionMessage,
+        transport: TransportVersion,
     ) -> Result<MainThreadMessage, NodeError> {
         if version_message.version < WTXID_VERSION {
             return Ok(MainThreadMessage::Disconnect);
@@ -418,6 +949,10 @@ impl Node {
             }
         }
         self.peer_map.tried(nonce).await;
+        self.peer_map.set_transport(nonce, transport);
+        if let Some(info) = self.peer_map.info(nonce) {
+            self.dialog.send_event(Event::PeerConnected(info));
+        }
         // First we signal for ADDRV2 support
         self.peer_map
             .send_message(nonce, MainThreadMessage::SendAddrV2)
@@ -441,7 +976,11 @@ impl Node {
         }
         // Inform the user we are connected to all required peers
         if self.peer_map.live().eq(&self.required_peers) {
+            self.connections_met = true;
             self.dialog.send_info(Info::ConnectionsMet);
+            if self.state != NodeState::Behind {
+                self.check_eclipse();
+            }
         }
         // Even if we start the node as caught up in terms of height, we need to check for reorgs. So we can send this unconditionally.
         let next_headers = GetHeadersMessage {
@@ -452,43 +991,164 @@ impl Node {
         Ok(MainThreadMessage::GetHeaders(next_headers))
     }
 
+    // Details of the connected peers, including the peer the node is syncing from
+    fn peer_info(&self) -> Vec<PeerInfo> {
+        let mut peers = self.peer_map.peer_info();
+        for peer in peers.iter_mut() {
+            peer.is_sync_peer = self.sync_peer.eq(&Some(peer.id));
+        }
+        peers
+    }
+
+    // A disconnected peer no longer counts towards confirming the tip or replacing a peer
+    fn forget_peer(&mut self, peer_id: PeerId) {
+        if self.sync_peer.eq(&Some(peer_id)) {
+            self.sync_peer = None;
+        }
+        self.tip_confirmed_by.remove(&peer_id);
+        self.unconnecting_headers.remove(&peer_id);
+        self.empty_headers.remove(&peer_id);
+    }
+
+    // Count the empty headers responses from a peer while the network is ahead of our tip,
+    // warning once the peer should be replaced. Any other empty response clears the count.
+    fn peer_stalled(&mut self, peer_id: PeerId, network_ahead: bool) -> bool {
+        if !network_ahead {
+            self.empty_headers.remove(&peer_id);
+            return false;
+        }
+        let empty = self.empty_headers.entry(peer_id).or_default();
+        *empty += 1;
+        if *empty < MAX_EMPTY_HEADERS {
+            return false;
+        }
+        self.empty_headers.remove(&peer_id);
+        self.dialog
+            .send_warning(Warning::PeerStalling { id: peer_id });
+        true
+    }
+
     // We always send headers to our peers, so our next message depends on our state
     async fn handle_headers(
         &mut self,
         peer_id: PeerId,
         headers: Vec<Header>,
     ) -> Option<MainThreadMessage> {
+        let last_hash = headers.last().map(|header| header.block_hash());
         let chain = &mut self.chain;
-        match chain.sync_chain(headers) {
+        let result = chain.sync_chain(headers);
+        // Track the tip each peer announces, as reported heights go stale after connecting
+        if let Some(height) =
+            last_hash.and_then(|hash| self.chain.header_chain.height_of_hash(hash))
+        {
+            self.peer_map.set_announced_height(peer_id, height);
+        }
+        match result {
             Ok(effect) => match effect {
                 HeaderSyncEffect::Added => {
-                    if self.state != NodeState::Behind {
-                        self.state = NodeState::Behind;
-                    }
-                    self.chain.send_chain_update();
+                    self.sync_peer = Some(peer_id);
+                    self.unconnecting_headers.remove(&peer_id);
+                    self.empty_headers.remove(&peer_id);
+                    self.peer_map.reward(peer_id);
+                    self.set_state(NodeState::Behind);
+                    self.rotated_one = false;
+                    self.tip_confirmed_by.clear();
+                    self.chain.send_chain_update(SyncStage::Headers);
                 }
                 HeaderSyncEffect::Empty => {
                     if self.state == NodeState::Behind {
-                        self.state = NodeState::HeadersSynced;
+                        // The peers report a tip well ahead of ours, so an empty response does
+                        // not confirm the tip. Ask another peer, and replace a peer that keeps
+                        // stalling.
+                        let local = self.chain.header_chain.height();
+                        let network_ahead = self.peer_map.network_ahead_of(local).is_some();
+                        if self.peer_stalled(peer_id, network_ahead) {
+                            return Some(MainThreadMessage::Disconnect);
+                        }
+                        if network_ahead {
+                            let headers = MainThreadMessage::GetHeaders(GetHeadersMessage {
+                                version: WTXID_VERSION,
+                                locator_hashes: self.chain.header_chain.locators(),
+                                stop_hash: BlockHash::all_zeros(),
+                            });
+                            // Without another peer, ask again until the peer is replaced
+                            if !self
+                                .peer_map
+                                .send_random_excluding(peer_id, headers.clone())
+                                .await
+                            {
+                                return Some(headers);
+                            }
+                            return None;
+                        }
+                        self.tip_confirmed_by.insert(peer_id);
+                        if self.tip_confirmed_by.len() < self.tip_confirmations {
+                            // Ask the other peers if they know of any more headers
+                            if self.tip_confirmed_by.len() == 1 {
+                                let headers = GetHeadersMessage {
+                                    version: WTXID_VERSION,
+                                    locator_hashes: self.chain.header_chain.locators(),
+                                    stop_hash: BlockHash::all_zeros(),
+                                };
+                                self.peer_map
+                                    .broadcast(MainThreadMessage::GetHeaders(headers))
+                                    .await;
+                            }
+                            return None;
+                        }
+                        self.set_state(NodeState::HeadersSynced);
+                        self.check_eclipse();
                     }
                 }
                 HeaderSyncEffect::Reorg(reorgs) => {
-                    if self.state != NodeState::HeadersSynced {
-                        self.state = NodeState::HeadersSynced;
-                    }
-                    self.chain.send_chain_update();
+                    self.tip_confirmed_by.clear();
+                    self.set_state(NodeState::HeadersSynced);
+                    self.chain.send_chain_update(SyncStage::Headers);
                     self.block_queue.remove(&reorgs);
                 }
             },
+            // With BIP-130, a peer announces new blocks with `headers`. If we missed a block in
+            // between, the announcement does not connect, so ask for the headers in between.
+            Err(HeaderSyncError::FloatingHeaders) => {
+                let unconnecting = self.unconnecting_headers.entry(peer_id).or_default();
+                *unconnecting += 1;
+                if *unconnecting < MAX_UNCONNECTING_HEADERS {
+                    crate::debug!("Requesting headers to connect an announcement");
+                    return Some(MainThreadMessage::GetHeaders(GetHeadersMessage {
+                        version: WTXID_VERSION,
+                        locator_hashes: self.chain.header_chain.locators(),
+                        stop_hash: BlockHash::all_zeros(),
+                    }));
+                }
+                self.dialog
+                    .send_warning(Warning::InvalidHeaderConnection { peer: peer_id });
+                self.peer_map.ban(peer_id).await;
+                return Some(MainThreadMessage::Disconnect);
+            }
+            Err(HeaderSyncError::DeepReorg(depth)) => {
+                self.dialog.send_warning(Warning::DeepReorg { depth });
+                self.peer_map.ban(peer_id).await;
+                return Some(MainThreadMessage::Disconnect);
+            }
             Err(e) => {
-                self.dialog.send_warning(Warning::UnexpectedSyncError {
-                    warning: format!("Unexpected header syncing error: {e}"),
-                });
+                let warning = match e {
+                    HeaderSyncError::HeadersNotConnected => {
+                        Warning::InvalidHeaderConnection { peer: peer_id }
+                    }
+                    HeaderSyncError::InvalidHeaderWork
+                    | HeaderSyncError::TargetExceedsPowLimit
+                    | HeaderSyncError::MiscalculatedDifficulty
+                    | HeaderSyncError::InvalidBits => Warning::InvalidPow { peer: peer_id },
+                    e => Warning::UnexpectedSyncError {
+                        warning: format!("Unexpected header syncing error: {e}"),
+                    },
+                };
+                self.dialog.send_warning(warning);
                 self.peer_map.ban(peer_id).await;
                 return Some(MainThreadMessage::Disconnect);
             }
         }
-        self.next_stateful_message().await
+        self.next_stateful_message(peer_id).await
     }
 
     // Compact filter headers may result in a number of outcomes, including the need to audit filters.
@@ -497,58 +1157,208 @@ impl Node {
         peer_id: PeerId,
         cf_headers: CFHeaders,
     ) -> Option<MainThreadMessage> {
-        self.chain.send_chain_update();
+        if !self.expected_filter_type(cf_headers.filter_type) {
+            return Some(MainThreadMessage::Disconnect);
+        }
+        if self.answer_filter_header_requests(&cf_headers)
+            && !self.chain.awaiting_cf_headers(&cf_headers.stop_hash)
+        {
+            return None;
+        }
+        self.chain.send_chain_update(SyncStage::FilterHeaders);
         match self.chain.sync_cf_headers(peer_id, cf_headers) {
             Ok(potential_message) => match potential_message {
                 CFHeaderChanges::AddedToQueue => None,
-                CFHeaderChanges::Extended => self.next_stateful_message().await,
+                CFHeaderChanges::Duplicate => {
+                    self.dialog
+                        .send_warning(Warning::DuplicateFilterHeader { peer: peer_id });
+                    None
+                }
+                CFHeaderChanges::Extended => {
+                    self.sync_peer = Some(peer_id);
+                    self.next_stateful_message(peer_id).await
+                }
                 CFHeaderChanges::Conflict => {
-                    self.dialog.send_warning(Warning::UnexpectedSyncError {
-                        warning: "Found a conflict while peers are sending filter headers".into(),
-                    });
+                    self.dialog
+                        .send_warning(Warning::FilterHeaderMismatch { peer: peer_id });
                     Some(MainThreadMessage::Disconnect)
                 }
             },
             Err(e) => {
-                self.dialog.send_warning(Warning::UnexpectedSyncError {
-                    warning: format!("Compact filter header syncing encountered an error: {e}"),
-                });
+                let warning = match e {
+                    CFHeaderSyncError::PrevHeaderMismatch
+                    | CFHeaderSyncError::InvalidCheckpoint => {
+                        Warning::FilterHeaderMismatch { peer: peer_id }
+                    }
+                    e => Warning::UnexpectedSyncError {
+                        warning: format!("Compact filter header syncing encountered an error: {e}"),
+                    },
+                };
+                self.dialog.send_warning(warning);
                 self.peer_map.ban(peer_id).await;
                 Some(MainThreadMessage::Disconnect)
             }
         }
     }
 
+    // Respond to any client requests for this range of filter headers, returning if a request was
+    // answered.
+    fn answer_filter_header_requests(&mut self, cf_headers: &CFHeaders) -> bool {
+        if self.filter_header_requests.is_empty() {
+            return false;
+        }
+        let Some(stop_height) = self
+            .chain
+            .header_chain
+            .height_of_hash_canonical_only(cf_headers.stop_hash)
+        else {
+            return false;
+        };
+        let Some(start_height) = cf_headers
+            .filter_hashes
+            .len()
+            .checked_sub(1)
+            .and_then(|offset| stop_height.checked_sub(offset as u32))
+        else {
+            return false;
+        };
+        let range = (start_height, cf_headers.stop_hash);
+        let (answered, pending) = core::mem::take(&mut self.filter_header_requests)
+            .into_iter()
+            .partition::<Vec<_>, _>(|pending| pending.request.data().eq(&range));
+        self.filter_header_requests = pending;
+        if answered.is_empty() {
+            return false;
+        }
+        let consistent = self
+            .chain
+            .filter_headers_consistent(start_height, cf_headers);
+        for pending in answered {
+            let response = if consistent {
+                Ok(cf_headers.clone())
+            } else {
+                Err(FetchFilterHeadersError::InvalidResponse.into())
+            };
+            self.resolve_filter_headers(pending.request, response);
+        }
+        true
+    }
+
+    // Resolve the client requests for filter headers that can no longer be answered
+    fn fail_filter_header_requests(
+        &mut self,
+        failed: impl Fn(&PendingFilterHeaders) -> bool,
+        error: impl Fn() -> ClientError,
+    ) {
+        let (failed, pending) = core::mem::take(&mut self.filter_header_requests)
+            .into_iter()
+            .partition::<Vec<_>, _>(failed);
+        self.filter_header_requests = pending;
+        for pending in failed {
+            self.resolve_filter_headers(pending.request, Err(error()));
+        }
+    }
+
+    fn resolve_filter_headers(
+        &self,
+        request: FilterHeadersRequest,
+        response: Result<CFHeaders, ClientError>,
+    ) {
+        let (_, oneshot) = request.into_values();
+        if oneshot.send(response).is_err() {
+            self.dialog.send_warning(Warning::ChannelDropped);
+        }
+    }
+
     // Handle a new compact block filter
     async fn handle_filter(
         &mut self,
         peer_id: PeerId,
         filter: CFilter,
     ) -> Option<MainThreadMessage> {
-        match self.chain.sync_filter(filter) {
+        if !self.expected_filter_type(filter.filter_type) {
+            return Some(MainThreadMessage::Disconnect);
+        }
+        match self.chain.sync_filter(peer_id, filter) {
             Ok(potential_message) => {
                 let FilterCheck { was_last_in_batch } = potential_message;
+                self.sync_peer = Some(peer_id);
+                self.filters_checked += 1;
                 if was_last_in_batch {
-                    self.chain.send_chain_update();
-                    if !self.chain.is_filters_synced() {
-                        let next_filters = self.chain.next_filter_message();
-                        return Some(MainThreadMessage::GetFilters(next_filters));
+                    // Filters beyond the batch may be cached
+                    self.filters_checked += self.chain.replay_cached_filters();
+                    self.peer_map.reward(peer_id);
+                    self.chain.send_chain_update(SyncStage::Filters);
+                    self.dialog.send_info(Info::FilterStats {
+                        filters_checked: self.filters_checked,
+                        blocks_requested: self.blocks_requested,
+                        blocks_received: self.blocks_received,
+                    });
+                }
+                // Keep the configured number of batches in flight with this peer, and with others
+                // when downloading from several peers
+                let peers: Vec<PeerId> = std::iter::once(peer_id)
+                    .chain(
+                        self.peer_map
+                            .peer_info()
+                            .into_iter()
+                            .map(|peer| peer.id)
+                            .filter(|id| id.ne(&peer_id)),
+                    )
+                    .take(self.filter_download_peers)
+                    .collect();
+                for peer in peers {
+                    while self.chain.filter_batches_in_flight_with(peer) < self.pipeline_depth {
+                        match self.chain.pipelined_filter_message(peer) {
+                            Some(next_filters) => {
+                                self.peer_map
+                                    .send_message(peer, MainThreadMessage::GetFilters(next_filters))
+                                    .await;
+                            }
+                            None => break,
+                        }
                     }
                 }
                 None
             }
             Err(e) => {
-                self.dialog.send_warning(Warning::UnexpectedSyncError {
-                    warning: format!("Compact filter syncing encountered an error: {e}"),
-                });
+                let warning = match e {
+                    CFilterSyncError::MisalignedFilterHash => {
+                        Warning::InvalidFilter { peer: peer_id }
+                    }
+                    e => Warning::UnexpectedSyncError {
+                        warning: format!("Compact filter syncing encountered an error: {e}"),
+                    },
+                };
+                self.dialog.send_warning(warning);
                 self.peer_map.ban(peer_id).await;
                 Some(MainThreadMessage::Disconnect)
             }
         }
     }
 
+    // Drop deep headers once the chain is scanned. Blocks still in flight must have a known hash
+    // when they arrive, so pruning waits for the block queue to empty.
+    fn prune_headers(&mut self) {
+        let Some(depth) = self.prune_depth else {
+            return;
+        };
+        if !self.block_queue.complete() || !self.sourcing.is_empty() {
+            return;
+        }
+        let pruned = self.chain.prune_headers(depth);
+        if pruned > 0 {
+            crate::debug!(format!("Pruned {pruned} headers"));
+        }
+    }
+
     // Scan a block for transactions.
-    async fn handle_block(&mut self, peer_id: PeerId, block: Block) -> Option<MainThreadMessage> {
+    async fn handle_block(
+        &mut self,
+        peer_id: PeerId,
+        block: Block,
+        raw_block: Option<Vec<u8>>,
+    ) -> Option<MainThreadMessage> {
         let block_hash = block.block_hash();
         let height = match self.chain.header_chain.height_of_hash(block_hash) {
             Some(height) => height,
@@ -560,39 +1370,176 @@ impl Node {
                 return Some(MainThreadMessage::Disconnect);
             }
         };
+        if let Err(warning) = self.check_block(&block, height) {
+            self.dialog.send_warning(warning);
+            self.peer_map.ban(peer_id).await;
+            return Some(MainThreadMessage::Disconnect);
+        }
+        match self.accept_block(block, raw_block, height).await {
+            Some(ProcessBlockResponse::LateResponse) => {
+                crate::debug!(
+                    format!(
+                        "Peer {} responded late to a request for hash {}",
+                        peer_id, block_hash
+                    ),
+                    peer = peer_id,
+                    message = "block"
+                );
+            }
+            Some(ProcessBlockResponse::UnknownHash) => {
+                crate::debug!(
+                    format!("Peer {} responded with an irrelevant block", peer_id),
+                    peer = peer_id,
+                    message = "block"
+                );
+            }
+            _ => (),
+        }
+        None
+    }
+
+    // Check a block commits to its transactions, returning the warning to report if not
+    fn check_block(&self, block: &Block, height: u32) -> Result<(), Warning> {
         if !block.check_merkle_root() {
-            self.dialog.send_warning(Warning::UnexpectedSyncError {
+            return Err(Warning::UnexpectedSyncError {
                 warning: "A block received does not have a valid merkle root".into(),
             });
-            self.peer_map.ban(peer_id).await;
-            return Some(MainThreadMessage::Disconnect);
         }
+        if !self.chain.check_witness_commitment(block, height) {
+            return Err(Warning::InvalidWitnessCommitment { height });
+        }
+        Ok(())
+    }
+
+    // Process a valid block and deliver it to the client, returning the response of the block
+    // queue if no request was waiting on it
+    async fn accept_block(
+        &mut self,
+        block: Block,
+        raw_block: Option<Vec<u8>>,
+        height: u32,
+    ) -> Option<ProcessBlockResponse> {
+        let block_hash = block.block_hash();
+        let mut tx_queue = self.peer_map.tx_queue.lock().await;
+        let confirmed = tx_queue.confirmed(&block);
+        let conflicts = tx_queue.remove_conflicts(&block);
+        drop(tx_queue);
+        for wtxid in confirmed {
+            self.dialog.send_event(Event::TxConfirmed { wtxid, height });
+        }
+        for conflict in conflicts {
+            self.dialog.send_event(Event::BroadcastConflicted {
+                wtxid: conflict.wtxid,
+                conflicting_txid: conflict.conflicting_txid,
+                height,
+            });
+        }
+        self.fee_estimates.add(&block, height);
+        let size = raw_block
+            .as_ref()
+            .map_or_else(|| block.total_size(), |raw| raw.len());
+        self.block_queue.record_download(size);
         let process_block_response = self.block_queue.process_block(&block_hash);
         match process_block_response {
             ProcessBlockResponse::Accepted { block_recipient } => {
+                self.blocks_received += 1;
+                self.chain.index_transactions(&block, height);
                 self.dialog
                     .send_info(Info::BlockReceived(block.block_hash()));
-                let send_err = block_recipient
-                    .send(Ok(IndexedBlock::new(height, block)))
-                    .is_err();
+                let send_err = match block_recipient {
+                    BlockRecipient::Block(recipient) => recipient
+                        .send(Ok(IndexedBlock::new(height, block, raw_block)))
+                        .is_err(),
+                    BlockRecipient::MerkleProof { txid, recipient } => {
+                        let proof = if block.txdata.iter().any(|tx| tx.compute_txid() == txid) {
+                            Ok(MerkleBlock::from_block_with_predicate(&block, |id| {
+                                *id == txid
+                            }))
+                        } else {
+                            Err(FetchBlockError::UnknownTransaction)
+                        };
+                        recipient.send(proof).is_err()
+                    }
+                };
                 if send_err {
                     self.dialog.send_warning(Warning::ChannelDropped);
                 };
+                None
             }
-            ProcessBlockResponse::LateResponse => {
-                crate::debug!(format!(
-                    "Peer {} responded late to a request for hash {}",
-                    peer_id, block_hash
-                ));
-            }
-            ProcessBlockResponse::UnknownHash => {
-                crate::debug!(format!(
-                    "Peer {} responded with an irrelevant block",
-                    peer_id
-                ));
+            response => Some(response),
+        }
+    }
+
+    // Let clients read the tip without a request, only waking readers when it moved
+    fn publish_tip(&self) {
+        let tip = HashCheckpoint::new(
+            self.chain.header_chain.height(),
+            self.chain.header_chain.tip_hash(),
+        );
+        if self.tip_tx.borrow().ne(&tip) {
+            let _ = self.tip_tx.send(tip);
+        }
+    }
+
+    fn sync_update(&self) -> SyncUpdate {
+        SyncUpdate::new(
+            HashCheckpoint::new(
+                self.chain.header_chain.height(),
+                self.chain.header_chain.tip_hash(),
+            ),
+            self.chain.last_ten(),
+        )
+    }
+
+    // Resolve any outstanding requests and report the final state of the chain
+    fn shutdown(&mut self) {
+        for recipient in self.block_queue.drain() {
+            if !recipient.send_error(FetchBlockError::Cancelled) {
+                self.dialog.send_warning(Warning::ChannelDropped);
             }
         }
-        None
+        self.fail_filter_header_requests(|_| true, || ClientError::NodeShutdown);
+        self.report_incomplete_rescan();
+        self.dialog.send_event(Event::Shutdown(self.sync_update()));
+        self.dialog.deliver_pending_events();
+    }
+
+    // Enter a new state, running any callbacks registered for it
+    fn set_state(&mut self, state: NodeState) {
+        if self.state == state {
+            return;
+        }
+        self.state = state;
+        self.state_callbacks.run(state);
+    }
+
+    // Filters are never requested below the chain state, so the scan starts at the first
+    // unchecked filter above it
+    fn report_scan_start(&self) {
+        if let Some(height) = self.chain.lowest_unchecked_filter() {
+            self.dialog.send_info(Info::ScanStart { height });
+        }
+    }
+
+    // Let the user know where to resume if filters were still being checked
+    fn report_incomplete_rescan(&self) {
+        if self.state == NodeState::FiltersSynced {
+            return;
+        }
+        if let Some(next_height) = self.chain.lowest_unchecked_filter() {
+            self.dialog
+                .send_event(Event::RescanIncomplete { next_height });
+        }
+    }
+
+    // Peers that respond with a different filter type do not serve the one we requested
+    fn expected_filter_type(&self, received: u8) -> bool {
+        if u8::from(self.chain.filter_type()) == received {
+            return true;
+        }
+        self.dialog
+            .send_warning(Warning::UnsupportedFilterType { received });
+        false
     }
 
     // The block queue holds all the block hashes we may be interested in
@@ -601,12 +1548,46 @@ impl Node {
             self.state,
             NodeState::FilterHeadersSynced | NodeState::FiltersSynced
         ) {
-            let next_block_hash = self.block_queue.pop();
-            return next_block_hash.map(MainThreadMessage::GetBlock);
+            // Warn once each time downloads begin to be slowed
+            if self.block_queue.throttled() {
+                if !self.rate_limited {
+                    self.rate_limited = true;
+                    self.dialog.send_warning(Warning::RateLimited);
+                }
+                return None;
+            }
+            self.rate_limited = false;
+            let batch = self.block_queue.pop_batch();
+            if !batch.is_empty() {
+                return Some(MainThreadMessage::GetBlocks(batch));
+            }
         }
         None
     }
 
+    // A peer responded `notfound` to a block request. Rather than wait for the request to time
+    // out, ask another peer for the blocks that are still wanted.
+    fn handle_blocks_not_found(
+        &mut self,
+        peer_id: PeerId,
+        blocks: Vec<BlockHash>,
+    ) -> Option<MainThreadMessage> {
+        let mut wanted = Vec::new();
+        for hash in blocks {
+            if self.block_queue.not_found(&hash) {
+                self.dialog.send_warning(Warning::BlockUnavailable {
+                    hash,
+                    peer: peer_id,
+                });
+                wanted.push(hash);
+            }
+        }
+        if wanted.is_empty() {
+            return None;
+        }
+        Some(MainThreadMessage::GetBlocks(wanted))
+    }
+
     // A peer announced new blocks with an `inv` instead of `headers`. Bitcoin Core
     // falls back to inv-of-tip, even after BIP-130 `sendheaders`, when more than
     // eight blocks connect in a single announcement round or when a block queued
@@ -614,15 +1595,22 @@ impl Node {
     // `getheaders` and let the response drive any state changes through the usual
     // `handle_headers` path. Deliberately no `NodeState` mutation, no filter queue
     // changes, no tip assumption, and no `LastBlockMonitor` reset on the inv itself.
+    // Peers tend to announce the same block within moments of each other, so only the
+    // first announcement of an unknown block results in a `getheaders`.
     fn handle_inventory_blocks(&mut self, blocks: Vec<BlockHash>) -> Option<MainThreadMessage> {
         // A header sync is already in progress.
         if self.state == NodeState::Behind {
             return None;
         }
-        if blocks
-            .into_iter()
-            .all(|block| self.chain.header_chain.contains(block))
-        {
+        let mut any_new = false;
+        for block in blocks {
+            if !self.chain.header_chain.contains(block)
+                && self.announcements.first_announcement(block)
+            {
+                any_new = true;
+            }
+        }
+        if !any_new {
             return None;
         }
         let next_headers = GetHeadersMessage {
@@ -633,21 +1621,172 @@ impl Node {
         Some(MainThreadMessage::GetHeaders(next_headers))
     }
 
-    // Clear the filter hash cache and redownload the filters.
-    fn rescan(&mut self, height_opt: Option<u32>) -> Option<MainThreadMessage> {
+    // Replay or redownload the filters that are no longer checked.
+    async fn rescan(&mut self) {
         match self.state {
-            NodeState::Behind => None,
-            NodeState::HeadersSynced => None,
+            NodeState::Behind | NodeState::HeadersSynced => (),
             _ => {
-                self.chain.clear_filters();
-                if let Some(height) = height_opt {
-                    self.chain.header_chain.assume_checked_to(height);
+                self.set_state(NodeState::FilterHeadersSynced);
+                self.report_scan_start();
+                let replayed = self.chain.replay_cached_filters();
+                if replayed > 0 {
+                    crate::debug!(format!("Rescanned {replayed} filters from the cache"));
+                    self.filters_checked += replayed;
+                }
+                if self.chain.is_filters_synced() {
+                    return;
                 }
-                self.state = NodeState::FilterHeadersSynced;
-                Some(MainThreadMessage::GetFilters(
-                    self.chain.next_filter_message(),
-                ))
+                let Some(peer_id) = self.peer_map.random_peer() else {
+                    return;
+                };
+                let Some(filters) = self.chain.next_filter_message(peer_id) else {
+                    return;
+                };
+                self.peer_map
+                    .send_message(peer_id, MainThreadMessage::GetFilters(filters))
+                    .await;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use bitcoin::{hashes::Hash, BlockHash, Network};
+    use tokio::time::Instant;
+
+    use super::{PendingFilterHeaders, TransportVersion, FILTER_HEADERS_TIMEOUT};
+    use crate::error::{ClientError, FetchFilterHeadersError};
+    use crate::messages::ClientRequest;
+    use crate::network::{LocalVersion, PeerId, MAX_USER_AGENT_LENGTH};
+    use crate::{Builder, Warning};
+
+    #[tokio::test]
+    async fn test_filter_header_requests_fail() {
+        let (mut node, _client) = Builder::new(Network::Regtest).build();
+        let mut receivers = Vec::new();
+        for (peer, deadline) in [
+            (1, Instant::now() + FILTER_HEADERS_TIMEOUT),
+            (2, Instant::now()),
+            (3, Instant::now() + FILTER_HEADERS_TIMEOUT),
+        ] {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            node.filter_header_requests.push(PendingFilterHeaders {
+                request: ClientRequest::new((0, BlockHash::all_zeros()), tx),
+                peer: PeerId(peer),
+                deadline,
+            });
+            receivers.push(rx);
+        }
+        let now = Instant::now();
+        node.fail_filter_header_requests(
+            |pending| pending.deadline <= now,
+            || ClientError::RequestTimeout,
+        );
+        node.fail_filter_header_requests(
+            |pending| pending.peer.eq(&PeerId(3)),
+            || FetchFilterHeadersError::NoResponse.into(),
+        );
+        assert_eq!(node.filter_header_requests.len(), 1);
+        let mut receivers = receivers.into_iter();
+        assert!(receivers.next().unwrap().try_recv().is_err());
+        assert!(matches!(
+            receivers.next().unwrap().try_recv(),
+            Ok(Err(ClientError::RequestTimeout))
+        ));
+        assert!(matches!(
+            receivers.next().unwrap().try_recv(),
+            Ok(Err(ClientError::FetchFilterHeaders(
+                FetchFilterHeadersError::NoResponse
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stalling_peer_is_replaced() {
+        let (mut node, mut client) = Builder::new(Network::Regtest).build();
+        let peer_id = PeerId(1);
+        // Empty responses while the network is ahead count towards replacing the peer
+        assert!(!node.peer_stalled(peer_id, true));
+        assert!(!node.peer_stalled(peer_id, true));
+        assert!(node.peer_stalled(peer_id, true));
+        assert!(matches!(
+            client.warn_rx.try_recv(),
+            Ok(Warning::PeerStalling { id }) if id == peer_id
+        ));
+        assert!(node.empty_headers.is_empty());
+        // An empty response when the network is not ahead clears the count
+        assert!(!node.peer_stalled(peer_id, true));
+        assert!(!node.peer_stalled(peer_id, true));
+        assert!(!node.peer_stalled(peer_id, false));
+        assert!(node.empty_headers.is_empty());
+        assert!(!node.peer_stalled(peer_id, true));
+        assert!(client.warn_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_long_user_agent_rejected() {
+        let user_agent = "a".repeat(MAX_USER_AGENT_LENGTH + 1);
+        let (node, mut client) = Builder::new(Network::Regtest)
+            .user_agent(user_agent)
+            .build();
+        assert!(matches!(
+            client.warn_rx.try_recv(),
+            Ok(Warning::UnexpectedSyncError { .. })
+        ));
+        assert_eq!(
+            node.peer_map.local_version().user_agent,
+            LocalVersion::default().user_agent
+        );
+        let user_agent = "a".repeat(MAX_USER_AGENT_LENGTH);
+        let (node, mut client) = Builder::new(Network::Regtest)
+            .user_agent(user_agent.clone())
+            .build();
+        assert!(client.warn_rx.try_recv().is_err());
+        assert_eq!(node.peer_map.local_version().user_agent, user_agent);
+    }
+
+    #[tokio::test]
+    async fn test_disconnected_peer_forgotten() {
+        let (mut node, _client) = Builder::new(Network::Regtest).build();
+        let (peer_id, other) = (PeerId(1), PeerId(2));
+        for id in [peer_id, other] {
+            node.tip_confirmed_by.insert(id);
+            node.unconnecting_headers.insert(id, 1);
+            node.empty_headers.insert(id, 1);
+        }
+        // A peer that disconnected after confirming the tip must not count towards the required
+        // confirmations
+        node.forget_peer(peer_id);
+        assert!(!node.tip_confirmed_by.contains(&peer_id));
+        assert!(!node.unconnecting_headers.contains_key(&peer_id));
+        assert!(!node.empty_headers.contains_key(&peer_id));
+        assert!(node.tip_confirmed_by.contains(&other));
+        assert_eq!(node.unconnecting_headers.len(), 1);
+        assert_eq!(node.empty_headers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_peer_info_marks_sync_peer() {
+        let (mut node, _client) = Builder::new(Network::Regtest).build();
+        for id in 1..=3 {
+            node.peer_map
+                .insert_live_peer(id, Ipv4Addr::new(10, 0, 0, id as u8), 100);
+        }
+        // The third peer has not completed the version handshake
+        node.peer_map.set_transport(PeerId(1), TransportVersion::V1);
+        node.peer_map.set_transport(PeerId(2), TransportVersion::V2);
+        node.sync_peer = Some(PeerId(2));
+        let mut peers = node.peer_info();
+        peers.sort_by_key(|peer| peer.id.0);
+        assert_eq!(peers.len(), 2);
+        assert!(!peers[0].is_sync_peer);
+        assert!(peers[1].is_sync_peer);
+        assert_eq!(peers[1].transport, TransportVersion::V2);
+        assert_eq!(peers[1].start_height, 100);
+        node.forget_peer(PeerId(2));
+        assert!(node.peer_info().iter().all(|peer| !peer.is_sync_peer));
+    }
+}
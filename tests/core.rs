@@ -1,6 +1,11 @@
 use std::{
+    collections::HashMap,
     net::{IpAddr, SocketAddrV4},
     path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
@@ -8,7 +13,8 @@ use bip157::{
     chain::{checkpoints::HashCheckpoint, BlockHeaderChanges, ChainState},
     client::Client,
     node::Node,
-    Address, BlockHash, Event, Info, ServiceFlags, Transaction, TrustedPeer, Warning,
+    Address, BlockHash, BlockSource, BlockSourceError, BlockingClient, Event, FutureResult, Info,
+    ServiceFlags, Transaction, TrustedPeer, Warning,
 };
 use bitcoin::{
     absolute,
@@ -87,7 +93,7 @@ async fn invalidate_block(rpc: &corepc_node::Client, hash: &bitcoin::BlockHash)
     tokio::time::sleep(Duration::from_secs(2)).await;
 }
 
-async fn sync_assert(best: &bitcoin::BlockHash, channel: &mut UnboundedReceiver<Event>) {
+async fn sync_assert(best: &bitcoin::BlockHash, channel: &mut Receiver<Event>) {
     loop {
         tokio::select! {
             event = channel.recv() => {
@@ -118,6 +124,65 @@ async fn print_logs(mut info_rx: Receiver<Info>, mut warn_rx: UnboundedReceiver<
     }
 }
 
+#[tokio::test]
+async fn regtest_default_sync() {
+    let (bitcoind, socket_addr) = start_bitcoind(true).unwrap();
+    let rpc = &bitcoind.client;
+    let tempdir = tempfile::TempDir::new().unwrap().path().to_owned();
+    let miner = rpc.new_address().unwrap();
+    mine_blocks(rpc, &miner, 5, 2).await;
+    let best = best_hash(rpc);
+    // Without a chain state, the node syncs from the regtest genesis on the default port
+    let host = (IpAddr::V4(*socket_addr.ip()), Some(socket_addr.port()));
+    let (node, client) = bip157::builder::Builder::new(bitcoin::Network::Regtest)
+        .add_peer(host)
+        .data_dir(tempdir)
+        .build();
+    tokio::task::spawn(async move { node.run().await });
+    let Client {
+        requester,
+        info_rx,
+        warn_rx,
+        event_rx: mut channel,
+    } = client;
+    tokio::task::spawn(async move { print_logs(info_rx, warn_rx).await });
+    sync_assert(&best, &mut channel).await;
+    let cp = requester.chain_tip().await.unwrap();
+    assert_eq!(cp.height, 5);
+    requester.shutdown().unwrap();
+    rpc.stop().unwrap();
+}
+
+#[test]
+fn blocking_client_sync() {
+    let (bitcoind, socket_addr) = start_bitcoind(true).unwrap();
+    let rpc = &bitcoind.client;
+    let tempdir = tempfile::TempDir::new().unwrap().path().to_owned();
+    let miner = rpc.new_address().unwrap();
+    rpc.generate_to_address(5, &miner).unwrap();
+    let best = best_hash(rpc);
+    let host = (IpAddr::V4(*socket_addr.ip()), Some(socket_addr.port()));
+    let (node, client) = bip157::builder::Builder::new(bitcoin::Network::Regtest)
+        .add_peer(host)
+        .data_dir(tempdir)
+        .build();
+    // The node runs on a runtime owned by the client, so this test is not async
+    let mut client = BlockingClient::new(node, client).unwrap();
+    while let Some(event) = client.next_event() {
+        if let Event::FiltersSynced(update) = event {
+            assert_eq!(update.tip().hash, best);
+            break;
+        }
+    }
+    assert_eq!(client.requester().tip(), HashCheckpoint::new(5, best));
+    let work = client
+        .block_on(client.requester().relative_chain_work())
+        .unwrap();
+    assert!(work > bitcoin::Work::from_be_bytes([0; 32]));
+    client.shutdown().unwrap();
+    rpc.stop().unwrap();
+}
+
 #[tokio::test]
 async fn live_reorg() {
     let (bitcoind, socket_addr) = start_bitcoind(true).unwrap();
@@ -275,6 +340,22 @@ async fn various_client_methods() {
     let fake_hash: BlockHash = bitcoin::hashes::Hash::all_zeros();
     let unknown = requester.height_of_hash(fake_hash).await.unwrap();
     assert!(unknown.is_none());
+    // A merkle proof is checked against the merkle root of the block
+    let block = requester.get_block(cp.hash).await.unwrap().block;
+    let coinbase = block.txdata[0].compute_txid();
+    let proof = requester.get_merkle_proof(cp.hash, coinbase).await.unwrap();
+    assert_eq!(proof.header, block.header);
+    let mut matches = Vec::new();
+    let mut indexes = Vec::new();
+    assert!(proof.extract_matches(&mut matches, &mut indexes).is_ok());
+    assert_eq!(matches, vec![coinbase]);
+    let fake_txid: bitcoin::Txid = bitcoin::hashes::Hash::all_zeros();
+    assert!(matches!(
+        requester.get_merkle_proof(cp.hash, fake_txid).await,
+        Err(bip157::ClientError::FetchBlock(
+            bip157::error::FetchBlockError::UnknownTransaction
+        ))
+    ));
     requester.shutdown().unwrap();
     rpc.stop().unwrap();
 }
@@ -725,6 +806,72 @@ async fn whitelist_only_sync() {
     rpc.stop().unwrap();
 }
 
+#[derive(Debug)]
+struct MapSource {
+    blocks: HashMap<BlockHash, bitcoin::Block>,
+    served: Arc<AtomicUsize>,
+}
+
+impl BlockSource for MapSource {
+    fn get_block<'a>(
+        &'a self,
+        hash: BlockHash,
+    ) -> FutureResult<'a, bitcoin::Block, BlockSourceError> {
+        Box::pin(async move {
+            let block = self
+                .blocks
+                .get(&hash)
+                .cloned()
+                .ok_or(BlockSourceError::NotFound)?;
+            self.served.fetch_add(1, Ordering::Relaxed);
+            Ok(block)
+        })
+    }
+}
+
+#[tokio::test]
+async fn block_source_serves_blocks() {
+    let (bitcoind, socket_addr) = start_bitcoind(true).unwrap();
+    let rpc = &bitcoind.client;
+    let tempdir = tempfile::TempDir::new().unwrap().path().to_owned();
+    let miner = rpc.new_address().unwrap();
+    mine_blocks(rpc, &miner, 10, 2).await;
+    let best = best_hash(rpc);
+    let served = Arc::new(AtomicUsize::new(0));
+    let source = MapSource {
+        blocks: HashMap::from([(best, rpc.get_block(best).unwrap())]),
+        served: Arc::clone(&served),
+    };
+    let host = (IpAddr::V4(*socket_addr.ip()), Some(socket_addr.port()));
+    let builder = bip157::builder::Builder::new(bitcoin::Network::Regtest)
+        .chain_state(ChainState::Checkpoint(HashCheckpoint::from_genesis(
+            bitcoin::Network::Regtest,
+        )))
+        .add_peer(host)
+        .block_source(source)
+        .data_dir(&tempdir);
+    let (node, client) = builder.build();
+    tokio::task::spawn(async move { node.run().await });
+    let Client {
+        requester,
+        info_rx,
+        warn_rx,
+        event_rx: mut channel,
+    } = client;
+    tokio::task::spawn(async move { print_logs(info_rx, warn_rx).await });
+    sync_assert(&best, &mut channel).await;
+    let block = requester.get_block(best).await.unwrap();
+    assert_eq!(block.block.block_hash(), best);
+    assert_eq!(served.load(Ordering::Relaxed), 1);
+    // Blocks the source does not have are downloaded from a peer
+    let header = requester.get_header(5).await.unwrap().unwrap();
+    let block = requester.get_block(header.block_hash()).await.unwrap();
+    assert_eq!(block.height, 5);
+    assert_eq!(served.load(Ordering::Relaxed), 1);
+    requester.shutdown().unwrap();
+    rpc.stop().unwrap();
+}
+
 #[tokio::test]
 async fn inv_fallback_after_burst_mine() {
     let (bitcoind, socket_addr) = start_bitcoind(true).unwrap();